@@ -0,0 +1,116 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use ray::Ray;
+use vector3::Vector3;
+
+/// An axis-aligned bounding box.
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    /// The corner with the smallest coordinates.
+    pub min: Vector3,
+
+    /// The corner with the largest coordinates.
+    pub max: Vector3
+}
+
+impl Aabb {
+    pub fn new(min: Vector3, max: Vector3) -> Aabb {
+        Aabb {
+            min: min,
+            max: max
+        }
+    }
+
+    /// Returns the smallest box that contains both boxes.
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Vector3::new(self.min.x.min(other.min.x),
+                              self.min.y.min(other.min.y),
+                              self.min.z.min(other.min.z)),
+            max: Vector3::new(self.max.x.max(other.max.x),
+                              self.max.y.max(other.max.y),
+                              self.max.z.max(other.max.z))
+        }
+    }
+
+    /// Returns the box that lies inside both boxes. If the boxes
+    /// do not overlap, the result is empty.
+    pub fn overlap(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Vector3::new(self.min.x.max(other.min.x),
+                              self.min.y.max(other.min.y),
+                              self.min.z.max(other.min.z)),
+            max: Vector3::new(self.max.x.min(other.max.x),
+                              self.max.y.min(other.max.y),
+                              self.max.z.min(other.max.z))
+        }
+    }
+
+    /// Returns the centre of the box.
+    pub fn centre(self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Returns the extent of the box along every axis.
+    pub fn size(self) -> Vector3 {
+        self.max - self.min
+    }
+
+    /// Returns the distances along the ray at which it enters and leaves
+    /// the box, if it hits the box at all. The entry distance is negative
+    /// if the ray starts inside the box.
+    pub fn intersect_ray(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let mut near = -1.0e30f32;
+        let mut far = 1.0e30f32;
+
+        // Clip the ray against the two planes (the slab) of every axis.
+        for i in 0 .. 3 {
+            let inv_d = 1.0 / ray.direction[i];
+            let t1 = (self.min[i] - ray.origin[i]) * inv_d;
+            let t2 = (self.max[i] - ray.origin[i]) * inv_d;
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+
+        if near <= far && far > 0.0 {
+            Some((near, far))
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn intersect_ray_with_box() {
+    let aabb = Aabb::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0));
+    let ray = Ray {
+        origin: Vector3::new(-5.0, 0.0, 0.0),
+        direction: Vector3::new(1.0, 0.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let (near, far) = aabb.intersect_ray(&ray).unwrap();
+    assert_eq!((near, far), (4.0, 6.0));
+
+    let miss = Ray {
+        origin: Vector3::new(-5.0, 2.0, 0.0),
+        direction: Vector3::new(1.0, 0.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    assert!(aabb.intersect_ray(&miss).is_none());
+}
@@ -16,112 +16,335 @@
 
 extern crate num_cpus;
 
-use std::sync::mpsc::{Sender, Receiver, channel};
 use std::f32::consts::PI;
-use std::sync::{Arc, Mutex};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time;
-use camera::Camera;
+use camera::{Camera, LensSampling};
+use checkpoint::Fingerprint;
 use constants::GOLDEN_RATIO;
+use environment::Environment;
 use gather_unit::GatherUnit;
+use latest::{Sender, Receiver, channel};
 use geometry::{Circle, Paraboloid, Plane, Sphere, Surface, new_hexagonal_prism};
 use material::{BlackBodyMaterial,
+               ColouredGlassMaterial,
                DiffuseGreyMaterial,
                DiffuseColouredMaterial,
                GlossyMirrorMaterial,
                Sf10GlassMaterial,
                SoapBubbleMaterial};
+use medium::Medium;
 use object::Object;
 use plot_unit::PlotUnit;
 use quaternion::Quaternion;
 use ray::Ray;
-use scene::Scene;
-use task_scheduler::{Task, TaskScheduler};
+use scene::{LightSelection, Scene};
+use settings::Settings;
+use task_scheduler::{Task, TaskScheduler, camera_path};
 use tonemap_unit::TonemapUnit;
-use trace_unit::TraceUnit;
+use trace_unit::{RenderMode, TraceUnit};
 use vector3::Vector3;
 
 pub type Image = Vec<u8>;
 
+/// The file that the gather unit saves its checkpoints to, so that an
+/// interrupted render can be resumed.
+const CHECKPOINT_PATH: &'static str = "checkpoint.raw";
+
+/// The file that the exposure of the latest image is saved to, next to the
+/// checkpoint of the gather unit, so a resumed render is exposed the same.
+const EXPOSURE_PATH: &'static str = "exposure.txt";
+
+/// Images that were handed back by the receiver, so that their
+/// buffers can be reused for the next image.
+type ImagePool = Arc<Mutex<Vec<Image>>>;
+
+/// Locks the mutex, also if it was poisoned. A mutex is poisoned when a
+/// worker panics while holding it. Neither the task scheduler nor the scene
+/// or image pool is left in an inconsistent state halfway through an update,
+/// so the other workers can safely continue instead of panicking too.
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 pub struct App {
-    /// Channel that produces a rendered image periodically.
-    pub images: Receiver<Image>
+    /// Channels that produce a rendered image periodically, one for every
+    /// camera of the scene. A channel holds only the latest image; older
+    /// images that were not received are recycled.
+    pub images: Vec<Receiver<Image>>,
+
+    /// The task scheduler that the workers get their tasks from.
+    task_scheduler: Arc<Mutex<TaskScheduler>>,
+
+    /// The scene that is being rendered. Workers take the current scene
+    /// for every task, so it can be replaced while rendering.
+    scene: Arc<Mutex<Arc<Scene>>>,
+
+    /// Images that can be overwritten with a new image.
+    spare_images: ImagePool,
+
+    /// Tells the workers to stop after their current task.
+    stop: Arc<AtomicBool>,
+
+    /// The worker threads, which are joined on shutdown.
+    workers: Vec<thread::JoinHandle<()>>
 }
 
 impl App {
     /// Constructs and starts a new path tracer that renders to a canvas of
     /// the specified size.
-    pub fn new(image_width: u32, image_height: u32) -> App {
+    pub fn new(image_width: u32, image_height: u32, settings: &Settings) -> App {
+        // Set up the scene that will be rendered, and warn about mistakes.
+        let mut scene = (settings.scene)();
+        if let Some(ref path) = settings.environment {
+            match Environment::open(path) {
+                Ok(environment) => scene.environment = Some(environment),
+                Err(reason) => println!("failed to read {}: {}", path.display(), reason)
+            }
+        }
+        match settings.light_selection {
+            LightSelection::Uniform => { }
+            LightSelection::Power => scene.weight_lights_by_power(),
+            LightSelection::Contribution => scene.weight_lights_by_contribution()
+        }
+        if let Err(errors) = scene.validate() {
+            for error in errors {
+                println!("invalid scene: {}", error);
+            }
+        }
+
         let concurrency = num_cpus::get();
-        let ts = TaskScheduler::new(concurrency, image_width, image_height);
+        let cameras = scene.number_of_cameras();
+        let mut ts = TaskScheduler::new(concurrency, cameras, image_width, image_height);
+
+        // A resumed render continues with the seed that it was started with.
+        ts.set_seed(settings.seed);
+        ts.set_frame_seed(settings.frame_seed);
+
+        // Try to continue a previous render, but only of the same scene in
+        // the same render mode.
+        let mut fingerprint = Fingerprint::new();
+        scene.fingerprint(&mut fingerprint);
+        settings.environment.hash(&mut fingerprint);
+        mem::discriminant(&settings.render_mode).hash(&mut fingerprint);
+        if let RenderMode::AmbientOcclusion(radius) = settings.render_mode {
+            fingerprint.write_u32(radius.to_bits());
+        }
+        ts.set_fingerprint(fingerprint.finish());
+        ts.set_checkpoint_path(Some(PathBuf::from(CHECKPOINT_PATH)));
+        ts.set_exposure_path(Some(PathBuf::from(EXPOSURE_PATH)));
+        ts.resume();
+        ts.set_time_budget(settings.max_duration);
+        ts.set_batch_budget(settings.max_batches);
+        if let Some(interval) = settings.tonemap_interval {
+            ts.set_tonemap_interval(interval);
+        }
+        ts.set_plot_batch_size(settings.plot_batch_size);
+        ts.configure_trace_units(|unit| {
+            unit.render_mode = settings.render_mode;
+            unit.wavelength_sampling = settings.wavelength_sampling;
+            unit.screen_sampling = settings.screen_sampling;
+            unit.direct_lighting = settings.direct_lighting;
+            unit.set_sobol_sampling(settings.sobol_sampling);
+        });
+        if let Some(ref weights) = settings.light_weights {
+            ts.configure_plot_units(|unit| unit.enable_light_groups(weights.len()));
+        }
+        ts.configure_gather_units(|unit| {
+            unit.light_weights = settings.light_weights.clone();
+            unit.set_double_precision(settings.double_precision);
+            unit.set_outlier_rejection(settings.outlier_threshold);
+            unit.print_snr = settings.print_stats;
+            unit.set_npy_path(settings.npy_path.as_ref().map(|p| camera_path(p, unit.camera)));
+            unit.set_channel_images_path(settings.channel_images_path.as_ref()
+                                             .map(|p| camera_path(p, unit.camera)));
+            #[cfg(feature = "mmap")]
+            {
+                if let Some(ref path) = settings.mapped_path {
+                    let path = camera_path(path, unit.camera);
+                    if let Err(reason) = unit.map_buffers(&path) {
+                        println!("failed to map {}: {}", path.display(), reason);
+                    }
+                }
+            }
+        });
+        ts.configure_tonemap_units(|unit| {
+            unit.exposure_mode = settings.exposure_mode;
+            unit.tone_map = (settings.tone_map)();
+            unit.grey_card = settings.grey_card;
+            unit.response_curve = settings.response_curve.clone();
+            unit.finite_check = settings.finite_check;
+        });
+
+        App::start(scene, ts, concurrency)
+    }
+
+    /// Starts `concurrency` workers that render `scene` with the tasks of the
+    /// task scheduler. If the scheduler has a budget, the workers stop when
+    /// the render is finished, and the image channels close after the final
+    /// images.
+    fn start(scene: Scene, ts: TaskScheduler, concurrency: usize) -> App {
+        let cameras = ts.number_of_cameras();
         let task_scheduler = Arc::new(Mutex::new(ts));
 
-        // Channel for communicating back to the main task.
-        let (img_tx, img_rx) = channel();
+        // Channels for communicating back to the main task, one per camera.
+        let (img_txs, img_rxs): (Vec<_>, Vec<_>) = (0 .. cameras).map(|_| channel()).unzip();
 
-        // Set up the scene that will be rendered.
-        let scene = Arc::new(App::set_up_scene());
+        let scene = Arc::new(Mutex::new(Arc::new(scene)));
+        let spare_images = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
 
         // Spawn as many workers as cores.
-        for _ in 0 .. concurrency {
+        let workers = (0 .. concurrency).map(|_| {
             App::start_worker(task_scheduler.clone(),
                               scene.clone(),
-                              img_tx.clone());
+                              spare_images.clone(),
+                              img_txs.clone(),
+                              stop.clone())
+        }).collect();
+
+        App {
+            images: img_rxs,
+            task_scheduler: task_scheduler,
+            scene: scene,
+            spare_images: spare_images,
+            stop: stop,
+            workers: workers
+        }
+    }
+
+    /// Stops rendering. The workers finish their current task, and this
+    /// returns once all of them have stopped.
+    pub fn shutdown(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for worker in self.workers {
+            // A worker that panicked has stopped too.
+            let _ = worker.join();
         }
+    }
+
+    /// Hands back an image that was received, and that is no longer needed.
+    /// Its buffer is then reused for a future image, instead of allocating
+    /// a new one. Images need not be recycled.
+    pub fn recycle(&self, img: Image) {
+        lock(&self.spare_images).push(img);
+    }
 
-        App { images: img_rx }
+    /// Returns the fraction of the batch budget that has been traced, between
+    /// 0.0 and 1.0. Without a batch budget this is 0.0.
+    pub fn progress_fraction(&self) -> f32 {
+        lock(&self.task_scheduler).progress_fraction()
+    }
+
+    /// Replaces the scene that is being rendered, and discards everything
+    /// that was rendered so far. The workers continue with the new scene,
+    /// which must have as many cameras as the scene it replaces.
+    #[allow(dead_code)]
+    pub fn restart(&self, scene: Scene) {
+        assert_eq!(scene.number_of_cameras(), lock(&self.task_scheduler).number_of_cameras());
+
+        // Replace the scene first, so that any task handed out after
+        // restarting the scheduler renders the new scene.
+        *lock(&self.scene) = Arc::new(scene);
+        lock(&self.task_scheduler).restart();
+    }
+
+    /// Changes the size of the images, and discards everything that was
+    /// rendered so far. An image that was tonemapped before may still arrive
+    /// at the old size; its length tells it apart.
+    #[allow(dead_code)]
+    pub fn resize(&self, image_width: u32, image_height: u32) {
+        lock(&self.task_scheduler).resize(image_width, image_height);
     }
 
     #[cfg(test)]
     pub fn new_test(image_width: u32, image_height: u32) -> App {
         // Set up a task scheduler and scene with no concurrency.
-        let mut ts = TaskScheduler::new(1, image_width, image_height);
-        let (mut img_tx, img_rx) = channel();
         let scene = Arc::new(App::set_up_scene());
+        let cameras = scene.number_of_cameras();
+        let mut ts = TaskScheduler::new(1, cameras, image_width, image_height);
+        let (img_txs, img_rxs): (Vec<_>, Vec<_>) = (0 .. cameras).map(|_| channel()).unzip();
+        let spare_images = Arc::new(Mutex::new(Vec::new()));
 
         // Run 5 tasks serially, on this thread.
         let mut task = Task::Sleep;
         for _ in 0u8 .. 5 {
             task = ts.get_new_task(task);
-            App::execute_task(&mut task, &scene, &mut img_tx);
+            App::execute_task(&mut task, &scene, &spare_images, &img_txs);
         }
 
-        App { images: img_rx }
+        App {
+            images: img_rxs,
+            task_scheduler: Arc::new(Mutex::new(ts)),
+            scene: Arc::new(Mutex::new(scene)),
+            spare_images: spare_images,
+            stop: Arc::new(AtomicBool::new(false)),
+            workers: Vec::new()
+        }
     }
 
     fn start_worker(task_scheduler: Arc<Mutex<TaskScheduler>>,
-                    scene: Arc<Scene>,
-                    img_tx: Sender<Image>) {
+                    scene: Arc<Mutex<Arc<Scene>>>,
+                    spare_images: ImagePool,
+                    img_txs: Vec<Sender<Image>>,
+                    stop: Arc<AtomicBool>)
+                    -> thread::JoinHandle<()> {
         thread::spawn(move || {
-            // Move img_tx into the proc.
-            let mut owned_img_tx = img_tx;
-
             // There is no task yet, but the task scheduler expects
             // a completed task. Therefore, this worker is done sleeping.
             let mut task = Task::Sleep;
 
-            // Continue rendering forever, unless the application is terminated.
-            loop {
+            // Continue rendering until the application shuts down.
+            while !stop.load(Ordering::SeqCst) {
                 // Ask the task scheduler for a new task, complete the old one.
-                // Then execute it.
-                task = task_scheduler.lock().unwrap().get_new_task(task);
-                App::execute_task(&mut task, &scene, &mut owned_img_tx);
+                // Then execute it. Once the render is finished, there is
+                // nothing left to do.
+                {
+                    let mut ts = lock(&task_scheduler);
+                    task = ts.get_new_task(task);
+                    if ts.is_finished() { break; }
+                }
+                let current_scene = lock(&scene).clone();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    App::execute_task(&mut task, &current_scene, &spare_images, &img_txs);
+                }));
+
+                // If the task panicked, its units would be lost with it, and
+                // the render would stall once a unit that there is only one
+                // of is gone. Hand them back, and carry on with a new task.
+                if result.is_err() {
+                    let failed = mem::replace(&mut task, Task::Sleep);
+                    lock(&task_scheduler).abandon_task(failed);
+                }
             }
-        });
+        })
     }
 
-    fn execute_task(task: &mut Task, scene: &Scene, img_tx: &mut Sender<Image>) {
+    /// Executes the task. A tonemapped image is sent to the sender of the
+    /// camera that it was rendered through.
+    fn execute_task(task: &mut Task,
+                    scene: &Scene,
+                    spare_images: &ImagePool,
+                    img_txs: &[Sender<Image>]) {
         match *task {
             Task::Sleep =>
                 App::execute_sleep_task(),
             Task::Trace(ref mut trace_unit) =>
                 App::execute_trace_task(scene, trace_unit),
-            Task::Plot(ref mut plot_unit, ref mut units) =>
-                App::execute_plot_task(plot_unit, &mut units[..]),
+            Task::Plot(ref mut plot_unit, ref units) =>
+                App::execute_plot_task(plot_unit, &units[..]),
             Task::Gather(ref mut gather_unit, ref mut units) =>
                 App::execute_gather_task(gather_unit, &mut units[..]),
-            Task::Tonemap(ref mut tonemap_unit, ref mut gather_unit) =>
-                App::execute_tonemap_task(img_tx, tonemap_unit, gather_unit)
+            Task::Tonemap(ref mut tonemap_unit, ref mut gather_unit) => {
+                let img_tx = &img_txs[tonemap_unit.camera];
+                App::execute_tonemap_task(img_tx, spare_images, tonemap_unit, gather_unit)
+            }
         }
     }
 
@@ -134,7 +357,7 @@ impl App {
     }
 
     fn execute_plot_task(plot_unit: &mut PlotUnit,
-                         units: &mut[Box<TraceUnit>]) {
+                         units: &[Box<TraceUnit>]) {
         for unit in units {
             plot_unit.plot(&unit.mapped_photons);
         }
@@ -144,26 +367,58 @@ impl App {
                            units: &mut[Box<PlotUnit>]) {
         for unit in units {
             gather_unit.accumulate(&unit.tristimulus_buffer);
+            gather_unit.accumulate_groups(&unit.group_buffers);
             unit.clear();
         }
 
         // Save the gather state, so that rendering can be continued later.
-        gather_unit.save();
+        // If that fails, the state is saved again after the next gather.
+        if let Err(reason) = gather_unit.save() {
+            println!("failed to save raw buffer: {}", reason);
+        }
     }
 
-    fn execute_tonemap_task(img_tx: &mut Sender<Image>,
+    fn execute_tonemap_task(img_tx: &Sender<Image>,
+                            spare_images: &ImagePool,
                             tonemap_unit: &mut TonemapUnit,
                             gather_unit: &mut GatherUnit) {
-        tonemap_unit.tonemap(&gather_unit.tristimulus_buffer);
+        // With light weights, the image is relit before it is displayed.
+        match gather_unit.light_weights {
+            Some(ref weights) => tonemap_unit.tonemap(&gather_unit.relight(weights)),
+            None => tonemap_unit.tonemap(&gather_unit.tristimulus_buffer)
+        }
+        if let Err(reason) = tonemap_unit.save() {
+            println!("failed to save exposure: {}", reason);
+        }
+        if let Some(report) = tonemap_unit.non_finite {
+            if let Some((x, y)) = report.first {
+                println!("{} pixels are not finite, the first one at ({}, {})", report.count, x, y);
+            }
+        }
+        if gather_unit.print_snr {
+            println!("signal-to-noise ratio: {:.1}", gather_unit.estimate_snr());
+        }
 
-        // Copy the rendered image.
-        let img = tonemap_unit.rgb_buffer.clone();
+        // Copy the rendered image, into a recycled buffer if there is one.
+        let spare = lock(spare_images).pop();
+        let img = match spare {
+            Some(mut img) => {
+                img.clear();
+                img.extend_from_slice(&tonemap_unit.rgb_buffer);
+                img
+            }
+            None => tonemap_unit.rgb_buffer.clone()
+        };
 
-        // And send it to the UI / main task.
-        img_tx.send(img).unwrap();
+        // And send it to the UI / main task. If the previous image has not
+        // been received yet, it is outdated now, and its buffer can be reused.
+        if let Some(stale) = img_tx.send(img) {
+            lock(spare_images).push(stale);
+        }
     }
 
-    fn set_up_scene() -> Scene {
+    /// Returns the scene that is rendered by default.
+    pub fn set_up_scene() -> Scene {
         use object::MaterialBox::{Emissive, Reflective};
 
         let mut objects = Vec::new();
@@ -318,8 +573,17 @@ impl App {
 
                 let prism = Box::new(new_hexagonal_prism(normal, position, 3.0, 1.0,
                                                          phi + phi_ofs, prism_height * h));
-                let glass = Box::new(Sf10GlassMaterial);
-                let object = Object::new(prism, Reflective(glass));
+                // The outer prisms are tinted, through the spectrum, the
+                // inner ones are slightly milky.
+                let object = if ofs > 0.0 {
+                    let wavelength = 420.0 + i as f32 * 260.0 / (prisms - 1) as f32;
+                    let glass = Box::new(ColouredGlassMaterial::new(0.4, wavelength, 40.0));
+                    Object::new(prism, Reflective(glass))
+                } else {
+                    let mut object = Object::new(prism, Reflective(Box::new(Sf10GlassMaterial)));
+                    object.set_medium(Medium::new(0.0, 0.05));
+                    object
+                };
                 objects.push(object);
             }
         }
@@ -352,13 +616,416 @@ impl App {
                 depth_of_field: 2.0,
                 // A subtle amount of chromatic abberation.
                 chromatic_abberation: 0.012,
-                orientation: orientation
+                orientation: orientation,
+                lens_sampling: LensSampling::Concentric,
+                lens_shift: (0.0, 0.0)
             }
         }
 
-        Scene {
-            objects: objects,
-            get_camera_at_time: make_camera
+        let mut scene = Scene::new(objects, make_camera);
+        scene.build_acceleration();
+        scene
+    }
+}
+
+#[test]
+fn restart_renders_new_scene() {
+    use object::MaterialBox::Emissive;
+
+    // A scene where the camera looks straight at a big light,
+    // and a scene that contains nothing at all.
+    let wall = Box::new(Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 5.0, 0.0)));
+    let light = Object::new(wall, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let bright_scene = Scene::new(vec!(light), ::camera::camera_at_origin);
+    let empty_scene = Scene::new(Vec::new(), ::camera::camera_at_origin);
+
+    // After restarting, nothing of the bright scene may remain.
+    let (before, after) = render_around_change(bright_scene, |app| app.restart(empty_scene));
+    assert!(before.iter().any(|&x| x > 0));
+    assert!(after.iter().all(|&x| x == 0));
+}
+
+#[test]
+fn resize_renders_at_new_size() {
+    use object::MaterialBox::Emissive;
+
+    let wall = Box::new(Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 5.0, 0.0)));
+    let light = Object::new(wall, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec!(light), ::camera::camera_at_origin);
+
+    // The units of the tonemap task are still busy while resizing, they are
+    // resized when the task completes.
+    let (before, after) = render_around_change(scene, |app| app.resize(4, 2));
+    assert_eq!(before.len(), 8 * 8 * 3);
+    assert_eq!(after.len(), 4 * 2 * 3);
+    assert!(after.iter().any(|&x| x > 0));
+}
+
+#[test]
+fn recycled_images_are_reused() {
+    let (img_tx, img_rx) = channel();
+    let spare_images = Arc::new(Mutex::new(Vec::new()));
+    let mut tonemap_unit = TonemapUnit::new(4, 4);
+    let mut gather_unit = GatherUnit::new(4, 4);
+
+    App::execute_tonemap_task(&img_tx, &spare_images, &mut tonemap_unit, &mut gather_unit);
+    let first = img_rx.recv().unwrap();
+    let first_ptr = first.as_ptr();
+    spare_images.lock().unwrap().push(first);
+
+    // The second image should be written into the buffer of the first one.
+    for _ in 0 .. 3 {
+        App::execute_tonemap_task(&img_tx, &spare_images, &mut tonemap_unit, &mut gather_unit);
+        let img = img_rx.recv().unwrap();
+        assert_eq!(img.as_ptr(), first_ptr);
+        assert_eq!(img.len(), 4 * 4 * 3);
+        spare_images.lock().unwrap().push(img);
+    }
+}
+
+/// Renders with a time budget of 200 ms, and returns the last image of every
+/// camera, once the render has finished.
+#[cfg(test)]
+fn render_briefly(scene: Scene, width: u32, height: u32) -> Vec<Image> {
+    let mut ts = TaskScheduler::new(2, scene.number_of_cameras(), width, height);
+    ts.set_time_budget(Some(::time::Duration::milliseconds(200)));
+    let app = App::start(scene, ts, 2);
+
+    // The channels close when the render is finished, like in main.
+    let images = app.images.iter().map(|images| {
+        let mut last = None;
+        while let Some(img) = images.recv() { last = Some(img); }
+        last.expect("every camera should produce a final image")
+    }).collect();
+    app.shutdown();
+    images
+}
+
+#[test]
+fn time_limited_render_stops_on_time() {
+    let start = time::Instant::now();
+    let images = render_briefly(::scenes::cornell_box(), 16, 9);
+
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+    assert_eq!(images.len(), 1);
+    assert_eq!(images[0].len(), 16 * 9 * 3);
+    assert!(images[0].iter().any(|&c| c > 0));
+}
+
+#[test]
+fn poisoned_scheduler_does_not_stop_workers() {
+    let task_scheduler = Arc::new(Mutex::new(TaskScheduler::new(2, 1, 4, 4)));
+
+    // Let a worker panic while it holds the lock on the task scheduler.
+    let ts = task_scheduler.clone();
+    let result = thread::spawn(move || {
+        let _guard = ts.lock().unwrap();
+        panic!("worker panicked");
+    }).join();
+    assert!(result.is_err());
+    assert!(task_scheduler.is_poisoned());
+
+    // The remaining workers must still be able to get new tasks.
+    let mut task = Task::Sleep;
+    for _ in 0 .. 4 {
+        task = lock(&task_scheduler).get_new_task(task);
+    }
+}
+
+#[test]
+fn panicking_task_does_not_stall_render() {
+    use std::sync::atomic::AtomicUsize;
+    use object::MaterialBox::Emissive;
+
+    // A camera that fails the first time it is used, in the middle of
+    // a trace task.
+    static CAMERA_CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn camera(_: f32) -> Camera {
+        if CAMERA_CALLS.fetch_add(1, Ordering::SeqCst) == 0 {
+            panic!("camera failed");
         }
+        ::camera::camera_at_origin(0.0)
     }
+
+    let wall = Box::new(Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 5.0, 0.0)));
+    let light = Object::new(wall, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Arc::new(Mutex::new(Arc::new(Scene::new(vec!(light), camera))));
+
+    // A single worker, so if it stopped, or if the unit of the failed task
+    // were lost, no image would ever be produced.
+    let mut ts = TaskScheduler::new(1, 1, 8, 8);
+    ts.set_tonemap_interval(::time::Duration::zero());
+    let task_scheduler = Arc::new(Mutex::new(ts));
+    let (img_tx, img_rx) = channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker = App::start_worker(task_scheduler, scene, Arc::new(Mutex::new(Vec::new())),
+                                   vec!(img_tx), stop.clone());
+
+    let img = img_rx.recv().expect("the worker should survive the panic");
+    assert!(img.iter().any(|&x| x > 0));
+    assert!(CAMERA_CALLS.load(Ordering::SeqCst) > 1);
+
+    stop.store(true, Ordering::SeqCst);
+    worker.join().unwrap();
+}
+
+#[test]
+fn every_camera_gets_its_own_image() {
+    use object::MaterialBox::Emissive;
+
+    fn camera_facing(yaw: f32) -> Camera {
+        let mut camera = ::camera::camera_at_origin(0.0);
+        camera.orientation = Quaternion::rotation(0.0, 0.0, 1.0, yaw);
+        camera
+    }
+    fn towards_light(_: f32) -> Camera { camera_facing(0.0) }
+    fn away_from_light(_: f32) -> Camera { camera_facing(PI) }
+
+    // A light in front of the first camera, behind the second one.
+    let scene = || {
+        let wall = Box::new(Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 5.0, 0.0)));
+        let light = Object::new(wall, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+        let mut scene = Scene::new(vec!(light), towards_light);
+        scene.extra_cameras.push(away_from_light);
+        scene
+    };
+
+    let images = render_briefly(scene(), 16, 9);
+    assert_eq!(images.len(), 2);
+    assert!(images[0].iter().any(|&c| c > 0));
+    assert!(images[1].iter().all(|&c| c == 0));
+    let scene = scene();
+
+    // The task scheduler renders every camera with units of its own, and
+    // every image is sent through the channel of its camera.
+    let mut ts = TaskScheduler::new(1, 2, 16, 9);
+    ts.set_tonemap_interval(::time::Duration::zero());
+    ts.set_batch_budget(Some(6));
+    let (img_txs, img_rxs): (Vec<_>, Vec<_>) = (0 .. 2).map(|_| channel()).unzip();
+    let spare_images = Arc::new(Mutex::new(Vec::new()));
+    let mut images = vec!(None, None);
+    let mut task = Task::Sleep;
+    loop {
+        task = ts.get_new_task(task);
+        if let Task::Sleep = task { break; }
+        App::execute_task(&mut task, &scene, &spare_images, &img_txs);
+        for (image, img_rx) in images.iter_mut().zip(img_rxs.iter()) {
+            if let Some(img) = img_rx.try_recv() { *image = Some(img); }
+        }
+    }
+    let towards = images[0].take().expect("the first camera should produce an image");
+    let away = images[1].take().expect("the second camera should produce an image");
+    assert!(towards.iter().any(|&c| c > 0));
+    assert!(away.iter().all(|&c| c == 0));
+}
+
+#[test]
+fn time_limited_render_survives_panicking_task() {
+    use std::sync::atomic::AtomicUsize;
+    use object::MaterialBox::Emissive;
+
+    // A camera that fails the first time it is used.
+    static CAMERA_CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn camera(_: f32) -> Camera {
+        if CAMERA_CALLS.fetch_add(1, Ordering::SeqCst) == 0 {
+            panic!("camera failed");
+        }
+        ::camera::camera_at_origin(0.0)
+    }
+
+    let wall = Box::new(Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 5.0, 0.0)));
+    let light = Object::new(wall, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec!(light), camera);
+
+    let images = render_briefly(scene, 16, 9);
+    assert!(images[0].iter().any(|&c| c > 0));
+    assert!(CAMERA_CALLS.load(Ordering::SeqCst) > 1);
+}
+
+#[test]
+fn shutdown_returns_after_workers_stop() {
+    let task_scheduler = Arc::new(Mutex::new(TaskScheduler::new(2, 1, 8, 8)));
+    let scene = Arc::new(Mutex::new(Arc::new(::scenes::cornell_box())));
+    let spare_images = Arc::new(Mutex::new(Vec::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (img_tx, img_rx) = channel();
+    let workers = (0 .. 2).map(|_| {
+        App::start_worker(task_scheduler.clone(), scene.clone(), spare_images.clone(),
+                          vec!(img_tx.clone()), stop.clone())
+    }).collect();
+    let app = App {
+        images: vec!(img_rx),
+        task_scheduler: task_scheduler.clone(),
+        scene: scene,
+        spare_images: spare_images,
+        stop: stop,
+        workers: workers
+    };
+
+    // Let the workers render for a while.
+    thread::sleep(time::Duration::from_millis(50));
+    app.shutdown();
+
+    // A worker that stopped has dropped its reference to the scheduler,
+    // so only the one of this test remains.
+    assert_eq!(Arc::strong_count(&task_scheduler), 1);
+}
+
+#[test]
+fn pipeline_renders_light_in_centre() {
+    use object::MaterialBox::Emissive;
+
+    // A white light straight ahead of the camera, in the centre of the image,
+    // with nothing around it. It is at the focal distance, so it is sharp.
+    let ball = Box::new(Sphere::new(Vector3::new(0.0, 1.0, 0.0), 0.3));
+    let light = Object::new(ball, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec!(light), ::camera::camera_at_origin);
+
+    // Trace, plot, gather and tonemap serially until the budget is used
+    // up and there is nothing left to do, and keep the last image. No
+    // checkpoint or exposure path is set, so nothing is read from or written
+    // to disk, and with a fixed frame seed the render is reproducible.
+    let render = || {
+        let mut ts = TaskScheduler::new(1, 1, 16, 16);
+        ts.set_tonemap_interval(::time::Duration::zero());
+        ts.set_batch_budget(Some(6));
+        ts.set_frame_seed(Some(42));
+        let (img_tx, img_rx) = channel();
+        let img_txs = vec!(img_tx);
+        let spare_images = Arc::new(Mutex::new(Vec::new()));
+
+        let mut task = Task::Sleep;
+        let mut last_img = None;
+        loop {
+            task = ts.get_new_task(task);
+            if let Task::Sleep = task { break; }
+            App::execute_task(&mut task, &scene, &spare_images, &img_txs);
+            if let Some(new_img) = img_rx.try_recv() { last_img = Some(new_img); }
+        }
+        assert_eq!(ts.progress_fraction(), 1.0);
+        last_img.expect("the pipeline should produce an image")
+    };
+
+    let img = render();
+    assert_eq!(img.len(), 16 * 16 * 3);
+    assert!(img == render());
+    let pixel = |x: usize, y: usize| {
+        let i = (y * 16 + x) * 3;
+        (img[i], img[i + 1], img[i + 2])
+    };
+
+    // The centre of the image is lit and close to white, the corners are
+    // black. There are only a few samples per pixel, so average the four
+    // centre pixels to smooth out the noise.
+    let centre = [pixel(7, 7), pixel(8, 7), pixel(7, 8), pixel(8, 8)];
+    let r = centre.iter().map(|p| p.0 as u32).sum::<u32>() / 4;
+    let g = centre.iter().map(|p| p.1 as u32).sum::<u32>() / 4;
+    let b = centre.iter().map(|p| p.2 as u32).sum::<u32>() / 4;
+    assert!(r > 200 && g > 200 && b > 200);
+    assert_eq!(pixel(0, 0), (0, 0, 0));
+    assert_eq!(pixel(15, 15), (0, 0, 0));
+}
+
+#[test]
+fn render_resumes_from_checkpoint() {
+    use checkpoint::Checkpoint;
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::{Emissive, Reflective};
+
+    // A light above a floor, so the image depends on every random bounce.
+    let floor = Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0)));
+    let ball = Box::new(Sphere::new(Vector3::new(0.0, 5.0, 1.0), 1.0));
+    let scene = Scene::new(vec!(
+        Object::new(floor, Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))),
+        Object::new(ball, Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))))
+    ), ::camera::camera_at_origin);
+
+    // Executes tasks serially until the budget is used up and everything
+    // has been gathered.
+    let render = |ts: &mut TaskScheduler| {
+        let (img_tx, _img_rx) = channel();
+        let img_txs = vec!(img_tx);
+        let spare_images = Arc::new(Mutex::new(Vec::new()));
+        let mut task = Task::Sleep;
+        loop {
+            task = ts.get_new_task(task);
+            if let Task::Sleep = task { break; }
+            App::execute_task(&mut task, &scene, &spare_images, &img_txs);
+        }
+    };
+    let dir = ::std::env::temp_dir().join(format!("robigo-luculenta-resume-{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let whole_path = dir.join("whole.raw");
+    let split_path = dir.join("split.raw");
+    let _ = ::std::fs::remove_file(&whole_path);
+    let _ = ::std::fs::remove_file(&split_path);
+
+    let mut ts = TaskScheduler::new(1, 1, 16, 16);
+    ts.set_tonemap_interval(::time::Duration::zero());
+    ts.set_checkpoint_path(Some(whole_path.clone()));
+    ts.set_seed(Some(7));
+    ts.set_batch_budget(Some(6));
+    render(&mut ts);
+
+    // The same render, stopped halfway, and continued by a new scheduler
+    // that knows nothing but the checkpoint.
+    let mut ts = TaskScheduler::new(1, 1, 16, 16);
+    ts.set_tonemap_interval(::time::Duration::zero());
+    ts.set_checkpoint_path(Some(split_path.clone()));
+    ts.set_seed(Some(7));
+    ts.set_batch_budget(Some(3));
+    render(&mut ts);
+
+    let mut ts = TaskScheduler::new(1, 1, 16, 16);
+    ts.set_tonemap_interval(::time::Duration::zero());
+    ts.set_checkpoint_path(Some(split_path.clone()));
+    ts.set_batch_budget(Some(6));
+    assert!(ts.resume());
+    assert_eq!(ts.progress_fraction(), 0.5);
+    render(&mut ts);
+
+    let whole = Checkpoint::load(&whole_path, 16, 16, 0).unwrap();
+    let split = Checkpoint::load(&split_path, 16, 16, 0).unwrap();
+    assert_eq!(whole.batches, split.batches);
+    assert_eq!(whole.sampling_state.unit_batches, split.sampling_state.unit_batches);
+    let energy = |c: &Checkpoint| c.tristimulus_buffer.iter().map(|t| t.y as f64).sum::<f64>();
+    assert!(energy(&whole) > 0.0);
+    assert!((energy(&whole) - energy(&split)).abs() < energy(&whole) * 1.0e-5);
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Renders `scene` on a canvas of 8 by 8 pixels with an app that has no
+/// workers, by executing its tasks serially, like a worker would. Returns
+/// the first image, and the first image after `change` was applied to the app.
+#[cfg(test)]
+fn render_around_change<F: FnOnce(&App)>(scene: Scene, change: F) -> (Image, Image) {
+    let mut ts = TaskScheduler::new(1, 1, 8, 8);
+    ts.set_tonemap_interval(::time::Duration::zero());
+    let (img_tx, img_rx) = channel();
+    let img_txs = vec!(img_tx);
+    let app = App {
+        images: vec!(img_rx),
+        task_scheduler: Arc::new(Mutex::new(ts)),
+        scene: Arc::new(Mutex::new(Arc::new(scene))),
+        spare_images: Arc::new(Mutex::new(Vec::new())),
+        stop: Arc::new(AtomicBool::new(false)),
+        workers: Vec::new()
+    };
+
+    // Execute tasks until an image is produced.
+    let mut task = Task::Sleep;
+    let mut render_image = || {
+        loop {
+            let old_task = mem::replace(&mut task, Task::Sleep);
+            task = app.task_scheduler.lock().unwrap().get_new_task(old_task);
+            let scene = app.scene.lock().unwrap().clone();
+            App::execute_task(&mut task, &scene, &app.spare_images, &img_txs);
+            if let Some(img) = app.images[0].try_recv() { return img; }
+        }
+    };
+
+    let before = render_image();
+    change(&app);
+    let after = render_image();
+    (before, after)
 }
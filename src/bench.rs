@@ -0,0 +1,113 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Benchmarks of the core intersection and material routines. They need a
+// nightly compiler, run them with `cargo bench --features bench`.
+
+use test::{Bencher, black_box};
+use app::App;
+use camera::Camera;
+use geometry::{Paraboloid, Sphere, Surface, new_hexagonal_prism};
+use intersection::Intersection;
+use material::{DiffuseGreyMaterial, Material};
+use ray::Ray;
+use task_scheduler::{Task, TaskScheduler};
+use vector3::Vector3;
+
+/// Returns a ray from `origin` towards `target`.
+fn ray_towards(origin: Vector3, target: Vector3) -> Ray {
+    Ray {
+        origin: origin,
+        direction: (target - origin).normalise(),
+        wavelength: 550.0,
+        probability: 1.0
+    }
+}
+
+#[bench]
+fn bench_sphere_intersect(b: &mut Bencher) {
+    let sphere = Sphere::new(Vector3::zero(), 1.0);
+    let ray = ray_towards(Vector3::new(0.3, -5.0, 0.2), Vector3::zero());
+    b.iter(|| black_box(sphere.intersect(black_box(&ray))));
+}
+
+#[bench]
+fn bench_paraboloid_intersect(b: &mut Bencher) {
+    let paraboloid = Paraboloid::new(Vector3::new(0.0, 0.0, 1.0), Vector3::zero(), 2.0);
+    let ray = ray_towards(Vector3::new(0.3, 0.2, 5.0), Vector3::new(0.0, 0.0, -1.0));
+    b.iter(|| black_box(paraboloid.intersect(black_box(&ray))));
+}
+
+#[bench]
+fn bench_hexagonal_prism_intersect(b: &mut Bencher) {
+    let prism = new_hexagonal_prism(Vector3::new(0.0, 0.0, 1.0), Vector3::zero(),
+                                    3.0, 1.0, 0.0, 2.0);
+    let ray = ray_towards(Vector3::new(0.3, -5.0, 0.5), Vector3::new(0.0, 0.0, 0.5));
+    b.iter(|| black_box(prism.intersect(black_box(&ray))));
+}
+
+#[bench]
+fn bench_scene_intersect(b: &mut Bencher) {
+    // Camera rays into the default scene, spread over the screen.
+    let scene = App::set_up_scene();
+    let camera: Camera = (scene.get_camera_at_time)(0.0);
+    let rays: Vec<Ray> = (0 .. 256).map(|i| {
+        let x = (i % 16) as f32 / 8.0 - 1.0;
+        let y = (i / 16) as f32 / 16.0 - 0.5;
+        camera.get_ray(x, y, 550.0)
+    }).collect();
+    b.iter(|| {
+        for ray in &rays {
+            black_box(scene.intersect(ray).map(|(isect, _)| isect.distance));
+        }
+    });
+}
+
+#[bench]
+fn bench_diffuse_new_ray(b: &mut Bencher) {
+    let material = DiffuseGreyMaterial::new(0.8);
+    let intersection = Intersection::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0),
+                                         Vector3::zero(), 1.0);
+    let ray = ray_towards(Vector3::new(0.0, 0.0, 1.0), Vector3::zero());
+    b.iter(|| black_box(material.get_new_ray(&ray, &intersection, 1.0)));
+}
+
+/// Executes scheduler tasks like a single worker would, without doing the
+/// work, so only the scheduling is measured. Every iteration traces and plots
+/// a budget of 64 batches.
+fn bench_schedule_without_work(b: &mut Bencher, plot_batch_size: usize) {
+    let mut ts = TaskScheduler::new(8, 1, 1, 1);
+    ts.set_batch_budget(Some(64));
+    ts.set_plot_batch_size(plot_batch_size);
+    b.iter(|| {
+        ts.restart();
+        let mut task = Task::Sleep;
+        loop {
+            task = ts.get_new_task(task);
+            if let Task::Sleep = task { break; }
+        }
+    });
+}
+
+#[bench]
+fn bench_schedule_plot_single_units(b: &mut Bencher) {
+    bench_schedule_without_work(b, 1);
+}
+
+#[bench]
+fn bench_schedule_plot_batches(b: &mut Bencher) {
+    bench_schedule_without_work(b, 8);
+}
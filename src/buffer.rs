@@ -0,0 +1,225 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::io;
+use std::iter::repeat;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use vector3::Vector3;
+
+#[cfg(feature = "mmap")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "mmap")]
+use std::mem::size_of;
+#[cfg(feature = "mmap")]
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "mmap")]
+use std::path::Path;
+#[cfg(feature = "mmap")]
+use std::ptr;
+#[cfg(feature = "mmap")]
+use libc;
+
+/// A buffer of tristimulus values. It is kept in memory, or with the `mmap`
+/// feature, it can be backed by a memory-mapped file, so that huge canvases
+/// do not have to fit in memory. Either way, it can be used as a slice.
+pub enum Buffer {
+    /// The values are kept in memory.
+    Memory(Vec<Vector3>),
+
+    /// The values are stored in a file that is mapped into memory. The
+    /// file holds the raw values, in the same format as `Checkpoint`.
+    #[cfg(feature = "mmap")]
+    Mapped(Mapping)
+}
+
+/// A shared, writable mapping of a whole file that holds `len` values.
+#[cfg(feature = "mmap")]
+pub struct Mapping {
+    file: File,
+    values: *mut Vector3,
+    len: usize
+}
+
+// The mapping is owned exclusively, like a `Vec`, so it can be sent to and
+// shared with other threads in the same way.
+#[cfg(feature = "mmap")]
+unsafe impl Send for Mapping { }
+#[cfg(feature = "mmap")]
+unsafe impl Sync for Mapping { }
+
+#[cfg(feature = "mmap")]
+impl Mapping {
+    /// Maps `file`, after adapting its length to hold `len` values.
+    fn new(file: File, len: usize) -> io::Result<Mapping> {
+        let bytes = match len.checked_mul(size_of::<Vector3>()) {
+            Some(bytes) => bytes,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "buffer too large to map"))
+        };
+        try!(file.set_len(bytes as u64));
+        let values = unsafe {
+            libc::mmap(ptr::null_mut(), bytes as libc::size_t,
+                       libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                       file.as_raw_fd(), 0)
+        };
+        if values == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { file: file, values: values as *mut Vector3, len: len })
+    }
+
+    fn byte_len(&self) -> libc::size_t {
+        (self.len * size_of::<Vector3>()) as libc::size_t
+    }
+
+    /// Writes the mapped values to the file and waits for it to complete.
+    fn flush(&self) -> io::Result<()> {
+        let result = unsafe {
+            libc::msync(self.values as *mut libc::c_void, self.byte_len(), libc::MS_SYNC)
+        };
+        if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.values as *mut libc::c_void, self.byte_len()); }
+    }
+}
+
+impl Buffer {
+    /// Constructs an in-memory buffer of `len` black values.
+    pub fn new(len: usize) -> Buffer {
+        Buffer::Memory(repeat(Vector3::zero()).take(len).collect())
+    }
+
+    /// Constructs a buffer that is backed by the file at `path`, with a copy
+    /// of `values`. The file is created if it does not exist, and any data
+    /// that it held is overwritten.
+    #[cfg(feature = "mmap")]
+    pub fn map<P: AsRef<Path>>(path: P, values: &[Vector3]) -> io::Result<Buffer> {
+        let file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        let mut buffer = Buffer::Mapped(try!(Mapping::new(file, values.len())));
+        buffer.copy_from_slice(values);
+        Ok(buffer)
+    }
+
+    /// Writes the values to the backing file, if there is one, so that it is
+    /// complete even if the program stops abruptly.
+    #[cfg(feature = "mmap")]
+    pub fn flush(&self) -> io::Result<()> {
+        match *self {
+            Buffer::Memory(_) => Ok(()),
+            Buffer::Mapped(ref mapping) => mapping.flush()
+        }
+    }
+
+    /// Changes the number of values to `len`, and makes them all black.
+    ///
+    /// If a file-backed buffer cannot be mapped at the new size, the buffer
+    /// moves into memory and the error is returned; the buffer is usable
+    /// either way.
+    pub fn reset(&mut self, len: usize) -> io::Result<()> {
+        // A mapping cannot grow, so the file is mapped anew.
+        #[cfg(feature = "mmap")]
+        {
+            let remapped = match *self {
+                Buffer::Mapped(ref mapping) if mapping.len != len => {
+                    Some(mapping.file.try_clone().and_then(|file| Mapping::new(file, len)))
+                }
+                _ => None
+            };
+            match remapped {
+                Some(Ok(mapping)) => *self = Buffer::Mapped(mapping),
+                Some(Err(err)) => {
+                    *self = Buffer::new(len);
+                    return Err(err);
+                }
+                None => {}
+            }
+        }
+
+        match *self {
+            Buffer::Memory(ref mut values) => values.resize(len, Vector3::zero()),
+            #[cfg(feature = "mmap")]
+            Buffer::Mapped(..) => {}
+        }
+        for x in self.iter_mut() {
+            *x = Vector3::zero();
+        }
+        Ok(())
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [Vector3];
+
+    fn deref(&self) -> &[Vector3] {
+        match *self {
+            Buffer::Memory(ref values) => values,
+            // The mapping is page-aligned, and holds a whole number of
+            // values, so it can be used as a slice of them.
+            #[cfg(feature = "mmap")]
+            Buffer::Mapped(ref mapping) => unsafe {
+                slice::from_raw_parts(mapping.values, mapping.len)
+            }
+        }
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [Vector3] {
+        match *self {
+            Buffer::Memory(ref mut values) => values,
+            #[cfg(feature = "mmap")]
+            Buffer::Mapped(ref mut mapping) => unsafe {
+                slice::from_raw_parts_mut(mapping.values, mapping.len)
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Buffer {
+    type Item = &'a Vector3;
+    type IntoIter = slice::Iter<'a, Vector3>;
+
+    fn into_iter(self) -> slice::Iter<'a, Vector3> {
+        self.iter()
+    }
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn failed_remap_falls_back_to_memory() {
+    let name = format!("robigo-luculenta-fallback-{}.raw", ::std::process::id());
+    let path = ::std::env::temp_dir().join(name);
+    let mut buffer = Buffer::map(&path, &[Vector3::new(1.0, 2.0, 3.0); 4]).unwrap();
+
+    // An empty mapping is rejected by the operating system.
+    assert!(buffer.reset(0).is_err());
+    match buffer {
+        Buffer::Memory(ref values) => assert!(values.is_empty()),
+        Buffer::Mapped(..) => panic!("buffer should have moved into memory")
+    }
+
+    // From then on, the buffer lives in memory.
+    buffer.reset(3).unwrap();
+    assert_eq!(buffer.len(), 3);
+    assert!(buffer.iter().all(|v| v.x == 0.0 && v.y == 0.0 && v.z == 0.0));
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,191 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use aabb::Aabb;
+use intersection::Intersection;
+use object::Object;
+use ray::Ray;
+
+/// The maximum number of objects in a leaf node.
+const MAX_LEAF_SIZE: usize = 4;
+
+/// A node in the bounding volume hierarchy.
+struct BvhNode {
+    /// A box that contains all objects below this node.
+    bounds: Aabb,
+
+    /// For a leaf, the index into the object indices of its first object.
+    /// For an interior node, the index of its second child. The first
+    /// child always directly follows its parent.
+    offset: usize,
+
+    /// The number of objects in a leaf, or 0 for an interior node.
+    count: usize
+}
+
+/// A bounding volume hierarchy over the objects of a scene, to avoid
+/// intersecting every object with every ray. It only stores indices into
+/// the objects, so it is built once and can be shared between threads.
+pub struct Bvh {
+    /// The nodes of the tree, the root comes first.
+    nodes: Vec<BvhNode>,
+
+    /// Indices of objects, ordered such that the objects of a leaf
+    /// are contiguous.
+    indices: Vec<usize>,
+
+    /// Indices of objects that are not bounded, such as planes. These
+    /// must be intersected with every ray.
+    unbounded: Vec<usize>
+}
+
+impl Bvh {
+    /// Builds a hierarchy for the specified objects.
+    pub fn new(objects: &[Object]) -> Bvh {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (i, obj) in objects.iter().enumerate() {
+            match obj.surface.get_bounding_box() {
+                Some(bounds) => bounded.push((i, bounds)),
+                None => unbounded.push(i)
+            }
+        }
+
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: Vec::new(),
+            unbounded: unbounded
+        };
+        if !bounded.is_empty() {
+            bvh.build(&mut bounded[..]);
+        }
+        bvh
+    }
+
+    /// Appends the nodes for the specified objects and their boxes.
+    fn build(&mut self, items: &mut [(usize, Aabb)]) {
+        let bounds = items[1 ..].iter().fold(items[0].1, |acc, &(_, b)| acc.union(b));
+        let node_index = self.nodes.len();
+
+        if items.len() <= MAX_LEAF_SIZE {
+            self.nodes.push(BvhNode {
+                bounds: bounds,
+                offset: self.indices.len(),
+                count: items.len()
+            });
+            self.indices.extend(items.iter().map(|&(i, _)| i));
+            return;
+        }
+
+        // Split along the axis where the centres are spread out most,
+        // so that both halves have the same number of objects.
+        let first_centre = items[0].1.centre();
+        let centres = items[1 ..].iter().fold(Aabb::new(first_centre, first_centre),
+            |acc, &(_, b)| acc.union(Aabb::new(b.centre(), b.centre())));
+        let size = centres.size();
+        let axis = if size.x > size.y && size.x > size.z { 0 }
+                   else if size.y > size.z { 1 }
+                   else { 2 };
+        items.sort_by(|a, b| {
+            a.1.centre()[axis].partial_cmp(&b.1.centre()[axis]).unwrap_or(Ordering::Equal)
+        });
+
+        self.nodes.push(BvhNode {
+            bounds: bounds,
+            offset: 0,
+            count: 0
+        });
+
+        let mid = items.len() / 2;
+        let (left, right) = items.split_at_mut(mid);
+        self.build(left);
+        self.nodes[node_index].offset = self.nodes.len();
+        self.build(right);
+    }
+
+    /// Calls `test_object` with the index of every object whose box the ray
+    /// hits closer than `max_distance`. The object test can lower the
+    /// distance, to skip nodes that lie behind an intersection that was
+    /// found already.
+    fn walk<F>(&self, ray: &Ray, max_distance: f32, mut test_object: F)
+        where F: FnMut(usize, &mut f32) {
+        let mut distance = max_distance;
+
+        for &i in &self.unbounded {
+            test_object(i, &mut distance);
+        }
+
+        // Walk the tree, skipping nodes that the ray misses, or that
+        // lie too far away.
+        let mut stack = if self.nodes.is_empty() { Vec::new() } else { vec!(0) };
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            match node.bounds.intersect_ray(ray) {
+                Some((near, _)) if near < distance => { },
+                _ => continue
+            }
+
+            if node.count > 0 {
+                for &i in &self.indices[node.offset .. node.offset + node.count] {
+                    test_object(i, &mut distance);
+                }
+            } else {
+                stack.push(node.offset);
+                stack.push(n + 1);
+            }
+        }
+    }
+
+    /// Returns the nearest intersection of the ray with one of the objects,
+    /// if it is closer than `max_distance`. The objects must be the ones
+    /// that the hierarchy was built for.
+    pub fn intersect<'a>(&self,
+                         objects: &'a [Object],
+                         ray: &Ray,
+                         max_distance: f32)
+                         -> Option<(Intersection, &'a Object)> {
+        let mut result = None;
+        self.walk(ray, max_distance, |i, distance| {
+            let obj = &objects[i];
+            if let Some(isect) = obj.surface.intersect(ray) {
+                if isect.distance < *distance {
+                    result = Some((isect, obj));
+                    *distance = isect.distance;
+                }
+            }
+        });
+        result
+    }
+
+    /// Returns every intersection of the ray with the objects that is closer
+    /// than `max_distance`, including the points where the ray leaves closed
+    /// surfaces, in no particular order.
+    pub fn intersect_all<'a>(&self,
+                             objects: &'a [Object],
+                             ray: &Ray,
+                             max_distance: f32)
+                             -> Vec<(Intersection, &'a Object)> {
+        let mut hits = Vec::new();
+        self.walk(ray, max_distance, |i, _| {
+            let obj = &objects[i];
+            hits.extend(obj.surface.intersect_all(ray).into_iter()
+                           .filter(|isect| isect.distance < max_distance)
+                           .map(|isect| (isect, obj)));
+        });
+        hits
+    }
+}
@@ -14,9 +14,84 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::f32::consts::PI;
 use quaternion::Quaternion;
 use ray::Ray;
-use vector3::Vector3;
+use scene::Scene;
+use screen::{ndc_to_screen, pixel_to_ndc};
+use vector3::{Vector3, dot};
+
+/// The field of view is clamped to this range (in radians), because the
+/// distance to the screen is infinite at 0, and zero at pi.
+const MIN_FIELD_OF_VIEW: f32 = 1.0e-4;
+const MAX_FIELD_OF_VIEW: f32 = 3.1405;
+
+/// The smallest depth of field that is used; the lens radius is inversely
+/// proportional to it.
+const MIN_DEPTH_OF_FIELD: f32 = 1.0e-3;
+
+/// From this depth of field on, the lens is so small that it cannot be told
+/// apart from a pinhole at single precision, so the lens is not sampled.
+const PINHOLE_DEPTH_OF_FIELD: f32 = 1.0e6;
+
+/// The smallest focal distance that is used. At distance 0 all rays would
+/// go through the lens point itself.
+const MIN_FOCAL_DISTANCE: f32 = 1.0e-3;
+
+/// Determines how points on the camera lens are chosen.
+#[derive(Copy, Clone)]
+pub enum LensSampling {
+    /// An angle and a radius are chosen uniformly. This puts more samples
+    /// near the centre of the lens than near the edge.
+    Polar,
+
+    /// A point in a square is mapped onto the disk with the concentric
+    /// mapping of Shirley and Chiu. It is uniform over the area of the
+    /// lens, and because the mapping has little distortion, the samples
+    /// remain well spread out, which makes bokeh converge faster.
+    Concentric
+}
+
+impl LensSampling {
+    /// Returns a random point on the unit disk.
+    pub fn sample(self) -> (f32, f32) {
+        match self {
+            LensSampling::Polar => {
+                let angle = ::monte_carlo::get_longitude();
+                let radius = ::monte_carlo::get_unit();
+                (angle.cos() * radius, angle.sin() * radius)
+            }
+            LensSampling::Concentric => {
+                self.sample_at(::monte_carlo::get_unit(), ::monte_carlo::get_unit())
+            }
+        }
+    }
+
+    /// Returns the point on the unit disk that the point (`u`, `v`) in the
+    /// unit square maps to, for when the lens is sampled quasi-randomly.
+    pub fn sample_at(self, u: f32, v: f32) -> (f32, f32) {
+        match self {
+            LensSampling::Polar => {
+                let angle = u * PI * 2.0;
+                (angle.cos() * v, angle.sin() * v)
+            }
+            LensSampling::Concentric => {
+                let a = u * 2.0 - 1.0;
+                let b = v * 2.0 - 1.0;
+                if a == 0.0 && b == 0.0 { return (0.0, 0.0); }
+
+                // Map every square around the origin onto the circle
+                // with the same radius.
+                let (radius, angle) = if a.abs() > b.abs() {
+                    (a, PI * 0.25 * (b / a))
+                } else {
+                    (b, PI * 0.5 - PI * 0.25 * (a / b))
+                };
+                (angle.cos() * radius, angle.sin() * radius)
+            }
+        }
+    }
+}
 
 pub struct Camera {
     /// Location of the camera in the scene.
@@ -38,7 +113,17 @@ pub struct Camera {
     pub chromatic_abberation: f32,
 
     /// The direction in which the camera is looking.
-    pub orientation: Quaternion
+    pub orientation: Quaternion,
+
+    /// How points on the lens are chosen for depth of field.
+    pub lens_sampling: LensSampling,
+
+    /// The offset of the image relative to the optical axis, horizontally
+    /// and vertically, in screen units, so a negative vertical shift moves
+    /// the view up. Shifting the image instead of rotating the camera keeps
+    /// lines that are parallel to the screen parallel, like the rise and
+    /// shift of a view camera. (0, 0) is an ordinary camera.
+    pub lens_shift: (f32, f32)
 }
 
 impl Camera {
@@ -48,34 +133,33 @@ impl Camera {
                       x: f32,
                       y: f32,
                       chromatic_abberation_factor: f32,
-                      dof_angle: f32,
-                      dof_radius: f32)
+                      lens_x: f32,
+                      lens_z: f32)
                       -> Ray {
         // The smaller the FOV, the further the screen is away;
         // the larger the FOV, the closer the screen is.
-        let screen_distance = 1.0 / (self.field_of_view * 0.5).tan();
-        
+        let fov = self.field_of_view.max(MIN_FIELD_OF_VIEW).min(MAX_FIELD_OF_VIEW);
+        let screen_distance = 1.0 / (fov * 0.5).tan();
+
         // Then apply some wavelength dependent zoom to create chromatic
         // abberation. Please note, this is not a physically correct model of
         // chromatic abberation, for a correct response, you can place a lens
         // in front of the camera, with a dispersive glass material.
-        let xs = x * chromatic_abberation_factor;
-        let ys = y * chromatic_abberation_factor;
+        let (shift_x, shift_y) = self.lens_shift;
+        let xs = (x + shift_x) * chromatic_abberation_factor;
+        let ys = (y + shift_y) * chromatic_abberation_factor;
 
         let direction = Vector3::new(xs, screen_distance, -ys).normalise();
 
         // Now find the intersection with the focal plane (which is trivial as
         // long as the ray has not been transformed yet).
-        let focus_point = direction * (self.focal_distance / direction.y);
+        let focal_distance = self.focal_distance.max(MIN_FOCAL_DISTANCE);
+        let focus_point = direction * (focal_distance / direction.y);
 
         // Then take a new point on the camera 'lens' (this is of course not
         // accurate, but then again, the pinhole camera does not have depth of
         // field at all, so it is a hack anyway).
-        let lens_point = Vector3 {
-            x: dof_angle.cos() * dof_radius,
-            y: 0.0,
-            z: dof_angle.sin() * dof_radius
-        };
+        let lens_point = Vector3::new(lens_x, 0.0, lens_z);
 
         // Then construct the new ray, from the lens point,
         // through the focus point.
@@ -92,18 +176,317 @@ impl Camera {
     /// Returns a camera ray through the screen at the specified position,
     /// where -1.0 is left and 1.0 is right, with square units.
     pub fn get_ray(&self, x: f32, y: f32, wavelength: f32) -> Ray {
-        // Pick depth of field coordinates randomly.
-        let dof_angle = ::monte_carlo::get_longitude();
-        let dof_radius = ::monte_carlo::get_unit() / self.depth_of_field;
+        // Pick a point on the lens randomly.
+        let lens = if self.depth_of_field >= PINHOLE_DEPTH_OF_FIELD {
+            (0.0, 0.0)
+        } else {
+            self.lens_sampling.sample()
+        };
+        self.get_ray_through_lens(x, y, wavelength, lens)
+    }
 
-        // Calculate a zoom factor based on the wavelength
-        // to simulate chromatic abberation of the lens.
-        let d = (wavelength - 580.0) / 200.0;
-        let chromatic_zoom = 1.0 + d * self.chromatic_abberation;
+    /// Like `get_ray`, but the point on the lens is the one that the point
+    /// (`u`, `v`) in the unit square maps to, instead of a random one.
+    pub fn get_ray_at(&self, x: f32, y: f32, wavelength: f32, u: f32, v: f32) -> Ray {
+        let lens = if self.depth_of_field >= PINHOLE_DEPTH_OF_FIELD {
+            (0.0, 0.0)
+        } else {
+            self.lens_sampling.sample_at(u, v)
+        };
+        self.get_ray_through_lens(x, y, wavelength, lens)
+    }
+
+    /// Returns a camera ray through the screen at the specified position,
+    /// that passes through `lens`, a point on the unit disk.
+    fn get_ray_through_lens(&self, x: f32, y: f32, wavelength: f32, lens: (f32, f32)) -> Ray {
+        // The lens radius is inversely proportional to the depth of field.
+        let lens_radius = 1.0 / self.depth_of_field.max(MIN_DEPTH_OF_FIELD);
+        let (lens_x, lens_z) = (lens.0 * lens_radius, lens.1 * lens_radius);
 
         // Then retrieve a ray through the screen.
-        let mut r = self.get_screen_ray(x, y, chromatic_zoom, dof_angle, dof_radius);
+        let chromatic_zoom = self.get_chromatic_zoom(wavelength);
+        let mut r = self.get_screen_ray(x, y, chromatic_zoom, lens_x, lens_z);
+        r.wavelength = wavelength;
+        r
+    }
+
+    /// Returns a zoom factor based on the wavelength, to simulate
+    /// chromatic abberation of the lens.
+    fn get_chromatic_zoom(&self, wavelength: f32) -> f32 {
+        if self.chromatic_abberation == 0.0 { return 1.0; }
+        let d = (wavelength - 580.0) / 200.0;
+        1.0 + d * self.chromatic_abberation
+    }
+
+    /// Returns the ray through the centre of the lens and the centre of the
+    /// pixel at column `px` and row `py` of an image of `width` by `height`
+    /// pixels, the pixel that the renderer plots a photon from that ray to.
+    /// Unlike `get_ray`, there is no random depth of field offset.
+    #[allow(dead_code)]
+    pub fn ray_through_pixel(&self, px: u32, py: u32, width: u32, height: u32,
+                             wavelength: f32) -> Ray {
+        let (u, v) = pixel_to_ndc(px as f32, py as f32, width, height);
+        let (x, y) = ndc_to_screen(u, v, width as f32 / height as f32);
+        let chromatic_zoom = self.get_chromatic_zoom(wavelength);
+        let mut r = self.get_screen_ray(x, y, chromatic_zoom, 0.0, 0.0);
         r.wavelength = wavelength;
         r
     }
-}       
+
+    /// Returns the screen position at which `point` is seen through the
+    /// centre of the lens, in the same units as `get_ray`, together with the
+    /// screen area per unit of solid angle in that direction. Depth of field
+    /// and chromatic abberation are ignored. Returns `None` for points
+    /// behind the camera.
+    pub fn project(&self, point: Vector3) -> Option<(f32, f32, f32)> {
+        let local = (point - self.position).rotate(self.orientation.conjugate());
+        if local.y <= 0.0 { return None; }
+
+        let fov = self.field_of_view.max(MIN_FIELD_OF_VIEW).min(MAX_FIELD_OF_VIEW);
+        let screen_distance = 1.0 / (fov * 0.5).tan();
+        let x = local.x * screen_distance / local.y - self.lens_shift.0;
+        let y = -local.z * screen_distance / local.y - self.lens_shift.1;
+
+        // A bit of screen at angle theta from the optical axis is further
+        // away, and tilted, so it covers d^2 / cos^3(theta) per steradian.
+        let cos_theta = local.y / local.magnitude();
+        let area_per_sr = screen_distance * screen_distance / (cos_theta * cos_theta * cos_theta);
+
+        Some((x, y, area_per_sr))
+    }
+
+    /// Sets the focal distance such that the object under the specified
+    /// screen position is perfectly in focus. If there is nothing at that
+    /// position, the focal distance is left unchanged.
+    #[allow(dead_code)]
+    pub fn focus_on(&mut self, scene: &Scene, x: f32, y: f32) {
+        // A ray through the centre of the lens, without chromatic abberation.
+        let ray = self.get_screen_ray(x, y, 1.0, 0.0, 0.0);
+
+        if let Some((intersection, _)) = scene.intersect(&ray) {
+            // The focal distance is measured along the optical axis, so for
+            // a point not at the centre, the distance must be projected.
+            let axis = Vector3::new(0.0, 1.0, 0.0).rotate(self.orientation);
+            self.focal_distance = intersection.distance * dot(ray.direction, axis);
+        }
+    }
+
+    /// Moves the camera back along its optical axis, keeping its orientation
+    /// and field of view, such that the bounding box of the scene fits in the
+    /// view of an image with the specified aspect ratio (width / height),
+    /// and focuses on its centre. The margin is the fraction of the scene
+    /// size that is kept free around it. Infinite surfaces are ignored, and
+    /// if the scene is empty, the camera is left unchanged.
+    #[allow(dead_code)]
+    pub fn frame_scene(&mut self, scene: &Scene, aspect_ratio: f32, margin: f32) {
+        let aabb = match scene.bounding_box(true) {
+            Some(aabb) => aabb,
+            None => return
+        };
+
+        // The box fits if its bounding sphere fits in the cone of the
+        // narrowest half field of view, horizontally or vertically.
+        let fov = self.field_of_view.max(MIN_FIELD_OF_VIEW).min(MAX_FIELD_OF_VIEW);
+        let tan_horizontal = (fov * 0.5).tan();
+        let half_angle = tan_horizontal.min(tan_horizontal / aspect_ratio).atan();
+        let radius = aabb.size().magnitude() * 0.5 * (1.0 + margin);
+        let distance = radius / half_angle.sin();
+
+        let axis = Vector3::new(0.0, 1.0, 0.0).rotate(self.orientation);
+        self.position = aabb.centre() - axis * distance;
+        self.focal_distance = distance;
+    }
+}
+
+#[cfg(test)]
+pub fn camera_at_origin(_: f32) -> Camera {
+    Camera {
+        position: Vector3::zero(),
+        field_of_view: PI * 0.5,
+        focal_distance: 1.0,
+        depth_of_field: 1.0,
+        chromatic_abberation: 0.0,
+        orientation: Quaternion::rotation(0.0, 0.0, 1.0, 0.0),
+        lens_sampling: LensSampling::Polar,
+        lens_shift: (0.0, 0.0)
+    }
+}
+
+/// Like `camera_at_origin`, but everything is sharp.
+#[cfg(test)]
+pub fn pinhole_camera_at_origin(t: f32) -> Camera {
+    Camera {
+        depth_of_field: PINHOLE_DEPTH_OF_FIELD,
+        .. camera_at_origin(t)
+    }
+}
+
+#[test]
+fn focus_on_sphere_in_centre() {
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::Reflective;
+    use object::Object;
+
+    let sphere = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 10.0, 0.0), 2.0)),
+                             Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec!(sphere), camera_at_origin);
+
+    let mut camera = camera_at_origin(0.0);
+    camera.focus_on(&scene, 0.0, 0.0);
+    assert!((camera.focal_distance - 8.0).abs() < 1.0e-4);
+}
+
+#[test]
+fn degenerate_camera_gives_finite_rays() {
+    fn is_finite(v: Vector3) -> bool {
+        v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+    }
+
+    let mut camera = camera_at_origin(0.0);
+    camera.field_of_view = 0.0;
+    camera.depth_of_field = 0.0;
+
+    for &(x, y) in [(0.0, 0.0), (1.0, 0.5), (-1.0, -0.5)].iter() {
+        let ray = camera.get_ray(x, y, 550.0);
+        assert!(is_finite(ray.origin));
+        assert!(is_finite(ray.direction));
+    }
+
+    camera.field_of_view = PI;
+    camera.focal_distance = 0.0;
+    let ray = camera.get_ray(1.0, 0.5, 550.0);
+    assert!(is_finite(ray.origin));
+    assert!(is_finite(ray.direction));
+}
+
+#[test]
+fn framed_scene_is_on_screen() {
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::Reflective;
+    use object::Object;
+
+    let sphere = |position: Vector3, radius: f32| {
+        Object::new(Box::new(Sphere::new(position, radius)),
+                    Reflective(Box::new(DiffuseGreyMaterial::new(0.8))))
+    };
+    let scene = Scene::new(vec!(sphere(Vector3::new(-4.0, 2.0, 1.0), 1.0),
+                                sphere(Vector3::new(6.0, -3.0, 0.0), 2.5)),
+                           camera_at_origin);
+    let aabb = scene.bounding_box(true).unwrap();
+    let aspect_ratio = 16.0 / 9.0;
+
+    let mut camera = camera_at_origin(0.0);
+    camera.orientation = Quaternion::rotation(0.6, 0.0, 0.8, 0.7);
+    camera.frame_scene(&scene, aspect_ratio, 0.1);
+
+    for i in 0 .. 8 {
+        let corner = Vector3 {
+            x: if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+            y: if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+            z: if i & 4 == 0 { aabb.min.z } else { aabb.max.z }
+        };
+        let (x, y, _) = camera.project(corner).unwrap();
+        assert!(x.abs() <= 1.0);
+        assert!(y.abs() <= 1.0 / aspect_ratio);
+    }
+}
+
+#[test]
+fn fixed_units_give_exact_ray() {
+    // The lens sample is at angle 0.25 * 2pi, at radius 0.5.
+    ::monte_carlo::set_fixed_units(&[0.25, 0.5]);
+    let ray = camera_at_origin(0.0).get_ray(0.0, 0.0, 580.0);
+
+    // The ray leaves the lens above the centre, towards the focus point
+    // at distance 1 straight ahead.
+    let d = 1.0 / 1.25f32.sqrt();
+    assert!(ray.origin.x.abs() < 1.0e-6);
+    assert!(ray.origin.y.abs() < 1.0e-6);
+    assert!((ray.origin.z - 0.5).abs() < 1.0e-6);
+    assert!(ray.direction.x.abs() < 1.0e-6);
+    assert!((ray.direction.y - d).abs() < 1.0e-6);
+    assert!((ray.direction.z + 0.5 * d).abs() < 1.0e-6);
+    assert_eq!(ray.wavelength, 580.0);
+}
+
+#[test]
+fn centre_pixel_ray_is_optical_axis() {
+    let camera = camera_at_origin(0.0);
+    let ray = camera.ray_through_pixel(50, 25, 101, 51, 550.0);
+    assert_eq!((ray.origin.x, ray.origin.y, ray.origin.z), (0.0, 0.0, 0.0));
+    assert!(ray.direction.x.abs() < 1.0e-6);
+    assert!((ray.direction.y - 1.0).abs() < 1.0e-6);
+    assert!(ray.direction.z.abs() < 1.0e-6);
+    assert_eq!(ray.wavelength, 550.0);
+
+    // The top left pixel looks left and up.
+    let corner = camera.ray_through_pixel(0, 0, 101, 51, 550.0);
+    assert!(corner.direction.x < 0.0 && corner.direction.z > 0.0);
+}
+
+#[test]
+fn concentric_lens_samples_are_even() {
+    // Divide the disk into 8 rings of equal area, and every ring into 8
+    // sectors, and count how many samples end up in every cell.
+    fn chi_squared(sampling: LensSampling) -> f32 {
+        let n = 8192;
+        let mut counts = [0u32; 64];
+        for _ in 0 .. n {
+            let (x, y) = sampling.sample();
+            let r2 = (x * x + y * y).min(0.9999);
+            let angle = y.atan2(x) + PI;
+            let ring = (r2 * 8.0) as usize;
+            let sector = ((angle / (2.0 * PI) * 8.0) as usize).min(7);
+            counts[ring * 8 + sector] += 1;
+        }
+        let expected = n as f32 / 64.0;
+        counts.iter().map(|&c| (c as f32 - expected).powi(2) / expected).sum()
+    }
+
+    // For uniform samples, the chi-squared statistic is around 63.
+    let polar = chi_squared(LensSampling::Polar);
+    let concentric = chi_squared(LensSampling::Concentric);
+    assert!(concentric < 150.0);
+    assert!(concentric < polar);
+}
+
+#[test]
+fn pinhole_camera_skips_lens_sampling() {
+    let mut camera = camera_at_origin(0.0);
+    camera.position = Vector3::new(1.0, 2.0, 3.0);
+    camera.depth_of_field = PINHOLE_DEPTH_OF_FIELD;
+
+    // No random numbers are drawn for the lens, the ray starts exactly
+    // at the camera, and goes through the same point as a pinhole ray.
+    ::monte_carlo::set_fixed_units(&[0.25, 0.5]);
+    let ray = camera.get_ray(0.3, -0.2, 450.0);
+    assert_eq!(::monte_carlo::get_unit(), 0.25);
+    assert_eq!((ray.origin.x, ray.origin.y, ray.origin.z), (1.0, 2.0, 3.0));
+
+    let pinhole = camera.get_screen_ray(0.3, -0.2, 1.0, 0.0, 0.0);
+    assert_eq!((ray.direction.x, ray.direction.y, ray.direction.z),
+               (pinhole.direction.x, pinhole.direction.y, pinhole.direction.z));
+    ::monte_carlo::set_fixed_units(&[]);
+}
+
+#[test]
+fn lens_shift_keeps_verticals_parallel() {
+    let mut camera = camera_at_origin(0.0);
+    camera.lens_shift = (0.0, -0.5);
+    camera.depth_of_field = PINHOLE_DEPTH_OF_FIELD;
+
+    // The centre of the image is now above the optical axis.
+    let ray = camera.get_ray(0.0, 0.0, 550.0);
+    assert!(ray.direction.x.abs() < 1.0e-6);
+    assert!((ray.direction.z / ray.direction.y - 0.5).abs() < 1.0e-5);
+    let (x, y, _) = camera.project(ray.direction * 10.0).unwrap();
+    assert!(x.abs() < 1.0e-5 && y.abs() < 1.0e-5);
+
+    // A vertical line is still vertical on screen, unlike when the camera
+    // would have been tilted upwards.
+    let (bottom, _, _) = camera.project(Vector3::new(1.0, 10.0, 0.0)).unwrap();
+    let (top, _, _) = camera.project(Vector3::new(1.0, 10.0, 5.0)).unwrap();
+    assert!((bottom - top).abs() < 1.0e-6);
+}
@@ -0,0 +1,351 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::path::Path;
+use vector3::{Vector3, Vector3d};
+
+/// Identifies a checkpoint file, and the version of its format.
+const MAGIC: &'static [u8; 8] = b"RLCKPT03";
+
+/// Identifies a checkpoint file of the second version, which does not
+/// contain the fingerprint.
+const MAGIC_V2: &'static [u8; 8] = b"RLCKPT02";
+
+/// Identifies a checkpoint file of the first version, which does not
+/// contain the sampling state either.
+const MAGIC_V1: &'static [u8; 8] = b"RLCKPT01";
+
+/// A hasher that summarizes the scene and settings that a checkpoint was
+/// rendered with, so that it is not resumed into a different render. Unlike
+/// the hasher of `HashMap`, it is not randomized, so the fingerprint of a
+/// render is the same on every run. It is 64-bit FNV-1a.
+pub struct Fingerprint {
+    hash: u64
+}
+
+impl Fingerprint {
+    pub fn new() -> Fingerprint {
+        Fingerprint { hash: 0xcbf29ce484222325 }
+    }
+}
+
+impl Hasher for Fingerprint {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.hash = (self.hash ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// Where the trace units were in their streams of random numbers, and how
+/// far the scheduler got, for the batches that made it into the gathered
+/// image. A render that was seeded then resumes as if it never stopped.
+#[derive(Clone, Default)]
+pub struct SamplingState {
+    /// The seed that the trace units derived their random numbers from.
+    pub seed: Option<u32>,
+
+    /// The number of batches that were gathered.
+    pub batches_traced: u32,
+
+    /// For every trace unit, the number of seeded batches it rendered.
+    pub unit_batches: Vec<u32>
+}
+
+/// Everything that the gather unit accumulated, so that a render can be
+/// resumed where it stopped. Unless the render was seeded, sampling uses
+/// thread-local generators that are seeded anew, so a resumed render is then
+/// equivalent to an uninterrupted one in expectation, not bit for bit.
+pub struct Checkpoint {
+    /// The width of the canvas (in pixels).
+    pub image_width: u32,
+
+    /// The height of the canvas (in pixels).
+    pub image_height: u32,
+
+    /// The fingerprint of the scene and settings that were rendered, see
+    /// `Fingerprint`. It is 0 for checkpoints of older versions, which
+    /// did not save one.
+    pub fingerprint: u64,
+
+    /// The number of batches that were gathered.
+    pub batches: u64,
+
+    /// The accumulated tristimulus values.
+    pub tristimulus_buffer: Vec<Vector3>,
+
+    /// The compensation for rounding errors in summing.
+    pub compensation_buffer: Vec<Vector3>,
+
+    /// The values accumulated in double precision, if enabled.
+    pub double_buffer: Option<Vec<Vector3d>>,
+
+    /// The accumulated tristimulus values per light group.
+    pub group_buffers: Vec<Vec<Vector3>>,
+
+    /// The state of the scheduler and the generators.
+    pub sampling_state: SamplingState
+}
+
+/// Writes an integer of `n` bytes in little-endian order.
+fn write_uint<W: Write>(writer: &mut W, value: u64, n: usize) -> io::Result<()> {
+    let bytes: Vec<u8> = (0 .. n).map(|i| (value >> (i * 8)) as u8).collect();
+    writer.write_all(&bytes)
+}
+
+/// Reads an integer of `n` bytes in little-endian order.
+fn read_uint<R: Read>(reader: &mut R, n: usize) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    try!(reader.read_exact(&mut bytes[.. n]));
+    Ok(bytes[.. n].iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+}
+
+/// Reads a little-endian 32-bit float.
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    try!(reader.read_exact(&mut bytes));
+    Ok(f32::from_bits(u32::from_le_bytes(bytes)))
+}
+
+/// Reads a little-endian 64-bit float.
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    try!(reader.read_exact(&mut bytes));
+    Ok(f64::from_bits(u64::from_le_bytes(bytes)))
+}
+
+fn write_buffer<W: Write>(writer: &mut W, buffer: &[Vector3]) -> io::Result<()> {
+    for trist in buffer {
+        for &value in [trist.x, trist.y, trist.z].iter() {
+            try!(writer.write_all(&value.to_bits().to_le_bytes()));
+        }
+    }
+    Ok(())
+}
+
+fn read_buffer<R: Read>(reader: &mut R, sz: usize) -> io::Result<Vec<Vector3>> {
+    let mut buffer = Vec::with_capacity(sz);
+    for _ in 0 .. sz {
+        let x = try!(read_f32(reader));
+        let y = try!(read_f32(reader));
+        let z = try!(read_f32(reader));
+        buffer.push(Vector3::new(x, y, z));
+    }
+    Ok(buffer)
+}
+
+fn write_double_buffer<W: Write>(writer: &mut W, buffer: &[Vector3d]) -> io::Result<()> {
+    for trist in buffer {
+        for &value in [trist.x, trist.y, trist.z].iter() {
+            try!(writer.write_all(&value.to_bits().to_le_bytes()));
+        }
+    }
+    Ok(())
+}
+
+fn read_double_buffer<R: Read>(reader: &mut R, sz: usize) -> io::Result<Vec<Vector3d>> {
+    let mut buffer = Vec::with_capacity(sz);
+    for _ in 0 .. sz {
+        let x = try!(read_f64(reader));
+        let y = try!(read_f64(reader));
+        let z = try!(read_f64(reader));
+        buffer.push(Vector3d { x: x, y: y, z: z });
+    }
+    Ok(buffer)
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint in a raw format, which is only meant to be
+    /// read back on the same machine.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writer.write_all(MAGIC));
+        try!(write_uint(writer, self.image_width as u64, 4));
+        try!(write_uint(writer, self.image_height as u64, 4));
+        try!(write_uint(writer, self.fingerprint, 8));
+        try!(write_uint(writer, self.batches, 8));
+        try!(write_uint(writer, self.double_buffer.is_some() as u64, 1));
+        try!(write_uint(writer, self.group_buffers.len() as u64, 4));
+
+        try!(write_buffer(writer, &self.tristimulus_buffer));
+        try!(write_buffer(writer, &self.compensation_buffer));
+        if let Some(ref double_buffer) = self.double_buffer {
+            try!(write_double_buffer(writer, double_buffer));
+        }
+        for buffer in &self.group_buffers {
+            try!(write_buffer(writer, buffer));
+        }
+
+        let state = &self.sampling_state;
+        try!(write_uint(writer, state.seed.is_some() as u64, 1));
+        try!(write_uint(writer, state.seed.unwrap_or(0) as u64, 4));
+        try!(write_uint(writer, state.batches_traced as u64, 4));
+        try!(write_uint(writer, state.unit_batches.len() as u64, 4));
+        for &batches in &state.unit_batches {
+            try!(write_uint(writer, batches as u64, 4));
+        }
+        Ok(())
+    }
+
+    /// Reads a checkpoint that was written by `write`, for a canvas of the
+    /// specified size and a render with the specified fingerprint. A
+    /// checkpoint of a different size or fingerprint is invalid. Older
+    /// checkpoints without a fingerprint are accepted.
+    pub fn read<R: Read>(reader: &mut R, width: u32, height: u32, fingerprint: u64)
+                         -> io::Result<Checkpoint> {
+        let invalid = |reason| Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+
+        let mut magic = [0u8; 8];
+        try!(reader.read_exact(&mut magic));
+        let (has_fingerprint, has_state) = if &magic == MAGIC {
+            (true, true)
+        } else if &magic == MAGIC_V2 {
+            (false, true)
+        } else if &magic == MAGIC_V1 {
+            (false, false)
+        } else {
+            return invalid("not a checkpoint");
+        };
+
+        // Check the size before anything is allocated for it, so a corrupt
+        // header cannot ask for an enormous buffer.
+        let file_width = try!(read_uint(reader, 4)) as u32;
+        let file_height = try!(read_uint(reader, 4)) as u32;
+        if file_width != width || file_height != height {
+            return invalid("checkpoint for a canvas of a different size");
+        }
+        let file_fingerprint = if has_fingerprint { try!(read_uint(reader, 8)) } else { 0 };
+        if has_fingerprint && file_fingerprint != fingerprint {
+            return invalid("checkpoint of a different scene or render mode");
+        }
+        let sz = match width.checked_mul(height) {
+            Some(sz) => sz as usize,
+            None => return invalid("canvas too large")
+        };
+
+        let batches = try!(read_uint(reader, 8));
+        let has_double = try!(read_uint(reader, 1)) != 0;
+        let groups = try!(read_uint(reader, 4)) as usize;
+
+        let tristimulus_buffer = try!(read_buffer(reader, sz));
+        let compensation_buffer = try!(read_buffer(reader, sz));
+        let double_buffer = if has_double {
+            Some(try!(read_double_buffer(reader, sz)))
+        } else {
+            None
+        };
+        // The number of groups is not trusted either, the buffers are only
+        // allocated as far as the file actually contains them.
+        let mut group_buffers = Vec::new();
+        for _ in 0 .. groups {
+            group_buffers.push(try!(read_buffer(reader, sz)));
+        }
+
+        let mut sampling_state = SamplingState::default();
+        if has_state {
+            let has_seed = try!(read_uint(reader, 1)) != 0;
+            let seed = try!(read_uint(reader, 4)) as u32;
+            sampling_state.seed = if has_seed { Some(seed) } else { None };
+            sampling_state.batches_traced = try!(read_uint(reader, 4)) as u32;
+            let units = try!(read_uint(reader, 4));
+            for _ in 0 .. units {
+                sampling_state.unit_batches.push(try!(read_uint(reader, 4)) as u32);
+            }
+        }
+
+        Ok(Checkpoint {
+            image_width: width,
+            image_height: height,
+            fingerprint: file_fingerprint,
+            batches: batches,
+            tristimulus_buffer: tristimulus_buffer,
+            compensation_buffer: compensation_buffer,
+            double_buffer: double_buffer,
+            group_buffers: group_buffers,
+            sampling_state: sampling_state
+        })
+    }
+
+    /// Reads the raw buffer that the gather unit used to save before there
+    /// were checkpoints: only the tristimulus and compensation buffers, for a
+    /// canvas of the specified size. It does not contain the number of
+    /// batches or the sampling state.
+    pub fn read_legacy<R: Read>(reader: &mut R, width: u32, height: u32) -> io::Result<Checkpoint> {
+        let sz = match width.checked_mul(height) {
+            Some(sz) => sz as usize,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "canvas too large"))
+        };
+        let tristimulus_buffer = try!(read_buffer(reader, sz));
+        let compensation_buffer = try!(read_buffer(reader, sz));
+
+        // A buffer for a larger canvas would have data left.
+        let mut rest = [0u8; 1];
+        if try!(reader.read(&mut rest)) != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "raw buffer for a canvas of a different size"));
+        }
+
+        Ok(Checkpoint {
+            image_width: width,
+            image_height: height,
+            fingerprint: 0,
+            batches: 0,
+            tristimulus_buffer: tristimulus_buffer,
+            compensation_buffer: compensation_buffer,
+            double_buffer: None,
+            group_buffers: Vec::new(),
+            sampling_state: SamplingState::default()
+        })
+    }
+
+    /// Saves the checkpoint to `path`. It is written to a temporary file
+    /// first, so a failed write never destroys the previous checkpoint.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let file = try!(File::create(&tmp_path));
+            let mut file = BufWriter::new(file);
+            try!(self.write(&mut file));
+            try!(file.flush());
+        }
+
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Loads a checkpoint for a canvas of the specified size and a render
+    /// with the specified fingerprint that was saved to `path`.
+    pub fn load<P: AsRef<Path>>(path: P, width: u32, height: u32, fingerprint: u64)
+                                -> io::Result<Checkpoint> {
+        let file = try!(File::open(path));
+        Checkpoint::read(&mut BufReader::new(file), width, height, fingerprint)
+    }
+
+    /// Loads a raw buffer in the legacy format from `path`.
+    pub fn load_legacy<P: AsRef<Path>>(path: P, width: u32, height: u32) -> io::Result<Checkpoint> {
+        let file = try!(File::open(path));
+        Checkpoint::read_legacy(&mut BufReader::new(file), width, height)
+    }
+}
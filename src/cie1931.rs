@@ -47,6 +47,23 @@ pub fn get_tristimulus(wavelength: f32) -> Vector3 {
     }
 }
 
+/// Returns the luminance of a CIE XYZ tristimulus value, which is its Y
+/// component. Wherever the brightness of a tristimulus value is needed,
+/// this function should be used.
+pub fn luminance(cie: Vector3) -> f32 {
+    cie.y
+}
+
+/// Returns the CIE XYZ tristimulus value of a spectrum, by integrating it
+/// against the colour matching functions at the 5 nm intervals of the tables.
+pub fn integrate<F: Fn(f32) -> f32>(spectrum: F) -> Vector3 {
+    (0 .. 81).fold(Vector3::zero(), |acc, i| {
+        let wavelength = 380.0 + i as f32 * 5.0;
+        let cmf = Vector3::new(X[i], Y[i], Z[i]);
+        acc + cmf * (spectrum(wavelength) * 5.0)
+    })
+}
+
 // Data obtained from http://cvrl.ioo.ucl.ac.uk/index.htm.
 
 /// CIE X tristimulus values, at 5nm intervals, starting at 380 nm.
@@ -303,3 +320,13 @@ const Z: [f32; 81] = [
     0.000000,
     0.000000
 ];
+
+#[test]
+fn luminance_is_y_component() {
+    assert_eq!(luminance(Vector3::new(0.0, 0.7, 0.0)), 0.7);
+    assert_eq!(luminance(Vector3::new(0.3, 0.7, 0.9)), 0.7);
+
+    // Luma of linear sRGB is the luminance of the same colour.
+    let rgb = Vector3::new(0.2, 0.5, 0.8);
+    assert!((::srgb::luma(rgb) - luminance(::srgb::srgb_to_xyz(rgb))).abs() < 1.0e-6);
+}
@@ -0,0 +1,437 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::f32;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::iter::repeat;
+use std::path::Path;
+use read;
+use vector3::Vector3;
+
+/// The largest number of texels that an environment map read from a file
+/// may have. A corrupt or malicious header should not make the renderer
+/// allocate an arbitrary amount of memory.
+const MAX_TEXELS: usize = 1 << 26;
+
+/// An equirectangular environment map that lights rays
+/// which escape the scene.
+pub struct Environment {
+    /// The number of texels in the horizontal (longitude) direction.
+    width: usize,
+
+    /// The number of texels in the vertical (latitude) direction.
+    height: usize,
+
+    /// Linear sRGB radiance values, row by row. The first row
+    /// is at the zenith (positive z), the last one at the nadir.
+    texels: Vec<Vector3>,
+
+    /// For every texel, its luminance times the solid angle that it covers
+    /// (up to a constant factor), the weight with which it is sampled.
+    weights: Vec<f32>,
+
+    /// The sum of all weights.
+    total_weight: f32,
+
+    /// The cumulative probability of choosing a row, or a row before it.
+    row_cdf: Vec<f32>,
+
+    /// For every row, the cumulative probability of choosing a texel in
+    /// the row, or a texel before it, given that the row was chosen.
+    texel_cdf: Vec<f32>
+}
+
+/// Returns the index of the first entry of the cumulative distribution
+/// that exceeds `u`, so entries of zero weight are never chosen.
+fn find_in_cdf(cdf: &[f32], u: f32) -> usize {
+    cdf.partition_point(|c| *c <= u).min(cdf.len() - 1)
+}
+
+/// Turns the weights into a cumulative distribution in place.
+/// Returns the sum of the weights.
+fn accumulate_cdf(cdf: &mut [f32]) -> f32 {
+    let mut total = 0.0;
+    for c in cdf.iter_mut() {
+        total += *c;
+        *c = total;
+    }
+    if total > 0.0 {
+        for c in cdf.iter_mut() { *c /= total; }
+    }
+    total
+}
+
+/// Returns an error that indicates a malformed Radiance file.
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Converts a Radiance RGBE pixel to linear radiance.
+fn rgbe_to_rgb(rgbe: &[u8]) -> Vector3 {
+    if rgbe[3] == 0 {
+        Vector3::zero()
+    } else {
+        // The exponent is shared, and biased by 128. The mantissas
+        // are 8-bit fractions, so there is another factor 2^-8.
+        let f = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+        Vector3::new(rgbe[0] as f32 * f, rgbe[1] as f32 * f, rgbe[2] as f32 * f)
+    }
+}
+
+/// Reads one run-length encoded scanline with separate channels,
+/// the 'new' Radiance RLE scheme.
+fn read_rle_scanline<R: Read>(reader: &mut R, scanline: &mut [u8]) -> Result<()> {
+    let width = scanline.len() / 4;
+    for channel in 0 .. 4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            try!(read::read_into(reader, &mut count));
+            if count[0] > 128 {
+                // A run of a single value.
+                let n = (count[0] - 128) as usize;
+                if x + n > width { return Err(invalid_data("scanline overrun")); }
+                let mut value = [0u8; 1];
+                try!(read::read_into(reader, &mut value));
+                for i in x .. x + n { scanline[i * 4 + channel] = value[0]; }
+                x += n;
+            } else {
+                // A sequence of literal values.
+                let n = count[0] as usize;
+                if n == 0 || x + n > width { return Err(invalid_data("scanline overrun")); }
+                let mut values = [0u8; 128];
+                try!(read::read_into(reader, &mut values[.. n]));
+                for i in 0 .. n { scanline[(x + i) * 4 + channel] = values[i]; }
+                x += n;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Environment {
+    /// Creates an environment from linear sRGB radiance values, given row by
+    /// row, starting at the zenith.
+    pub fn new(width: usize, height: usize, texels: Vec<Vector3>) -> Environment {
+        assert!(width > 0 && height > 0, "environment map must not be empty");
+        assert_eq!(width * height, texels.len());
+
+        // Texels near the poles cover a smaller solid angle,
+        // which is proportional to the sine of the latitude. The luminance
+        // is clamped so that the sum of all weights stays finite, even if
+        // the map holds a NaN or infinity.
+        let max_luma = f32::MAX / texels.len() as f32;
+        let weights: Vec<f32> = texels.iter().enumerate().map(|(i, &rgb)| {
+            let latitude = ((i / width) as f32 + 0.5) / height as f32 * PI;
+            let luma = ::srgb::luma(rgb);
+            let luma = if luma.is_nan() { 0.0 } else { luma.max(0.0).min(max_luma) };
+            luma * latitude.sin()
+        }).collect();
+
+        // Rows are chosen by their total weight, and texels within the row
+        // by their own weight.
+        let mut texel_cdf = weights.clone();
+        let mut row_cdf: Vec<f32> = texel_cdf.chunks_mut(width).map(accumulate_cdf).collect();
+        let total_weight = accumulate_cdf(&mut row_cdf);
+
+        Environment {
+            width: width,
+            height: height,
+            texels: texels,
+            weights: weights,
+            total_weight: total_weight,
+            row_cdf: row_cdf,
+            texel_cdf: texel_cdf
+        }
+    }
+
+    /// Reads an equirectangular environment map from a Radiance `.hdr` file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Environment> {
+        let file = try!(File::open(path));
+        Environment::read_hdr(&mut BufReader::new(file))
+    }
+
+    /// Reads an equirectangular environment map in the Radiance RGBE format.
+    pub fn read_hdr<R: BufRead>(reader: &mut R) -> Result<Environment> {
+        let mut line = String::new();
+        try!(reader.read_line(&mut line));
+        if !line.starts_with("#?") {
+            return Err(invalid_data("not a Radiance file"));
+        }
+
+        // Skip over the header, which ends with an empty line.
+        loop {
+            line.clear();
+            if try!(reader.read_line(&mut line)) == 0 {
+                return Err(invalid_data("unexpected end of header"));
+            }
+            let trimmed = line.trim();
+            if trimmed.is_empty() { break; }
+            if trimmed.starts_with("FORMAT=") && trimmed != "FORMAT=32-bit_rle_rgbe" {
+                return Err(invalid_data("unsupported pixel format"));
+            }
+        }
+
+        // Then comes the resolution. Only the standard orientation,
+        // top to bottom and left to right, is supported.
+        line.clear();
+        try!(reader.read_line(&mut line));
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 4 || parts[0] != "-Y" || parts[2] != "+X" {
+            return Err(invalid_data("unsupported resolution string"));
+        }
+        let height: usize = try!(parts[1].parse().map_err(|_| invalid_data("invalid height")));
+        let width: usize = try!(parts[3].parse().map_err(|_| invalid_data("invalid width")));
+        let size = match width.checked_mul(height) {
+            Some(size) if size > 0 && size <= MAX_TEXELS => size,
+            _ => return Err(invalid_data("unsupported resolution"))
+        };
+
+        let mut texels = Vec::with_capacity(size);
+        let mut scanline: Vec<u8> = repeat(0).take(width * 4).collect();
+        for _ in 0 .. height {
+            // Scanlines of a reasonable width can be run-length encoded,
+            // which is indicated by a pixel that would be invalid otherwise.
+            let mut start = [0u8; 4];
+            try!(read::read_into(reader, &mut start));
+            let is_rle = width >= 8 && width < 32768 &&
+                         start[0] == 2 && start[1] == 2 &&
+                         ((start[2] as usize) << 8 | start[3] as usize) == width;
+            if is_rle {
+                try!(read_rle_scanline(reader, &mut scanline));
+            } else {
+                scanline[.. 4].copy_from_slice(&start);
+                try!(read::read_into(reader, &mut scanline[4 ..]));
+            }
+
+            texels.extend(scanline.chunks(4).map(rgbe_to_rgb));
+        }
+
+        Ok(Environment::new(width, height, texels))
+    }
+
+    /// Returns the continuous texel coordinates in the specified direction,
+    /// where texel (col, row) covers [col, col + 1) by [row, row + 1).
+    fn get_texel_position(&self, direction: Vector3) -> (f32, f32) {
+        let d = direction.normalise();
+
+        // The longitude is measured around the z-axis, the latitude
+        // goes from 0 at the zenith to pi at the nadir.
+        let longitude = d.y.atan2(d.x) + PI;
+        let latitude = d.z.max(-1.0).min(1.0).acos();
+
+        let u = longitude / (PI * 2.0) * self.width as f32;
+        let v = latitude / PI * self.height as f32;
+        (u, v)
+    }
+
+    /// Returns the column and row of the texel that lies in the
+    /// specified direction.
+    #[cfg(test)]
+    fn get_texel_coordinates(&self, direction: Vector3) -> (usize, usize) {
+        let (u, v) = self.get_texel_position(direction);
+        let col = (u.floor() as usize).min(self.width - 1);
+        let row = (v.floor() as usize).min(self.height - 1);
+        (col, row)
+    }
+
+    /// Returns the linear sRGB radiance in the specified direction,
+    /// interpolated bilinearly between the four nearest texels.
+    fn get_radiance(&self, direction: Vector3) -> Vector3 {
+        let (u, v) = self.get_texel_position(direction);
+
+        // Interpolate between texel centres. The longitude wraps around,
+        // so the first and last column are neighbours. The latitude does
+        // not wrap, at the poles the nearest row is used.
+        let u = u - 0.5;
+        let v = (v - 0.5).max(0.0).min(self.height as f32 - 1.0);
+        let fu = u - u.floor();
+        let fv = v - v.floor();
+        let w = self.width as isize;
+        let col0 = ((u.floor() as isize % w + w) % w) as usize;
+        let col1 = (col0 + 1) % self.width;
+        let row0 = v.floor() as usize;
+        let row1 = (row0 + 1).min(self.height - 1);
+
+        let texel = |col: usize, row: usize| self.texels[row * self.width + col];
+        let top = texel(col0, row0) * (1.0 - fu) + texel(col1, row0) * fu;
+        let bottom = texel(col0, row1) * (1.0 - fu) + texel(col1, row1) * fu;
+        top * (1.0 - fv) + bottom * fv
+    }
+
+    /// Returns the probability density (per steradian) of choosing a
+    /// direction at the continuous texel position (`u`, `v`).
+    fn get_pdf_at(&self, u: f32, v: f32) -> f32 {
+        let col = (u.floor() as usize).min(self.width - 1);
+        let row = (v.floor() as usize).min(self.height - 1);
+        let sin_latitude = (v / self.height as f32 * PI).sin();
+        if self.total_weight <= 0.0 || sin_latitude <= 0.0 {
+            return 0.0;
+        }
+
+        // A texel covers 2pi / width by pi / height radians, and the solid
+        // angle shrinks with the sine of the latitude.
+        let texel_probability = self.weights[row * self.width + col] / self.total_weight;
+        let texel_area = 2.0 * PI * PI / (self.width * self.height) as f32;
+        texel_probability / (texel_area * sin_latitude)
+    }
+
+    /// Returns the probability density (per steradian) with which
+    /// `sample_direction` chooses the specified direction.
+    pub fn get_pdf(&self, direction: Vector3) -> f32 {
+        let (u, v) = self.get_texel_position(direction);
+        self.get_pdf_at(u, v)
+    }
+
+    /// Chooses a random direction, proportional to the luminance of the
+    /// environment in that direction, so bright regions such as the sun are
+    /// found by shadow rays. Returns the direction and its probability
+    /// density (per steradian), or `None` if the environment is black.
+    pub fn sample_direction(&self) -> Option<(Vector3, f32)> {
+        if self.total_weight <= 0.0 {
+            return None;
+        }
+
+        let row = find_in_cdf(&self.row_cdf, ::monte_carlo::get_unit());
+        let row_cdf = &self.texel_cdf[row * self.width .. (row + 1) * self.width];
+        let col = find_in_cdf(row_cdf, ::monte_carlo::get_unit());
+
+        // Pick a point within the texel, and invert `get_texel_position`.
+        let u = col as f32 + ::monte_carlo::get_unit();
+        let v = row as f32 + ::monte_carlo::get_unit();
+        let longitude = u / self.width as f32 * PI * 2.0 - PI;
+        let latitude = v / self.height as f32 * PI;
+        let direction = Vector3 {
+            x: latitude.sin() * longitude.cos(),
+            y: latitude.sin() * longitude.sin(),
+            z: latitude.cos()
+        };
+
+        match self.get_pdf_at(u, v) {
+            pdf if pdf > 0.0 => Some((direction, pdf)),
+            _ => None
+        }
+    }
+
+    /// Returns the spectral intensity of the environment in the specified
+    /// direction, at the specified wavelength.
+    pub fn sample(&self, direction: Vector3, wavelength: f32) -> f32 {
+        ::srgb::upsample(self.get_radiance(direction), wavelength)
+    }
+}
+
+#[test]
+fn constant_environment_is_uniform() {
+    use std::io::Cursor;
+
+    // A 4x2 image where every pixel has radiance 1.0 (128 * 2^(129 - 136)).
+    let mut hdr: Vec<u8> = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 4\n".to_vec();
+    for _ in 0 .. 8 { hdr.extend_from_slice(&[128, 128, 128, 129]); }
+
+    let env = Environment::read_hdr(&mut Cursor::new(hdr)).unwrap();
+    let directions = [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0),
+                      Vector3::new(0.0, 0.0, 1.0), Vector3::new(-1.0, 1.0, -1.0)];
+    for &d in directions.iter() {
+        for &wavelength in [400.0, 550.0, 700.0].iter() {
+            assert!((env.sample(d, wavelength) - 1.0).abs() < 1.0e-5);
+        }
+    }
+}
+
+#[test]
+fn invalid_resolution_is_rejected() {
+    use std::io::Cursor;
+
+    let resolutions = ["-Y 0 +X 0", "-Y 0 +X 4", "-Y 2 +X 0",
+                       "-Y 100000 +X 100000",
+                       "-Y 18446744073709551615 +X 2"];
+    for resolution in resolutions.iter() {
+        let hdr = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution);
+        let err = Environment::read_hdr(&mut Cursor::new(hdr.into_bytes())).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn direction_maps_to_lat_long_texel() {
+    let env = Environment::new(4, 2, repeat(Vector3::zero()).take(8).collect());
+
+    // Longitude starts at -x, and increases counter-clockwise around z.
+    assert_eq!(env.get_texel_coordinates(Vector3::new(-1.0, -0.01, 0.5)), (0, 0));
+    assert_eq!(env.get_texel_coordinates(Vector3::new(0.01, -1.0, 0.5)), (1, 0));
+    assert_eq!(env.get_texel_coordinates(Vector3::new(1.0, 0.01, -0.5)), (2, 1));
+    assert_eq!(env.get_texel_coordinates(Vector3::new(-0.01, 1.0, -0.5)), (3, 1));
+}
+
+#[test]
+fn lookup_wraps_around_longitude_seam() {
+    // The first column is white, the others are black.
+    let white = Vector3::new(1.0, 1.0, 1.0);
+    let black = Vector3::zero();
+    let row = [white, black, black, black];
+    let env = Environment::new(4, 2, row.iter().chain(row.iter()).cloned().collect());
+
+    // The seam is at -x. Just past it, and just before it, the lookup
+    // must blend the last and first column, rather than snap to one.
+    let past = env.sample(Vector3::new(-1.0, -0.01, 0.0), 550.0);
+    let before = env.sample(Vector3::new(-1.0, 0.01, 0.0), 550.0);
+    assert!(past > 0.4 && past < 0.6);
+    assert!(before > 0.4 && before < 0.6);
+    assert!(past > before);
+
+    // Near the poles, the latitude is clamped.
+    let zenith = env.sample(Vector3::new(-1.0, -0.01, 100.0), 550.0);
+    assert!(zenith > 0.4 && zenith < 0.6);
+}
+
+#[test]
+fn bright_texel_is_sampled_most() {
+    // A dim environment with one bright texel.
+    let mut texels: Vec<Vector3> = repeat(Vector3::new(0.1, 0.1, 0.1)).take(32).collect();
+    texels[8 + 5] = Vector3::new(100.0, 100.0, 100.0);
+    let env = Environment::new(8, 4, texels);
+
+    let n = 1000;
+    let mut bright = 0;
+    for _ in 0 .. n {
+        let (direction, _) = env.sample_direction().unwrap();
+        if env.get_texel_coordinates(direction) == (5, 1) { bright += 1; }
+    }
+    assert!(bright > n * 9 / 10);
+}
+
+#[test]
+fn non_finite_texels_do_not_break_sampling() {
+    let mut texels: Vec<Vector3> = repeat(Vector3::new(0.1, 0.1, 0.1)).take(32).collect();
+    texels[3] = Vector3::new(f32::NAN, f32::NAN, f32::NAN);
+    texels[8 + 5] = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+    let env = Environment::new(8, 4, texels);
+    assert!(env.total_weight.is_finite());
+    assert!(env.row_cdf.iter().chain(env.texel_cdf.iter()).all(|c| c.is_finite()));
+
+    // The NaN texel is never chosen, the infinite one nearly always.
+    for _ in 0 .. 100 {
+        let (direction, pdf) = env.sample_direction().unwrap();
+        assert!(pdf.is_finite() && pdf > 0.0);
+        assert_eq!(env.get_texel_coordinates(direction), (5, 1));
+    }
+
+    // A NaN in the distribution itself does not make the search panic.
+    assert!(find_in_cdf(&[0.5, 1.0], f32::NAN) < 2);
+}
@@ -14,19 +14,131 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp::Ordering;
 use std::fs::File;
-use std::io::{Write, BufReader, BufWriter};
+use std::io;
+use std::io::{Write, BufWriter};
 use std::iter::repeat;
-use std::mem::transmute;
-use read;
-use vector3::Vector3;
+use std::path::{Path, PathBuf};
+use buffer::Buffer;
+use checkpoint::{Checkpoint, SamplingState};
+use vector3::{Vector3, Vector3d};
+
+/// The width and height of the square regions in which outliers are found.
+const REGION_SIZE: u32 = 16;
+
+/// The number of recent batches per region that outliers are compared to.
+const MAX_BATCHES: usize = 32;
+
+/// The number of batches per region that must have been seen before
+/// outliers are rejected.
+const MIN_BATCHES: usize = 5;
+
+/// Rejects the part of a batch in a region of the image if its luminance is
+/// much higher than that of recent batches in the same region, which removes
+/// fireflies with less bias than clamping individual pixels.
+struct OutlierRejection {
+    /// The number of median absolute deviations above the median at which
+    /// a batch is rejected.
+    threshold: f32,
+
+    /// For every region, the luminance of the most recent batches.
+    history: Vec<Vec<f32>>
+}
+
+/// Returns the median of the values, which must not be empty.
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let n = values.len();
+    if n % 2 == 1 { values[n / 2] } else { (values[n / 2 - 1] + values[n / 2]) * 0.5 }
+}
+
+impl OutlierRejection {
+    /// Returns whether the luminance of a batch in a region is an outlier
+    /// compared to the history of the region, and adds it to the history.
+    /// Rejected batches are recorded too, so the history can follow a
+    /// region that really becomes brighter. If most of the history is the
+    /// same, as in a dark region that light only rarely reaches, there is no
+    /// deviation to compare to, and nothing is rejected.
+    fn is_outlier(&mut self, region: usize, luminance: f32) -> bool {
+        let history = &mut self.history[region];
+        let is_outlier = if history.len() >= MIN_BATCHES {
+            let mut values = history.clone();
+            let med = median(&mut values);
+            let mut deviations: Vec<f32> = values.iter().map(|x| (x - med).abs()).collect();
+            let mad = median(&mut deviations);
+            mad > 0.0 && luminance - med > self.threshold * mad
+        } else {
+            false
+        };
+
+        if history.len() == MAX_BATCHES {
+            history.remove(0);
+        }
+        history.push(luminance);
+        is_outlier
+    }
+}
 
 pub struct GatherUnit {
+    /// The width of the canvas (in pixels).
+    image_width: u32,
+
+    /// The height of the canvas (in pixels).
+    image_height: u32,
+
     /// The buffer of tristimulus values.
-    pub tristimulus_buffer: Vec<Vector3>,
+    pub tristimulus_buffer: Buffer,
 
     /// A buffer that contains compensation for rounding errors in summing.
-    compensation_buffer: Vec<Vector3>
+    compensation_buffer: Buffer,
+
+    /// When accumulating in double precision, the accumulated values.
+    /// The tristimulus buffer then holds a rounded copy.
+    double_buffer: Option<Vec<Vector3d>>,
+
+    /// The accumulated tristimulus values per light group, if the plot
+    /// units plot light groups separately.
+    pub group_buffers: Vec<Vec<Vector3>>,
+
+    /// When rejecting outlier batches, the threshold and the history.
+    outlier_rejection: Option<OutlierRejection>,
+
+    /// The number of batches that were accumulated.
+    batches: u64,
+
+    /// The state of the scheduler and the generators for the batches that
+    /// were accumulated, set by the task scheduler, and saved with the
+    /// checkpoint.
+    pub sampling_state: SamplingState,
+
+    /// The index of the scene camera whose image this unit gathers.
+    pub camera: usize,
+
+    /// The weight of every light group, if the image is displayed relit,
+    /// see `relight`. `None` by default.
+    pub light_weights: Option<Vec<f32>>,
+
+    /// Whether the signal-to-noise ratio, see `estimate_snr`, is printed
+    /// for every image. False by default.
+    pub print_snr: bool,
+
+    /// The file that checkpoints are saved to and resumed from, if any.
+    /// `None` by default.
+    checkpoint_path: Option<PathBuf>,
+
+    /// The fingerprint of the scene and settings that are rendered, which
+    /// is saved with the checkpoint. Only a checkpoint with the same
+    /// fingerprint is resumed. 0 by default.
+    pub fingerprint: u64,
+
+    /// The file that the image is exported to as a NumPy array on every
+    /// save, if any. `None` by default.
+    npy_path: Option<PathBuf>,
+
+    /// The file that the channel images are named after on every save, if
+    /// any. `None` by default.
+    channel_images_path: Option<PathBuf>
 }
 
 impl GatherUnit {
@@ -34,19 +146,124 @@ impl GatherUnit {
     /// of the specified size.
     pub fn new(width: u32, height: u32) -> GatherUnit {
         let sz = (width * height) as usize;
-        let mut unit = GatherUnit {
-            tristimulus_buffer: repeat(Vector3::zero()).take(sz).collect(),
-            compensation_buffer: repeat(Vector3::zero()).take(sz).collect()
+        GatherUnit {
+            image_width: width,
+            image_height: height,
+            tristimulus_buffer: Buffer::new(sz),
+            compensation_buffer: Buffer::new(sz),
+            double_buffer: None,
+            group_buffers: Vec::new(),
+            outlier_rejection: None,
+            batches: 0,
+            sampling_state: SamplingState::default(),
+            camera: 0,
+            light_weights: None,
+            print_snr: false,
+            checkpoint_path: None,
+            fingerprint: 0,
+            npy_path: None,
+            channel_images_path: None
+        }
+    }
+
+    /// Sets the file that checkpoints are saved to by `save`, and resumed
+    /// from by `resume`. With `None`, nothing is saved.
+    pub fn set_checkpoint_path(&mut self, path: Option<PathBuf>) {
+        self.checkpoint_path = path;
+    }
+
+    /// Sets the file that `save` also exports the image to, see `save_npy`.
+    /// With `None`, nothing is exported.
+    pub fn set_npy_path(&mut self, path: Option<PathBuf>) {
+        self.npy_path = path;
+    }
+
+    /// Sets the file that `save` also writes the channel images next to, see
+    /// `save_channel_images`. With `None`, nothing is written.
+    pub fn set_channel_images_path(&mut self, path: Option<PathBuf>) {
+        self.channel_images_path = path;
+    }
+
+    /// Enables or disables accumulating in double precision. Single
+    /// precision with compensation is accurate enough for most renders,
+    /// but very long renders can still lose the low-order bits.
+    pub fn set_double_precision(&mut self, enabled: bool) {
+        self.double_buffer = if enabled {
+            Some(self.tristimulus_buffer.iter().map(|&v| Vector3d::from_vector3(v)).collect())
+        } else {
+            None
         };
+    }
+
+    /// Enables rejecting outlier batches when `threshold` is `Some`, or
+    /// disables it. A region of a batch is left out when its luminance is
+    /// more than `threshold` median absolute deviations above the median of
+    /// recent batches. Leaving out a batch darkens the region slightly, but
+    /// much less than a firefly brightens it.
+    pub fn set_outlier_rejection(&mut self, threshold: Option<f32>) {
+        let regions = self.get_region_count();
+        self.outlier_rejection = threshold.map(|threshold| OutlierRejection {
+            threshold: threshold,
+            history: (0 .. regions).map(|_| Vec::new()).collect()
+        });
+    }
+
+    /// Returns the number of regions per row of the canvas.
+    fn get_regions_per_row(&self) -> u32 {
+        (self.image_width + REGION_SIZE - 1) / REGION_SIZE
+    }
+
+    /// Returns the number of regions that the canvas is divided into.
+    fn get_region_count(&self) -> usize {
+        let rows = (self.image_height + REGION_SIZE - 1) / REGION_SIZE;
+        (self.get_regions_per_row() * rows) as usize
+    }
+
+    /// Returns the region that contains the pixel at `index`.
+    fn get_region(&self, index: usize) -> usize {
+        let x = index as u32 % self.image_width;
+        let y = index as u32 / self.image_width;
+        ((y / REGION_SIZE) * self.get_regions_per_row() + x / REGION_SIZE) as usize
+    }
+
+    /// If outliers are rejected, returns a copy of the batch where the
+    /// regions that are outliers are black.
+    fn reject_outliers(&mut self, tristimuli: &[Vector3]) -> Option<Vec<Vector3>> {
+        if self.outlier_rejection.is_none() { return None; }
+
+        let mut luminances: Vec<f32> = repeat(0.0).take(self.get_region_count()).collect();
+        for (i, px) in tristimuli.iter().enumerate() {
+            luminances[self.get_region(i)] += ::cie1931::luminance(*px);
+        }
 
-        // Try to continue a previous render.
-        unit.read();
+        let rejection = self.outlier_rejection.as_mut().unwrap();
+        let rejected: Vec<bool> = luminances.iter().enumerate()
+                                            .map(|(r, &y)| rejection.is_outlier(r, y))
+                                            .collect();
 
-        unit
+        Some(tristimuli.iter().enumerate().map(|(i, &px)| {
+            if rejected[self.get_region(i)] { Vector3::zero() } else { px }
+        }).collect())
     }
 
     /// Add the results of the PlotUnit to the canvas.
     pub fn accumulate(&mut self, tristimuli: &[Vector3]) {
+        let accepted = self.reject_outliers(tristimuli);
+        let tristimuli = match accepted {
+            Some(ref accepted) => &accepted[..],
+            None => tristimuli
+        };
+        self.batches += 1;
+
+        if let Some(ref mut double_buffer) = self.double_buffer {
+            let accs = self.tristimulus_buffer.iter_mut();
+            for ((wide, acc), px) in double_buffer.iter_mut().zip(accs).zip(tristimuli.iter()) {
+                *wide = *wide + Vector3d::from_vector3(*px);
+                *acc = wide.to_vector3();
+            }
+            return;
+        }
+
         let accs = self.tristimulus_buffer.iter_mut();
         let comps = self.compensation_buffer.iter_mut();
         let pixels = tristimuli.iter();
@@ -63,32 +280,557 @@ impl GatherUnit {
         }
     }
 
-    /// Saves the tristimulus buffer to a file, so that rendering
-    /// can be resumed later.
-    pub fn save(&self) {
-        let file = File::create("buffer.raw").ok()
-                       .expect("failed to open file");
+    /// Add the light group buffers of the PlotUnit to the light groups.
+    /// Groups that were not gathered before start out black.
+    pub fn accumulate_groups(&mut self, groups: &[Vec<Vector3>]) {
+        let sz = self.tristimulus_buffer.len();
+        while self.group_buffers.len() < groups.len() {
+            self.group_buffers.push(repeat(Vector3::zero()).take(sz).collect());
+        }
+        for (acc_buffer, buffer) in self.group_buffers.iter_mut().zip(groups.iter()) {
+            for (acc, px) in acc_buffer.iter_mut().zip(buffer.iter()) {
+                *acc = *acc + *px;
+            }
+        }
+    }
+
+    /// Returns the image as it would have been rendered if the light of each
+    /// group had been scaled by its weight, so lights can be adjusted without
+    /// rendering again. The result can be tonemapped like the tristimulus
+    /// buffer. Groups without a weight are left out.
+    pub fn relight(&self, weights: &[f32]) -> Vec<Vector3> {
+        let mut result: Vec<Vector3> = repeat(Vector3::zero()).take(self.tristimulus_buffer.len()).collect();
+        for (buffer, &weight) in self.group_buffers.iter().zip(weights.iter()) {
+            for (px, &group_px) in result.iter_mut().zip(buffer.iter()) {
+                *px = *px + group_px * weight;
+            }
+        }
+        result
+    }
+
+    /// Estimates the signal-to-noise ratio of the image: the mean luminance
+    /// divided by the standard deviation of the noise. The noise is estimated
+    /// from the second difference of horizontally adjacent pixels, which is
+    /// zero for smooth gradients, and has six times the variance of the
+    /// noise otherwise. Edges in the image count as noise, so the estimate
+    /// is conservative. Rendering can stop once the ratio is high enough.
+    /// Returns infinity for an image without noise.
+    pub fn estimate_snr(&self) -> f32 {
+        let w = self.image_width as usize;
+        let luminance: Vec<f32> = self.tristimulus_buffer.iter()
+            .map(|&px| ::cie1931::luminance(px))
+            .collect();
+        let signal = luminance.iter().sum::<f32>() / luminance.len() as f32;
+
+        let mut sum_squares = 0.0f32;
+        let mut n = 0;
+        for row in luminance.chunks(w) {
+            for i in 1 .. w.saturating_sub(1) {
+                let d = row[i - 1] - 2.0 * row[i] + row[i + 1];
+                sum_squares = sum_squares + d * d;
+                n = n + 1;
+            }
+        }
+        if n == 0 { return ::std::f32::INFINITY; }
+
+        let noise = (sum_squares / (6 * n) as f32).sqrt();
+        if noise > 0.0 { signal / noise } else { ::std::f32::INFINITY }
+    }
+
+    /// Returns 8-bit greyscale images of the X, Y and Z channels of the
+    /// tristimulus buffer, for finding where the energy of each channel is
+    /// before the colour is transformed. The channels are scaled by the same
+    /// factor, such that the brightest value of all channels is white, so
+    /// they can be compared. There is no gamma correction.
+    pub fn channel_images(&self) -> [Vec<u8>; 3] {
+        let max = self.tristimulus_buffer.iter().fold(0.0f32, |max, px| {
+            [px.x, px.y, px.z].iter().filter(|v| v.is_finite()).fold(max, |max, &v| max.max(v))
+        });
+        let scale = if max > 0.0 { 255.0 / max } else { 0.0 };
+        let channel = |get: &Fn(&Vector3) -> f32| -> Vec<u8> {
+            self.tristimulus_buffer.iter().map(|px| {
+                let v = get(px) * scale;
+                if v.is_finite() { (v + 0.5).max(0.0).min(255.0) as u8 } else { 0 }
+            }).collect()
+        };
+        [channel(&|px| px.x), channel(&|px| px.y), channel(&|px| px.z)]
+    }
+
+    /// Writes the channel images as PNG files next to `path`, with `-x`, `-y`
+    /// and `-z` appended to its file stem.
+    pub fn save_channel_images<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned())
+                                   .unwrap_or_default();
+        for (image, channel) in self.channel_images().iter().zip(["x", "y", "z"].iter()) {
+            let channel_path = path.with_file_name(format!("{}-{}.png", stem, channel));
+            try!(::image::save_buffer(&channel_path, image, self.image_width,
+                                      self.image_height, ::image::Gray(8)));
+        }
+        Ok(())
+    }
+
+    /// Adapts the unit to a canvas of a different size. The canvas
+    /// cannot be scaled, so everything gathered so far is discarded. If a
+    /// memory-mapped buffer cannot follow the new size, it moves into memory
+    /// and the error is returned, but the unit is resized regardless.
+    pub fn resize(&mut self, width: u32, height: u32) -> io::Result<()> {
+        let sz = (width * height) as usize;
+        self.image_width = width;
+        self.image_height = height;
+        let tristimulus_result = self.tristimulus_buffer.reset(sz);
+        let compensation_result = self.compensation_buffer.reset(sz);
+        if let Some(ref mut double_buffer) = self.double_buffer {
+            double_buffer.clear();
+            double_buffer.extend(repeat(Vector3d::zero()).take(sz));
+        }
+        for buffer in &mut self.group_buffers {
+            buffer.clear();
+            buffer.extend(repeat(Vector3::zero()).take(sz));
+        }
+        let threshold = self.outlier_rejection.as_ref().map(|r| r.threshold);
+        self.set_outlier_rejection(threshold);
+        self.batches = 0;
+        tristimulus_result.and(compensation_result)
+    }
+
+    /// Resets the accumulated image to black.
+    pub fn clear(&mut self) {
+        for x in self.tristimulus_buffer.iter_mut()
+                     .chain(self.compensation_buffer.iter_mut()) {
+            *x = Vector3::zero();
+        }
+        if let Some(ref mut double_buffer) = self.double_buffer {
+            for x in double_buffer.iter_mut() {
+                *x = Vector3d::zero();
+            }
+        }
+        for x in self.group_buffers.iter_mut().flat_map(|b| b.iter_mut()) {
+            *x = Vector3::zero();
+        }
+        if let Some(ref mut rejection) = self.outlier_rejection {
+            for history in &mut rejection.history {
+                history.clear();
+            }
+        }
+        self.batches = 0;
+    }
+
+    /// Returns a copy of everything that was accumulated so far.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            image_width: self.image_width,
+            image_height: self.image_height,
+            fingerprint: self.fingerprint,
+            batches: self.batches,
+            tristimulus_buffer: self.tristimulus_buffer.to_vec(),
+            compensation_buffer: self.compensation_buffer.to_vec(),
+            double_buffer: self.double_buffer.clone(),
+            group_buffers: self.group_buffers.clone(),
+            sampling_state: self.sampling_state.clone()
+        }
+    }
+
+    /// Continues from a checkpoint, as if everything in it had been
+    /// accumulated by this unit. Returns false, and leaves the unit as it
+    /// is, if the checkpoint is for a canvas of a different size.
+    pub fn restore(&mut self, checkpoint: Checkpoint) -> bool {
+        if checkpoint.image_width != self.image_width ||
+           checkpoint.image_height != self.image_height {
+            return false;
+        }
+
+        self.batches = checkpoint.batches;
+        self.tristimulus_buffer.copy_from_slice(&checkpoint.tristimulus_buffer);
+        self.compensation_buffer.copy_from_slice(&checkpoint.compensation_buffer);
+        self.double_buffer = checkpoint.double_buffer;
+        self.group_buffers = checkpoint.group_buffers;
+        self.sampling_state = checkpoint.sampling_state;
+        true
+    }
+
+    /// Moves the tristimulus buffer into a memory-mapped file at `path`, and
+    /// the compensation buffer into one next to it, so that a huge canvas does
+    /// not have to fit in memory. Accumulation writes through to the files.
+    /// The double precision and light group buffers stay in memory.
+    #[cfg(feature = "mmap")]
+    pub fn map_buffers<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        self.tristimulus_buffer = try!(Buffer::map(path, &self.tristimulus_buffer));
+        self.compensation_buffer = try!(Buffer::map(path.with_extension("compensation"),
+                                                    &self.compensation_buffer));
+        Ok(())
+    }
+
+    /// Saves a checkpoint to the checkpoint file, if there is one, so that
+    /// rendering can be resumed later. Memory-mapped buffers are written to
+    /// their files too.
+    pub fn save(&self) -> io::Result<()> {
+        #[cfg(feature = "mmap")]
+        {
+            try!(self.tristimulus_buffer.flush());
+            try!(self.compensation_buffer.flush());
+        }
+
+        if let Some(ref path) = self.npy_path {
+            try!(self.save_npy(path));
+        }
+        if let Some(ref path) = self.channel_images_path {
+            try!(self.save_channel_images(path));
+        }
+        match self.checkpoint_path {
+            Some(ref path) => self.checkpoint().save(path),
+            None => Ok(())
+        }
+    }
+
+    /// Writes the tristimulus buffer to `path` as a NumPy `.npy` file, an
+    /// array of little-endian 32-bit floats with shape (height, width, 3).
+    /// Unlike the raw buffer, it can be read with `numpy.load`.
+    pub fn save_npy<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = try!(File::create(path));
         let mut file = BufWriter::new(file);
-        let data = self.tristimulus_buffer.iter()
-                       .chain(self.compensation_buffer.iter());
-        for trist in data {
-            let xyz: &[u8; 12] = unsafe { transmute(trist) };
-            file.write_all(xyz).ok().expect("failed to write raw buffer");
-        }
-    }
-
-    /// Reads the tristimulus buffer from a file, to resume rendering.
-    fn read(&mut self) {
-        if let Ok(ref file) = File::open("buffer.raw") {
-            let mut file = BufReader::new(file);
-            let data = self.tristimulus_buffer.iter_mut()
-                           .chain(self.compensation_buffer.iter_mut());
-            for trist in data {
-                let xyz: &mut [u8; 12] = unsafe { transmute(trist) };
-                read::read_into(&mut file, xyz).ok()
-                     .expect("failed to read from raw buffer");
+
+        // The header is a Python dictionary literal, padded with spaces and
+        // terminated by a newline, such that the data is aligned to 64 bytes.
+        let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}, 3), }}",
+                                 self.image_height, self.image_width);
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+
+        let header_len = header.len() as u16;
+        try!(file.write_all(b"\x93NUMPY\x01\x00"));
+        try!(file.write_all(&[header_len as u8, (header_len >> 8) as u8]));
+        try!(file.write_all(header.as_bytes()));
+
+        for trist in &self.tristimulus_buffer {
+            for &value in [trist.x, trist.y, trist.z].iter() {
+                try!(file.write_all(&value.to_bits().to_le_bytes()));
             }
         }
+
+        file.flush()
+    }
+
+    /// Continues from the checkpoint that was saved last, if there is one.
+    /// If there is no checkpoint, the `buffer.raw` next to it that older
+    /// versions saved is used instead. A checkpoint of a different scene or
+    /// render mode is ignored. Returns whether anything was resumed.
+    pub fn resume(&mut self) -> bool {
+        let path = match self.checkpoint_path {
+            Some(ref path) => path.clone(),
+            None => return false
+        };
+        let (w, h) = (self.image_width, self.image_height);
+        let checkpoint = match Checkpoint::load(&path, w, h, self.fingerprint) {
+            Ok(checkpoint) => checkpoint,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                match Checkpoint::load_legacy(path.with_file_name("buffer.raw"), w, h) {
+                    Ok(checkpoint) => checkpoint,
+                    Err(_) => return false
+                }
+            }
+            Err(reason) => {
+                println!("ignoring checkpoint: {}", reason);
+                return false;
+            }
+        };
+        self.restore(checkpoint)
+    }
+
+}
+
+#[test]
+fn double_precision_is_more_accurate() {
+    let value = Vector3::new(0.1, 0.1, 0.1);
+    let n = 1000003;
+    let exact = n as f64 * value.x as f64;
+
+    let mut single = GatherUnit::new(1, 1);
+    single.clear();
+    let mut double = GatherUnit::new(1, 1);
+    double.clear();
+    double.set_double_precision(true);
+
+    for _ in 0 .. n {
+        single.accumulate(&[value]);
+        double.accumulate(&[value]);
+    }
+
+    let single_error = (single.tristimulus_buffer[0].x as f64 - exact).abs();
+    let double_error = (double.double_buffer.as_ref().unwrap()[0].x - exact).abs();
+    assert!(double_error < single_error);
+}
+
+#[test]
+fn save_npy_writes_header_and_data() {
+    use std::fs;
+    use std::io::Read;
+
+    let mut unit = GatherUnit::new(3, 2);
+    unit.clear();
+    unit.tristimulus_buffer[0] = Vector3::new(1.0, 2.0, 3.0);
+
+    let path = ::std::env::temp_dir().join("robigo_luculenta_test.npy");
+    unit.save_npy(&path).unwrap();
+    let mut data = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(&data[.. 8], b"\x93NUMPY\x01\x00");
+    let header_len = data[8] as usize | (data[9] as usize) << 8;
+    assert_eq!((10 + header_len) % 64, 0);
+    let header = ::std::str::from_utf8(&data[10 .. 10 + header_len]).unwrap();
+    assert!(header.contains("'descr': '<f4'"));
+    assert!(header.contains("'fortran_order': False"));
+    assert!(header.contains("'shape': (2, 3, 3)"));
+    assert!(header.ends_with("\n"));
+
+    // The data follows the header, 3 floats per pixel.
+    let body = &data[10 + header_len ..];
+    assert_eq!(body.len(), 2 * 3 * 3 * 4);
+    assert_eq!(&body[.. 4], &[0x00, 0x00, 0x80, 0x3f]);
+}
+
+#[test]
+fn outlier_batch_is_rejected() {
+    let mut unit = GatherUnit::new(32, 32);
+    unit.clear();
+    unit.set_outlier_rejection(Some(5.0));
+
+    // Batches with a bit of noise, and one with a firefly in the top left.
+    let batch = |value: f32| -> Vec<Vector3> {
+        repeat(Vector3::new(value, value, value)).take(32 * 32).collect()
+    };
+    let mut expected = 0.0;
+    for i in 0 .. 20 {
+        let value = 1.0 + 0.01 * (i % 3) as f32;
+        unit.accumulate(&batch(value));
+        expected += value;
+    }
+    let mut firefly = batch(1.0);
+    firefly[0] = Vector3::new(1.0e4, 1.0e4, 1.0e4);
+    unit.accumulate(&firefly);
+    unit.accumulate(&batch(1.0));
+    expected += 1.0;
+
+    // The region with the firefly skipped that batch, the others did not.
+    assert!((unit.tristimulus_buffer[0].y - expected).abs() < 1.0e-3);
+    assert!((unit.tristimulus_buffer[32 * 32 - 1].y - expected - 1.0).abs() < 1.0e-3);
+}
+
+#[test]
+fn sparse_light_is_not_rejected() {
+    let mut unit = GatherUnit::new(32, 32);
+    unit.clear();
+    unit.set_outlier_rejection(Some(5.0));
+
+    // A region that is dark in most batches, and only sometimes lit.
+    let batch = |value: f32| -> Vec<Vector3> {
+        repeat(Vector3::new(value, value, value)).take(32 * 32).collect()
+    };
+    let mut expected = 0.0;
+    for i in 0 .. 40 {
+        let value = if i % 7 == 3 { 2.0 } else { 0.0 };
+        unit.accumulate(&batch(value));
+        expected += value;
+    }
+
+    // All of the light was gathered.
+    assert!(expected > 0.0);
+    assert!((unit.tristimulus_buffer[0].y - expected).abs() < 1.0e-3);
+}
+
+#[test]
+fn checkpoint_resumes_accumulation() {
+    // Batches that are not exactly representable, so rounding matters.
+    let batch = |i: usize| -> Vec<Vector3> {
+        (0 .. 12).map(|j| Vector3::new(0.1 * i as f32, 0.3, 1.0 / (j + 1) as f32)).collect()
+    };
+
+    let mut uninterrupted = GatherUnit::new(4, 3);
+    uninterrupted.clear();
+    for i in 0 .. 5 {
+        uninterrupted.accumulate(&batch(i));
+    }
+
+    // Accumulate part of the batches, then continue in a new unit from a
+    // checkpoint that went through its file format.
+    let mut first = GatherUnit::new(4, 3);
+    first.clear();
+    first.fingerprint = 17;
+    for i in 0 .. 3 {
+        first.accumulate(&batch(i));
+    }
+    let mut bytes = Vec::new();
+    first.checkpoint().write(&mut bytes).unwrap();
+
+    let mut resumed = GatherUnit::new(4, 3);
+    assert!(resumed.restore(Checkpoint::read(&mut &bytes[..], 4, 3, 17).unwrap()));
+    for i in 3 .. 5 {
+        resumed.accumulate(&batch(i));
+    }
+
+    assert_eq!(resumed.batches, 5);
+    for (a, b) in resumed.tristimulus_buffer.iter().zip(uninterrupted.tristimulus_buffer.iter()) {
+        assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z));
+    }
+
+    // A checkpoint of a different size is not used, nor one of a different
+    // scene or render mode.
+    assert!(Checkpoint::read(&mut &bytes[..], 2, 2, 17).is_err());
+    assert!(Checkpoint::read(&mut &bytes[..], 4, 3, 18).is_err());
+}
+
+#[test]
+fn corrupt_checkpoint_is_rejected() {
+    let mut unit = GatherUnit::new(4, 3);
+    unit.accumulate(&repeat(Vector3::new(1.0, 1.0, 1.0)).take(12).collect::<Vec<_>>());
+    let mut bytes = Vec::new();
+    unit.checkpoint().write(&mut bytes).unwrap();
+
+    // A header that claims an enormous canvas, or an enormous number of
+    // light groups, fails without allocating anything for it.
+    let mut huge = bytes.clone();
+    for b in &mut huge[8 .. 16] { *b = 0xff; }
+    assert!(Checkpoint::read(&mut &huge[..], 0xffffffff, 0xffffffff, 0).is_err());
+    let mut groups = bytes.clone();
+    for b in &mut groups[33 .. 37] { *b = 0xff; }
+    assert!(Checkpoint::read(&mut &groups[..], 4, 3, 0).is_err());
+
+    // A truncated checkpoint is not used either.
+    assert!(Checkpoint::read(&mut &bytes[.. bytes.len() / 2], 4, 3, 0).is_err());
+}
+
+#[test]
+fn legacy_raw_buffer_is_resumed() {
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("robigo-luculenta-legacy-buffer-{}", ::std::process::id()));
+    ::std::fs::create_dir_all(&dir).unwrap();
+    let checkpoint_path = dir.join("checkpoint.raw");
+    let _ = ::std::fs::remove_file(&checkpoint_path);
+
+    // Older versions wrote only the tristimulus and compensation buffers.
+    {
+        let mut file = File::create(dir.join("buffer.raw")).unwrap();
+        for i in 0 .. 24 {
+            let value = if i < 12 { 2.0f32 } else { 0.0f32 };
+            for _ in 0 .. 3 { file.write_all(&value.to_bits().to_ne_bytes()).unwrap(); }
+        }
+    }
+
+    let mut unit = GatherUnit::new(4, 3);
+    unit.set_checkpoint_path(Some(checkpoint_path));
+    assert!(unit.resume());
+    assert!(unit.tristimulus_buffer.iter().all(|t| t.y == 2.0));
+
+    // A raw buffer of a different size is ignored.
+    let mut other = GatherUnit::new(2, 2);
+    other.set_checkpoint_path(Some(dir.join("checkpoint.raw")));
+    assert!(!other.resume());
+    ::std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn channel_images_show_only_y() {
+    let mut unit = GatherUnit::new(4, 3);
+    unit.clear();
+    unit.accumulate(&repeat(Vector3::new(0.0, 0.5, 0.0)).take(12).collect::<Vec<_>>());
+
+    let [x, y, z] = unit.channel_images();
+    assert!(y.iter().all(|&v| v == 255));
+    assert!(x.iter().all(|&v| v == 0));
+    assert!(z.iter().all(|&v| v == 0));
+    assert_eq!(y.len(), 4 * 3);
+}
+
+#[test]
+fn noisy_image_has_low_snr() {
+    let (w, h) = (32, 16);
+
+    // A smooth gradient is what a converged render looks like.
+    let mut unit = GatherUnit::new(w, h);
+    unit.clear();
+    let gradient: Vec<Vector3> = (0 .. w * h).map(|i| {
+        let y = 1.0 + (i % w) as f32 / w as f32;
+        Vector3::new(y, y, y)
+    }).collect();
+    unit.accumulate(&gradient);
+    assert!(unit.estimate_snr() > 100.0);
+
+    // The same gradient with noise of standard deviation 0.5.
+    unit.clear();
+    let noisy: Vec<Vector3> = gradient.iter().map(|&px| {
+        let noise = (::monte_carlo::get_unit() - 0.5) * 12.0f32.sqrt() * 0.5;
+        px + Vector3::new(noise, noise, noise)
+    }).collect();
+    unit.accumulate(&noisy);
+    let snr = unit.estimate_snr();
+    assert!(snr > 2.0 && snr < 4.0);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_buffer_accumulates_into_file() {
+    use std::io::Read;
+
+    let name = format!("robigo-luculenta-mapped-{}.raw", ::std::process::id());
+    let path = ::std::env::temp_dir().join(name);
+    let read_file = || {
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    };
+    let mut unit = GatherUnit::new(4, 3);
+    unit.accumulate(&repeat(Vector3::new(1.0, 2.0, 3.0)).take(12).collect::<Vec<_>>());
+    unit.map_buffers(&path).unwrap();
+
+    // Accumulating continues where the in-memory buffer was.
+    let batch: Vec<Vector3> = (0 .. 12).map(|i| Vector3::new(i as f32, 0.5, 0.25)).collect();
+    unit.accumulate(&batch);
+    for (i, t) in unit.tristimulus_buffer.iter().enumerate() {
+        assert_eq!((t.x, t.y, t.z), (1.0 + i as f32, 2.5, 3.25));
+    }
+
+    // The file holds the same values, in the raw format.
+    unit.save().unwrap();
+    let bytes = read_file();
+    assert_eq!(bytes.len(), 12 * 12);
+    for (i, t) in unit.tristimulus_buffer.iter().enumerate() {
+        let xyz: Vec<u8> = [t.x, t.y, t.z].iter()
+            .flat_map(|v| v.to_bits().to_ne_bytes().to_vec())
+            .collect();
+        assert_eq!(&bytes[i * 12 .. i * 12 + 12], &xyz[..]);
     }
 
+    // After resizing, the file follows the new size.
+    unit.resize(2, 2).unwrap();
+    unit.save().unwrap();
+    assert_eq!(read_file().len(), 4 * 12);
+    assert!(unit.tristimulus_buffer.iter().all(|t| t.x == 0.0));
+
+    drop(unit);
+    ::std::fs::remove_file(&path).unwrap();
+    ::std::fs::remove_file(path.with_extension("compensation")).unwrap();
+}
+
+#[test]
+fn relight_scales_light_groups() {
+    let mut unit = GatherUnit::new(2, 1);
+    let warm = vec!(Vector3::new(1.0, 0.5, 0.0), Vector3::new(2.0, 1.0, 0.0));
+    let cool = vec!(Vector3::new(0.0, 0.5, 1.0), Vector3::zero());
+    unit.accumulate_groups(&[warm.clone(), cool.clone()]);
+    unit.accumulate_groups(&[warm, cool]);
+
+    let close = |a: Vector3, b: Vector3| (a - b).magnitude() < 1.0e-6;
+    let relit = unit.relight(&[1.0, 0.5]);
+    assert!(close(relit[0], Vector3::new(2.0, 1.5, 1.0)));
+    assert!(close(relit[1], Vector3::new(4.0, 2.0, 0.0)));
+
+    // Groups without a weight are left out.
+    let warm_only = unit.relight(&[1.0]);
+    assert!(close(warm_only[0], Vector3::new(2.0, 1.0, 0.0)));
 }
@@ -14,7 +14,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp::Ordering;
 use std::f32::consts::PI;
+use aabb::Aabb;
 use intersection::Intersection;
 use ray::Ray;
 use vector3::{Vector3, cross, dot};
@@ -23,6 +25,38 @@ use vector3::{Vector3, cross, dot};
 pub trait Surface {
     /// Returns whether the surface was intersected, and if so, where.
     fn intersect(&self, ray: &Ray) -> Option<Intersection>;
+
+    /// Returns every intersection of the ray with the surface, ordered by
+    /// increasing distance. For a closed surface these are the points where
+    /// the ray enters and leaves the volume inside. By default, only the
+    /// nearest intersection is reported.
+    fn intersect_all(&self, ray: &Ray) -> Vec<Intersection> {
+        self.intersect(ray).into_iter().collect()
+    }
+
+    /// Returns a box that contains the surface, or `None` if the surface
+    /// is unbounded (or the bounds are unknown).
+    fn get_bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    /// Returns the area of the surface, or `None` if it is infinite
+    /// (or unknown).
+    fn get_area(&self) -> Option<f32> {
+        None
+    }
+
+    /// Returns a random point on the surface and the normal there, chosen
+    /// uniformly by area. Only surfaces that have an area can be sampled.
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        None
+    }
+
+    /// Returns the volume that the surface encloses, if the surface is
+    /// closed, so that it can be the boundary of a medium.
+    fn as_volume(&self) -> Option<&Volume> {
+        None
+    }
 }
 
 /// Represents a part of space.
@@ -31,13 +65,62 @@ pub trait Volume {
     fn lies_inside(&self, p: Vector3) -> bool;
 }
 
-/// An infinitely large plane.
+/// A rectangle in a plane, centred around the offset of the plane.
+#[derive(Copy, Clone)]
+pub struct Extent {
+    /// A unit vector in the plane, along the width of the rectangle.
+    u: Vector3,
+
+    /// Half of the width of the rectangle.
+    half_width: f32,
+
+    /// A unit vector in the plane, along the height of the rectangle.
+    v: Vector3,
+
+    /// Half of the height of the rectangle.
+    half_height: f32
+}
+
+impl Extent {
+    /// Creates a rectangle that extends `half_width` along `u` and
+    /// `half_height` along `v` in both directions. The axes must be
+    /// perpendicular unit vectors that lie in the plane.
+    pub fn new(u: Vector3, half_width: f32, v: Vector3, half_height: f32) -> Extent {
+        Extent {
+            u: u,
+            half_width: half_width,
+            v: v,
+            half_height: half_height
+        }
+    }
+
+    /// Returns whether a point in the plane, relative to the centre of
+    /// the rectangle, lies inside the rectangle.
+    fn contains(&self, p: Vector3) -> bool {
+        dot(p, self.u).abs() <= self.half_width && dot(p, self.v).abs() <= self.half_height
+    }
+
+    /// Returns the texture coordinates of a point in the plane, relative to
+    /// the centre of the rectangle. They go from 0 to 1 along `u` and `v`.
+    fn get_uv(&self, p: Vector3) -> (f32, f32) {
+        (0.5 + dot(p, self.u) * 0.5 / self.half_width,
+         0.5 + dot(p, self.v) * 0.5 / self.half_height)
+    }
+}
+
+/// An infinitely large plane, unless it is bounded by an extent.
 pub struct Plane {
     /// A unit vector perpendicular to the plane.
     normal: Vector3,
 
     /// A point in the plane.
-    offset: Vector3
+    offset: Vector3,
+
+    /// The part of the plane that can be hit, all of it if `None`.
+    extent: Option<Extent>,
+
+    /// Whether the plane can be hit from behind, true by default.
+    two_sided: bool
 }
 
 impl Plane {
@@ -45,9 +128,28 @@ impl Plane {
     pub fn new(normal: Vector3, offset: Vector3) -> Plane {
         Plane {
             normal: normal,
-            offset: offset
+            offset: offset,
+            extent: None,
+            two_sided: true
+        }
+    }
+
+    /// Creates a finite plane with the specified `normal`, that can only be
+    /// hit inside the `extent` around `offset`.
+    pub fn new_bounded(normal: Vector3, offset: Vector3, extent: Extent) -> Plane {
+        Plane {
+            normal: normal,
+            offset: offset,
+            extent: Some(extent),
+            two_sided: true
         }
     }
+
+    /// Sets whether the plane can be hit from both sides. A one-sided plane
+    /// can only be hit from the side that the normal points to.
+    pub fn set_two_sided(&mut self, two_sided: bool) {
+        self.two_sided = two_sided;
+    }
 }
 
 /// Intersects a plane, and returns the position, distance, and the dot
@@ -73,26 +175,61 @@ fn intersect_plane(normal: &Vector3, offset: &Vector3, ray: &Ray)
 impl Surface for Plane {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         intersect_plane(&self.normal, &self.offset, ray)
+        .filter(|&(pos, _, d)| {
+            let in_extent = match self.extent {
+                Some(ref extent) => extent.contains(pos - self.offset),
+                None => true
+            };
+            // A ray in the direction of the normal hits the back.
+            in_extent && (self.two_sided || d < 0.0)
+        })
         .map(|(pos, t, d)| {
-            Intersection {
-                position: pos,
-                // Planes are two-sided.
-                normal: if d < 0.0 { self.normal } else { -self.normal },
-                // Tangent is not used here.
-                tangent: Vector3::zero(),
-                distance: t
+            // Planes are two-sided.
+            let normal = if d < 0.0 { self.normal } else { -self.normal };
+            let mut intersection = Intersection::new(pos, normal, Vector3::zero(), t);
+            if let Some(ref extent) = self.extent {
+                intersection.uv = extent.get_uv(pos - self.offset);
             }
+            intersection
+        })
+    }
+
+    fn get_bounding_box(&self) -> Option<Aabb> {
+        self.extent.map(|e| {
+            let extent = Vector3 {
+                x: e.u.x.abs() * e.half_width + e.v.x.abs() * e.half_height,
+                y: e.u.y.abs() * e.half_width + e.v.y.abs() * e.half_height,
+                z: e.u.z.abs() * e.half_width + e.v.z.abs() * e.half_height
+            };
+            Aabb::new(self.offset - extent, self.offset + extent)
+        })
+    }
+
+    fn get_area(&self) -> Option<f32> {
+        self.extent.map(|e| 4.0 * e.half_width * e.half_height)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        self.extent.map(|e| {
+            let position = self.offset
+                + e.u * (::monte_carlo::get_bi_unit() * e.half_width)
+                + e.v * (::monte_carlo::get_bi_unit() * e.half_height);
+            (position, self.normal)
         })
     }
 }
 
-/// An infinitely large one-sided plane that cuts space in half.
+/// An infinitely large one-sided plane that cuts space in half. Its surface
+/// can be bounded by an extent, but the volume remains a half-space.
 pub struct SpacePartitioning {
     /// A unit vector perpendicular to the plane.
     normal: Vector3,
 
     /// A point in the plane.
-    offset: Vector3
+    offset: Vector3,
+
+    /// The part of the plane that can be hit, all of it if `None`.
+    extent: Option<Extent>
 }
 
 impl SpacePartitioning {
@@ -101,7 +238,19 @@ impl SpacePartitioning {
     pub fn new(normal: Vector3, offset: Vector3) -> SpacePartitioning {
         SpacePartitioning {
             normal: normal,
-            offset: offset
+            offset: offset,
+            extent: None
+        }
+    }
+
+    /// Creates a space partitioning like `new`, of which the surface can
+    /// only be hit inside the `extent` around `offset`.
+    #[allow(dead_code)]
+    pub fn new_bounded(normal: Vector3, offset: Vector3, extent: Extent) -> SpacePartitioning {
+        SpacePartitioning {
+            normal: normal,
+            offset: offset,
+            extent: Some(extent)
         }
     }
 }
@@ -109,15 +258,17 @@ impl SpacePartitioning {
 impl Surface for SpacePartitioning {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         intersect_plane(&self.normal, &self.offset, ray)
-        .map(|(pos, t, _)| {
-            Intersection {
-                position: pos,
-                normal: self.normal,
-                // Tangent is not used here.
-                tangent: Vector3::zero(),
-                distance: t
+        .filter(|&(pos, _, _)| {
+            match self.extent {
+                Some(ref extent) => extent.contains(pos - self.offset),
+                None => true
             }
         })
+        .map(|(pos, t, _)| Intersection::new(pos, self.normal, Vector3::zero(), t))
+    }
+
+    fn as_volume(&self) -> Option<&Volume> {
+        Some(self)
     }
 }
 
@@ -135,7 +286,10 @@ pub struct Circle {
     position: Vector3,
 
     /// The square of the radius of the circle.
-    radius_squared: f32
+    radius_squared: f32,
+
+    /// Whether the circle can be hit from behind, true by default.
+    two_sided: bool
 }
 
 impl Circle {
@@ -143,9 +297,16 @@ impl Circle {
         Circle {
             normal: normal,
             position: position,
-            radius_squared: radius * radius
+            radius_squared: radius * radius,
+            two_sided: true
         }
     }
+
+    /// Sets whether the circle can be hit from both sides. A one-sided circle
+    /// can only be hit from the side that the normal points to.
+    pub fn set_two_sided(&mut self, two_sided: bool) {
+        self.two_sided = two_sided;
+    }
 }
 
 // Filter(ed) is implemented manually, because it is deprecated in the standard
@@ -166,21 +327,138 @@ impl<T> Filter<T> for Option<T> {
 impl Surface for Circle {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         intersect_plane(&self.normal, &self.position, ray)
-        .filter(|&(pos, _, _)| {
-            // Allow only indersections that lie inside the circle.
+        .filter(|&(pos, _, d)| {
+            // Allow only indersections that lie inside the circle,
+            // and on the front, if the circle is one-sided.
             (pos - self.position).magnitude_squared() <= self.radius_squared
+                && (self.two_sided || d < 0.0)
         })
         .map(|(pos, t, d)| {
-            Intersection {
-                position: pos,
-                // Planes are two-sided.
-                normal: if d < 0.0 { self.normal } else { -self.normal },
-                // Tangent is not used here.
-                tangent: Vector3::zero(),
-                distance: t
-            }
+            // Planes are two-sided.
+            let normal = if d < 0.0 { self.normal } else { -self.normal };
+            Intersection::new(pos, normal, Vector3::zero(), t)
+        })
+    }
+
+    fn get_bounding_box(&self) -> Option<Aabb> {
+        // The extent of a disc along an axis is the radius, scaled by the
+        // sine of the angle between the axis and the normal.
+        let r = self.radius_squared.sqrt();
+        let extent = Vector3 {
+            x: r * (1.0 - self.normal.x * self.normal.x).max(0.0).sqrt(),
+            y: r * (1.0 - self.normal.y * self.normal.y).max(0.0).sqrt(),
+            z: r * (1.0 - self.normal.z * self.normal.z).max(0.0).sqrt()
+        };
+        Some(Aabb::new(self.position - extent, self.position + extent))
+    }
+
+    fn get_area(&self) -> Option<f32> {
+        Some(PI * self.radius_squared)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // The square root compensates for the circumference growing
+        // linearly with the distance to the centre.
+        let r = (::monte_carlo::get_unit() * self.radius_squared).sqrt();
+        let phi = ::monte_carlo::get_longitude();
+        let offset = Vector3::new(phi.cos() * r, phi.sin() * r, 0.0);
+        Some((self.position + offset.rotate_towards(self.normal), self.normal))
+    }
+}
+
+/// A convex polygon.
+pub struct Polygon {
+    /// The corners of the polygon, in counter-clockwise order
+    /// when looking at the front.
+    vertices: Vec<Vector3>,
+
+    /// A unit vector perpendicular to the polygon, pointing to the front.
+    normal: Vector3,
+
+    /// Whether the polygon can be hit from behind, true by default.
+    two_sided: bool
+}
+
+impl Polygon {
+    /// Creates a convex polygon with the specified corners, which must lie
+    /// in one plane. The normal is determined by the first three corners:
+    /// seen from the front, they are in counter-clockwise order.
+    pub fn new(vertices: Vec<Vector3>) -> Polygon {
+        assert!(vertices.len() >= 3, "a polygon needs at least three vertices");
+        let normal = cross(vertices[1] - vertices[0], vertices[2] - vertices[0]).normalise();
+        Polygon {
+            vertices: vertices,
+            normal: normal,
+            two_sided: true
+        }
+    }
+
+    /// Sets whether the polygon can be hit from both sides. A one-sided
+    /// polygon can only be hit from the side that the normal points to.
+    pub fn set_two_sided(&mut self, two_sided: bool) {
+        self.two_sided = two_sided;
+    }
+
+    /// Returns whether a point in the plane of the polygon lies inside it,
+    /// which is the case if it lies on the inner side of every edge.
+    fn contains(&self, p: Vector3) -> bool {
+        let n = self.vertices.len();
+        (0 .. n).all(|i| {
+            let a = self.vertices[i];
+            let b = self.vertices[(i + 1) % n];
+            dot(cross(b - a, p - a), self.normal) >= 0.0
         })
     }
+
+    /// Returns the area of the triangle between the first corner and
+    /// corners `i` and `i + 1`. Together the triangles cover the polygon.
+    fn get_triangle_area(&self, i: usize) -> f32 {
+        let v0 = self.vertices[0];
+        cross(self.vertices[i] - v0, self.vertices[i + 1] - v0).magnitude() * 0.5
+    }
+}
+
+impl Surface for Polygon {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        intersect_plane(&self.normal, &self.vertices[0], ray)
+        .filter(|&(pos, _, d)| self.contains(pos) && (self.two_sided || d < 0.0))
+        .map(|(pos, t, d)| {
+            let normal = if d < 0.0 { self.normal } else { -self.normal };
+            Intersection::new(pos, normal, Vector3::zero(), t)
+        })
+    }
+
+    fn get_bounding_box(&self) -> Option<Aabb> {
+        let v0 = self.vertices[0];
+        let aabb = Aabb::new(v0, v0);
+        Some(self.vertices.iter().fold(aabb, |aabb, &v| aabb.union(Aabb::new(v, v))))
+    }
+
+    fn get_area(&self) -> Option<f32> {
+        Some((1 .. self.vertices.len() - 1).map(|i| self.get_triangle_area(i)).sum())
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // Pick one of the triangles of the fan, proportional to its area.
+        let n = self.vertices.len();
+        let mut remaining = ::monte_carlo::get_unit() * self.get_area().unwrap();
+        let mut i = 1;
+        while i < n - 2 && remaining > self.get_triangle_area(i) {
+            remaining = remaining - self.get_triangle_area(i);
+            i = i + 1;
+        }
+
+        // Then pick a point in the triangle, reflecting points that fall
+        // in the other half of the parallelogram back into the triangle.
+        let (mut u, mut v) = (::monte_carlo::get_unit(), ::monte_carlo::get_unit());
+        if u + v > 1.0 {
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+        let v0 = self.vertices[0];
+        let p = v0 + (self.vertices[i] - v0) * u + (self.vertices[i + 1] - v0) * v;
+        Some((p, self.normal))
+    }
 }
 
 pub struct Sphere {
@@ -188,17 +466,27 @@ pub struct Sphere {
     position: Vector3,
 
     /// The square of the radius of the spere.
-    radius_squared: f32
+    radius_squared: f32,
+
+    /// Whether the normal points inward, false by default.
+    hollow: bool
 }
 
 impl Sphere {
     pub fn new(position: Vector3, radius: f32) -> Sphere {
         Sphere {
             position: position,
-            radius_squared: radius * radius
+            radius_squared: radius * radius,
+            hollow: false
         }
     }
 
+    /// Makes the normal point inward, towards the centre, so the inside of
+    /// the sphere is the surface that gets lit, for a dome or a room.
+    pub fn set_hollow(&mut self, hollow: bool) {
+        self.hollow = hollow;
+    }
+
     /// Returns whether a ray intersects the sphere, and if it does,
     /// the distances along the ray.
     fn get_intersections(&self, ray: &Ray) -> Option<(f32, f32)> {
@@ -223,6 +511,33 @@ impl Sphere {
     }
 }
 
+impl Sphere {
+    /// Returns the intersection at distance `t` along the ray.
+    fn get_intersection_at(&self, ray: &Ray, t: f32) -> Intersection {
+        // The intersection point can be calculated from the distance.
+        let position = ray.origin + ray.direction * t;
+
+        // The normal points radially outward everywhere, or inward
+        // everywhere if the sphere is hollow.
+        let outward = (position - self.position).normalise();
+        let normal = if self.hollow { -outward } else { outward };
+
+        // The tangent vector is perpendicular to the surface normal
+        // and the up vector. The choice is quite arbitrary. At the poles
+        // it is zero, and then the intersection picks one.
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        let tangent = cross(up, normal);
+
+        Intersection::new(position, normal, tangent, t)
+    }
+}
+
+/// A ray that leaves a surface can start just below it due to rounding
+/// errors; it should not hit the surface again where it started, so
+/// intersections closer than this fraction of the radius are ignored. The
+/// rounding error grows with the size of the sphere, so the distance does too.
+const MIN_SPHERE_DISTANCE: f32 = 1.0e-4;
+
 impl Surface for Sphere {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         // TODO: is there a macro for this, like try!?
@@ -233,36 +548,63 @@ impl Surface for Sphere {
         };
 
         // One of the ts must be positive at least, for an intersection.
+        // If only one of them is, the ray starts inside the sphere, and only
+        // a hollow sphere can be seen from the inside.
+        let min_t = MIN_SPHERE_DISTANCE * self.radius_squared.sqrt();
+        let (near, far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
         let t: f32;
-        if t1 > 0.0 && t1 < t2 { t = t1; }
-        else if t2 > 0.0 && t2 < t1 { t = t2; }
+        if near > min_t { t = near; }
+        else if self.hollow && far > min_t { t = far; }
         // For negative t, the sphere lies behind the ray entirely.
         else { return None; }
 
-        // The intersection point can be calculated from the distance.
-        let position = ray.origin + ray.direction * t;
+        Some(self.get_intersection_at(ray, t))
+    }
 
-        // The normal points radially outward everywhere.
-        let normal = (position - self.position).normalise();
+    fn intersect_all(&self, ray: &Ray) -> Vec<Intersection> {
+        let (t1, t2) = match self.get_intersections(ray) {
+            None => return Vec::new(),
+            Some(x) => x
+        };
+        // Every crossing counts here, also the way out of a solid sphere,
+        // because compounds need both to tell where the inside is.
+        let min_t = MIN_SPHERE_DISTANCE * self.radius_squared.sqrt();
+        let (near, far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+        [near, far].iter()
+                   .filter(|&&t| t > min_t)
+                   .map(|&t| self.get_intersection_at(ray, t))
+                   .collect()
+    }
 
-        // The tangent vector is perpendicular to the surface normal
-        // and the up vector. The choice is quite arbitrary.
-        let up = Vector3::new(0.0, 1.0, 0.0);
-        let tangent = cross(up, normal).normalise();
+    fn get_bounding_box(&self) -> Option<Aabb> {
+        let r = self.radius_squared.sqrt();
+        let extent = Vector3::new(r, r, r);
+        Some(Aabb::new(self.position - extent, self.position + extent))
+    }
 
-        let intersection = Intersection {
-            position: position,
-            normal: normal,
-            tangent: tangent,
-            distance: t
-        };
-        Some(intersection)
+    fn get_area(&self) -> Option<f32> {
+        Some(4.0 * PI * self.radius_squared)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // A uniform z-coordinate gives a uniform point on the sphere.
+        let z = ::monte_carlo::get_bi_unit();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = ::monte_carlo::get_longitude();
+        let outward = Vector3::new(phi.cos() * r, phi.sin() * r, z);
+        let normal = if self.hollow { -outward } else { outward };
+        Some((self.position + outward * self.radius_squared.sqrt(), normal))
+    }
+
+    fn as_volume(&self) -> Option<&Volume> {
+        Some(self)
     }
 }
 
 impl Volume for Sphere {
+    /// Points on the surface count as inside.
     fn lies_inside(&self, p: Vector3) -> bool {
-        (p - self.position).magnitude_squared() < self.radius_squared
+        (p - self.position).magnitude_squared() <= self.radius_squared
     }
 }
 
@@ -346,14 +688,7 @@ impl Surface for Paraboloid {
         let plane_pr = local_pos - self.normal * dot(local_pos, self.normal);
         let normal = (self.focal_point - plane_pr).normalise();
 
-        let intersection = Intersection {
-            position: pos,
-            normal: normal,
-            tangent: Vector3::zero(), // Not used here.
-            distance: t
-        };
-
-        Some(intersection)
+        Some(Intersection::new(pos, normal, Vector3::zero(), t))
     }
 }
 
@@ -388,8 +723,8 @@ impl<T1, T2> Surface for Compound<T1, T2>
         let i2 = i2.filter(|i| { self.surface1.lies_inside(i.position) });
 
         // If both intersections are valid, pick the closest one.
-        if i1.is_some() && i2.is_some() {
-            if i1.unwrap().distance < i2.unwrap().distance {
+        if let (Some(h1), Some(h2)) = (i1, i2) {
+            if h1.distance < h2.distance {
                 return i1;
             } else {
                 return i2;
@@ -398,6 +733,30 @@ impl<T1, T2> Surface for Compound<T1, T2>
 
         i1.or(i2)
     }
+
+    fn intersect_all(&self, ray: &Ray) -> Vec<Intersection> {
+        // The surface of the compound consists of the parts of either
+        // surface that lie inside the other volume.
+        let mut hits: Vec<Intersection> = self.surface1.intersect_all(ray).into_iter()
+            .filter(|i| self.surface2.lies_inside(i.position))
+            .chain(self.surface2.intersect_all(ray).into_iter()
+                   .filter(|i| self.surface1.lies_inside(i.position)))
+            .collect();
+        hits.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal));
+        hits
+    }
+
+    fn get_bounding_box(&self) -> Option<Aabb> {
+        // The compound lies inside both surfaces, so inside both boxes.
+        match (self.surface1.get_bounding_box(), self.surface2.get_bounding_box()) {
+            (Some(b1), Some(b2)) => Some(b1.overlap(b2)),
+            (b1, b2) => b1.or(b2)
+        }
+    }
+
+    fn as_volume(&self) -> Option<&Volume> {
+        Some(self)
+    }
 }
 
 impl<T1, T2> Volume for Compound<T1, T2> where T1: Volume, T2: Volume {
@@ -513,3 +872,215 @@ pub fn new_hexagonal_prism(axis: Vector3,
 
     Compound::new(iprism, prism)
 }
+
+#[test]
+fn bounded_plane_is_hit_inside_extent_only() {
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let extent = Extent::new(Vector3::new(1.0, 0.0, 0.0), 2.0, Vector3::new(0.0, 1.0, 0.0), 1.0);
+    let plane = Plane::new_bounded(up, Vector3::zero(), extent);
+    let ray_at = |x: f32, y: f32| Ray {
+        origin: Vector3::new(x, y, 1.0),
+        direction: -up,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    assert!(plane.intersect(&ray_at(1.5, 0.5)).is_some());
+    assert!(plane.intersect(&ray_at(-1.5, -0.5)).is_some());
+    assert!(plane.intersect(&ray_at(2.5, 0.0)).is_none());
+    assert!(plane.intersect(&ray_at(0.0, 1.5)).is_none());
+
+    // Without an extent, the plane is infinite.
+    let infinite = Plane::new(up, Vector3::zero());
+    assert!(infinite.intersect(&ray_at(100.0, 100.0)).is_some());
+
+    // A bounded space partitioning has the same surface.
+    let partitioning = SpacePartitioning::new_bounded(up, Vector3::zero(), extent);
+    assert!(partitioning.intersect(&ray_at(1.5, 0.5)).is_some());
+    assert!(partitioning.intersect(&ray_at(2.5, 0.0)).is_none());
+}
+
+#[test]
+fn one_sided_plane_is_invisible_from_behind() {
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let ray_from = |z: f32| Ray {
+        origin: Vector3::new(0.0, 0.0, z),
+        direction: Vector3::new(0.0, 0.0, -z.signum()),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    let mut plane = Plane::new(up, Vector3::zero());
+    assert!(plane.intersect(&ray_from(-1.0)).is_some());
+    plane.set_two_sided(false);
+    assert!(plane.intersect(&ray_from(1.0)).is_some());
+    assert!(plane.intersect(&ray_from(-1.0)).is_none());
+
+    let mut circle = Circle::new(up, Vector3::zero(), 1.0);
+    circle.set_two_sided(false);
+    assert!(circle.intersect(&ray_from(1.0)).is_some());
+    assert!(circle.intersect(&ray_from(-1.0)).is_none());
+}
+
+#[test]
+fn sphere_hit_has_right_handed_frame() {
+    let sphere = Sphere::new(Vector3::new(0.0, 0.0, 0.0), 1.0);
+
+    // Rays towards the centre from several sides, including the poles,
+    // where the tangent cannot be based on the up vector.
+    let origins = [Vector3::new(0.0, 0.0, 5.0), Vector3::new(3.0, -2.0, 1.0),
+                   Vector3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -5.0, 0.0)];
+    for &origin in origins.iter() {
+        let ray = Ray {
+            origin: origin,
+            direction: (-origin).normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let isect = sphere.intersect(&ray).unwrap();
+        let (t, b, n) = (isect.tangent, isect.bitangent, isect.normal);
+
+        for &v in [t, b, n].iter() {
+            assert!((v.magnitude() - 1.0).abs() < 1.0e-5);
+        }
+        assert!(dot(t, b).abs() < 1.0e-5);
+        assert!(dot(t, n).abs() < 1.0e-5);
+        assert!(dot(b, n).abs() < 1.0e-5);
+        assert!((cross(t, b) - n).magnitude() < 1.0e-5);
+    }
+}
+
+#[test]
+fn hollow_sphere_normal_points_to_centre() {
+    let centre = Vector3::new(1.0, 2.0, 3.0);
+    let ray = Ray {
+        origin: centre + Vector3::new(0.5, 0.0, 0.0),
+        direction: Vector3::new(0.0, 0.0, 1.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // A solid sphere is not seen from the inside, but the way out is
+    // still one of its crossings.
+    let mut sphere = Sphere::new(centre, 2.0);
+    assert!(sphere.intersect(&ray).is_none());
+    let crossings = sphere.intersect_all(&ray);
+    assert_eq!(crossings.len(), 1);
+    assert!(dot(crossings[0].normal, centre - crossings[0].position) < 0.0);
+
+    sphere.set_hollow(true);
+    let isect = sphere.intersect(&ray).unwrap();
+    assert!(dot(isect.normal, centre - isect.position) > 0.0);
+    assert!((isect.distance - 3.75f32.sqrt()).abs() < 1.0e-5);
+    assert!(((isect.position - centre).magnitude() - 2.0).abs() < 1.0e-5);
+}
+
+#[test]
+fn large_sphere_is_not_hit_where_the_ray_leaves_it() {
+    // Rounding errors grow with the radius, so a ray that leaves the surface
+    // of a large sphere can start slightly inside it.
+    let sphere = Sphere::new(Vector3::new(0.0, 0.0, -1000.0), 1000.0);
+    let ray = Ray {
+        origin: Vector3::new(0.0, 0.0, -1.0e-3),
+        direction: Vector3::new(0.0, 0.0, 1.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    assert!(sphere.intersect_all(&ray).is_empty());
+}
+
+#[test]
+fn plane_intersection_distances_and_normals() {
+    let ray = |origin: Vector3, direction: Vector3| Ray {
+        origin: origin,
+        direction: direction.normalise(),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let floor = Plane::new(up, Vector3::zero());
+
+    // From above and from below, the normal faces the ray.
+    let above = floor.intersect(&ray(Vector3::new(1.0, 2.0, 3.0), -up)).unwrap();
+    assert!((above.distance - 3.0).abs() < 1.0e-5);
+    assert!((above.normal - up).magnitude() < 1.0e-5);
+    let below = floor.intersect(&ray(Vector3::new(1.0, 2.0, -2.0), up)).unwrap();
+    assert!((below.distance - 2.0).abs() < 1.0e-5);
+    assert!((below.normal + up).magnitude() < 1.0e-5);
+
+    // Parallel rays, in or above the plane, and rays pointing away miss.
+    let x = Vector3::new(1.0, 0.0, 0.0);
+    assert!(floor.intersect(&ray(Vector3::new(0.0, 0.0, 1.0), x)).is_none());
+    assert!(floor.intersect(&ray(Vector3::zero(), x)).is_none());
+    assert!(floor.intersect(&ray(Vector3::new(0.0, 0.0, 3.0), up)).is_none());
+    assert!(floor.intersect(&ray(Vector3::new(0.0, 0.0, -3.0), -up)).is_none());
+
+    // The distance is measured from the ray origin, not from the offset.
+    let raised = Plane::new(up, Vector3::new(5.0, 5.0, 2.0));
+    let hit = raised.intersect(&ray(Vector3::new(1.0, 1.0, 7.0), -up)).unwrap();
+    assert!((hit.distance - 5.0).abs() < 1.0e-5);
+    assert!((hit.position.z - 2.0).abs() < 1.0e-5);
+    let hit = raised.intersect(&ray(Vector3::new(1.0, 1.0, -1.0), up)).unwrap();
+    assert!((hit.distance - 3.0).abs() < 1.0e-5);
+
+    // An oblique ray travels further than the perpendicular distance.
+    let wall = Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -4.0, 0.0));
+    let hit = wall.intersect(&ray(Vector3::zero(), Vector3::new(0.8, -0.6, 0.0))).unwrap();
+    assert!((hit.distance - 4.0 / 0.6).abs() < 1.0e-4);
+    assert!((hit.position.y + 4.0).abs() < 1.0e-5);
+
+    // A tilted plane through the origin.
+    let n = Vector3::new(1.0, 1.0, 0.0).normalise();
+    let tilted = Plane::new(n, Vector3::zero());
+    let hit = tilted.intersect(&ray(Vector3::new(3.0, 3.0, 0.0), -x)).unwrap();
+    assert!((hit.distance - 6.0).abs() < 1.0e-5);
+    assert!((hit.normal - n).magnitude() < 1.0e-5);
+}
+
+#[test]
+fn closed_surfaces_report_entry_and_exit() {
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let ray = |z: f32| Ray {
+        origin: Vector3::new(0.0, 0.0, z),
+        direction: up,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // From outside, a sphere is entered and left, from inside only left.
+    let sphere = Sphere::new(Vector3::zero(), 2.0);
+    let distances: Vec<f32> = sphere.intersect_all(&ray(-5.0)).iter().map(|i| i.distance).collect();
+    assert_eq!(distances.len(), 2);
+    assert!((distances[0] - 3.0).abs() < 1.0e-4 && (distances[1] - 7.0).abs() < 1.0e-4);
+    let distances: Vec<f32> = sphere.intersect_all(&ray(0.0)).iter().map(|i| i.distance).collect();
+    assert_eq!(distances.len(), 1);
+    assert!((distances[0] - 2.0).abs() < 1.0e-4);
+
+    // A compound reports only the parts of its surfaces that bound it,
+    // the bottom and top of the prism here, not the infinite sides.
+    let prism = new_hexagonal_prism(up, Vector3::zero(), 2.0, 0.1, 0.0, 1.0);
+    let hits = prism.intersect_all(&ray(-5.0));
+    assert_eq!(hits.len(), 2);
+    assert!((hits[0].distance - 5.0).abs() < 1.0e-4 && (hits[1].distance - 6.0).abs() < 1.0e-4);
+    assert!((hits[0].distance - prism.intersect(&ray(-5.0)).unwrap().distance).abs() < 1.0e-6);
+}
+
+#[test]
+fn polygon_is_hit_inside_edges_only() {
+    let square = Polygon::new(vec!(Vector3::new(-1.0, 5.0, -1.0),
+                                   Vector3::new(-1.0, 5.0, 1.0),
+                                   Vector3::new(1.0, 5.0, 1.0),
+                                   Vector3::new(1.0, 5.0, -1.0)));
+    let ray_towards = |target: Vector3| Ray {
+        origin: Vector3::zero(),
+        direction: target.normalise(),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    let hit = square.intersect(&ray_towards(Vector3::new(0.9, 5.0, 0.9))).unwrap();
+    assert!((hit.position.y - 5.0).abs() < 1.0e-5);
+    assert!((hit.normal.y + 1.0).abs() < 1.0e-5);
+    assert!(square.intersect(&ray_towards(Vector3::new(1.05, 5.0, 1.05))).is_none());
+    assert!((square.get_area().unwrap() - 4.0).abs() < 1.0e-5);
+}
@@ -14,9 +14,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use vector3::Vector3;
+use vector3::{Vector3, cross};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Intersection {
     /// The position at which the intersection occurred.
     pub position: Vector3,
@@ -27,6 +27,49 @@ pub struct Intersection {
     /// The surface tangent at the intersection.
     pub tangent: Vector3,
 
+    /// The cross product of the normal and the tangent, so that the tangent,
+    /// bitangent and normal form a right-handed orthonormal frame.
+    pub bitangent: Vector3,
+
     /// The distance between the intersection point and the ray origin.
-    pub distance: f32
+    pub distance: f32,
+
+    /// Texture coordinates of the intersection, in the range [0, 1] for
+    /// surfaces that define them, and (0, 0) for other surfaces.
+    pub uv: (f32, f32)
+}
+
+impl Intersection {
+    /// Constructs an intersection, and completes the surface frame. A zero
+    /// tangent means that the surface has no preferred tangent, and then an
+    /// arbitrary one is chosen, which only depends on the normal.
+    pub fn new(position: Vector3,
+               normal: Vector3,
+               tangent: Vector3,
+               distance: f32)
+               -> Intersection {
+        let tangent = if tangent.magnitude_squared() > 1.0e-12 {
+            tangent.normalise()
+        } else {
+            // Take the axis that is closest to perpendicular to the normal,
+            // and make it perpendicular.
+            let axis = if normal.x.abs() <= normal.y.abs() && normal.x.abs() <= normal.z.abs() {
+                Vector3::new(1.0, 0.0, 0.0)
+            } else if normal.y.abs() <= normal.z.abs() {
+                Vector3::new(0.0, 1.0, 0.0)
+            } else {
+                Vector3::new(0.0, 0.0, 1.0)
+            };
+            cross(axis, normal).normalise()
+        };
+
+        Intersection {
+            position: position,
+            normal: normal,
+            tangent: tangent,
+            bitangent: cross(normal, tangent),
+            distance: distance,
+            uv: (0.0, 0.0)
+        }
+    }
 }
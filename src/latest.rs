@@ -0,0 +1,109 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The state shared by the sending and receiving side of a channel.
+struct Shared<T> {
+    /// The most recent value that has not been received yet, if any.
+    value: Option<T>,
+
+    /// The number of senders that are still alive.
+    senders: usize
+}
+
+/// The sending side of a channel that holds at most one value.
+pub struct Sender<T> {
+    shared: Arc<(Mutex<Shared<T>>, Condvar)>
+}
+
+/// The receiving side of a channel that holds at most one value.
+pub struct Receiver<T> {
+    shared: Arc<(Mutex<Shared<T>>, Condvar)>
+}
+
+/// Creates a channel that keeps only the latest value. Unlike an unbounded
+/// channel, a slow receiver does not cause values to pile up: a new value
+/// replaces the one that has not been received yet.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new((Mutex::new(Shared { value: None, senders: 1 }), Condvar::new()));
+    (Sender { shared: shared.clone() }, Receiver { shared: shared })
+}
+
+impl<T> Sender<T> {
+    /// Sends a value, and returns the value that it replaced, if the
+    /// receiver had not received that yet.
+    pub fn send(&self, value: T) -> Option<T> {
+        let &(ref lock, ref cvar) = &*self.shared;
+        let stale = lock.lock().unwrap().value.replace(value);
+        cvar.notify_one();
+        stale
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.0.lock().unwrap().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let &(ref lock, ref cvar) = &*self.shared;
+        if let Ok(mut shared) = lock.lock() {
+            shared.senders -= 1;
+        }
+        cvar.notify_one();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for a value and returns it. Returns `None` if there is no
+    /// value, and there are no senders left that could send one.
+    pub fn recv(&self) -> Option<T> {
+        let &(ref lock, ref cvar) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        loop {
+            if let Some(value) = shared.value.take() { return Some(value); }
+            if shared.senders == 0 { return None; }
+            shared = cvar.wait(shared).unwrap();
+        }
+    }
+
+    /// Returns the value if there is one, without waiting.
+    #[allow(dead_code)]
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.0.lock().unwrap().value.take()
+    }
+}
+
+#[test]
+fn receiver_gets_latest_value() {
+    let (tx, rx) = channel();
+    assert_eq!(tx.send(1), None);
+    assert_eq!(tx.send(2), Some(1));
+    assert_eq!(tx.send(3), Some(2));
+    assert_eq!(rx.recv(), Some(3));
+    assert_eq!(rx.try_recv(), None);
+
+    // After the last sender is gone, there is nothing left to wait for.
+    let tx2 = tx.clone();
+    drop(tx);
+    ::std::thread::spawn(move || { tx2.send(4); });
+    assert_eq!(rx.recv(), Some(4));
+    assert_eq!(rx.recv(), None);
+}
@@ -14,20 +14,45 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+#![cfg_attr(feature = "bench", feature(test))]
+
 extern crate image;
 extern crate rand;
 extern crate time;
 
+#[cfg(feature = "mmap")]
+extern crate libc;
+
+#[cfg(feature = "bench")]
+extern crate test;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
 use app::App;
+use settings::Settings;
 
+mod aabb;
 mod app;
+#[cfg(all(test, feature = "bench"))]
+mod bench;
+mod buffer;
+mod bvh;
 mod camera;
+mod checkpoint;
 mod cie1931;
 mod constants;
+mod environment;
 mod gather_unit;
 mod geometry;
 mod intersection;
+mod latest;
 mod material;
+mod medium;
 mod monte_carlo;
 mod object;
 mod plot_unit;
@@ -36,6 +61,9 @@ mod quaternion;
 mod ray;
 mod read;
 mod scene;
+mod scenes;
+mod screen;
+mod settings;
 mod srgb;
 mod task_scheduler;
 mod tonemap_unit;
@@ -43,25 +71,99 @@ mod trace_unit;
 mod vector3;
 
 fn main() {
+    let settings = match Settings::from_args(env::args().skip(1)) {
+        Ok(settings) => settings,
+        Err(reason) => {
+            println!("{}", reason);
+            process::exit(1);
+        }
+    };
+
     // Start up the path tracer. It begins rendering immediately.
     let width = 1280u32;
     let height = 720u32;
-    let app = App::new(width, height);
-    let images = app.images;
+    let app = App::new(width, height, &settings);
 
-    println!("press ctrl+c to stop rendering");
+    if let Some(duration) = settings.max_duration {
+        println!("rendering for {} seconds", duration.num_seconds());
+    }
+    if let Some(batches) = settings.max_batches {
+        println!("rendering {} batches", batches);
+    }
+    if settings.max_duration.is_none() && settings.max_batches.is_none() {
+        println!("press ctrl+c to stop rendering");
+    }
 
     // Then wait for news from other tasks: when an image has been rendered,
-    // write it out. Loop forever; the application must be stopped by
-    // terminating it.
-    loop {
-        let img = images.recv().unwrap();
+    // write it out. Without a time or batch limit this loops forever, and
+    // the application must be stopped by terminating it. Otherwise the
+    // channels close once the final images have been sent.
+    let mut rendering = true;
+    while rendering {
+        // Every camera produces an image at the same interval.
+        for (camera, images) in app.images.iter().enumerate() {
+            let img = match images.recv() {
+                Some(img) => img,
+                None => { rendering = false; continue; }
+            };
+
+            // Write the image to output.png, or output-1.png and so on
+            // for the other cameras.
+            let path = task_scheduler::camera_path(Path::new("output.png"), camera);
+            let write_png = |path: &Path, img: &[u8]| {
+                image::save_buffer(path, img, width, height, image::RGB(8))
+            };
+            match save_image(&path, &img, write_png) {
+                Ok(_) => println!("wrote image to {}", path.display()),
+                Err(reason) => println!("failed to write output png: {}", reason)
+            }
+
+            // The thumbnail goes to thumbnail.png, and so on.
+            if let Some(factor) = settings.thumbnail_factor {
+                let path = task_scheduler::camera_path(Path::new("thumbnail.png"), camera);
+                let thumbnail = srgb::downscale(&img, width, height, factor);
+                let write_png = |path: &Path, img: &[u8]| {
+                    image::save_buffer(path, img, width / factor, height / factor, image::RGB(8))
+                };
+                match save_image(&path, &thumbnail, write_png) {
+                    Ok(_) => println!("wrote thumbnail to {}", path.display()),
+                    Err(reason) => println!("failed to write thumbnail png: {}", reason)
+                }
+            }
+
+            // The buffer can be used for the next image.
+            app.recycle(img);
+        }
+
+        if rendering && settings.max_batches.is_some() {
+            println!("progress: {:.0}%", app.progress_fraction() * 100.0);
+        }
+    }
+
+    app.shutdown();
+}
 
-        // Write the image to output.png.
-        match image::save_buffer("output.png", &img,
-                                 width, height, image::RGB(8)) {
-            Ok(_) => println!("wrote image to output.png"),
-            Err(reason) => println!("failed to write output png: {}", reason)
+/// Saves an image to `path` by having `write` write it to a temporary file,
+/// which then replaces the file at `path`. This way there is always a complete
+/// image on disk. A failed write is retried a few times, so a transient
+/// problem does not cause the image to be lost.
+fn save_image<F>(path: &Path, img: &[u8], mut write: F) -> io::Result<()>
+    where F: FnMut(&Path, &[u8]) -> io::Result<()> {
+    // Keep the extension, the image format is determined by it.
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let tmp_path = path.with_extension(format!("tmp.{}", extension));
+
+    let mut attempts = 0;
+    loop {
+        let result = write(&tmp_path, img).and_then(|_| fs::rename(&tmp_path, path));
+        match result {
+            Ok(()) => return Ok(()),
+            Err(reason) => {
+                attempts += 1;
+                if attempts == 5 { return Err(reason); }
+                println!("failed to write {}: {}, retrying", path.display(), reason);
+                thread::sleep(Duration::from_millis(200));
+            }
         }
     }
 }
@@ -72,3 +174,32 @@ fn simulate_main() {
     let height = 720u32;
     App::new_test(width, height);
 }
+
+#[test]
+fn save_image_retries_failed_write() {
+    use std::io::{Read, Write};
+
+    // Include the process id, so that concurrent test runs do not collide.
+    let name = format!("robigo-luculenta-save-test-{}.png", std::process::id());
+    let path = std::env::temp_dir().join(name);
+    let img = [1u8, 2, 3, 4, 5, 6];
+
+    // A writer that fails the first time, as if the disk were full.
+    let mut calls = 0;
+    let flaky_write = |tmp_path: &Path, img: &[u8]| {
+        calls += 1;
+        if calls == 1 {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        } else {
+            fs::File::create(tmp_path).and_then(|mut f| f.write_all(img))
+        }
+    };
+
+    save_image(&path, &img, flaky_write).unwrap();
+
+    let mut written = Vec::new();
+    fs::File::open(&path).unwrap().read_to_end(&mut written).unwrap();
+    assert_eq!(&written[..], &img[..]);
+    assert!(!path.with_extension("tmp.png").exists());
+    fs::remove_file(&path).unwrap();
+}
@@ -17,21 +17,70 @@
 use std::f32::consts::PI;
 use intersection::Intersection;
 use ray::Ray;
-use vector3::dot;
+use vector3::{Vector3, dot};
 use constants::{BOLTZMANNS_CONSTANT, SPEED_OF_LIGHT, PLANCKS_CONSTANT, WIENS_CONSTANT};
 
 /// Models the behaviour of a ray when it bounces off a surface.
 pub trait Material {
     /// Returns the ray that continues the light path, backwards from the
-    /// camera to the light source.
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray;
+    /// camera to the light source. The `exterior_ior` is the index of
+    /// refraction of the medium on the other side of the surface than the
+    /// material, 1.0 for air.
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   exterior_ior: f32)
+                   -> Ray;
+
+    /// For transmissive materials, returns the index of refraction at the
+    /// specified wavelength. Rays that enter the material travel through it
+    /// as a medium. Opaque materials return `None`.
+    fn get_index_of_refraction(&self, _wavelength: f32) -> Option<f32> {
+        None
+    }
+
+    /// For transmissive materials, returns the absorption coefficient at the
+    /// specified wavelength, per meter that light travels through the
+    /// material. Light is absorbed by the Beer-Lambert law. By default
+    /// nothing is absorbed.
+    fn get_absorption(&self, _wavelength: f32) -> f32 {
+        0.0
+    }
+
+    /// For a path that continues in `direction`, returns the reflected
+    /// fraction of the light (including the cosine term), and the
+    /// probability density (per steradian) with which `get_new_ray` would
+    /// have chosen that direction. Materials that cannot be evaluated for
+    /// an arbitrary direction, such as perfect mirrors, return `None`.
+    fn evaluate(&self,
+                _incoming_ray: &Ray,
+                _intersection: &Intersection,
+                _direction: Vector3)
+                -> Option<(f32, f32)> {
+        None
+    }
 }
 
 /// Models the behavior of a light-emitting surface. Light-emitting surfaces
 /// are handled independently of reflecting surfaces.
 pub trait EmissiveMaterial {
-    /// Returns the light intensity at the specified `wavelength`.
+    /// Returns the light intensity at the specified `wavelength`. For
+    /// materials that vary over the surface, this is the average intensity.
     fn get_intensity(&self, wavelength: f32) -> f32;
+
+    /// Returns the light intensity at the specified `wavelength`, at the
+    /// point of the surface that was intersected. By default the intensity
+    /// is the same everywhere.
+    fn get_intensity_at(&self, wavelength: f32, _intersection: &Intersection) -> f32 {
+        self.get_intensity(wavelength)
+    }
+
+    /// Returns the light intensity as `get_intensity_at`, at time `t` between
+    /// 0.0 and 1.0, the time at which the camera is animated. By default the
+    /// intensity does not change over time.
+    fn get_intensity_at_time(&self, wavelength: f32, intersection: &Intersection, _t: f32) -> f32 {
+        self.get_intensity_at(wavelength, intersection)
+    }
 }
 
 /// Returns a ray as if reflected by a perfectly diffuse white material.
@@ -40,14 +89,14 @@ fn get_diffuse_ray(incoming_ray: &Ray, intersection: &Intersection) -> Ray {
     // originating from the intersection.
     let hemi_vec = ::monte_carlo::get_hemisphere_vector();
 
-    // However, the new ray is now facing in the wrong direction,
-    // it must be rotated towards the surface normal.
-    let normal = if dot(incoming_ray.direction, intersection.normal) < 0.0 {
-        intersection.normal
-    } else {
-        -intersection.normal
-    };
-    let direction = hemi_vec.rotate_towards(normal);
+    // However, the new ray is now facing in the wrong direction, it must be
+    // expressed in the surface frame, on the side the ray came from. The
+    // distribution is symmetric around the normal, so the handedness of the
+    // frame does not matter.
+    let normal = get_facing_normal(incoming_ray, intersection);
+    let direction = intersection.tangent * hemi_vec.x
+                  + intersection.bitangent * hemi_vec.y
+                  + normal * hemi_vec.z;
 
     Ray {
         origin: intersection.position,
@@ -57,6 +106,24 @@ fn get_diffuse_ray(incoming_ray: &Ray, intersection: &Intersection) -> Ray {
     }
 }
 
+/// Returns the normal of the intersection on the side where the
+/// incoming ray came from.
+fn get_facing_normal(incoming_ray: &Ray, intersection: &Intersection) -> Vector3 {
+    if dot(incoming_ray.direction, intersection.normal) < 0.0 {
+        intersection.normal
+    } else {
+        -intersection.normal
+    }
+}
+
+/// Evaluates a perfectly diffuse white material, with the same distribution
+/// as `get_diffuse_ray`. Returns the cosine term and the density, which for
+/// cosine-weighted sampling are both cos(theta) / pi.
+fn evaluate_diffuse(incoming_ray: &Ray, intersection: &Intersection, direction: Vector3) -> f32 {
+    let cos_theta = dot(direction, get_facing_normal(incoming_ray, intersection));
+    cos_theta.max(0.0) / PI
+}
+
 /// The Boltzmann distribution.
 fn boltzmann(wavelength: f64, temperature: f64) -> f64 {
     // Use double precision here, the numbers are quite large/small,
@@ -73,6 +140,16 @@ fn boltzmann(wavelength: f64, temperature: f64) -> f64 {
     (2.0 * h * f * f * f) / (c * c * ((h * f / (k * temperature)).exp() - 1.0))
 }
 
+/// Linearly interpolates a table of 81 values sampled every 5 nm from 380
+/// to 780 nm, at the specified wavelength. Outside of the sampled range,
+/// the nearest sample is used.
+fn interpolate_table(table: &[f32; 81], wavelength: f32) -> f32 {
+    let indexf = ((wavelength - 380.0) / 5.0).max(0.0).min(80.0);
+    let index = (indexf.floor() as usize).min(79);
+    let remainder = indexf - index as f32;
+    table[index] * (1.0 - remainder) + table[index + 1] * remainder
+}
+
 /// Has the spectrum of a black body.
 pub struct BlackBodyMaterial {
     /// The temperature of the black body, in Kelvin. 6504 is a warm white,
@@ -83,24 +160,190 @@ pub struct BlackBodyMaterial {
     /// but for the purposes of a light source, only the distribution
     /// is important, not the intensity, so the distribution must be
     /// normalised.
-    normalisation_factor: f32
+    normalisation_factor: f32,
+
+    /// The intensity every 5 nm from 380 to 780 nm.
+    table: [f32; 81],
+
+    /// Whether the intensity in the visible range is interpolated from
+    /// the table, instead of evaluating Planck's law.
+    tabulated: bool
 }
 
 impl BlackBodyMaterial {
     /// Constructs a black body material with the specified
     /// temperature in Kelvin.
     pub fn new(kelvins: f32, intensity: f32) -> BlackBodyMaterial {
-        BlackBodyMaterial {
+        let mut material = BlackBodyMaterial {
             temperature: kelvins,
             normalisation_factor: intensity
-                / boltzmann((WIENS_CONSTANT / kelvins as f64) * 1.0e9, kelvins as f64) as f32
+                / boltzmann((WIENS_CONSTANT / kelvins as f64) * 1.0e9, kelvins as f64) as f32,
+            table: [0.0; 81],
+            tabulated: false
+        };
+        for i in 0 .. 81 {
+            material.table[i] = material.get_exact_intensity(380.0 + i as f32 * 5.0);
         }
+        material
+    }
+
+    /// Sets whether the intensity is interpolated from a table. That avoids
+    /// an exponential for every emissive hit, at a small loss of accuracy.
+    /// Outside of the visible range, the intensity is always exact.
+    pub fn set_tabulated(&mut self, tabulated: bool) {
+        self.tabulated = tabulated;
+    }
+
+    fn get_exact_intensity(&self, wavelength: f32) -> f32 {
+        boltzmann(wavelength as f64, self.temperature as f64) as f32 * self.normalisation_factor
     }
 }
 
 impl EmissiveMaterial for BlackBodyMaterial {
     fn get_intensity(&self, wavelength: f32) -> f32 {
-        boltzmann(wavelength as f64, self.temperature as f64) as f32 * self.normalisation_factor
+        if self.tabulated && wavelength >= 380.0 && wavelength <= 780.0 {
+            interpolate_table(&self.table, wavelength)
+        } else {
+            self.get_exact_intensity(wavelength)
+        }
+    }
+}
+
+/// Returns the approximate sRGB colour of a `BlackBodyMaterial` of the
+/// specified temperature in Kelvin, for showing the colour of a light in a
+/// user interface. It is the colour that the light has in a render, because
+/// the spectrum that the material emits is integrated as is. The colour is
+/// as bright as possible with all components in the range [0, 1]; colours
+/// outside of the sRGB gamut are clipped.
+#[allow(dead_code)]
+pub fn black_body_rgb(kelvins: f32) -> Vector3 {
+    let body = BlackBodyMaterial::new(kelvins, 1.0);
+    let cie = ::cie1931::integrate(|wavelength| body.get_intensity(wavelength));
+    let rgb = ::srgb::xyz_to_srgb(cie);
+    let brightest = rgb.x.max(rgb.y).max(rgb.z);
+    let srgb = ::srgb::transform(cie * (1.0 / brightest));
+    Vector3 {
+        x: srgb.x.max(0.0).min(1.0),
+        y: srgb.y.max(0.0).min(1.0),
+        z: srgb.z.max(0.0).min(1.0)
+    }
+}
+
+/// Emits the same intensity at every visible wavelength, an equal-energy
+/// illuminant. It is the simplest light there is, which makes it a good
+/// reference to compare other lights against.
+pub struct FlatEmissiveMaterial {
+    /// The intensity at every visible wavelength.
+    intensity: f32
+}
+
+impl FlatEmissiveMaterial {
+    /// Constructs a light that emits `intensity` between 380 and 780 nm.
+    pub fn new(intensity: f32) -> FlatEmissiveMaterial {
+        FlatEmissiveMaterial {
+            intensity: intensity
+        }
+    }
+}
+
+impl EmissiveMaterial for FlatEmissiveMaterial {
+    fn get_intensity(&self, wavelength: f32) -> f32 {
+        if wavelength >= 380.0 && wavelength <= 780.0 { self.intensity } else { 0.0 }
+    }
+}
+
+/// Emits light that blends from one spectrum into another over time, like a
+/// sun that cools as it sets.
+pub struct BlendedEmissiveMaterial {
+    /// The light emitted at time 0.0.
+    start: Box<EmissiveMaterial + Sync + Send>,
+
+    /// The light emitted at time 1.0.
+    end: Box<EmissiveMaterial + Sync + Send>
+}
+
+impl BlendedEmissiveMaterial {
+    /// Constructs a light that emits like `start` at time 0.0, and like `end`
+    /// at time 1.0, interpolating linearly in between.
+    pub fn new(start: Box<EmissiveMaterial + Sync + Send>,
+               end: Box<EmissiveMaterial + Sync + Send>)
+               -> BlendedEmissiveMaterial {
+        BlendedEmissiveMaterial {
+            start: start,
+            end: end
+        }
+    }
+}
+
+impl EmissiveMaterial for BlendedEmissiveMaterial {
+    fn get_intensity(&self, wavelength: f32) -> f32 {
+        (self.start.get_intensity(wavelength) + self.end.get_intensity(wavelength)) * 0.5
+    }
+
+    fn get_intensity_at(&self, wavelength: f32, intersection: &Intersection) -> f32 {
+        (self.start.get_intensity_at(wavelength, intersection)
+            + self.end.get_intensity_at(wavelength, intersection)) * 0.5
+    }
+
+    fn get_intensity_at_time(&self, wavelength: f32, intersection: &Intersection, t: f32) -> f32 {
+        let start = self.start.get_intensity_at_time(wavelength, intersection, t);
+        let end = self.end.get_intensity_at_time(wavelength, intersection, t);
+        start * (1.0 - t) + end * t
+    }
+}
+
+/// Emits light with the colour of an image, mapped onto the surface with
+/// its texture coordinates, like a screen. The colours are upsampled to
+/// spectra.
+pub struct TexturedEmissiveMaterial {
+    /// The width of the image in pixels.
+    width: usize,
+
+    /// The height of the image in pixels.
+    height: usize,
+
+    /// The linear sRGB colours of the pixels, row by row. Row 0 is at v = 0.
+    texels: Vec<Vector3>,
+
+    /// The average colour of the image.
+    average: Vector3,
+
+    /// The intensity of a white pixel.
+    intensity: f32
+}
+
+impl TexturedEmissiveMaterial {
+    /// Constructs a light that shows the image of `width` by `height` linear
+    /// sRGB pixels, where a white pixel emits `intensity` at every wavelength.
+    pub fn new(width: usize, height: usize, texels: Vec<Vector3>, intensity: f32)
+               -> TexturedEmissiveMaterial {
+        assert_eq!(texels.len(), width * height);
+        let sum = texels.iter().fold(Vector3::zero(), |acc, &t| acc + t);
+        TexturedEmissiveMaterial {
+            width: width,
+            height: height,
+            average: sum * (1.0 / texels.len() as f32),
+            texels: texels,
+            intensity: intensity
+        }
+    }
+
+    /// Returns the colour of the pixel at the texture coordinates.
+    fn get_texel(&self, u: f32, v: f32) -> Vector3 {
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.texels[y * self.width + x]
+    }
+}
+
+impl EmissiveMaterial for TexturedEmissiveMaterial {
+    fn get_intensity(&self, wavelength: f32) -> f32 {
+        ::srgb::upsample(self.average, wavelength) * self.intensity
+    }
+
+    fn get_intensity_at(&self, wavelength: f32, intersection: &Intersection) -> f32 {
+        let (u, v) = intersection.uv;
+        ::srgb::upsample(self.get_texel(u.max(0.0), v.max(0.0)), wavelength) * self.intensity
     }
 }
 
@@ -120,13 +363,26 @@ impl DiffuseGreyMaterial {
 }
 
 impl Material for DiffuseGreyMaterial {
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
         let mut ray = get_diffuse_ray(incoming_ray, intersection);
 
         // The probability that the ray was reflected is the reflectance.
         ray.probability = self.reflectance;
         ray
     }
+
+    fn evaluate(&self,
+                incoming_ray: &Ray,
+                intersection: &Intersection,
+                direction: Vector3)
+                -> Option<(f32, f32)> {
+        let pdf = evaluate_diffuse(incoming_ray, intersection, direction);
+        Some((self.reflectance * pdf, pdf))
+    }
 }
 
 /// Reflects light of a certain wavelength better than others,
@@ -139,7 +395,12 @@ pub struct DiffuseColouredMaterial {
     wavelength: f32,
 
     /// The standard deviation of the reflectance distribution.
-    deviation: f32
+    deviation: f32,
+
+    /// An optional function of the position on the surface, that shifts the
+    /// wavelength which is best reflected (in nm), for a colour that varies
+    /// over the surface.
+    wavelength_shift: Option<Box<Fn(Vector3) -> f32 + Sync + Send>>
 }
 
 impl DiffuseColouredMaterial {
@@ -147,24 +408,136 @@ impl DiffuseColouredMaterial {
         DiffuseColouredMaterial {
             reflectance: refl,
             wavelength: wavel,
-            deviation: dev
+            deviation: dev,
+            wavelength_shift: None
+        }
+    }
+
+    /// Makes the wavelength that is best reflected depend on the position,
+    /// by adding the result of `shift` at the intersection to it.
+    pub fn set_wavelength_shift(&mut self, shift: Box<Fn(Vector3) -> f32 + Sync + Send>) {
+        self.wavelength_shift = Some(shift);
+    }
+
+    /// Returns the wavelength that is best reflected at the position.
+    fn get_peak_wavelength(&self, position: Vector3) -> f32 {
+        match self.wavelength_shift {
+            Some(ref shift) => self.wavelength + shift(position),
+            None => self.wavelength
         }
     }
+
+    /// Returns the reflectance for a wavelength at the position, using
+    /// Gaussian falloff around the peak wavelength.
+    fn get_reflectance(&self, wavelength: f32, position: Vector3) -> f32 {
+        let p = (self.get_peak_wavelength(position) - wavelength) / self.deviation;
+        self.reflectance * (-0.5 * p * p).exp()
+    }
 }
 
 impl Material for DiffuseColouredMaterial {
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
-        // Compute the probability using Gaussian falloff.
-        let p = (self.wavelength - incoming_ray.wavelength) / self.deviation;
-        let q = (-0.5 * p * p).exp();
-
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
         let mut ray = get_diffuse_ray(incoming_ray, intersection);
         
         // The probablity is a combination of reflectance, and the probability
         // based on the wavelength.
-        ray.probability = self.reflectance * q;
+        ray.probability = self.get_reflectance(incoming_ray.wavelength, intersection.position);
         ray
     }
+
+    fn evaluate(&self,
+                incoming_ray: &Ray,
+                intersection: &Intersection,
+                direction: Vector3)
+                -> Option<(f32, f32)> {
+        let reflectance = self.get_reflectance(incoming_ray.wavelength, intersection.position);
+        let pdf = evaluate_diffuse(incoming_ray, intersection, direction);
+        Some((reflectance * pdf, pdf))
+    }
+}
+
+/// A diffuse material with a measured reflectance spectrum, sampled every
+/// 5 nm from 380 nm to 780 nm, like the CIE 1931 tables.
+pub struct TabulatedDiffuseMaterial {
+    /// The reflectance at the sampled wavelengths, between 0.0 and 1.0.
+    reflectance: [f32; 81]
+}
+
+impl TabulatedDiffuseMaterial {
+    pub fn new(reflectance: [f32; 81]) -> TabulatedDiffuseMaterial {
+        TabulatedDiffuseMaterial {
+            reflectance: reflectance
+        }
+    }
+
+    /// Interpolates the reflectance at the specified wavelength. Outside
+    /// of the sampled range, the nearest sample is used.
+    fn get_reflectance(&self, wavelength: f32) -> f32 {
+        interpolate_table(&self.reflectance, wavelength)
+    }
+}
+
+impl Material for TabulatedDiffuseMaterial {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
+        let mut ray = get_diffuse_ray(incoming_ray, intersection);
+        ray.probability = self.get_reflectance(incoming_ray.wavelength);
+        ray
+    }
+
+    fn evaluate(&self,
+                incoming_ray: &Ray,
+                intersection: &Intersection,
+                direction: Vector3)
+                -> Option<(f32, f32)> {
+        let reflectance = self.get_reflectance(incoming_ray.wavelength);
+        let pdf = evaluate_diffuse(incoming_ray, intersection, direction);
+        Some((reflectance * pdf, pdf))
+    }
+}
+
+/// A diffuse material with the colour of a linear sRGB value, which is
+/// upsampled to a smooth reflectance spectrum.
+pub struct DiffuseRgbMaterial {
+    /// The reflectance for red, green and blue, all between 0.0 and 1.0.
+    colour: Vector3
+}
+
+impl DiffuseRgbMaterial {
+    pub fn new(colour: Vector3) -> DiffuseRgbMaterial {
+        DiffuseRgbMaterial {
+            colour: colour
+        }
+    }
+}
+
+impl Material for DiffuseRgbMaterial {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
+        let mut ray = get_diffuse_ray(incoming_ray, intersection);
+        ray.probability = ::srgb::upsample(self.colour, incoming_ray.wavelength);
+        ray
+    }
+
+    fn evaluate(&self,
+                incoming_ray: &Ray,
+                intersection: &Intersection,
+                direction: Vector3)
+                -> Option<(f32, f32)> {
+        let reflectance = ::srgb::upsample(self.colour, incoming_ray.wavelength);
+        let pdf = evaluate_diffuse(incoming_ray, intersection, direction);
+        Some((reflectance * pdf, pdf))
+    }
 }
 
 /// Blends between perfect reflection and diffuse.
@@ -183,7 +556,11 @@ impl GlossyMirrorMaterial {
 }
 
 impl Material for GlossyMirrorMaterial {
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
         // The diffuse component is as usual.
         let mut ray = get_diffuse_ray(incoming_ray, intersection);
 
@@ -195,12 +572,67 @@ impl Material for GlossyMirrorMaterial {
     }
 }
 
+/// A glossy material that reflects light in a lobe around the mirror
+/// direction. Unlike `GlossyMirrorMaterial`, it can be evaluated for
+/// any direction, so it can be combined with light sampling.
+pub struct PhongMaterial {
+    /// How much the material reflects; 0.0 is black, 1.0 is white.
+    reflectance: f32,
+
+    /// The sharpness of the lobe. Higher exponents are more mirror-like.
+    exponent: f32
+}
+
+impl PhongMaterial {
+    pub fn new(refl: f32, exponent: f32) -> PhongMaterial {
+        PhongMaterial {
+            reflectance: refl,
+            exponent: exponent
+        }
+    }
+}
+
+impl Material for PhongMaterial {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
+        let reflection = incoming_ray.direction.reflect(intersection.normal);
+        let direction = ::monte_carlo::get_cosine_power_vector(self.exponent)
+                        .rotate_towards(reflection);
+
+        // Parts of the lobe that point into the surface are absorbed.
+        let normal = get_facing_normal(incoming_ray, intersection);
+        let probability = if dot(direction, normal) > 0.0 { self.reflectance } else { 0.0 };
+
+        Ray {
+            origin: intersection.position,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: probability
+        }
+    }
+
+    fn evaluate(&self,
+                incoming_ray: &Ray,
+                intersection: &Intersection,
+                direction: Vector3)
+                -> Option<(f32, f32)> {
+        let reflection = incoming_ray.direction.reflect(intersection.normal);
+        let pdf = ::monte_carlo::get_cosine_power_pdf(self.exponent, dot(direction, reflection));
+        let normal = get_facing_normal(incoming_ray, intersection);
+        let reflected = if dot(direction, normal) > 0.0 { self.reflectance * pdf } else { 0.0 };
+        Some((reflected, pdf))
+    }
+}
+
 /// Refractive glass.
 pub struct Sf10GlassMaterial;
 
 impl Sf10GlassMaterial {
     /// Returns the index of refraction for SF10 glass.
-    fn get_index_of_refraction(wavelength: f32) -> f32 {
+    fn get_sf10_index_of_refraction(wavelength: f32) -> f32 {
         // See http://refractiveindex.info/?group=GLASSES&material=SF11
 
         // Square and convert nanometer to micrometer
@@ -211,22 +643,27 @@ impl Sf10GlassMaterial {
             + 1.898781010 * w2 / (w2 - 155.23629000))
         .sqrt() as f32
     }
-}
 
-impl Material for Sf10GlassMaterial {
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+    /// Computes how the incoming ray interacts with the surface. Returns the
+    /// direction of the reflected ray, and unless total internal reflection
+    /// occurs, the direction of the refracted ray together with the Fresnel
+    /// reflectance: the fraction of the light that is reflected.
+    fn get_interaction(incoming_ray: &Ray,
+                       intersection: &Intersection,
+                       exterior_ior: f32)
+                       -> (Vector3, Option<(Vector3, f32)>) {
         let mut cos_i = -dot(incoming_ray.direction, intersection.normal);
 
         // Retrieve the index of refraction to be used,
         // which can be wavelength-dependent.
-        let mut ior = Sf10GlassMaterial::get_index_of_refraction(incoming_ray.wavelength);
+        let glass_ior = Sf10GlassMaterial::get_sf10_index_of_refraction(incoming_ray.wavelength);
         let mut normal = intersection.normal;
 
-        // The IOR in this formula is n1 / n2, where n1 is air (1.0) when the
-        // ray enters, otherwise, when the ray leaves the material, the IOR is
-        // correct as is.
+        // The IOR in this formula is n1 / n2, where n1 is the exterior
+        // medium when the ray enters, and the glass when it leaves.
+        let mut ior = glass_ior / exterior_ior;
         if cos_i > 0.0 {
-            ior = 1.0 / ior;
+            ior = exterior_ior / glass_ior;
         } else {
             // The formula below assumes the normal to be at the same side as
             // the incident ray. If this is not the case, reverse the normal.
@@ -235,15 +672,67 @@ impl Material for Sf10GlassMaterial {
         }
 
         let sin_t_sqr = ior * ior * (1.0 - cos_i * cos_i);
+        let reflection = incoming_ray.direction.reflect(normal);
 
-        let dir = if sin_t_sqr > 1.0 {
+        if sin_t_sqr > 1.0 {
             // When refraction is impossible, total internal reflection must
             // have occurred.
-            incoming_ray.direction.reflect(normal)
+            (reflection, None)
         } else {
-            // Otherwise compute the reflected ray.
+            // Otherwise compute the refracted ray.
             let cos_t = (1.0 - sin_t_sqr).sqrt();
-            incoming_ray.direction * ior + normal * (ior * cos_i - cos_t)
+            let refraction = incoming_ray.direction * ior + normal * (ior * cos_i - cos_t);
+
+            // The Fresnel equations, for unpolarised light.
+            let rs = (ior * cos_i - cos_t) / (ior * cos_i + cos_t);
+            let rp = (ior * cos_t - cos_i) / (ior * cos_t + cos_i);
+            let reflectance = (rs * rs + rp * rp) * 0.5;
+
+            (reflection, Some((refraction, reflectance)))
+        }
+    }
+
+    /// Returns both the reflected and the refracted ray, instead of choosing
+    /// one of them, for a renderer that follows both branches. The probability
+    /// of the reflected ray is the Fresnel reflectance, and that of the
+    /// refracted ray the transmittance, so together they conserve energy.
+    /// When total internal reflection occurs, there is no refracted ray.
+    #[allow(dead_code)]
+    pub fn get_split_rays(&self,
+                          incoming_ray: &Ray,
+                          intersection: &Intersection,
+                          exterior_ior: f32)
+                          -> (Ray, Option<Ray>) {
+        let (reflection, refraction) =
+            Sf10GlassMaterial::get_interaction(incoming_ray, intersection, exterior_ior);
+        let reflectance = refraction.map(|(_, r)| r).unwrap_or(1.0);
+
+        let reflected = Ray {
+            origin: intersection.position,
+            direction: reflection,
+            wavelength: incoming_ray.wavelength,
+            probability: reflectance
+        };
+        let refracted = refraction.map(|(direction, r)| Ray {
+            origin: intersection.position,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: 1.0 - r
+        });
+
+        (reflected, refracted)
+    }
+}
+
+impl Material for Sf10GlassMaterial {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   exterior_ior: f32)
+                   -> Ray {
+        let dir = match Sf10GlassMaterial::get_interaction(incoming_ray, intersection, exterior_ior) {
+            (_, Some((refraction, _))) => refraction,
+            (reflection, None) => reflection
         };
 
         // There is only one way in which the ray can be refracted,
@@ -258,6 +747,53 @@ impl Material for Sf10GlassMaterial {
             wavelength: incoming_ray.wavelength
         }
     }
+
+    fn get_index_of_refraction(&self, wavelength: f32) -> Option<f32> {
+        Some(Sf10GlassMaterial::get_sf10_index_of_refraction(wavelength))
+    }
+}
+
+/// Coloured glass: it refracts like SF10 glass, but absorbs light inside,
+/// except around the wavelength that gives it its colour.
+pub struct ColouredGlassMaterial {
+    /// The absorption coefficient (per meter) far from the wavelength
+    /// that is transmitted.
+    absorption: f32,
+
+    /// The wavelength that is transmitted best, in nm.
+    wavelength: f32,
+
+    /// The standard deviation of the transmission around that wavelength.
+    deviation: f32
+}
+
+impl ColouredGlassMaterial {
+    pub fn new(absorption: f32, wavel: f32, dev: f32) -> ColouredGlassMaterial {
+        ColouredGlassMaterial {
+            absorption: absorption,
+            wavelength: wavel,
+            deviation: dev
+        }
+    }
+}
+
+impl Material for ColouredGlassMaterial {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   exterior_ior: f32)
+                   -> Ray {
+        Sf10GlassMaterial.get_new_ray(incoming_ray, intersection, exterior_ior)
+    }
+
+    fn get_index_of_refraction(&self, wavelength: f32) -> Option<f32> {
+        Sf10GlassMaterial.get_index_of_refraction(wavelength)
+    }
+
+    fn get_absorption(&self, wavelength: f32) -> f32 {
+        let p = (self.wavelength - wavelength) / self.deviation;
+        self.absorption * (1.0 - (-0.5 * p * p).exp())
+    }
 }
 
 /// Not a physically accurate thin-film material, but still an aesthetically
@@ -265,7 +801,11 @@ impl Material for Sf10GlassMaterial {
 pub struct SoapBubbleMaterial;
 
 impl Material for SoapBubbleMaterial {
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+    fn get_new_ray(&self,
+                   incoming_ray: &Ray,
+                   intersection: &Intersection,
+                   _exterior_ior: f32)
+                   -> Ray {
         let cos_alpha = dot(incoming_ray.direction, intersection.normal);
 
         // Reflect or pass through, based on the angle
@@ -304,3 +844,257 @@ impl Material for SoapBubbleMaterial {
         }
     }
 }
+
+#[test]
+fn fixed_units_give_exact_diffuse_direction() {
+    use vector3::Vector3;
+
+    // A ray comes straight down onto a floor. The bounce is sampled at
+    // longitude 0.25 * 2pi, where sin^2 of the angle with the normal is 0.36,
+    // so it lies between the bitangent and the normal.
+    let ray = Ray {
+        origin: Vector3::new(0.0, 0.0, 1.0),
+        direction: Vector3::new(0.0, 0.0, -1.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let intersection = Intersection::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0),
+                                         Vector3::zero(), 1.0);
+    ::monte_carlo::set_fixed_units(&[0.25, 0.36]);
+    let bounce = DiffuseGreyMaterial::new(0.8).get_new_ray(&ray, &intersection, 1.0);
+
+    let expected = intersection.bitangent * 0.6 + intersection.normal * 0.8;
+    assert!((bounce.direction - expected).magnitude() < 1.0e-6);
+    assert_eq!(bounce.probability, 0.8);
+
+    // Once the fixed values are used up, directions are random again.
+    let random = DiffuseGreyMaterial::new(0.8).get_new_ray(&ray, &intersection, 1.0);
+    assert!((random.direction.magnitude() - 1.0).abs() < 1.0e-5);
+}
+
+#[test]
+fn refraction_between_glasses_uses_relative_ior() {
+    use vector3::Vector3;
+
+    // A ray enters glass at 45 degrees through a flat surface.
+    let (c, s) = (0.5f32.sqrt(), 0.5f32.sqrt());
+    let ray = Ray {
+        origin: Vector3::new(-c, 0.0, s),
+        direction: Vector3::new(c, 0.0, -s),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let intersection = Intersection::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0),
+                                         Vector3::zero(), 1.0);
+
+    let glass = Sf10GlassMaterial;
+    let n = glass.get_index_of_refraction(550.0).unwrap();
+
+    // Coming from air, Snell's law applies with n1 = 1.0.
+    let from_air = glass.get_new_ray(&ray, &intersection, 1.0);
+    assert!((from_air.direction.x - s / n).abs() < 1.0e-4);
+
+    // Coming from another medium, it applies with that medium's index.
+    // From a medium with the same index, the ray is not refracted at all.
+    let from_water = glass.get_new_ray(&ray, &intersection, 1.33);
+    assert!((from_water.direction.x - 1.33 * s / n).abs() < 1.0e-4);
+    let from_glass = glass.get_new_ray(&ray, &intersection, n);
+    assert!((from_glass.direction.x - s).abs() < 1.0e-4);
+    assert!((from_glass.direction.z + c).abs() < 1.0e-4);
+}
+
+#[test]
+fn fresnel_weights_conserve_energy() {
+    use vector3::Vector3;
+
+    let intersection = Intersection::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0),
+                                         Vector3::zero(), 1.0);
+    let ray_at = |angle: f32| Ray {
+        origin: Vector3::new(-angle.sin(), 0.0, angle.cos()),
+        direction: Vector3::new(angle.sin(), 0.0, -angle.cos()),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let glass = Sf10GlassMaterial;
+
+    for &angle in [0.0f32, 0.3, PI * 0.25, 1.2].iter() {
+        let (reflected, refracted) = glass.get_split_rays(&ray_at(angle), &intersection, 1.0);
+        let refracted = refracted.unwrap();
+        assert!((reflected.probability + refracted.probability - 1.0).abs() < 1.0e-5);
+        assert!(reflected.direction.z > 0.0 && refracted.direction.z < 0.0);
+    }
+
+    // At normal incidence, the reflectance is ((n - 1) / (n + 1))^2.
+    let n = glass.get_index_of_refraction(550.0).unwrap();
+    let (reflected, _) = glass.get_split_rays(&ray_at(0.0), &intersection, 1.0);
+    let expected = ((n - 1.0) / (n + 1.0)).powi(2);
+    assert!((reflected.probability - expected).abs() < 1.0e-5);
+}
+
+#[test]
+fn flat_emissive_material_is_constant_in_visible_range() {
+    let light = FlatEmissiveMaterial::new(0.7);
+    for i in 0 .. 41 {
+        let wavelength = 380.0 + i as f32 * 10.0;
+        assert_eq!(light.get_intensity(wavelength), 0.7);
+    }
+    assert_eq!(light.get_intensity(379.0), 0.0);
+    assert_eq!(light.get_intensity(781.0), 0.0);
+}
+
+#[test]
+fn flat_tabulated_reflectance_reflects_half() {
+    use vector3::Vector3;
+
+    let intersection = Intersection::new(Vector3::zero(), Vector3::new(0.0, 0.0, 1.0),
+                                         Vector3::zero(), 1.0);
+    let material = TabulatedDiffuseMaterial::new([0.5; 81]);
+    for i in 0 .. 81 {
+        let ray = Ray {
+            origin: Vector3::new(0.0, 0.0, 1.0),
+            direction: Vector3::new(0.0, 0.0, -1.0),
+            wavelength: 380.0 + i as f32 * 5.0,
+            probability: 1.0
+        };
+        let reflected = material.get_new_ray(&ray, &intersection, 1.0);
+        assert_eq!(reflected.probability, 0.5);
+        assert!(reflected.direction.z > 0.0);
+    }
+}
+
+#[test]
+fn wavelength_shift_varies_colour_over_surface() {
+    use vector3::Vector3;
+
+    // A gradient from green at the origin towards red along the x-axis.
+    let mut material = DiffuseColouredMaterial::new(0.9, 530.0, 30.0);
+    material.set_wavelength_shift(Box::new(|p: Vector3| p.x * 10.0));
+    assert_eq!(material.get_peak_wavelength(Vector3::zero()), 530.0);
+    assert_eq!(material.get_peak_wavelength(Vector3::new(7.0, 0.0, 0.0)), 600.0);
+
+    let ray = Ray {
+        origin: Vector3::new(0.0, 0.0, 1.0),
+        direction: Vector3::new(0.0, 0.0, -1.0),
+        wavelength: 600.0,
+        probability: 1.0
+    };
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let green = Intersection::new(Vector3::zero(), up, Vector3::zero(), 1.0);
+    let red = Intersection::new(Vector3::new(7.0, 0.0, 0.0), up, Vector3::zero(), 1.0);
+    let p_green = material.get_new_ray(&ray, &green, 1.0).probability;
+    let p_red = material.get_new_ray(&ray, &red, 1.0).probability;
+    assert!((p_red - 0.9).abs() < 1.0e-5);
+    assert!(p_green < 0.1);
+}
+
+#[test]
+fn tabulated_black_body_matches_planck() {
+    // The spectrum is steepest for cool bodies at short wavelengths,
+    // where the interpolation is off by up to about 1%.
+    for &kelvins in [1800.0, 4000.0, 6504.0, 12000.0].iter() {
+        let exact = BlackBodyMaterial::new(kelvins, 1.0);
+        let mut tabulated = BlackBodyMaterial::new(kelvins, 1.0);
+        tabulated.set_tabulated(true);
+
+        for i in 0 .. 400 {
+            let wavelength = 380.0 + i as f32 + 0.5;
+            let a = exact.get_intensity(wavelength);
+            let b = tabulated.get_intensity(wavelength);
+            assert!((a - b).abs() <= a * 1.0e-2);
+        }
+    }
+}
+
+#[test]
+fn textured_light_shows_its_image() {
+    use geometry::{Extent, Plane, Surface};
+
+    // A screen of 2 by 1 pixels, red on the left and blue on the right.
+    let red = Vector3::new(1.0, 0.0, 0.0);
+    let blue = Vector3::new(0.0, 0.0, 1.0);
+    let light = TexturedEmissiveMaterial::new(2, 1, vec!(red, blue), 2.0);
+    let extent = Extent::new(Vector3::new(1.0, 0.0, 0.0), 1.0, Vector3::new(0.0, 0.0, 1.0), 0.5);
+    let screen = Plane::new_bounded(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 5.0, 0.0), extent);
+
+    let hit = |x: f32| {
+        let ray = Ray {
+            origin: Vector3::new(x, 0.0, 0.0),
+            direction: Vector3::new(0.0, 1.0, 0.0),
+            wavelength: 650.0,
+            probability: 1.0
+        };
+        screen.intersect(&ray).unwrap()
+    };
+    let left = hit(-0.5);
+    let right = hit(0.5);
+    assert!((left.uv.0 - 0.25).abs() < 1.0e-5);
+    assert!((right.uv.0 - 0.75).abs() < 1.0e-5);
+
+    for &wavelength in [450.0, 650.0].iter() {
+        let expected_left = ::srgb::upsample(red, wavelength) * 2.0;
+        let expected_right = ::srgb::upsample(blue, wavelength) * 2.0;
+        assert_eq!(light.get_intensity_at(wavelength, &left), expected_left);
+        assert_eq!(light.get_intensity_at(wavelength, &right), expected_right);
+    }
+    assert!(light.get_intensity_at(650.0, &left) > light.get_intensity_at(650.0, &right));
+}
+
+#[test]
+fn black_body_rgb_matches_emitted_spectrum() {
+    // Gather the emitted spectrum the way the renderer does, one wavelength
+    // at a time, but in finer steps than the tables.
+    for &kelvins in &[2000.0, 4000.0, 6504.0, 10000.0] {
+        let body = BlackBodyMaterial::new(kelvins, 1.0);
+        let cie = (0 .. 401).fold(Vector3::zero(), |acc, i| {
+            let wavelength = 380.0 + i as f32;
+            acc + ::cie1931::get_tristimulus(wavelength) * body.get_intensity(wavelength)
+        });
+        let rgb = ::srgb::xyz_to_srgb(cie);
+        let brightest = rgb.x.max(rgb.y).max(rgb.z);
+        let expected = ::srgb::transform(cie * (1.0 / brightest));
+
+        let actual = black_body_rgb(kelvins);
+        assert!((actual.x - expected.x.max(0.0).min(1.0)).abs() < 0.02);
+        assert!((actual.y - expected.y.max(0.0).min(1.0)).abs() < 0.02);
+        assert!((actual.z - expected.z.max(0.0).min(1.0)).abs() < 0.02);
+    }
+
+    // A cool body is orange, and hotter bodies are bluer.
+    let orange = black_body_rgb(2000.0);
+    assert!(orange.x > 0.99 && orange.y < 0.8 && orange.z < orange.y);
+    let hot = black_body_rgb(10000.0);
+    assert!(hot.z / hot.x > orange.z / orange.x);
+}
+
+#[test]
+fn blended_light_changes_over_time() {
+    use geometry::{Plane, Surface};
+
+    // A sun that cools from daylight to a candle-like glow.
+    let sun = BlendedEmissiveMaterial::new(Box::new(BlackBodyMaterial::new(6500.0, 1.0)),
+                                           Box::new(BlackBodyMaterial::new(2000.0, 1.0)));
+    let day = BlackBodyMaterial::new(6500.0, 1.0);
+    let dusk = BlackBodyMaterial::new(2000.0, 1.0);
+
+    let ground = Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::zero());
+    let ray = Ray {
+        origin: Vector3::new(0.0, 0.0, 1.0),
+        direction: Vector3::new(0.0, 0.0, -1.0),
+        wavelength: 450.0,
+        probability: 1.0
+    };
+    let isect = ground.intersect(&ray).unwrap();
+
+    for &wavelength in [450.0, 550.0, 650.0].iter() {
+        let at_start = sun.get_intensity_at_time(wavelength, &isect, 0.0);
+        let at_end = sun.get_intensity_at_time(wavelength, &isect, 1.0);
+        assert!((at_start - day.get_intensity(wavelength)).abs() <= at_start * 1.0e-5);
+        assert!((at_end - dusk.get_intensity(wavelength)).abs() <= at_end * 1.0e-5);
+    }
+
+    // At dusk the light is relatively more red than during the day.
+    let ratio = |t: f32| {
+        sun.get_intensity_at_time(650.0, &isect, t) / sun.get_intensity_at_time(450.0, &isect, t)
+    };
+    assert!(ratio(1.0) > ratio(0.0) * 2.0);
+}
@@ -0,0 +1,48 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use monte_carlo::get_unit;
+
+/// A homogeneous participating medium, such as smoke or murky water, that
+/// absorbs and scatters light along the way, not only at surfaces. Both
+/// coefficients are the same for all wavelengths.
+#[derive(Copy, Clone)]
+pub struct Medium {
+    /// The fraction of light absorbed per meter travelled.
+    pub absorption: f32,
+    /// The fraction of light scattered in a random direction per meter.
+    pub scattering: f32
+}
+
+impl Medium {
+    /// Creates a medium with the specified coefficients, in units per meter.
+    pub fn new(absorption: f32, scattering: f32) -> Medium {
+        Medium {
+            absorption: absorption,
+            scattering: scattering
+        }
+    }
+
+    /// Returns the distance in meters that a photon travels before it
+    /// scatters, which is infinite if the medium does not scatter.
+    pub fn sample_scatter_distance(&self) -> f32 {
+        if self.scattering > 0.0 {
+            -(1.0 - get_unit()).ln() / self.scattering
+        } else {
+            ::std::f32::INFINITY
+        }
+    }
+}
@@ -14,16 +14,113 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::VecDeque;
 use std::f32::consts::PI;
 use rand;
-use rand::Closed01;
+use rand::{Closed01, Rng, SeedableRng, XorShiftRng};
 use vector3::Vector3;
 
 // Note that it is safe to just use rand::random: it uses a task-local rng.
+// The `_with` variants of the functions below take an explicit generator
+// instead, for when the samples must be reproducible.
+
+thread_local!(static SEEDED_RNG: RefCell<Option<XorShiftRng>> = RefCell::new(None));
+
+/// The generator used by the functions without `_with`: the generator set
+/// by `seed_thread` if there is one, and the thread-local generator of
+/// `rand` otherwise.
+struct ThreadSampler;
+
+impl Rng for ThreadSampler {
+    fn next_u32(&mut self) -> u32 {
+        SEEDED_RNG.with(|seeded| match *seeded.borrow_mut() {
+            Some(ref mut rng) => rng.next_u32(),
+            None => rand::thread_rng().next_u32()
+        })
+    }
+}
+
+/// Makes the random functions on this thread draw from a generator with
+/// the specified seed, until they are seeded again. With `None` they draw
+/// from the thread-local generator again.
+pub fn seed_thread(seed: Option<[u32; 4]>) {
+    SEEDED_RNG.with(|seeded| {
+        *seeded.borrow_mut() = seed.map(|mut s| {
+            // The generator cannot be seeded with all zeros.
+            if s == [0; 4] { s[0] = 1; }
+            XorShiftRng::from_seed(s)
+        });
+    });
+}
+
+/// Mixes the bits of an integer, such that similar inputs give very
+/// different outputs.
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+/// Returns a seed for `seed_thread` for the sample with the specified
+/// number that the trace unit with ID `unit_id` traces in pixel (`px`, `py`).
+/// For the same frame seed, a pixel always gets the same sequence of random
+/// numbers, so the noise in an animation stays in place instead of flickering.
+pub fn get_pixel_seed(px: u32, py: u32, unit_id: usize, sample: u32, frame_seed: u32) -> [u32; 4] {
+    let h = hash(hash(hash(hash(hash(frame_seed) ^ px) ^ py) ^ unit_id as u32) ^ sample);
+    [h, hash(h ^ 1), hash(h ^ 2), hash(h ^ 3)]
+}
+
+/// Returns a seed for `seed_thread` for the batch with the specified number
+/// of the trace unit with ID `unit_id`. Units with different IDs get streams
+/// of random numbers that are unrelated, even if the IDs are adjacent.
+pub fn get_unit_seed(unit_id: usize, batch: u32, seed: u32) -> [u32; 4] {
+    let h = hash(hash(hash(seed) ^ unit_id as u32) ^ batch);
+    [h, hash(h ^ 1), hash(h ^ 2), hash(h ^ 3)]
+}
+
+#[cfg(test)]
+thread_local!(static FIXED_UNITS: RefCell<VecDeque<f32>> = RefCell::new(VecDeque::new()));
+
+/// Makes the next calls to `get_unit` and `get_longitude` on this thread
+/// return the specified values in the range [0, 1], in order, instead of
+/// random numbers. For `get_longitude` the value is scaled by 2pi. This
+/// makes cameras and materials deterministic in tests.
+#[cfg(test)]
+pub fn set_fixed_units(values: &[f32]) {
+    FIXED_UNITS.with(|units| {
+        let mut units = units.borrow_mut();
+        units.clear();
+        units.extend(values.iter().cloned());
+    });
+}
+
+/// Returns the next fixed value set by `set_fixed_units`, if any is left.
+#[cfg(test)]
+fn next_fixed_unit() -> Option<f32> {
+    FIXED_UNITS.with(|units| units.borrow_mut().pop_front())
+}
+
+#[cfg(not(test))]
+fn next_fixed_unit() -> Option<f32> {
+    None
+}
 
 /// Returns a random number in the range [0, 1].
 pub fn get_unit() -> f32 {
-    let Closed01(x) = rand::random::<Closed01<f32>>();
+    match next_fixed_unit() {
+        Some(x) => x,
+        None => get_unit_with(&mut ThreadSampler)
+    }
+}
+
+/// Returns a random number in the range [0, 1], drawn from `rng`.
+pub fn get_unit_with<R: Rng>(rng: &mut R) -> f32 {
+    let Closed01(x) = rng.gen::<Closed01<f32>>();
     x
 }
 
@@ -33,8 +130,16 @@ pub fn get_bi_unit() -> f32 {
 }
 
 /// Returns a random number in the range [0, 2pi).
-pub fn  get_longitude() -> f32 {
-    rand::random::<f32>() * PI * 2.0
+pub fn get_longitude() -> f32 {
+    match next_fixed_unit() {
+        Some(x) => x * PI * 2.0,
+        None => get_longitude_with(&mut ThreadSampler)
+    }
+}
+
+/// Returns a random number in the range [0, 2pi), drawn from `rng`.
+pub fn get_longitude_with<R: Rng>(rng: &mut R) -> f32 {
+    rng.gen::<f32>() * PI * 2.0
 }
 
 /// Returns a random number in the range [380, 780].
@@ -42,11 +147,183 @@ pub fn get_wavelength() -> f32 {
     get_unit() * 400.0 + 380.0
 }
 
+/// Determines how the wavelengths of photons are chosen.
+#[derive(Copy, Clone)]
+pub enum WavelengthSampling {
+    /// All wavelengths in the range [380, 780] are equally likely.
+    Uniform,
+
+    /// Wavelengths in the middle of the visible spectrum, where the eye is
+    /// most sensitive, are chosen more often. Half of the wavelengths are
+    /// chosen uniformly, the other half with a triangular distribution that
+    /// peaks at 580 nm, so every visible wavelength can still be chosen.
+    Importance
+}
+
+impl WavelengthSampling {
+    /// Returns a random wavelength in the range [380, 780].
+    pub fn sample(self) -> f32 {
+        match self {
+            WavelengthSampling::Uniform => get_wavelength(),
+            WavelengthSampling::Importance => {
+                if get_unit() < 0.5 {
+                    get_wavelength()
+                } else {
+                    // The sum of two uniform variables is triangular.
+                    380.0 + (get_unit() + get_unit()) * 200.0
+                }
+            }
+        }
+    }
+
+    /// Returns the wavelength that the number `u` in the range [0, 1] maps
+    /// to, with the same distribution as `sample`, for when wavelengths are
+    /// chosen quasi-randomly.
+    pub fn sample_at(self, u: f32) -> f32 {
+        match self {
+            WavelengthSampling::Uniform => u * 400.0 + 380.0,
+            WavelengthSampling::Importance => {
+                if u < 0.5 {
+                    u * 800.0 + 380.0
+                } else {
+                    // Invert the cumulative distribution of the triangle.
+                    let t = u * 2.0 - 1.0;
+                    if t < 0.5 {
+                        380.0 + 400.0 * (t * 0.5).sqrt()
+                    } else {
+                        780.0 - 400.0 * ((1.0 - t) * 0.5).sqrt()
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the probability density of `sample` returning the specified
+    /// wavelength, relative to uniform sampling. For uniform sampling this
+    /// is 1.0 everywhere in the visible spectrum.
+    pub fn get_relative_pdf(self, wavelength: f32) -> f32 {
+        if wavelength < 380.0 || wavelength > 780.0 { return 0.0; }
+        match self {
+            WavelengthSampling::Uniform => 1.0,
+            WavelengthSampling::Importance => {
+                let triangle = (1.0 - (wavelength - 580.0).abs() / 200.0) / 200.0;
+                0.5 + 0.5 * triangle * 400.0
+            }
+        }
+    }
+}
+
+/// The number of dimensions of the points of a `Sobol` sequence.
+pub const SOBOL_DIMENSIONS: usize = 5;
+
+/// For every dimension after the first, the degree, the coefficients, and the
+/// initial direction numbers of its primitive polynomial, from the table of
+/// Joe and Kuo.
+const SOBOL_POLYNOMIALS: [(usize, u32, [u32; 3]); SOBOL_DIMENSIONS - 1] = [
+    (1, 0, [1, 0, 0]),
+    (2, 1, [1, 3, 0]),
+    (3, 1, [1, 3, 1]),
+    (3, 2, [1, 1, 1])
+];
+
+/// Scrambles the bits of a fraction (of 2^32) such that the scrambled points
+/// of a Sobol sequence remain well distributed (nested uniform scrambling, as
+/// proposed by Owen). The hash is the one of Laine and Karras, as improved by
+/// Burley.
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+/// A quasi-random sequence of points in the unit hypercube. The first n
+/// points are spread out more evenly than n random points, so estimates
+/// converge faster.
+pub struct Sobol {
+    /// The direction numbers of every dimension, as fractions of 2^32.
+    directions: [[u32; 32]; SOBOL_DIMENSIONS],
+
+    /// The seed of the scrambling, if the points are scrambled.
+    seed: Option<u32>
+}
+
+impl Sobol {
+    /// Constructs a Sobol sequence. With a seed the points are scrambled,
+    /// so sequences with different seeds do not produce the same points.
+    pub fn new(seed: Option<u32>) -> Sobol {
+        let mut directions = [[0u32; 32]; SOBOL_DIMENSIONS];
+
+        // The first dimension is the van der Corput sequence.
+        for (k, d) in directions[0].iter_mut().enumerate() {
+            *d = 1 << (31 - k);
+        }
+
+        let polynomials = SOBOL_POLYNOMIALS.iter();
+        for (v, &(degree, coefficients, m)) in directions[1 ..].iter_mut().zip(polynomials) {
+            for k in 0 .. 32 {
+                v[k] = if k < degree {
+                    m[k] << (31 - k)
+                } else {
+                    let mut x = v[k - degree] ^ (v[k - degree] >> degree);
+                    for l in 1 .. degree {
+                        if (coefficients >> (degree - 1 - l)) & 1 == 1 {
+                            x ^= v[k - l];
+                        }
+                    }
+                    x
+                };
+            }
+        }
+
+        Sobol {
+            directions: directions,
+            seed: seed
+        }
+    }
+
+    /// Returns coordinate `dimension` of the point with the specified index,
+    /// in the range [0, 1). Points are in Gray code order.
+    pub fn get(&self, index: u32, dimension: usize) -> f32 {
+        let mut gray = index ^ (index >> 1);
+        let mut bits = 0u32;
+        let mut k = 0;
+        while gray != 0 {
+            if gray & 1 == 1 { bits ^= self.directions[dimension][k]; }
+            gray >>= 1;
+            k += 1;
+        }
+        if let Some(seed) = self.seed {
+            bits = owen_scramble(bits, hash(seed ^ hash(dimension as u32)));
+        }
+
+        // Keep only as many bits as fit in the mantissa,
+        // so the value is never rounded up to 1.0.
+        (bits >> 8) as f32 / (1u32 << 24) as f32
+    }
+}
+
 /// Returns a random unit vector, pointing up along the z-axis, in the
 /// hemisphere bounded by the xy-plane, with a cosine-weighted probability.
 pub fn get_hemisphere_vector() -> Vector3 {
     let phi = get_longitude();
-    let rq = get_unit();
+    hemisphere_vector(phi, get_unit())
+}
+
+/// Returns a cosine-weighted random unit vector in the hemisphere around
+/// the positive z-axis, drawn from `rng`.
+#[allow(dead_code)]
+pub fn get_hemisphere_vector_with<R: Rng>(rng: &mut R) -> Vector3 {
+    let phi = get_longitude_with(rng);
+    hemisphere_vector(phi, get_unit_with(rng))
+}
+
+/// Maps a longitude and a number in [0, 1] to a cosine-weighted vector
+/// in the hemisphere around the positive z-axis.
+fn hemisphere_vector(phi: f32, rq: f32) -> Vector3 {
     let r = rq.sqrt();
 
     // Calculate the direction based on polar coordinates.
@@ -56,3 +333,201 @@ pub fn get_hemisphere_vector() -> Vector3 {
         z: (1.0 - rq).sqrt()
     }
 }
+
+/// Returns a random unit vector, with all directions equally likely.
+pub fn get_sphere_vector() -> Vector3 {
+    let phi = get_longitude();
+    sphere_vector(phi, get_unit())
+}
+
+/// Maps a longitude and a number in [0, 1] to a unit vector, such that
+/// uniform inputs give uniformly distributed vectors.
+fn sphere_vector(phi: f32, u: f32) -> Vector3 {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+
+    Vector3 {
+        x: phi.cos() * r,
+        y: phi.sin() * r,
+        z: z
+    }
+}
+
+/// Returns a random unit vector in the hemisphere around the positive z-axis,
+/// with a probability density proportional to cos^n of the angle with the
+/// z-axis, where n is `exponent`. Higher exponents concentrate the vectors
+/// more around the z-axis.
+pub fn get_cosine_power_vector(exponent: f32) -> Vector3 {
+    let phi = get_longitude();
+    cosine_power_vector(phi, get_unit(), exponent)
+}
+
+/// Maps a longitude and a number in [0, 1] to a vector with a density
+/// proportional to cos^n of the angle with the z-axis.
+fn cosine_power_vector(phi: f32, u: f32, exponent: f32) -> Vector3 {
+    // Inverting the cumulative distribution of the cosine gives this.
+    let cos_theta = u.powf(1.0 / (exponent + 1.0));
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+
+    Vector3 {
+        x: phi.cos() * sin_theta,
+        y: phi.sin() * sin_theta,
+        z: cos_theta
+    }
+}
+
+/// Returns the probability density (per steradian) with which
+/// `get_cosine_power_vector` generates a vector at an angle with the
+/// z-axis that has cosine `cos_theta`.
+pub fn get_cosine_power_pdf(exponent: f32, cos_theta: f32) -> f32 {
+    if cos_theta <= 0.0 {
+        0.0
+    } else {
+        (exponent + 1.0) / (PI * 2.0) * cos_theta.powf(exponent)
+    }
+}
+
+/// A weighted reservoir, which selects one candidate from a stream of
+/// candidates, with a probability proportional to the candidate's weight.
+/// It only needs to store the current selection, so candidates can be
+/// generated on the fly, for example to select a light based on its
+/// estimated contribution at a point. Only this per-path resampling is done:
+/// selections are not merged or reused across pixels or frames, which would
+/// need the candidates to be reweighted for the point they are reused at.
+#[derive(Copy, Clone)]
+pub struct Reservoir<T> {
+    /// The selected candidate, if any candidate had a nonzero weight.
+    sample: Option<T>,
+
+    /// The sum of the weights of all candidates seen so far.
+    total_weight: f32
+}
+
+impl<T: Copy> Reservoir<T> {
+    /// Creates an empty reservoir.
+    pub fn new() -> Reservoir<T> {
+        Reservoir {
+            sample: None,
+            total_weight: 0.0
+        }
+    }
+
+    /// Offers a candidate with the specified weight to the reservoir.
+    /// Candidates with a weight that is not positive and finite are never
+    /// selected, and they do not count towards the total weight.
+    pub fn update(&mut self, candidate: T, weight: f32) {
+        if !(weight > 0.0 && weight.is_finite()) { return; }
+
+        // Replacing the current selection with probability weight / total,
+        // results in every candidate being selected with probability
+        // proportional to its weight.
+        self.total_weight += weight;
+        if get_unit() * self.total_weight <= weight {
+            self.sample = Some(candidate);
+        }
+    }
+
+    /// Returns the selected candidate.
+    pub fn get_sample(&self) -> Option<T> {
+        self.sample
+    }
+
+    /// Returns the sum of the weights of all candidates seen. The selected
+    /// candidate was chosen with its weight divided by this probability.
+    pub fn get_total_weight(&self) -> f32 {
+        self.total_weight
+    }
+}
+
+#[test]
+fn hemisphere_vector_is_reproducible() {
+    use rand::{SeedableRng, XorShiftRng};
+
+    let mut rng1 = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let mut rng2 = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let v1 = get_hemisphere_vector_with(&mut rng1);
+    let v2 = get_hemisphere_vector_with(&mut rng2);
+    assert_eq!((v1.x, v1.y, v1.z), (v2.x, v2.y, v2.z));
+}
+
+#[test]
+fn cosine_power_vector_concentrates_at_pole() {
+    let n = 10000;
+
+    // A high exponent is almost a perfect reflection.
+    let mean_z = (0 .. n).map(|_| get_cosine_power_vector(1000.0).z).sum::<f32>() / n as f32;
+    assert!(mean_z > 0.99);
+
+    // For a moderate exponent, the mean cosine is (n + 1) / (n + 2).
+    let mean_z = (0 .. n).map(|_| get_cosine_power_vector(10.0).z).sum::<f32>() / n as f32;
+    assert!((mean_z - 11.0 / 12.0).abs() < 0.01);
+
+    // And the density integrates to one over the hemisphere. For uniformly
+    // distributed directions the cosine is uniform in [0, 1].
+    let integral = (0 .. n).map(|i| {
+        let cos_theta = (i as f32 + 0.5) / n as f32;
+        get_cosine_power_pdf(10.0, cos_theta) * PI * 2.0
+    }).sum::<f32>() / n as f32;
+    assert!((integral - 1.0).abs() < 0.01);
+}
+
+#[test]
+fn reservoir_selects_proportional_to_weight() {
+    let weights = [1.0f32, 2.0, 0.0, 7.0];
+    let mut counts = [0u32; 4];
+    let n = 100000;
+
+    for _ in 0 .. n {
+        let mut reservoir = Reservoir::new();
+        for (i, &weight) in weights.iter().enumerate() {
+            reservoir.update(i, weight);
+        }
+        assert_eq!(reservoir.get_total_weight(), 10.0);
+        counts[reservoir.get_sample().unwrap()] += 1;
+    }
+
+    for i in 0 .. 4 {
+        let frequency = counts[i] as f32 / n as f32;
+        assert!((frequency - weights[i] / 10.0).abs() < 0.01);
+    }
+}
+
+#[test]
+fn reservoir_ignores_non_finite_weights() {
+    let mut reservoir = Reservoir::new();
+    reservoir.update(0, ::std::f32::NAN);
+    reservoir.update(1, ::std::f32::INFINITY);
+    reservoir.update(2, -1.0);
+    assert!(reservoir.get_sample().is_none());
+
+    reservoir.update(3, 2.0);
+    reservoir.update(4, ::std::f32::NAN);
+    assert_eq!(reservoir.get_sample(), Some(3));
+    assert_eq!(reservoir.get_total_weight(), 2.0);
+}
+
+#[test]
+fn sobol_sequence_matches_known_points() {
+    let sobol = Sobol::new(None);
+    let first = [0.0, 0.5, 0.75, 0.25, 0.375, 0.875, 0.625, 0.125];
+    let second = [0.0, 0.5, 0.25, 0.75, 0.375, 0.875, 0.125, 0.625];
+    let third = [0.0, 0.5, 0.25, 0.75, 0.625, 0.125, 0.875, 0.375];
+    for i in 0 .. 8 {
+        assert_eq!(sobol.get(i, 0), first[i as usize]);
+        assert_eq!(sobol.get(i, 1), second[i as usize]);
+        assert_eq!(sobol.get(i, 2), third[i as usize]);
+    }
+
+    // Scrambling with the same seed gives the same points,
+    // but they differ from the unscrambled ones.
+    let a = Sobol::new(Some(42));
+    let b = Sobol::new(Some(42));
+    for i in 0 .. 64 {
+        for d in 0 .. SOBOL_DIMENSIONS {
+            let x = a.get(i, d);
+            assert_eq!(x, b.get(i, d));
+            assert!(x >= 0.0 && x < 1.0);
+        }
+    }
+    assert!((0 .. 8).any(|i| a.get(i, 0) != first[i as usize]));
+}
@@ -16,10 +16,13 @@
 
 use geometry::Surface;
 use material::{Material, EmissiveMaterial};
+use medium::Medium;
 
 pub enum MaterialBox {
     Reflective(Box<Material + Sync + Send>),
-    Emissive(Box<EmissiveMaterial + Sync + Send>)
+    Emissive(Box<EmissiveMaterial + Sync + Send>),
+    /// A surface that glows, and also reflects light like the material.
+    EmissiveReflective(Box<EmissiveMaterial + Sync + Send>, Box<Material + Sync + Send>)
 }
 
 /// Represents a surface with a material.
@@ -27,7 +30,13 @@ pub struct Object {
     /// The surface that defines the geometry of the object.
     pub surface: Box<Surface + Sync + Send>,
     /// Either an emissive or a reflective material.
-    pub material: MaterialBox
+    pub material: MaterialBox,
+    /// For an emissive object, the light group that its light is gathered
+    /// into, so lights can be reweighted after rendering. 0 by default.
+    pub light_group: usize,
+    /// A participating medium that fills the volume inside the surface,
+    /// if any. None by default.
+    pub medium: Option<Medium>
 }
 
 impl Object {
@@ -37,7 +46,18 @@ impl Object {
                -> Object {
         Object {
             surface: surface,
-            material: material
+            material: material,
+            light_group: 0,
+            medium: None
         }
     }
+
+    /// Fills the inside of the surface with `medium`, so paths that pass
+    /// through the surface may be absorbed and scattered before they reach
+    /// the next one. The surface must be closed, and only a transmissive
+    /// material lets paths in.
+    pub fn set_medium(&mut self, medium: Medium) {
+        assert!(self.surface.as_volume().is_some(), "a medium needs a closed surface");
+        self.medium = Some(medium);
+    }
 }
@@ -16,9 +16,15 @@
 
 use std::cmp::{min, max};
 use std::iter::repeat;
+use screen::{screen_to_ndc, ndc_to_pixel};
 use trace_unit::MappedPhoton;
 use vector3::Vector3;
 
+/// The weight of a tent filter of radius `r`, at distance `d` from its centre.
+fn tent(d: f32, r: f32) -> f32 {
+    (1.0 - d.abs() / r).max(0.0)
+}
+
 /// Handles plotting the result of a `TraceUnit`.
 pub struct PlotUnit {
     /// The width of the canvas (in pixels).
@@ -33,6 +39,19 @@ pub struct PlotUnit {
     /// The buffer of tristimulus values.
     pub tristimulus_buffer: Vec<Vector3>,
 
+    /// A buffer of tristimulus values per light group, which together add
+    /// up to the tristimulus buffer. Empty unless light groups are enabled.
+    pub group_buffers: Vec<Vec<Vector3>>,
+
+    /// The radius (in pixels) over which a photon is spread with a tent
+    /// filter, 1.0 by default, which spreads it over the nearest 2x2
+    /// pixels. Smaller values give a sharper image, larger values a softer
+    /// one with less noise.
+    pub splat_radius: f32,
+
+    /// The index of the scene camera whose image this unit plots.
+    pub camera: usize,
+
     /// An ID for identifying this unit in the UI.
     pub id: usize
 }
@@ -47,57 +66,239 @@ impl PlotUnit {
             image_height: height,
             aspect_ratio: width as f32 / height as f32,
             tristimulus_buffer: repeat(Vector3::zero()).take(sz).collect(),
+            group_buffers: Vec::new(),
+            splat_radius: 1.0,
+            camera: 0,
             id: id
         }
     }
 
-    /// Plots a pixel, anti-aliased into the buffer
-    /// (adding it to existing content).
-    fn plot_pixel(&mut self, x: f32, y: f32, cie: Vector3) {
+    /// Plots into a separate buffer for each of the first `n` light groups,
+    /// in addition to the tristimulus buffer. Photons of other groups are
+    /// only plotted into the tristimulus buffer.
+    pub fn enable_light_groups(&mut self, n: usize) {
+        let sz = self.tristimulus_buffer.len();
+        self.group_buffers = (0 .. n).map(|_| repeat(Vector3::zero()).take(sz).collect()).collect();
+    }
+
+    /// Returns the first and last pixel along an axis of `size` pixels that
+    /// a photon at pixel coordinate `p` is spread over, and the sum of their
+    /// weights. Pixels outside of the canvas are not covered. If no pixel is
+    /// in reach, because the splat radius is very small or the photon is
+    /// far outside of the canvas, only the nearest pixel is covered, and the
+    /// sum is zero.
+    fn get_footprint(&self, p: f32, size: isize) -> (isize, isize, f32) {
+        let r = self.splat_radius;
+        let first = max(0, (p - r).ceil() as isize);
+        let last = min(size - 1, (p + r).floor() as isize);
+        let sum = (first .. last + 1).map(|i| tent(i as f32 - p, r)).sum::<f32>();
+        if sum > 0.0 {
+            (first, last, sum)
+        } else {
+            let nearest = max(0, min(size - 1, p.round() as isize));
+            (nearest, nearest, 0.0)
+        }
+    }
+
+    /// Plots a pixel, anti-aliased into the buffer, and into the buffer of
+    /// the light group if there is one (adding it to existing content).
+    fn plot_pixel(&mut self, x: f32, y: f32, cie: Vector3, light_group: usize) {
         // Map the position to pixels.
         let w = self.image_width as isize;
         let h = self.image_height as isize;
-        let px = (x * 0.5 + 0.5) * (w as f32 - 1.0);
-        let py = (y * self.aspect_ratio * 0.5 + 0.5) * (h as f32 - 1.0);
-
-        // Then map them to discrete pixels.
-        let px1 = max(0, min(w - 1, px.floor() as isize)) as usize;
-        let px2 = max(0, min(w - 1, px.ceil() as isize)) as usize;
-        let py1 = max(0, min(h - 1, py.floor() as isize)) as usize;
-        let py2 = max(0, min(h - 1, py.ceil() as isize)) as usize;
-
-        // Compute pixel coefficients.
-        let cx = px - px1 as f32;
-        let cy = py - py1 as f32;
-        let c11 = (1.0 - cx) * (1.0 - cy);
-        let c12 = (1.0 - cx) * cy;
-        let c21 = cx * (1.0 - cy);
-        let c22 = cx * cy;
-
-        // Then plot the four pixels.
-        let buffer = &mut self.tristimulus_buffer;
-        let w = self.image_width as usize;
-        buffer[py1 * w + px1] = buffer[py1 * w + px1] + cie * c11;
-        buffer[py1 * w + px2] = buffer[py1 * w + px2] + cie * c21;
-        buffer[py2 * w + px1] = buffer[py2 * w + px1] + cie * c12;
-        buffer[py2 * w + px2] = buffer[py2 * w + px2] + cie * c22;
+        let (u, v) = screen_to_ndc(x, y, self.aspect_ratio);
+        let (px, py) = ndc_to_pixel(u, v, self.image_width, self.image_height);
+
+        // Then find the pixels on the canvas within the splat radius. The
+        // weights are normalised over those pixels, so the photon always
+        // contributes its full energy, also near the edges.
+        let r = self.splat_radius;
+        let (x1, x2, sum_x) = self.get_footprint(px, w);
+        let (y1, y2, sum_y) = self.get_footprint(py, h);
+        let weight = |d: f32, sum: f32| if sum > 0.0 { tent(d, r) / sum } else { 1.0 };
+
+        // Then plot the pixels.
+        let group_buffer = self.group_buffers.get_mut(light_group);
+        for buffer in Some(&mut self.tristimulus_buffer).into_iter().chain(group_buffer) {
+            for j in y1 .. y2 + 1 {
+                let cy = weight(j as f32 - py, sum_y);
+                for i in x1 .. x2 + 1 {
+                    let c = cy * weight(i as f32 - px, sum_x);
+                    if c <= 0.0 { continue; }
+                    let k = (j * w + i) as usize;
+                    buffer[k] = buffer[k] + cie * c;
+                }
+            }
+        }
     }
 
     /// Plots the result of the specified TraceUnit onto the canvas.
     pub fn plot(&mut self, photons: &[MappedPhoton]) {
         for photon in photons {
-            // Calculate the CIE tristimulus values, given the wavelength.
-            let cie = ::cie1931::get_tristimulus(photon.wavelength);
+            // A photon that found no light adds nothing.
+            if photon.probability == 0.0 { continue; }
+
+            // Calculate the CIE tristimulus values, given the wavelength,
+            // unless the photon carries a tristimulus value already. The
+            // response is divided by the probability density of the
+            // wavelength, so that wavelengths which are chosen more often
+            // do not contribute more in total.
+            let cie = match photon.tristimulus {
+                Some(cie) => cie,
+                None => ::cie1931::get_tristimulus(photon.wavelength)
+                        * (1.0 / photon.wavelength_pdf)
+            };
 
             // Then plot the pixel into the buffer.
-            self.plot_pixel(photon.x, photon.y, cie * photon.probability);
+            self.plot_pixel(photon.x, photon.y, cie * photon.probability, photon.light_group);
+        }
+    }
+
+    /// Adapts the unit to a canvas of a different size, and clears it.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let sz = (width * height) as usize;
+        self.image_width = width;
+        self.image_height = height;
+        self.aspect_ratio = width as f32 / height as f32;
+        self.tristimulus_buffer.clear();
+        self.tristimulus_buffer.extend(repeat(Vector3::zero()).take(sz));
+        for buffer in &mut self.group_buffers {
+            buffer.clear();
+            buffer.extend(repeat(Vector3::zero()).take(sz));
         }
     }
 
-    /// Resets the tristimulus buffer to black.
+    /// Resets the tristimulus buffer and the light group buffers to black.
     pub fn clear(&mut self) {
-        for x in &mut self.tristimulus_buffer {
+        let groups = self.group_buffers.iter_mut().flat_map(|b| b.iter_mut());
+        for x in self.tristimulus_buffer.iter_mut().chain(groups) {
             *x = Vector3::zero();
         }
     }
 }
+
+#[test]
+fn wavelength_sampling_preserves_luminance() {
+    use monte_carlo::WavelengthSampling;
+
+    // Plot many photons of a grey surface into a single pixel, and
+    // compute the average luminance per photon.
+    let mean_luminance = |sampling: WavelengthSampling| {
+        let n = 200000;
+        let photons: Vec<MappedPhoton> = (0 .. n).map(|_| {
+            let wavelength = sampling.sample();
+            MappedPhoton {
+                x: 0.0,
+                y: 0.0,
+                probability: 0.5,
+                wavelength: wavelength,
+                wavelength_pdf: sampling.get_relative_pdf(wavelength),
+                tristimulus: None,
+                light_group: 0
+            }
+        }).collect();
+        let mut unit = PlotUnit::new(0, 1, 1);
+        unit.plot(&photons);
+        unit.tristimulus_buffer[0].y / n as f32
+    };
+
+    let uniform = mean_luminance(WavelengthSampling::Uniform);
+    let importance = mean_luminance(WavelengthSampling::Importance);
+    assert!((uniform - importance).abs() < uniform * 0.02);
+}
+
+#[test]
+fn light_groups_add_up_to_combined_image() {
+    use geometry::{Plane, Sphere};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::Object;
+    use object::MaterialBox::{Emissive, Reflective};
+    use scene::Scene;
+    use trace_unit::TraceUnit;
+
+    // A floor lit by a warm and a cool light, in different groups.
+    let floor = Object::new(Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0))),
+                            Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let light = |x: f32, kelvins: f32| Object::new(Box::new(Sphere::new(Vector3::new(x, 5.0, 1.0), 1.0)),
+                                                   Emissive(Box::new(BlackBodyMaterial::new(kelvins, 1.0))));
+    let mut cool = light(2.0, 8000.0);
+    cool.light_group = 1;
+    let scene = Scene::new(vec!(floor, light(-2.0, 3000.0), cool), ::camera::camera_at_origin);
+
+    let mut trace_unit = TraceUnit::new(0, 16, 9);
+    let mut plot_unit = PlotUnit::new(0, 16, 9);
+    plot_unit.enable_light_groups(2);
+    trace_unit.render(&scene);
+    plot_unit.plot(&trace_unit.mapped_photons);
+
+    let total = |buffer: &[Vector3]| buffer.iter().map(|px| px.y).sum::<f32>();
+    assert!(total(&plot_unit.group_buffers[0]) > 0.0);
+    assert!(total(&plot_unit.group_buffers[1]) > 0.0);
+
+    for (i, px) in plot_unit.tristimulus_buffer.iter().enumerate() {
+        let sum = plot_unit.group_buffers[0][i] + plot_unit.group_buffers[1][i];
+        assert!((sum - *px).magnitude() <= 1.0e-3 * (1.0 + px.magnitude()));
+    }
+}
+
+#[test]
+fn larger_splat_radius_spreads_energy() {
+    let photon = MappedPhoton {
+        x: 0.03,
+        y: 0.02,
+        probability: 1.0,
+        wavelength: 550.0,
+        wavelength_pdf: 1.0,
+        tristimulus: Some(Vector3::new(1.0, 1.0, 1.0)),
+        light_group: 0
+    };
+
+    let splat = |radius: f32| {
+        let mut unit = PlotUnit::new(0, 16, 16);
+        unit.splat_radius = radius;
+        unit.plot(&[photon]);
+        let covered = unit.tristimulus_buffer.iter().filter(|px| px.y > 0.0).count();
+        let total = unit.tristimulus_buffer.iter().map(|px| px.y).sum::<f32>();
+        (covered, total)
+    };
+
+    let (sharp_covered, sharp_total) = splat(0.5);
+    let (covered, total) = splat(1.0);
+    let (soft_covered, soft_total) = splat(3.0);
+
+    assert!(sharp_covered <= covered);
+    assert_eq!(covered, 4);
+    assert!(soft_covered > covered);
+    for &t in [sharp_total, total, soft_total].iter() {
+        assert!((t - 1.0).abs() < 1.0e-5);
+    }
+}
+
+#[test]
+fn photon_in_corner_keeps_its_energy() {
+    // A photon exactly on the top left pixel, spread over two pixels in
+    // every direction, half of which are outside of the canvas.
+    let photon = MappedPhoton {
+        x: -1.0,
+        y: -0.5,
+        probability: 1.0,
+        wavelength: 550.0,
+        wavelength_pdf: 1.0,
+        tristimulus: Some(Vector3::new(1.0, 1.0, 1.0)),
+        light_group: 0
+    };
+    let mut unit = PlotUnit::new(0, 16, 8);
+    unit.splat_radius = 2.0;
+    unit.plot(&[photon]);
+
+    let buffer = &unit.tristimulus_buffer;
+    let total = buffer.iter().map(|px| px.y).sum::<f32>();
+    assert!((total - 1.0).abs() < 1.0e-5);
+
+    // The pixels on the canvas keep the shape of the tent filter,
+    // the corner does not receive the weight of the pixels beyond it.
+    assert!((buffer[0].y - 4.0 / 9.0).abs() < 1.0e-5);
+    assert!((buffer[1].y - 2.0 / 9.0).abs() < 1.0e-5);
+    assert!((buffer[16].y - 2.0 / 9.0).abs() < 1.0e-5);
+    assert!((buffer[17].y - 1.0 / 9.0).abs() < 1.0e-5);
+}
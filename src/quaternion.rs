@@ -15,8 +15,9 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::ops::{Add, Sub, Neg, Mul};
+use vector3::Vector3;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct Quaternion {
     pub x: f32,
     pub y: f32,
@@ -40,6 +41,20 @@ impl Quaternion {
         }
     }
 
+    /// Returns the axis and angle (in radians, in [0, 2pi]) of the rotation
+    /// that this unit quaternion represents, the inverse of `rotation`. For
+    /// (nearly) no rotation the axis is arbitrary, and the angle is 0.
+    pub fn to_axis_angle(self) -> (Vector3, f32) {
+        let sin_half = (self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        if sin_half < 1.0e-6 {
+            return (Vector3::new(0.0, 0.0, 1.0), 0.0);
+        }
+
+        let angle = 2.0 * sin_half.atan2(self.w);
+        let axis = Vector3::new(self.x, self.y, self.z) * (1.0 / sin_half);
+        (axis, angle)
+    }
+
     pub fn conjugate(self) -> Quaternion {
         Quaternion::new(-self.x, -self.y, -self.z, self.w)
     }
@@ -109,3 +124,16 @@ impl Mul<Quaternion> for Quaternion {
         }
     }
 }
+
+#[test]
+fn axis_angle_round_trips() {
+    for &angle in [0.1, 1.0, 3.0, 5.5].iter() {
+        let (axis, a) = Quaternion::rotation(0.0, 0.0, 1.0, angle).to_axis_angle();
+        assert!((axis - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1.0e-5);
+        assert!((a - angle).abs() < 1.0e-5);
+    }
+
+    let (axis, angle) = Quaternion::rotation(0.0, 0.0, 1.0, 0.0).to_axis_angle();
+    assert!((axis.magnitude() - 1.0).abs() < 1.0e-6);
+    assert_eq!(angle, 0.0);
+}
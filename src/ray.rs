@@ -16,6 +16,7 @@
 
 use vector3::Vector3;
 
+#[derive(Debug)]
 pub struct Ray {
     /// The 'position' of the ray.
     pub origin: Vector3,
@@ -14,10 +14,66 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp::Ordering;
+use std::f32::consts::PI;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use aabb::Aabb;
+use bvh::Bvh;
 use camera::Camera;
+use environment::Environment;
 use intersection::Intersection;
-use object::Object;
+use material::{BlackBodyMaterial, EmissiveMaterial};
+use medium::Medium;
+use monte_carlo::Reservoir;
+use object::{MaterialBox, Object};
 use ray::Ray;
+use vector3::{Vector3, dot};
+
+/// A sky that fades from one black body spectrum at the horizon
+/// to another at the zenith.
+pub struct BackgroundGradient {
+    /// The spectrum at and below the horizon.
+    horizon: BlackBodyMaterial,
+
+    /// The spectrum straight up.
+    zenith: BlackBodyMaterial
+}
+
+impl BackgroundGradient {
+    /// Creates a gradient between black bodies of the specified temperatures
+    /// in Kelvin, for example a warm horizon and a blue zenith.
+    pub fn new(horizon_temp: f32, zenith_temp: f32, intensity: f32) -> BackgroundGradient {
+        BackgroundGradient {
+            horizon: BlackBodyMaterial::new(horizon_temp, intensity),
+            zenith: BlackBodyMaterial::new(zenith_temp, intensity)
+        }
+    }
+
+    /// Returns the intensity in the specified direction at `wavelength`.
+    pub fn sample(&self, direction: Vector3, wavelength: f32) -> f32 {
+        let t = direction.normalise().z.max(0.0);
+        let horizon = self.horizon.get_intensity(wavelength);
+        let zenith = self.zenith.get_intensity(wavelength);
+        horizon * (1.0 - t) + zenith * t
+    }
+}
+
+/// Determines how `Scene::sample_light` chooses a light.
+#[derive(Copy, Clone)]
+pub enum LightSelection {
+    /// Every light is equally likely, the default.
+    Uniform,
+
+    /// Lights are chosen proportional to their power, see
+    /// `Scene::weight_lights_by_power`.
+    Power,
+
+    /// Lights are chosen by their estimated contribution at the point that
+    /// is lit, see `Scene::weight_lights_by_contribution`.
+    Contribution
+}
 
 /// A collection of objects.
 pub struct Scene {
@@ -31,15 +87,487 @@ pub struct Scene {
     // TODO: apparently there is no such thing as an immutable closure
     // any more, but I'd prefer to be able to use a pure function here,
     // which might be a closure.
-    pub get_camera_at_time: fn (f32) -> Camera
+    pub get_camera_at_time: fn (f32) -> Camera,
+
+    /// Functions for additional cameras, like `get_camera_at_time`, for
+    /// rendering several views of the scene at once, such as a stereo pair.
+    /// Camera 0 is `get_camera_at_time`, these are cameras 1 and up.
+    pub extra_cameras: Vec<fn (f32) -> Camera>,
+
+    /// The number of scene units in one meter, for features that need a
+    /// physical length scale, such as absorption. 1.0 by default.
+    pub units_per_meter: f32,
+
+    /// The radius of the world, in scene units. Intersections that are
+    /// further away than this are ignored, so rays that get this far are in
+    /// The Void, and parts of the acceleration structure beyond it are never
+    /// visited. 1.0e12 by default, so that Nothing is Very Far Away (tm).
+    pub clip_distance: f32,
+
+    /// An optional environment that lights rays which escape the scene.
+    pub environment: Option<Environment>,
+
+    /// An optional sky, used for escaped rays if there is no environment.
+    pub background_gradient: Option<BackgroundGradient>,
+
+    /// A structure that speeds up intersection, built by
+    /// `build_acceleration`.
+    acceleration: Option<Bvh>,
+
+    /// If lights are chosen proportional to their power, the index of every
+    /// sampleable light in `objects`, with the cumulative probability of
+    /// choosing it or a light before it. Built by `weight_lights_by_power`.
+    light_distribution: Option<Vec<(usize, f32)>>,
+
+    /// If lights are chosen by their estimated contribution at the point
+    /// that is lit, the data to estimate it with, for every sampleable
+    /// light. Built by `weight_lights_by_contribution`.
+    light_estimates: Option<Vec<LightEstimate>>
+}
+
+/// What is needed to quickly estimate how much light a light contributes
+/// at a point, without tracing a ray.
+struct LightEstimate {
+    /// The index of the light in `objects`.
+    index: usize,
+
+    /// The approximate radiant power of the light.
+    power: f32,
+
+    /// The centre of the bounding box of the light.
+    centre: Vector3,
+
+    /// The square of half the diagonal of the bounding box. Closer than
+    /// this, the light is not a point any more, and the estimate does not
+    /// grow further.
+    radius_squared: f32
+}
+
+impl LightEstimate {
+    /// Returns the estimated contribution of the light at point `p`,
+    /// which falls off with the square of the distance.
+    fn at(&self, p: Vector3) -> f32 {
+        let to_light = self.centre - p;
+        self.power / (dot(to_light, to_light) + self.radius_squared)
+    }
+}
+
+/// A problem with a scene that makes it render incorrectly.
+#[derive(Debug, PartialEq)]
+pub enum SceneError {
+    /// The object at the index has a non-finite position or size.
+    NonFiniteObject(usize),
+
+    /// The object at the index has no area, so it cannot be seen.
+    DegenerateObject(usize),
+
+    /// The emissive object at the index emits no light.
+    DarkLight(usize),
+
+    /// There are no emissive objects, environment or sky,
+    /// so the image will be black.
+    NoLights,
+
+    /// The camera position or orientation is not finite.
+    NonFiniteCamera,
+
+    /// The field of view of the camera (in radians) is not in (0, pi).
+    InvalidFieldOfView(f32)
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneError::NonFiniteObject(i) =>
+                write!(f, "object {} has a non-finite position or size", i),
+            SceneError::DegenerateObject(i) =>
+                write!(f, "object {} has no area", i),
+            SceneError::DarkLight(i) =>
+                write!(f, "emissive object {} emits no light", i),
+            SceneError::NoLights =>
+                write!(f, "the scene contains no lights"),
+            SceneError::NonFiniteCamera =>
+                write!(f, "the camera has a non-finite position or orientation"),
+            SceneError::InvalidFieldOfView(fov) =>
+                write!(f, "the camera field of view of {} radians is not in (0, pi)", fov)
+        }
+    }
+}
+
+fn is_finite(v: Vector3) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+/// Returns the approximate radiant power of an emissive object: the area
+/// times the intensity integrated over the visible spectrum.
+fn get_light_power(light: &Object) -> f32 {
+    let emissive = match light.material {
+        MaterialBox::Emissive(ref mat) | MaterialBox::EmissiveReflective(ref mat, _) => mat,
+        MaterialBox::Reflective(_) => return 0.0
+    };
+    let area = light.surface.get_area().unwrap_or(0.0);
+    let intensity = (0 .. 81).map(|i| emissive.get_intensity(380.0 + i as f32 * 5.0)).sum::<f32>();
+    area * intensity * 5.0
 }
 
 impl Scene {
-    /// Intersects the specified ray with the scene.
+    /// Creates a scene with the specified objects and camera,
+    /// surrounded by The Void.
+    pub fn new(objects: Vec<Object>, get_camera_at_time: fn (f32) -> Camera) -> Scene {
+        Scene {
+            objects: objects,
+            get_camera_at_time: get_camera_at_time,
+            extra_cameras: Vec::new(),
+            units_per_meter: 1.0,
+            clip_distance: 1.0e12,
+            environment: None,
+            background_gradient: None,
+            acceleration: None,
+            light_distribution: None,
+            light_estimates: None
+        }
+    }
+
+    /// Builds a bounding volume hierarchy over the objects, to speed up
+    /// intersection. It must be rebuilt when the objects change. The scene
+    /// is shared by all workers, so the hierarchy is built only once.
+    pub fn build_acceleration(&mut self) {
+        self.acceleration = Some(Bvh::new(&self.objects));
+    }
+
+    /// Returns the smallest box that contains all objects, or `None` if the
+    /// scene is empty. Infinite surfaces, such as unbounded planes, have no
+    /// box; they are skipped if `ignore_infinite` is true, and otherwise the
+    /// scene has no box either.
+    pub fn bounding_box(&self, ignore_infinite: bool) -> Option<Aabb> {
+        let mut result: Option<Aabb> = None;
+        for obj in &self.objects {
+            match obj.surface.get_bounding_box() {
+                Some(aabb) => result = Some(result.map_or(aabb, |r| r.union(aabb))),
+                None if ignore_infinite => { },
+                None => return None
+            }
+        }
+        result
+    }
+
+    /// Feeds what sets the scene apart to `hasher`: the number of objects,
+    /// their bounding boxes, areas, light groups and kinds of material, the
+    /// power of the lights, and the cameras at the start and end of the shot.
+    /// Materials themselves cannot be compared, so two scenes that differ only
+    /// in the colour of a reflective material are not told apart.
+    pub fn fingerprint<H: Hasher>(&self, hasher: &mut H) {
+        let write_vector = |hasher: &mut H, v: Vector3| {
+            for &c in [v.x, v.y, v.z].iter() {
+                hasher.write_u32(c.to_bits());
+            }
+        };
+
+        hasher.write_usize(self.objects.len());
+        for obj in &self.objects {
+            match obj.surface.get_bounding_box() {
+                Some(aabb) => { write_vector(hasher, aabb.min); write_vector(hasher, aabb.max); }
+                None => hasher.write_u8(0)
+            }
+            hasher.write_u32(obj.surface.get_area().unwrap_or(0.0).to_bits());
+            hasher.write_usize(obj.light_group);
+            mem::discriminant(&obj.material).hash(hasher);
+            hasher.write_u32(get_light_power(obj).to_bits());
+        }
+
+        hasher.write_usize(self.number_of_cameras());
+        for index in 0 .. self.number_of_cameras() {
+            for &t in [0.0, 1.0].iter() {
+                let camera = self.get_camera(index, t);
+                let q = camera.orientation;
+                write_vector(hasher, camera.position);
+                for &c in [q.x, q.y, q.z, q.w, camera.field_of_view].iter() {
+                    hasher.write_u32(c.to_bits());
+                }
+            }
+        }
+    }
+
+    /// Checks the scene for mistakes that would otherwise go unnoticed until
+    /// the image turns out black or full of garbage, and returns all of the
+    /// problems that were found. Infinite surfaces are not checked.
+    pub fn validate(&self) -> Result<(), Vec<SceneError>> {
+        let mut errors = Vec::new();
+
+        for (i, obj) in self.objects.iter().enumerate() {
+            let area = obj.surface.get_area();
+            let emissive = match obj.material {
+                MaterialBox::Reflective(_) => false,
+                _ => true
+            };
+            let finite = obj.surface.get_bounding_box()
+                .map_or(true, |aabb| is_finite(aabb.min) && is_finite(aabb.max));
+            if !finite || area.map_or(false, |a| !a.is_finite()) {
+                errors.push(SceneError::NonFiniteObject(i));
+            } else if area.map_or(false, |a| a <= 0.0) {
+                errors.push(SceneError::DegenerateObject(i));
+            } else if area.is_some() && emissive && !(get_light_power(obj) > 0.0) {
+                errors.push(SceneError::DarkLight(i));
+            }
+        }
+
+        let has_sky = self.environment.is_some() || self.background_gradient.is_some();
+        if self.emissive_objects().next().is_none() && !has_sky {
+            errors.push(SceneError::NoLights);
+        }
+
+        // The cameras may move, so check them at the start and end of the shot.
+        for index in 0 .. self.number_of_cameras() {
+            for &t in [0.0, 1.0].iter() {
+                let camera = self.get_camera(index, t);
+                let q = camera.orientation;
+                let finite_orientation = [q.x, q.y, q.z, q.w].iter().all(|c| c.is_finite());
+                let fov = camera.field_of_view;
+                let mut report = |error| if !errors.contains(&error) { errors.push(error); };
+                if !is_finite(camera.position) || !finite_orientation {
+                    report(SceneError::NonFiniteCamera);
+                }
+                if !(fov > 0.0 && fov < PI) {
+                    report(SceneError::InvalidFieldOfView(fov));
+                }
+            }
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Returns the fraction of light that is left after travelling `distance`
+    /// scene units through a medium with the specified absorption coefficient
+    /// (per meter), by the Beer-Lambert law.
+    pub fn get_transmittance(&self, absorption: f32, distance: f32) -> f32 {
+        (-absorption * distance / self.units_per_meter).exp()
+    }
+
+    /// Returns the medium that fills the space at `p`, if the point lies
+    /// inside an object with a medium. Where objects overlap, the first
+    /// object wins. Paths switch media where they cross a surface, so this
+    /// is only needed to find the medium in which a path starts.
+    pub fn get_medium(&self, p: Vector3) -> Option<Medium> {
+        self.objects.iter()
+            .filter(|obj| obj.medium.is_some())
+            .find(|obj| obj.surface.as_volume().map_or(false, |v| v.lies_inside(p)))
+            .and_then(|obj| obj.medium)
+    }
+
+    /// Returns the number of cameras through which the scene is seen.
+    pub fn number_of_cameras(&self) -> usize {
+        1 + self.extra_cameras.len()
+    }
+
+    /// Returns the camera with the specified index at time `t`,
+    /// in the range 0.0 - 1.0.
+    pub fn get_camera(&self, index: usize, t: f32) -> Camera {
+        match index {
+            0 => (self.get_camera_at_time)(t),
+            _ => (self.extra_cameras[index - 1])(t)
+        }
+    }
+
+    /// Makes `sample_light` choose lights proportional to their power,
+    /// instead of uniformly, so bright and large lights are sampled more
+    /// often. Like the acceleration structure, it must be rebuilt when the
+    /// objects change.
+    pub fn weight_lights_by_power(&mut self) {
+        let powers: Vec<(usize, f32)> = self.objects.iter().enumerate()
+            .filter(|&(_, obj)| obj.surface.get_area().is_some())
+            .map(|(i, obj)| (i, get_light_power(obj)))
+            .filter(|&(_, power)| power > 0.0)
+            .collect();
+        let total = powers.iter().map(|&(_, power)| power).sum::<f32>();
+        let mut cumulative = 0.0;
+        self.light_distribution = Some(powers.iter().map(|&(i, power)| {
+            cumulative += power / total;
+            (i, cumulative)
+        }).collect());
+    }
+
+    /// Makes `sample_light` choose lights by their estimated contribution
+    /// at the point that is lit, so nearby lights are sampled more often
+    /// than bright lights far away. Every light is offered to a reservoir,
+    /// so this takes no memory per light at sampling time. The reservoir is
+    /// filled anew for every point, nothing is reused. Light paths that
+    /// start at a light have no such point, they choose lights by power.
+    pub fn weight_lights_by_contribution(&mut self) {
+        self.weight_lights_by_power();
+        self.light_estimates = Some(self.objects.iter().enumerate()
+            .filter_map(|(i, obj)| {
+                let power = match obj.surface.get_area() {
+                    Some(_) => get_light_power(obj),
+                    None => return None
+                };
+                let aabb = match obj.surface.get_bounding_box() {
+                    Some(aabb) => aabb,
+                    None => return None
+                };
+                let half_diagonal = aabb.size() * 0.5;
+                Some(LightEstimate {
+                    index: i,
+                    power: power,
+                    centre: aabb.centre(),
+                    radius_squared: dot(half_diagonal, half_diagonal)
+                })
+            })
+            .filter(|estimate| estimate.power > 0.0)
+            .collect());
+    }
+
+    /// Returns the probability that `sample_light` chooses the light,
+    /// for a point `from` that is lit.
+    fn get_light_probability(&self, light: &Object, from: Option<Vector3>) -> f32 {
+        match (&self.light_estimates, from) {
+            (&Some(ref estimates), Some(p)) => {
+                let mut weight = 0.0;
+                let mut total_weight = 0.0;
+                for estimate in estimates {
+                    let w = estimate.at(p);
+                    if !(w > 0.0 && w.is_finite()) { continue; }
+                    if &self.objects[estimate.index] as *const Object == light as *const Object {
+                        weight = w;
+                    }
+                    total_weight += w;
+                }
+                if total_weight > 0.0 { weight / total_weight } else { 0.0 }
+            }
+            _ => match self.light_distribution {
+                Some(ref distribution) => {
+                    let mut previous = 0.0;
+                    for &(i, cumulative) in distribution {
+                        if &self.objects[i] as *const Object == light as *const Object {
+                            return cumulative - previous;
+                        }
+                        previous = cumulative;
+                    }
+                    0.0
+                }
+                None => 1.0 / self.get_sampleable_lights().count() as f32
+            }
+        }
+    }
+
+    /// Picks a light, uniformly, by power, or by its estimated contribution
+    /// at `from`. Returns the light and the probability of choosing it.
+    fn choose_light(&self, from: Option<Vector3>) -> Option<(&Object, f32)> {
+        if let (&Some(ref estimates), Some(p)) = (&self.light_estimates, from) {
+            let mut reservoir = Reservoir::new();
+            for estimate in estimates {
+                let weight = estimate.at(p);
+                reservoir.update((estimate.index, weight), weight);
+            }
+            let total_weight = reservoir.get_total_weight();
+            return reservoir.get_sample().map(|(i, weight)| {
+                (&self.objects[i], weight / total_weight)
+            });
+        }
+
+        let u = ::monte_carlo::get_unit();
+        let light = match self.light_distribution {
+            Some(ref distribution) => {
+                distribution.iter().find(|&&(_, cumulative)| u <= cumulative)
+                            .or(distribution.last())
+                            .map(|&(i, _)| &self.objects[i])
+            }
+            None => {
+                let n = self.get_sampleable_lights().count();
+                if n == 0 { return None; }
+                let k = ((u * n as f32) as usize).min(n - 1);
+                self.get_sampleable_lights().nth(k)
+            }
+        };
+        light.map(|light| (light, self.get_light_probability(light, None)))
+    }
+
+    /// Returns the objects that emit light.
+    pub fn emissive_objects<'a>(&'a self) -> impl Iterator<Item = &'a Object> + 'a {
+        self.objects.iter().filter(|obj| match obj.material {
+            MaterialBox::Emissive(_) => true,
+            MaterialBox::EmissiveReflective(_, _) => true,
+            MaterialBox::Reflective(_) => false
+        })
+    }
+
+    /// Returns the emissive objects that can be sampled directly,
+    /// the ones with a finite area.
+    fn get_sampleable_lights<'a>(&'a self) -> impl Iterator<Item = &'a Object> + 'a {
+        self.emissive_objects().filter(|obj| obj.surface.get_area().is_some())
+    }
+
+    /// Picks a random point on a random light, for estimating direct
+    /// illumination at the point `from`, or for starting a light path if
+    /// there is no such point. Returns the light, the point and the normal
+    /// there, and the probability density of choosing the point (per unit
+    /// area).
+    pub fn sample_light(&self, from: Option<Vector3>) -> Option<(&Object, Vector3, Vector3, f32)> {
+        // Lights are chosen uniformly, unless they are weighted.
+        let (light, probability) = match self.choose_light(from) {
+            Some(choice) => choice,
+            None => return None
+        };
+        let (position, normal) = light.surface.sample_point().unwrap();
+        let pdf = probability / light.surface.get_area().unwrap();
+        Some((light, position, normal, pdf))
+    }
+
+    /// Returns the probability density (per unit area) with which
+    /// `sample_light` picks a point on the specified light, when lighting
+    /// the point `from`, or 0.0 if the light is never sampled.
+    pub fn get_light_pdf(&self, light: &Object, from: Option<Vector3>) -> f32 {
+        match light.surface.get_area() {
+            Some(area) => self.get_light_probability(light, from) / area,
+            None => 0.0
+        }
+    }
+
+    /// Returns the intensity of the background for a ray that did not
+    /// intersect any object.
+    pub fn get_background(&self, ray: &Ray) -> f32 {
+        match (&self.environment, &self.background_gradient) {
+            (&Some(ref env), _) => env.sample(ray.direction, ray.wavelength),
+            (&None, &Some(ref gradient)) => gradient.sample(ray.direction, ray.wavelength),
+            (&None, &None) => 0.0
+        }
+    }
+
+    /// Intersects the specified ray with the scene. Intersections beyond the
+    /// clip distance are not found.
     pub fn intersect(&self, ray: &Ray) -> Option<(Intersection, &Object)> {
-        // Assume Nothing is found, and that Nothing is Very Far Away (tm).
+        let max_distance = self.clip_distance;
+
+        match self.acceleration {
+            Some(ref bvh) => bvh.intersect(&self.objects, ray, max_distance),
+            None => self.intersect_linear(ray, max_distance)
+        }
+    }
+
+    /// Returns every intersection of the ray with the objects, ordered by
+    /// increasing distance. A ray that passes through a closed surface, such
+    /// as a sphere, reports both the point where it enters and the point
+    /// where it leaves. Like `intersect`, this stops at the clip distance.
+    pub fn intersect_all(&self, ray: &Ray) -> Vec<(Intersection, &Object)> {
+        let max_distance = self.clip_distance;
+        let mut hits = match self.acceleration {
+            Some(ref bvh) => bvh.intersect_all(&self.objects, ray, max_distance),
+            None => self.objects.iter().flat_map(|obj| {
+                obj.surface.intersect_all(ray).into_iter().map(move |isect| (isect, obj))
+            }).filter(|&(ref isect, _)| isect.distance < max_distance).collect()
+        };
+        hits.sort_by(|a, b| {
+            a.0.distance.partial_cmp(&b.0.distance).unwrap_or(Ordering::Equal)
+        });
+        hits
+    }
+
+    /// Intersects the ray with every object, and returns the nearest
+    /// intersection closer than `max_distance`.
+    fn intersect_linear(&self, ray: &Ray, max_distance: f32)
+                        -> Option<(Intersection, &Object)> {
+        // Assume Nothing is found.
         let mut result = None;
-        let mut distance = 1.0e12f32;
+        let mut distance = max_distance;
 
         // Then intersect all surfaces.
         for obj in &self.objects {
@@ -59,3 +587,312 @@ impl Scene {
         result
     }
 }
+
+#[test]
+fn shared_acceleration_matches_linear() {
+    use std::sync::Arc;
+    use std::thread;
+    use geometry::{Plane, Sphere};
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::Reflective;
+
+    // A grid of spheres above an (unbounded) floor.
+    let mut objects = Vec::new();
+    for i in 0 .. 10 {
+        for j in 0 .. 10 {
+            let position = Vector3::new(i as f32 * 3.0 - 15.0, j as f32 * 3.0 + 5.0, 0.0);
+            objects.push(Object::new(Box::new(Sphere::new(position, 1.0)),
+                                     Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))));
+        }
+    }
+    objects.push(Object::new(Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0),
+                                                 Vector3::new(0.0, 0.0, -1.0))),
+                             Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))));
+
+    let mut scene = Scene::new(objects, ::camera::camera_at_origin);
+    scene.build_acceleration();
+    let scene = Arc::new(scene);
+
+    // Two workers share the scene, and must both find exactly the
+    // intersections that testing every object finds.
+    let workers: Vec<_> = (0 .. 2).map(|w| {
+        let scene = scene.clone();
+        thread::spawn(move || {
+            for k in 0 .. 500 {
+                let phi = (k * 2 + w) as f32 * 0.01;
+                let ray = Ray {
+                    origin: Vector3::zero(),
+                    direction: Vector3::new(phi.cos() * 0.5, 1.0, phi.sin() - 0.5).normalise(),
+                    wavelength: 550.0,
+                    probability: 1.0
+                };
+                let fast = scene.intersect(&ray).map(|(i, _)| i.distance);
+                let slow = scene.intersect_linear(&ray, 1.0e12).map(|(i, _)| i.distance);
+                assert_eq!(fast, slow);
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[test]
+fn emissive_objects_are_the_lights() {
+    use geometry::Sphere;
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::MaterialBox::{Emissive, Reflective};
+
+    let sphere = |x: f32| Box::new(Sphere::new(Vector3::new(x, 0.0, 0.0), 1.0));
+    let objects = vec!(
+        Object::new(sphere(0.0), Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))),
+        Object::new(sphere(1.0), Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0)))),
+        Object::new(sphere(2.0), Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))),
+        Object::new(sphere(3.0), Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))),
+        Object::new(sphere(4.0), Emissive(Box::new(BlackBodyMaterial::new(2000.0, 1.0))))
+    );
+    let scene = Scene::new(objects, ::camera::camera_at_origin);
+
+    let lights: Vec<&Object> = scene.emissive_objects().collect();
+    assert_eq!(lights.len(), 2);
+    assert!(lights[0] as *const Object == &scene.objects[1] as *const Object);
+    assert!(lights[1] as *const Object == &scene.objects[4] as *const Object);
+}
+
+#[test]
+fn intersect_all_orders_hits_by_distance() {
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::Reflective;
+
+    // The far sphere comes first in the list of objects.
+    let sphere = |y: f32| Object::new(Box::new(Sphere::new(Vector3::new(0.0, y, 0.0), 1.0)),
+                                      Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let mut scene = Scene::new(vec!(sphere(10.0), sphere(5.0), sphere(-5.0)), ::camera::camera_at_origin);
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // Both spheres are reported twice, where the ray enters them and where
+    // it leaves them, also when intersecting through the hierarchy.
+    let check = |scene: &Scene| {
+        let hits = scene.intersect_all(&ray);
+        let distances: Vec<f32> = hits.iter().map(|&(ref isect, _)| isect.distance).collect();
+        assert_eq!(distances.len(), 4);
+        for (&d, &expected) in distances.iter().zip([4.0, 6.0, 9.0, 11.0].iter()) {
+            assert!((d - expected).abs() < 1.0e-4);
+        }
+        assert!(hits[0].1 as *const Object == &scene.objects[1] as *const Object);
+        assert!(hits[1].1 as *const Object == &scene.objects[1] as *const Object);
+        assert!(hits[2].1 as *const Object == &scene.objects[0] as *const Object);
+
+        // The normal points out of the sphere at both points.
+        assert!(hits[0].0.normal.y < -0.99);
+        assert!(hits[1].0.normal.y > 0.99);
+    };
+    check(&scene);
+    scene.build_acceleration();
+    check(&scene);
+}
+
+#[test]
+fn background_gradient_fades_from_horizon_to_zenith() {
+    let mut scene = Scene::new(Vec::new(), ::camera::camera_at_origin);
+    scene.background_gradient = Some(BackgroundGradient::new(3000.0, 12000.0, 1.0));
+    let horizon = BlackBodyMaterial::new(3000.0, 1.0);
+    let zenith = BlackBodyMaterial::new(12000.0, 1.0);
+
+    let ray = |direction: Vector3, wavelength: f32| Ray {
+        origin: Vector3::zero(),
+        direction: direction,
+        wavelength: wavelength,
+        probability: 1.0
+    };
+
+    for &wavelength in [420.0, 550.0, 680.0].iter() {
+        let up = scene.get_background(&ray(Vector3::new(0.0, 0.0, 1.0), wavelength));
+        let out = scene.get_background(&ray(Vector3::new(1.0, 0.0, 0.0), wavelength));
+        assert!((up - zenith.get_intensity(wavelength)).abs() < 1.0e-5);
+        assert!((out - horizon.get_intensity(wavelength)).abs() < 1.0e-5);
+    }
+}
+
+#[test]
+fn bounding_box_encloses_all_spheres() {
+    use geometry::{Plane, Sphere};
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::Reflective;
+
+    let grey = || Reflective(Box::new(DiffuseGreyMaterial::new(0.8)));
+    let mut scene = Scene::new(vec!(
+        Object::new(Box::new(Sphere::new(Vector3::new(-2.0, 0.0, 1.0), 1.0)), grey()),
+        Object::new(Box::new(Sphere::new(Vector3::new(3.0, 4.0, 0.0), 2.0)), grey())
+    ), ::camera::camera_at_origin);
+
+    let aabb = scene.bounding_box(false).unwrap();
+    assert_eq!((aabb.min.x, aabb.min.y, aabb.min.z), (-3.0, -1.0, -2.0));
+    assert_eq!((aabb.max.x, aabb.max.y, aabb.max.z), (5.0, 6.0, 2.0));
+
+    // An infinite floor has no box, unless it is ignored.
+    scene.objects.push(Object::new(Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::zero())), grey()));
+    assert!(scene.bounding_box(false).is_none());
+    let aabb = scene.bounding_box(true).unwrap();
+    assert_eq!((aabb.max.x, aabb.max.y, aabb.max.z), (5.0, 6.0, 2.0));
+}
+
+#[test]
+fn bright_light_is_sampled_more_often() {
+    use geometry::Sphere;
+    use material::BlackBodyMaterial;
+    use object::MaterialBox::Emissive;
+
+    let light = |x: f32, intensity: f32| {
+        Object::new(Box::new(Sphere::new(Vector3::new(x, 0.0, 0.0), 1.0)),
+                    Emissive(Box::new(BlackBodyMaterial::new(5000.0, intensity))))
+    };
+    let mut scene = Scene::new(vec!(light(-2.0, 1.0), light(2.0, 0.25)), ::camera::camera_at_origin);
+    scene.weight_lights_by_power();
+
+    let n = 20000;
+    let bright = (0 .. n).filter(|_| {
+        let (light, _, _, _) = scene.sample_light(None).unwrap();
+        light as *const Object == &scene.objects[0] as *const Object
+    }).count();
+    assert!((bright as f32 / n as f32 - 0.8).abs() < 0.02);
+
+    // The density must match the choice, for unbiased light sampling.
+    let area = 4.0 * ::std::f32::consts::PI;
+    assert!((scene.get_light_pdf(&scene.objects[0], None) - 0.8 / area).abs() < 1.0e-4);
+    assert!((scene.get_light_pdf(&scene.objects[1], None) - 0.2 / area).abs() < 1.0e-4);
+}
+
+#[test]
+fn contribution_weighted_lights_match_uniform_estimate() {
+    use geometry::Sphere;
+    use material::BlackBodyMaterial;
+    use object::MaterialBox::Emissive;
+
+    // A dim light close by, and two bright lights far away.
+    let light = |position: Vector3, intensity: f32| {
+        Object::new(Box::new(Sphere::new(position, 0.5)),
+                    Emissive(Box::new(BlackBodyMaterial::new(5000.0, intensity))))
+    };
+    let lights = || vec!(light(Vector3::new(2.0, 0.0, 0.0), 1.0),
+                         light(Vector3::new(30.0, 0.0, 0.0), 20.0),
+                         light(Vector3::new(0.0, -40.0, 0.0), 50.0));
+    let uniform = Scene::new(lights(), ::camera::camera_at_origin);
+    let mut weighted = Scene::new(lights(), ::camera::camera_at_origin);
+    weighted.weight_lights_by_contribution();
+
+    // Estimate the light that arrives at the origin, ignoring the angles,
+    // and return the mean and variance of the estimate.
+    let estimate = |scene: &Scene| {
+        let n = 40000;
+        let samples: Vec<f32> = (0 .. n).map(|_| {
+            let (light, position, _, pdf) = scene.sample_light(Some(Vector3::zero())).unwrap();
+            let emitted = match light.material {
+                Emissive(ref mat) => mat.get_intensity(550.0),
+                _ => unreachable!()
+            };
+            emitted / dot(position, position) / pdf
+        }).collect();
+        let mean = samples.iter().sum::<f32>() / n as f32;
+        let variance = samples.iter().map(|&x| (x - mean) * (x - mean)).sum::<f32>() / n as f32;
+        (mean, variance)
+    };
+
+    // Both are unbiased, but weighting by contribution has less variance.
+    let (uniform_mean, uniform_variance) = estimate(&uniform);
+    let (weighted_mean, weighted_variance) = estimate(&weighted);
+    assert!((weighted_mean / uniform_mean - 1.0).abs() < 0.03);
+    assert!(weighted_variance < uniform_variance * 0.5);
+}
+
+#[test]
+fn validate_reports_nan_sphere_and_missing_lights() {
+    use camera::camera_at_origin;
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+
+    let sphere = |position: Vector3| {
+        Object::new(Box::new(Sphere::new(position, 1.0)),
+                    MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8))))
+    };
+    let nan = ::std::f32::NAN;
+    let scene = Scene::new(vec!(sphere(Vector3::new(0.0, 5.0, 0.0)),
+                                sphere(Vector3::new(nan, 5.0, 0.0))),
+                           camera_at_origin);
+
+    let errors = scene.validate().unwrap_err();
+    assert_eq!(errors, vec!(SceneError::NonFiniteObject(1), SceneError::NoLights));
+
+    // A light and a valid sphere make a valid scene.
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 0.0, 5.0), 1.0)),
+                            MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec!(sphere(Vector3::new(0.0, 5.0, 0.0)), light), camera_at_origin);
+    assert_eq!(scene.validate(), Ok(()));
+}
+
+#[test]
+fn transmittance_depends_on_units_per_meter() {
+    use camera::camera_at_origin;
+
+    // Half of the light is absorbed over one meter.
+    let absorption = 2.0f32.ln();
+    let mut scene = Scene::new(Vec::new(), camera_at_origin);
+    assert!((scene.get_transmittance(absorption, 1.0) - 0.5).abs() < 1.0e-6);
+
+    // If the scene is modelled in centimeters, 100 units is one meter.
+    scene.units_per_meter = 100.0;
+    assert!((scene.get_transmittance(absorption, 1.0) - 0.5f32.powf(0.01)).abs() < 1.0e-6);
+    assert!((scene.get_transmittance(absorption, 200.0) - 0.25).abs() < 1.0e-6);
+}
+
+#[test]
+fn intersection_beyond_clip_distance_is_a_miss() {
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+    use object::MaterialBox::Reflective;
+
+    let sphere = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 100.0, 0.0), 1.0)),
+                             Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let mut scene = Scene::new(vec!(sphere), ::camera::camera_at_origin);
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    assert!(scene.intersect(&ray).is_some());
+
+    scene.clip_distance = 50.0;
+    assert!(scene.intersect(&ray).is_none());
+    assert!(scene.intersect_all(&ray).is_empty());
+    scene.build_acceleration();
+    assert!(scene.intersect(&ray).is_none());
+}
+
+#[test]
+fn fingerprint_tells_scenes_apart() {
+    use checkpoint::Fingerprint;
+
+    let fingerprint = |scene: &Scene| {
+        let mut hasher = Fingerprint::new();
+        scene.fingerprint(&mut hasher);
+        hasher.finish()
+    };
+
+    // The same scene has the same fingerprint every time it is built.
+    let cornell_box = fingerprint(&::scenes::cornell_box());
+    assert_eq!(cornell_box, fingerprint(&::scenes::cornell_box()));
+    assert!(cornell_box != fingerprint(&::scenes::materials()));
+
+    // Removing an object changes it too.
+    let mut moved = ::scenes::cornell_box();
+    moved.objects.pop();
+    assert!(cornell_box != fingerprint(&moved));
+}
@@ -0,0 +1,427 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2014-2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::f32::consts::PI;
+use camera::{Camera, LensSampling};
+use geometry::{Circle, Compound, Extent, Plane, Polygon, Sphere, ThickPlane, new_thick_plane};
+use material::{BlackBodyMaterial, BlendedEmissiveMaterial, DiffuseColouredMaterial,
+               DiffuseGreyMaterial, DiffuseRgbMaterial, FlatEmissiveMaterial, PhongMaterial,
+               TabulatedDiffuseMaterial, TexturedEmissiveMaterial};
+use object::{MaterialBox, Object};
+use object::MaterialBox::{Emissive, EmissiveReflective, Reflective};
+use quaternion::Quaternion;
+use scene::{BackgroundGradient, Scene};
+use vector3::{Vector3, cross};
+
+pub type Cuboid = Compound<Compound<ThickPlane, ThickPlane>, ThickPlane>;
+
+/// Constructs a box with the specified centre and half sizes, standing on
+/// the xy-plane, rotated around the z-axis by `angle`.
+pub fn new_cuboid(centre: Vector3, half_size: Vector3, angle: f32) -> Cuboid {
+    let u = Vector3::new(angle.cos(), angle.sin(), 0.0);
+    let v = Vector3::new(-angle.sin(), angle.cos(), 0.0);
+    let w = Vector3::new(0.0, 0.0, 1.0);
+
+    let slab_u = new_thick_plane(u, centre - u * half_size.x, half_size.x * 2.0);
+    let slab_v = new_thick_plane(v, centre - v * half_size.y, half_size.y * 2.0);
+    let slab_w = new_thick_plane(w, centre - w * half_size.z, half_size.z * 2.0);
+
+    Compound::new(Compound::new(slab_u, slab_v), slab_w)
+}
+
+/// Returns a bounded plane with the specified normal through `centre`,
+/// that extends `half_width` along `u` and `half_height` along `v`.
+fn new_quad(normal: Vector3, centre: Vector3,
+            u: Vector3, half_width: f32,
+            v: Vector3, half_height: f32) -> Box<Plane> {
+    Box::new(Plane::new_bounded(normal, centre, Extent::new(u, half_width, v, half_height)))
+}
+
+/// Returns a camera at `position` that looks along the positive y-axis with
+/// virtually no blur, like a pinhole camera, as the preset scenes use.
+fn new_pinhole_camera(position: Vector3, field_of_view: f32, focal_distance: f32) -> Camera {
+    Camera {
+        position: position,
+        field_of_view: field_of_view,
+        focal_distance: focal_distance,
+        depth_of_field: 1000.0,
+        chromatic_abberation: 0.0,
+        orientation: Quaternion::rotation(0.0, 0.0, 1.0, 0.0),
+        lens_sampling: LensSampling::Polar,
+        lens_shift: (0.0, 0.0)
+    }
+}
+
+/// Constructs the Cornell box: a white room of 2 by 2 by 2 units with a red
+/// wall on the left, a green wall on the right, two white boxes on the floor,
+/// and a small light in the ceiling. The front of the box is open, and the
+/// camera looks into it from there. It is a simple reference scene for
+/// checking light transport.
+pub fn cornell_box() -> Scene {
+    let x = Vector3::new(1.0, 0.0, 0.0);
+    let y = Vector3::new(0.0, 1.0, 0.0);
+    let z = Vector3::new(0.0, 0.0, 1.0);
+
+    let white = || Reflective(Box::new(DiffuseGreyMaterial::new(0.75)));
+    let mut objects = Vec::new();
+
+    // The walls, floor and ceiling. The floor is at z = 0, the back wall at
+    // y = 2, and the side walls at x = -1 and x = 1.
+    let floor = new_quad(z, Vector3::new(0.0, 1.0, 0.0), x, 1.0, y, 1.0);
+    objects.push(Object::new(floor, white()));
+    let ceiling = new_quad(-z, Vector3::new(0.0, 1.0, 2.0), x, 1.0, y, 1.0);
+    objects.push(Object::new(ceiling, white()));
+    let back = new_quad(-y, Vector3::new(0.0, 2.0, 1.0), x, 1.0, z, 1.0);
+    objects.push(Object::new(back, white()));
+
+    let left = new_quad(x, Vector3::new(-1.0, 1.0, 1.0), y, 1.0, z, 1.0);
+    let red = Box::new(DiffuseColouredMaterial::new(0.75, 660.0, 60.0));
+    objects.push(Object::new(left, Reflective(red)));
+    let right = new_quad(-x, Vector3::new(1.0, 1.0, 1.0), y, 1.0, z, 1.0);
+    let green = Box::new(DiffuseColouredMaterial::new(0.75, 530.0, 40.0));
+    objects.push(Object::new(right, Reflective(green)));
+
+    // The light, just below the ceiling so the two do not overlap. It only
+    // shines down, from above it would be hidden by the ceiling anyway.
+    let mut light = new_quad(-z, Vector3::new(0.0, 1.0, 1.999), x, 0.25, y, 0.25);
+    light.set_two_sided(false);
+    let light_emissive = Box::new(BlackBodyMaterial::new(6504.0, 10.0));
+    objects.push(Object::new(light, Emissive(light_emissive)));
+
+    // A tall box at the back left, and a short box at the front right.
+    let tall = new_cuboid(Vector3::new(-0.35, 1.3, 0.6), Vector3::new(0.3, 0.3, 0.6), 0.3);
+    objects.push(Object::new(Box::new(tall), white()));
+    let short = new_cuboid(Vector3::new(0.35, 0.7, 0.3), Vector3::new(0.3, 0.3, 0.3), -0.3);
+    objects.push(Object::new(Box::new(short), white()));
+
+    fn make_camera(_: f32) -> Camera {
+        new_pinhole_camera(Vector3::new(0.0, -2.4, 1.0), PI * 0.3, 3.4)
+    }
+
+    let mut scene = Scene::new(objects, make_camera);
+    scene.build_acceleration();
+    scene
+}
+
+/// The sRGB values of the 24 patches of the ColorChecker chart, row by row
+/// from the top left, under D65 light.
+const COLOR_CHECKER: [[u8; 3]; 24] = [
+    [115, 82, 68], [194, 150, 130], [98, 122, 157],
+    [87, 108, 67], [133, 128, 177], [103, 189, 170],
+    [214, 126, 44], [80, 91, 166], [193, 90, 99],
+    [94, 60, 108], [157, 188, 64], [224, 163, 46],
+    [56, 61, 150], [70, 148, 73], [175, 54, 60],
+    [231, 199, 31], [187, 86, 149], [8, 133, 161],
+    [243, 243, 242], [200, 200, 200], [160, 160, 160],
+    [122, 122, 121], [85, 85, 85], [52, 52, 52]
+];
+
+/// The distance between the centres of two adjacent color checker patches.
+const PATCH_SPACING: f32 = 1.2;
+
+/// Constructs a ColorChecker chart: 6 by 4 diffuse patches of 1 by 1 unit,
+/// lit evenly by a 6504 K light behind the camera, which approximates D65.
+/// A rendered patch should have the sRGB value of the chart, which makes
+/// this a test for the entire spectral pipeline. The reflectance spectra
+/// are upsampled from the sRGB values, they are not the measured spectra,
+/// so saturated patches are reproduced less accurately.
+pub fn color_checker() -> Scene {
+    let x = Vector3::new(1.0, 0.0, 0.0);
+    let y = Vector3::new(0.0, 1.0, 0.0);
+    let z = Vector3::new(0.0, 0.0, 1.0);
+    let mut objects = Vec::new();
+
+    // The chart is in the xz-plane, facing the camera.
+    for (i, srgb) in COLOR_CHECKER.iter().enumerate() {
+        let (column, row) = ((i % 6) as f32, (i / 6) as f32);
+        let centre = Vector3::new((column - 2.5) * PATCH_SPACING, 0.0, (1.5 - row) * PATCH_SPACING);
+        let linear = Vector3::new(::srgb::gamma_expand(srgb[0] as f32 / 255.0),
+                                  ::srgb::gamma_expand(srgb[1] as f32 / 255.0),
+                                  ::srgb::gamma_expand(srgb[2] as f32 / 255.0));
+        let patch = new_quad(-y, centre, x, 0.5, z, 0.5);
+        objects.push(Object::new(patch, Reflective(Box::new(DiffuseRgbMaterial::new(linear)))));
+    }
+
+    // Every patch reflects into a light that covers the entire half space
+    // behind the camera, so the patches are lit evenly.
+    let light = Box::new(Plane::new(y, Vector3::new(0.0, -20.0, 0.0)));
+    let light_emissive = Box::new(BlackBodyMaterial::new(6504.0, 1.0));
+    objects.push(Object::new(light, Emissive(light_emissive)));
+
+    fn make_camera(_: f32) -> Camera {
+        // The chart is 7.2 units wide, leave some room around it.
+        new_pinhole_camera(Vector3::new(0.0, -10.0, 0.0), 2.0 * 0.45f32.atan(), 10.0)
+    }
+
+    let mut scene = Scene::new(objects, make_camera);
+    scene.build_acceleration();
+    scene
+}
+
+/// Constructs a furnace: a white sphere inside a hollow sphere that emits
+/// the same intensity at every wavelength. The white sphere reflects all
+/// light, so a path tracer that conserves energy renders it exactly as
+/// bright as its surroundings, and it becomes invisible.
+pub fn furnace() -> Scene {
+    let white = Box::new(Sphere::new(Vector3::new(0.0, 5.0, 0.0), 2.0));
+    let white_reflective = Box::new(DiffuseGreyMaterial::new(1.0));
+
+    let mut enclosure = Box::new(Sphere::new(Vector3::zero(), 20.0));
+    enclosure.set_hollow(true);
+    let enclosure_emissive = Box::new(FlatEmissiveMaterial::new(1.0));
+
+    let objects = vec!(Object::new(white, Reflective(white_reflective)),
+                       Object::new(enclosure, Emissive(enclosure_emissive)));
+
+    fn make_camera(_: f32) -> Camera {
+        new_pinhole_camera(Vector3::zero(), PI * 0.5, 5.0)
+    }
+
+    let mut scene = Scene::new(objects, make_camera);
+    scene.build_acceleration();
+    scene
+}
+
+/// The distance between the centres of two adjacent spheres in the
+/// materials scene.
+const SPHERE_SPACING: f32 = 1.2;
+
+/// Constructs a row of spheres on a grey floor, lit by a disc overhead,
+/// one sphere for every kind of material, to compare the materials side by
+/// side. The first sphere is plain diffuse grey, for reference. Behind the
+/// spheres is a screen, and the other kinds of lights light them too. The
+/// fill light is in light group 1 and the screen in group 2, so they can be
+/// relit.
+pub fn materials() -> Scene {
+    let x = Vector3::new(1.0, 0.0, 0.0);
+    let y = Vector3::new(0.0, 1.0, 0.0);
+    let z = Vector3::new(0.0, 0.0, 1.0);
+    let mut objects = Vec::new();
+
+    let floor = new_quad(z, Vector3::zero(), x, 6.0, y, 6.0);
+    objects.push(Object::new(floor, Reflective(Box::new(DiffuseGreyMaterial::new(0.5)))));
+
+    let mut light = Box::new(Circle::new(-z, Vector3::new(0.0, 0.0, 5.0), 1.5));
+    light.set_two_sided(false);
+    // Most paths end at a light, so look up its spectrum in a table rather
+    // than evaluating Planck's law for every hit.
+    let mut light_emissive = Box::new(BlackBodyMaterial::new(6504.0, 5.0));
+    light_emissive.set_tabulated(true);
+    objects.push(Object::new(light, Emissive(light_emissive)));
+
+    // A dim hexagonal fill light at the front left, facing the spheres, so
+    // their shadow sides are not black. The corners go counter-clockwise
+    // around the normal, so the front faces the spheres.
+    let fill_centre = Vector3::new(-4.0, -4.0, 2.0);
+    let fill_normal = (Vector3::new(0.0, 0.0, 0.5) - fill_centre).normalise();
+    let fill_u = cross(fill_normal, z).normalise();
+    let fill_v = cross(fill_normal, fill_u);
+    let corners = (0 .. 6).map(|i| {
+        let phi = i as f32 * PI / 3.0;
+        fill_centre + fill_u * phi.cos() + fill_v * phi.sin()
+    }).collect();
+    let mut fill = Box::new(Polygon::new(corners));
+    fill.set_two_sided(false);
+    // Over the animation it cools down, like a setting sun.
+    let fill_emissive = Box::new(BlendedEmissiveMaterial::new(
+        Box::new(BlackBodyMaterial::new(6504.0, 1.0)),
+        Box::new(BlackBodyMaterial::new(3000.0, 1.0))));
+    let mut fill = Object::new(fill, Emissive(fill_emissive));
+    fill.light_group = 1;
+    objects.push(fill);
+
+    // A screen behind the spheres that shows colour bars, for coloured
+    // reflections. The bars go along the width of the screen.
+    let bars = vec!(Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 0.0),
+                    Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 1.0, 1.0),
+                    Vector3::new(0.0, 0.0, 1.0), Vector3::new(1.0, 0.0, 1.0));
+    let screen = new_quad(-y, Vector3::new(0.0, 3.0, 1.5), x, 2.0, z, 1.0);
+    let screen_emissive = Box::new(TexturedEmissiveMaterial::new(6, 1, bars, 1.0));
+    let mut screen = Object::new(screen, Emissive(screen_emissive));
+    screen.light_group = 2;
+    objects.push(screen);
+
+    // A sampled reflectance curve like that of orange paint, which reflects
+    // little light below 570 nm, and most of it above.
+    let mut paint = [0.0; 81];
+    for (i, reflectance) in paint.iter_mut().enumerate() {
+        let wavelength = 380.0 + i as f32 * 5.0;
+        *reflectance = 0.05 + 0.75 / (1.0 + (-(wavelength - 570.0) / 15.0).exp());
+    }
+
+    // A colour that goes from blue at the bottom of a sphere to red at the top.
+    let mut rainbow = Box::new(DiffuseColouredMaterial::new(0.75, 550.0, 40.0));
+    rainbow.set_wavelength_shift(Box::new(|p: Vector3| (p.z - 0.5) * 150.0));
+
+    let samples: Vec<MaterialBox> = vec!(
+        Reflective(Box::new(DiffuseGreyMaterial::new(0.75))),
+        Reflective(Box::new(PhongMaterial::new(0.75, 40.0))),
+        // A sphere that glows warmly, but also reflects the light around it.
+        EmissiveReflective(Box::new(BlackBodyMaterial::new(3000.0, 0.5)),
+                           Box::new(DiffuseGreyMaterial::new(0.75))),
+        Reflective(Box::new(TabulatedDiffuseMaterial::new(paint))),
+        Reflective(rainbow)
+    );
+
+    // Centre the row of spheres in front of the camera.
+    let n = samples.len() as f32;
+    for (i, material) in samples.into_iter().enumerate() {
+        let centre = Vector3::new((i as f32 - (n - 1.0) * 0.5) * SPHERE_SPACING, 0.0, 0.5);
+        objects.push(Object::new(Box::new(Sphere::new(centre, 0.5)), material));
+    }
+
+    fn make_camera(_: f32) -> Camera {
+        new_pinhole_camera(Vector3::new(0.0, -8.0, 1.0), PI * 0.3, 8.0)
+    }
+
+    // A dim sky, so glossy spheres have something to reflect. An
+    // environment map replaces it.
+    let mut scene = Scene::new(objects, make_camera);
+    scene.background_gradient = Some(BackgroundGradient::new(5000.0, 10000.0, 0.5));
+    scene.build_acceleration();
+    scene
+}
+
+#[test]
+fn cornell_box_renders() {
+    use plot_unit::PlotUnit;
+    use trace_unit::TraceUnit;
+
+    let scene = cornell_box();
+    assert!(scene.emissive_objects().count() >= 1);
+
+    let mut trace_unit = TraceUnit::new(0, 9, 9);
+    let mut plot_unit = PlotUnit::new(0, 9, 9);
+    for _ in 0 .. 16 {
+        trace_unit.render(&scene);
+        plot_unit.plot(&trace_unit.mapped_photons);
+    }
+
+    // The centre of the image shows the back wall, lit by the light.
+    assert!(plot_unit.tristimulus_buffer[4 * 9 + 4].y > 0.0);
+}
+
+#[test]
+fn color_checker_patches_have_reference_colours() {
+    use gather_unit::GatherUnit;
+    use plot_unit::PlotUnit;
+    use screen::{screen_to_ndc, ndc_to_pixel};
+    use trace_unit::TraceUnit;
+
+    let (width, height) = (96, 64);
+    let scene = color_checker();
+    let mut trace_unit = TraceUnit::new(0, width, height);
+    trace_unit.min_depth = 2;
+    let mut plot_unit = PlotUnit::new(0, width, height);
+    for _ in 0 .. 1500 {
+        trace_unit.render(&scene);
+        plot_unit.plot(&trace_unit.mapped_photons);
+    }
+    let mut gather_unit = GatherUnit::new(width, height);
+    gather_unit.clear();
+    gather_unit.accumulate(&plot_unit.tristimulus_buffer);
+
+    // The average tristimulus value of the 7x7 pixels at a patch centre,
+    // well inside the patch, which is about 10 pixels wide.
+    let patch_cie = |i: usize| {
+        let (column, row) = ((i % 6) as f32, (i / 6) as f32);
+        let tan_half_fov = 0.45;
+        let x = (column - 2.5) * PATCH_SPACING / 10.0 / tan_half_fov;
+        let y = (row - 1.5) * PATCH_SPACING / 10.0 / tan_half_fov;
+        let (u, v) = screen_to_ndc(x, y, width as f32 / height as f32);
+        let (px, py) = ndc_to_pixel(u, v, width, height);
+        let (px, py) = (px.round() as u32, py.round() as u32);
+        let mut sum = Vector3::zero();
+        for y in py - 3 .. py + 4 {
+            for x in px - 3 .. px + 4 {
+                sum = sum + gather_unit.tristimulus_buffer[(y * width + x) as usize];
+            }
+        }
+        sum * (1.0 / 49.0)
+    };
+
+    // Calibrate on the white patch: scale every tristimulus component such
+    // that the white patch gets its reference value. This compensates for
+    // both the exposure and the difference between the light and D65.
+    let white = COLOR_CHECKER[18];
+    let white_linear = Vector3::new(::srgb::gamma_expand(white[0] as f32 / 255.0),
+                                    ::srgb::gamma_expand(white[1] as f32 / 255.0),
+                                    ::srgb::gamma_expand(white[2] as f32 / 255.0));
+    let white_cie = ::srgb::srgb_to_xyz(white_linear);
+    let white_measured = patch_cie(18);
+    let calibrate = |cie: Vector3| Vector3::new(cie.x * white_cie.x / white_measured.x,
+                                                cie.y * white_cie.y / white_measured.y,
+                                                cie.z * white_cie.z / white_measured.z);
+
+    // The neutral patches should be reproduced closely, the skin tones
+    // suffer from the upsampled spectra, so they get a looser tolerance.
+    let patches = [(19, 15.0), (20, 15.0), (21, 15.0), (22, 15.0), (0, 30.0), (1, 30.0)];
+    for &(i, tolerance) in patches.iter() {
+        let rgb = ::srgb::transform(calibrate(patch_cie(i)));
+        let reference = COLOR_CHECKER[i];
+        for (&c, &r) in [rgb.x, rgb.y, rgb.z].iter().zip(reference.iter()) {
+            assert!((c * 255.0 - r as f32).abs() < tolerance);
+        }
+    }
+}
+
+#[test]
+fn furnace_conserves_energy() {
+    use trace_unit::{DirectLighting, TraceUnit};
+
+    // Russian roulette terminates paths without compensating the survivors,
+    // so it must not kick in before a path reaches the enclosure.
+    let scene = furnace();
+    let mut unit = TraceUnit::new(0, 16, 9);
+    unit.min_depth = 4;
+
+    // Following the material, every path ends at the enclosure exactly.
+    unit.render(&scene);
+    for photon in &unit.mapped_photons {
+        assert!((photon.probability - 1.0).abs() < 1.0e-5);
+    }
+
+    // With light sampling every path is still 1.0 on average.
+    for &direct_lighting in [DirectLighting::LightSampling, DirectLighting::MultipleImportance].iter() {
+        unit.direct_lighting = direct_lighting;
+        let (mut sum, mut n) = (0.0f64, 0);
+        for _ in 0 .. 20 {
+            unit.render(&scene);
+            sum += unit.mapped_photons.iter().map(|p| p.probability as f64).sum::<f64>();
+            n += unit.mapped_photons.len();
+        }
+        assert!((sum / n as f64 - 1.0).abs() < 0.02);
+    }
+}
+
+#[test]
+fn materials_scene_renders() {
+    use plot_unit::PlotUnit;
+    use trace_unit::TraceUnit;
+
+    let scene = materials();
+    let mut trace_unit = TraceUnit::new(0, 16, 9);
+    let mut plot_unit = PlotUnit::new(0, 16, 9);
+    for _ in 0 .. 16 {
+        trace_unit.render(&scene);
+        plot_unit.plot(&trace_unit.mapped_photons);
+    }
+
+    // Every sphere stands on the floor, in the lower half of the image.
+    let lower_half = &plot_unit.tristimulus_buffer[5 * 16 ..];
+    assert!(lower_half.iter().all(|cie| cie.y.is_finite()));
+    assert!(lower_half.iter().any(|cie| cie.y > 0.0));
+}
@@ -0,0 +1,80 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2014-2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Mappings between the coordinate systems of the image. Screen coordinates
+// are what the camera uses: x ranges from -1 to 1, and y is scaled by the
+// aspect ratio, so that pixels are square. Normalised device coordinates
+// (NDC) range from -1 to 1 in both directions, and pixel coordinates range
+// from 0 to the width or height minus one.
+
+/// Converts a screen coordinate to normalised device coordinates.
+pub fn screen_to_ndc(x: f32, y: f32, aspect_ratio: f32) -> (f32, f32) {
+    (x, y * aspect_ratio)
+}
+
+/// Converts normalised device coordinates to a screen coordinate.
+pub fn ndc_to_screen(u: f32, v: f32, aspect_ratio: f32) -> (f32, f32) {
+    (u, v / aspect_ratio)
+}
+
+/// Converts normalised device coordinates to a (fractional) pixel
+/// coordinate on a canvas of `width` by `height` pixels.
+pub fn ndc_to_pixel(u: f32, v: f32, width: u32, height: u32) -> (f32, f32) {
+    ((u * 0.5 + 0.5) * (width as f32 - 1.0),
+     (v * 0.5 + 0.5) * (height as f32 - 1.0))
+}
+
+/// Converts a (fractional) pixel coordinate to normalised device coordinates.
+/// Along a dimension of a single pixel, that pixel is the centre, 0.0.
+pub fn pixel_to_ndc(px: f32, py: f32, width: u32, height: u32) -> (f32, f32) {
+    fn to_ndc(p: f32, size: u32) -> f32 {
+        if size <= 1 { return 0.0; }
+        p / (size as f32 - 1.0) * 2.0 - 1.0
+    }
+    (to_ndc(px, width), to_ndc(py, height))
+}
+
+#[test]
+fn screen_to_pixel_round_trips() {
+    for &(width, height) in [(1280, 720), (720, 1280), (100, 100), (37, 5)].iter() {
+        let aspect_ratio = width as f32 / height as f32;
+        for &(u, v) in [(-1.0, -1.0), (0.3, -0.7), (0.0, 0.0), (1.0, 0.9)].iter() {
+            // A screen coordinate that the camera could use.
+            let (x, y) = ndc_to_screen(u, v, aspect_ratio);
+            assert!((y * aspect_ratio - v).abs() < 1.0e-5);
+
+            let (u1, v1) = screen_to_ndc(x, y, aspect_ratio);
+            let (px, py) = ndc_to_pixel(u1, v1, width, height);
+            assert!(px >= 0.0 && px <= width as f32 - 1.0);
+            assert!(py >= 0.0 && py <= height as f32 - 1.0);
+
+            let (u2, v2) = pixel_to_ndc(px, py, width, height);
+            let (x2, y2) = ndc_to_screen(u2, v2, aspect_ratio);
+            assert!((x2 - x).abs() < 1.0e-5);
+            assert!((y2 - y).abs() < 1.0e-5);
+        }
+    }
+}
+
+#[test]
+fn single_pixel_dimension_maps_to_centre() {
+    assert_eq!(pixel_to_ndc(0.0, 0.0, 1, 1), (0.0, 0.0));
+    assert_eq!(pixel_to_ndc(0.0, 3.0, 1, 7), (0.0, 0.0));
+    assert_eq!(pixel_to_ndc(2.0, 0.0, 5, 1), (0.0, 0.0));
+
+    // And back again.
+    assert_eq!(ndc_to_pixel(0.0, 0.0, 1, 1), (0.0, 0.0));
+}
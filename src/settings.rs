@@ -0,0 +1,539 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use time::Duration;
+use app::App;
+use monte_carlo::WavelengthSampling;
+use scene::{LightSelection, Scene};
+use tonemap_unit::{AcesToneMap, ExposureMode, FiniteCheck, LogToneMap, ReinhardToneMap,
+                   ResponseCurve, ToneMap};
+use trace_unit::{DirectLighting, RenderMode, ScreenSampling};
+
+/// How a render is set up, as chosen on the command line.
+pub struct Settings {
+    /// The time to render for, after which the final images are written and
+    /// the program exits. With `None`, rendering continues until the program
+    /// is terminated.
+    pub max_duration: Option<Duration>,
+
+    /// The number of trace batches to render, after which the final images
+    /// are written and the program exits, like for `max_duration`.
+    pub max_batches: Option<u32>,
+
+    /// What the trace units compute, path tracing by default.
+    pub render_mode: RenderMode,
+
+    /// The time between two images, if not the default of the task
+    /// scheduler.
+    pub tonemap_interval: Option<Duration>,
+
+    /// The number of trace batches that a plot task takes at least, 1 by
+    /// default.
+    pub plot_batch_size: usize,
+
+    /// How the wavelengths of photons are chosen, uniformly by default.
+    pub wavelength_sampling: WavelengthSampling,
+
+    /// How the screen positions of camera paths are chosen, uniformly by
+    /// default.
+    pub screen_sampling: ScreenSampling,
+
+    /// How light that reaches surfaces directly from a light is found, by
+    /// following the material by default.
+    pub direct_lighting: DirectLighting,
+
+    /// How lights are chosen when they are sampled, uniformly by default.
+    pub light_selection: LightSelection,
+
+    /// Whether camera paths take their screen position, wavelength and point
+    /// on the lens from a Sobol sequence instead of random numbers.
+    pub sobol_sampling: bool,
+
+    /// The seed from which all random numbers are derived, so that a render
+    /// can be reproduced, if any.
+    pub seed: Option<u32>,
+
+    /// The frame seed that camera paths are seeded with, if any, so that
+    /// frames of an animation have the same noise pattern.
+    pub frame_seed: Option<u32>,
+
+    /// How the exposure of images is chosen, from the standard deviation of
+    /// luminance by default.
+    pub exposure_mode: ExposureMode,
+
+    /// Constructs the operator that compresses intensities for display.
+    pub tone_map: fn() -> Box<ToneMap + Sync + Send>,
+
+    /// The rectangle of pixels (x, y, width, height) that the white balance
+    /// is calibrated on, as with a grey card, if any.
+    pub grey_card: Option<(u32, u32, u32, u32)>,
+
+    /// Whether the gather units accumulate in double precision.
+    pub double_precision: bool,
+
+    /// The number of median absolute deviations above the median of recent
+    /// batches at which a region of a batch is rejected as an outlier, if
+    /// outliers are rejected.
+    pub outlier_threshold: Option<f32>,
+
+    /// The file that the gathered image is exported to as a NumPy array,
+    /// if any.
+    pub npy_path: Option<PathBuf>,
+
+    /// The file that greyscale images of the X, Y and Z channels are named
+    /// after, if any.
+    pub channel_images_path: Option<PathBuf>,
+
+    /// The weight of every light group, if the light of the groups is
+    /// gathered separately, and images are relit with these weights.
+    pub light_weights: Option<Vec<f32>>,
+
+    /// The file that the gathered image is memory-mapped to, if any.
+    #[cfg(feature = "mmap")]
+    pub mapped_path: Option<PathBuf>,
+
+    /// The factor by which images are shrunk for a thumbnail, that is
+    /// written next to every image, if any.
+    pub thumbnail_factor: Option<u32>,
+
+    /// The response curve that images are displayed with, none by default.
+    pub response_curve: ResponseCurve,
+
+    /// Whether images are checked for pixels that are not finite, which are
+    /// reported when found. Off by default.
+    pub finite_check: FiniteCheck,
+
+    /// Whether statistics of every image, such as its estimated
+    /// signal-to-noise ratio, are printed. Off by default.
+    pub print_stats: bool,
+
+    /// Constructs the scene to render.
+    pub scene: fn() -> Scene,
+
+    /// A Radiance `.hdr` file with an equirectangular environment map that
+    /// lights the scene, if any.
+    pub environment: Option<PathBuf>
+}
+
+/// Returns the value that follows the option `name`.
+fn get_value<I: Iterator<Item = String>>(name: &str, args: &mut I) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{} needs a value", name))
+}
+
+/// Parses a number, such as a count or a radius.
+fn parse_number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} needs a number, not '{}'", name, value))
+}
+
+/// Parses a number of seconds.
+fn parse_seconds(name: &str, value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 && seconds < 1.0e12 => {
+            Ok(Duration::milliseconds((seconds * 1000.0) as i64))
+        }
+        _ => Err(format!("{} needs a number of seconds, not '{}'", name, value))
+    }
+}
+
+/// Parses a list of numbers separated by commas, such as `0.5,-0.2`.
+fn parse_numbers<T: FromStr>(name: &str, value: &str) -> Result<Vec<T>, String> {
+    value.split(',').map(|v| parse_number(name, v)).collect()
+}
+
+/// Parses the value of `--mode`.
+fn parse_render_mode(value: &str) -> Result<RenderMode, String> {
+    match value {
+        "paths" => Ok(RenderMode::PathTracing),
+        "light-paths" => Ok(RenderMode::LightTracing),
+        "normals" => Ok(RenderMode::Normals),
+        "depth" => Ok(RenderMode::Depth),
+        "edges" => Ok(RenderMode::Edges),
+        "occlusion" => Ok(RenderMode::AmbientOcclusion(1.0)),
+        _ if value.starts_with("occlusion:") => {
+            let radius = try!(parse_number("occlusion", &value["occlusion:".len() ..]));
+            Ok(RenderMode::AmbientOcclusion(radius))
+        }
+        _ => Err(format!("unknown render mode '{}'", value))
+    }
+}
+
+/// Parses the value of `--scene`.
+fn parse_scene(value: &str) -> Result<fn() -> Scene, String> {
+    match value {
+        "default" => Ok(App::set_up_scene),
+        "cornell-box" => Ok(::scenes::cornell_box),
+        "color-checker" => Ok(::scenes::color_checker),
+        "furnace" => Ok(::scenes::furnace),
+        "materials" => Ok(::scenes::materials),
+        _ => Err(format!("unknown scene '{}'", value))
+    }
+}
+
+// The operators that `--tone-map` chooses from.
+
+fn new_log_tone_map() -> Box<ToneMap + Sync + Send> {
+    Box::new(LogToneMap)
+}
+
+fn new_reinhard_tone_map() -> Box<ToneMap + Sync + Send> {
+    Box::new(ReinhardToneMap)
+}
+
+fn new_aces_tone_map() -> Box<ToneMap + Sync + Send> {
+    Box::new(AcesToneMap)
+}
+
+impl Settings {
+    /// Returns the settings that are used without any options.
+    pub fn new() -> Settings {
+        Settings {
+            max_duration: None,
+            max_batches: None,
+            render_mode: RenderMode::PathTracing,
+            tonemap_interval: None,
+            plot_batch_size: 1,
+            wavelength_sampling: WavelengthSampling::Uniform,
+            screen_sampling: ScreenSampling::Uniform,
+            direct_lighting: DirectLighting::BsdfSampling,
+            light_selection: LightSelection::Uniform,
+            sobol_sampling: false,
+            seed: None,
+            frame_seed: None,
+            exposure_mode: ExposureMode::StandardDeviation,
+            tone_map: new_log_tone_map,
+            grey_card: None,
+            double_precision: false,
+            outlier_threshold: None,
+            npy_path: None,
+            channel_images_path: None,
+            light_weights: None,
+            #[cfg(feature = "mmap")]
+            mapped_path: None,
+            thumbnail_factor: None,
+            response_curve: ResponseCurve::Identity,
+            finite_check: FiniteCheck::Off,
+            print_stats: false,
+            scene: App::set_up_scene,
+            environment: None
+        }
+    }
+
+    /// Parses the command-line arguments, without the name of the program.
+    /// The options are:
+    ///
+    ///  * `--time <seconds>`: render for the specified time, then write the
+    ///    final images and exit.
+    ///  * `--batches <n>`: render the specified number of trace batches, then
+    ///    write the final images and exit. Progress is printed along the way.
+    ///  * `--mode <mode>`: what to render: `paths` (the default), `light-paths`,
+    ///    `normals`, `depth`, `edges`, or `occlusion` with an optional radius,
+    ///    as in `occlusion:2.5`.
+    ///  * `--interval <seconds>`: the time between two images, 30 seconds by
+    ///    default.
+    ///  * `--plot-batch <n>`: plot at least this many trace batches at once,
+    ///    which saves scheduling work when batches are small.
+    ///  * `--wavelengths <uniform|importance>`: choose wavelengths uniformly,
+    ///    or more often where the eye is most sensitive.
+    ///  * `--screen-focus <x>,<y>,<strength>`: trace more paths near the
+    ///    point (`x`, `y`) in normalised device coordinates. The strength, in
+    ///    [0, 1), is the fraction of paths that is concentrated there.
+    ///  * `--lighting <material|lights|both>`: find direct light by following
+    ///    the material, by sampling lights, or with both combined.
+    ///  * `--light-selection <uniform|power|contribution>`: when sampling
+    ///    lights, choose every light equally often, bright and large lights
+    ///    more often, or the lights that contribute most to the lit point.
+    ///  * `--sobol`: sample camera paths quasi-randomly, with a Sobol sequence.
+    ///  * `--seed <n>`: derive all random numbers from a seed, so that the
+    ///    render can be reproduced.
+    ///  * `--frame-seed <n>`: trace camera paths through the pixels in order,
+    ///    seeded per pixel, so frames rendered with the same frame seed have
+    ///    the same noise pattern.
+    ///  * `--exposure <deviation|histogram>`: expose images based on the
+    ///    standard deviation of luminance, or equalize their histogram to keep
+    ///    both shadows and highlights visible.
+    ///  * `--tone-map <log|reinhard|aces>`: the operator that compresses
+    ///    intensities for display, `log` by default.
+    ///  * `--grey-card <x>,<y>,<width>,<height>`: white balance the image such
+    ///    that the rectangle of pixels becomes neutral grey.
+    ///  * `--double`: accumulate the image in double precision, for very long
+    ///    renders.
+    ///  * `--reject-outliers <k>`: leave out regions of batches that are more
+    ///    than `k` median absolute deviations brighter than recent batches,
+    ///    to suppress fireflies.
+    ///  * `--npy <file.npy>`: also save the unprocessed CIE XYZ image as a
+    ///    NumPy array, every time the image is saved.
+    ///  * `--channel-images <file.png>`: also save greyscale images of the X,
+    ///    Y and Z channels, with `-x`, `-y` and `-z` appended to the name.
+    ///  * `--map <file>`: with the `mmap` feature, keep the gathered image in
+    ///    memory-mapped files instead of memory, for huge images.
+    ///  * `--relight <w0>,<w1>,...`: gather the light of every light group
+    ///    separately, and show images with the light of group i scaled by
+    ///    weight i. Light of groups without a weight is left out.
+    ///  * `--thumbnail <factor>`: also write every image to `thumbnail.png`,
+    ///    shrunk by an integer factor.
+    ///  * `--response <identity|s-curve>`: the response curve of the film,
+    ///    where an S-curve gives more contrast.
+    ///  * `--check-finite`: report pixels that are NaN or infinite, which
+    ///    are usually caused by a bug in a material.
+    ///  * `--stats`: print statistics of every image, such as its estimated
+    ///    signal-to-noise ratio.
+    ///  * `--scene <name>`: the scene to render: `default`, or one of the
+    ///    presets `cornell-box`, `color-checker`, `furnace` and `materials`.
+    ///  * `--environment <file.hdr>`: light the scene with an environment map.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Settings, String> {
+        let mut settings = Settings::new();
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--time" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.max_duration = Some(try!(parse_seconds(&arg, &value)));
+                }
+                "--batches" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.max_batches = Some(try!(parse_number(&arg, &value)));
+                }
+                "--mode" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.render_mode = try!(parse_render_mode(&value));
+                }
+                "--interval" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.tonemap_interval = Some(try!(parse_seconds(&arg, &value)));
+                }
+                "--plot-batch" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.plot_batch_size = try!(parse_number(&arg, &value));
+                }
+                "--wavelengths" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.wavelength_sampling = match &value[..] {
+                        "uniform" => WavelengthSampling::Uniform,
+                        "importance" => WavelengthSampling::Importance,
+                        _ => return Err(format!("unknown wavelength sampling '{}'", value))
+                    };
+                }
+                "--screen-focus" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.screen_sampling = match &try!(parse_numbers(&arg, &value))[..] {
+                        &[x, y, strength] if strength >= 0.0 && strength < 1.0 => {
+                            ScreenSampling::Focused(x, y, strength)
+                        }
+                        &[_, _, strength] => {
+                            return Err(format!("{} needs a strength in [0, 1), not {}", arg, strength));
+                        }
+                        _ => return Err(format!("{} needs three numbers, not '{}'", arg, value))
+                    };
+                }
+                "--lighting" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.direct_lighting = match &value[..] {
+                        "material" => DirectLighting::BsdfSampling,
+                        "lights" => DirectLighting::LightSampling,
+                        "both" => DirectLighting::MultipleImportance,
+                        _ => return Err(format!("unknown direct lighting '{}'", value))
+                    };
+                }
+                "--light-selection" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.light_selection = match &value[..] {
+                        "uniform" => LightSelection::Uniform,
+                        "power" => LightSelection::Power,
+                        "contribution" => LightSelection::Contribution,
+                        _ => return Err(format!("unknown light selection '{}'", value))
+                    };
+                }
+                "--sobol" => settings.sobol_sampling = true,
+                "--seed" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.seed = Some(try!(parse_number(&arg, &value)));
+                }
+                "--frame-seed" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.frame_seed = Some(try!(parse_number(&arg, &value)));
+                }
+                "--exposure" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.exposure_mode = match &value[..] {
+                        "deviation" => ExposureMode::StandardDeviation,
+                        "histogram" => ExposureMode::Histogram,
+                        _ => return Err(format!("unknown exposure mode '{}'", value))
+                    };
+                }
+                "--tone-map" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.tone_map = match &value[..] {
+                        "log" => new_log_tone_map,
+                        "reinhard" => new_reinhard_tone_map,
+                        "aces" => new_aces_tone_map,
+                        _ => return Err(format!("unknown tone map '{}'", value))
+                    };
+                }
+                "--grey-card" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.grey_card = match &try!(parse_numbers(&arg, &value))[..] {
+                        &[x, y, width, height] => Some((x, y, width, height)),
+                        _ => return Err(format!("{} needs four numbers, not '{}'", arg, value))
+                    };
+                }
+                "--double" => settings.double_precision = true,
+                "--reject-outliers" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    let threshold: f32 = try!(parse_number(&arg, &value));
+                    if !(threshold > 0.0) {
+                        return Err(format!("{} needs a positive number, not '{}'", arg, value));
+                    }
+                    settings.outlier_threshold = Some(threshold);
+                }
+                "--npy" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.npy_path = Some(PathBuf::from(value));
+                }
+                "--channel-images" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.channel_images_path = Some(PathBuf::from(value));
+                }
+                #[cfg(feature = "mmap")]
+                "--map" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.mapped_path = Some(PathBuf::from(value));
+                }
+                "--relight" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.light_weights = Some(try!(parse_numbers(&arg, &value)));
+                }
+                "--thumbnail" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    let factor = try!(parse_number(&arg, &value));
+                    if factor == 0 {
+                        return Err(format!("{} needs a factor of at least 1", arg));
+                    }
+                    settings.thumbnail_factor = Some(factor);
+                }
+                "--response" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.response_curve = match &value[..] {
+                        "identity" => ResponseCurve::Identity,
+                        "s-curve" => ResponseCurve::s_curve(),
+                        _ => return Err(format!("unknown response curve '{}'", value))
+                    };
+                }
+                "--check-finite" => settings.finite_check = FiniteCheck::Report,
+                "--stats" => settings.print_stats = true,
+                "--scene" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.scene = try!(parse_scene(&value));
+                }
+                "--environment" => {
+                    let value = try!(get_value(&arg, &mut args));
+                    settings.environment = Some(PathBuf::from(value));
+                }
+                _ => return Err(format!("unknown option '{}'", arg))
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[test]
+fn time_is_parsed_in_seconds() {
+    let args = |args: &[&str]| args.iter().map(|&a| String::from(a)).collect::<Vec<_>>();
+
+    let settings = Settings::from_args(args(&[]).into_iter()).unwrap();
+    assert!(settings.max_duration.is_none());
+
+    let settings = Settings::from_args(args(&["--time", "2.5"]).into_iter()).unwrap();
+    assert_eq!(settings.max_duration, Some(Duration::milliseconds(2500)));
+
+    assert!(Settings::from_args(args(&["--time"]).into_iter()).is_err());
+    assert!(Settings::from_args(args(&["--time", "-1"]).into_iter()).is_err());
+    assert!(Settings::from_args(args(&["--frobnicate"]).into_iter()).is_err());
+}
+
+#[test]
+fn render_mode_is_parsed() {
+    let mode = |value: &str| {
+        let args = vec!(String::from("--mode"), String::from(value));
+        Settings::from_args(args.into_iter()).map(|s| s.render_mode)
+    };
+
+    match mode("occlusion:2.5") {
+        Ok(RenderMode::AmbientOcclusion(radius)) => assert_eq!(radius, 2.5),
+        _ => panic!("expected ambient occlusion")
+    }
+    match mode("edges") {
+        Ok(RenderMode::Edges) => {}
+        _ => panic!("expected edges")
+    }
+    assert!(mode("occlusion:far").is_err());
+    assert!(mode("wireframe").is_err());
+}
+
+#[test]
+fn screen_focus_needs_three_numbers() {
+    let focus = |value: &str| {
+        let args = vec!(String::from("--screen-focus"), String::from(value));
+        Settings::from_args(args.into_iter()).map(|s| s.screen_sampling)
+    };
+
+    match focus("0.5,-0.25,0.8") {
+        Ok(ScreenSampling::Focused(x, y, strength)) => assert_eq!((x, y, strength), (0.5, -0.25, 0.8)),
+        _ => panic!("expected focused sampling")
+    }
+    assert!(focus("0.5,-0.25").is_err());
+    assert!(focus("0.5,-0.25,strong").is_err());
+
+    // The strength is a fraction of the paths, and not all of them.
+    assert!(focus("0.5,-0.25,0").is_ok());
+    assert!(focus("0.5,-0.25,1").is_err());
+    assert!(focus("0.5,-0.25,-0.1").is_err());
+    assert!(focus("0.5,-0.25,NaN").is_err());
+}
+
+#[test]
+fn reject_outliers_needs_a_positive_threshold() {
+    let threshold = |value: &str| {
+        let args = vec!(String::from("--reject-outliers"), String::from(value));
+        Settings::from_args(args.into_iter()).map(|s| s.outlier_threshold)
+    };
+
+    assert_eq!(threshold("5"), Ok(Some(5.0)));
+    assert!(threshold("0").is_err());
+    assert!(threshold("-2").is_err());
+    assert!(threshold("many").is_err());
+}
+
+#[test]
+fn scene_is_chosen_by_name() {
+    let args = vec!(String::from("--scene"), String::from("furnace"));
+    let settings = Settings::from_args(args.into_iter()).unwrap();
+    let furnace: fn() -> Scene = ::scenes::furnace;
+    assert_eq!(settings.scene as usize, furnace as usize);
+
+    let args = vec!(String::from("--scene"), String::from("teapot"));
+    assert!(Settings::from_args(args.into_iter()).is_err());
+}
+
+#[test]
+fn relight_takes_a_weight_per_group() {
+    let args = vec!(String::from("--relight"), String::from("1,0.5,0"));
+    let settings = Settings::from_args(args.into_iter()).unwrap();
+    assert_eq!(settings.light_weights, Some(vec!(1.0, 0.5, 0.0)));
+
+    let args = vec!(String::from("--relight"), String::from("1,dim"));
+    assert!(Settings::from_args(args.into_iter()).is_err());
+}
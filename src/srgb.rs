@@ -25,17 +25,126 @@ fn gamma_correct(f: f32) -> f32 {
     }
 }
 
+/// Undoes the sRGB gamma correction of the component.
+pub fn gamma_expand(f: f32) -> f32 {
+    if f <= 0.04045 {
+        f / 12.92
+    } else {
+        ((f + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Shrinks an 8-bit sRGB image of `width` by `height` pixels by an integer
+/// `factor`, by averaging blocks of pixels. The averaging is done on linear
+/// values, because averaging gamma-corrected values darkens the image.
+/// Pixels at the edges that do not fill a block are dropped.
+pub fn downscale(rgb: &[u8], width: u32, height: u32, factor: u32) -> Vec<u8> {
+    let w = (width / factor) as usize;
+    let h = (height / factor) as usize;
+    let f = factor as usize;
+    let n = (f * f) as f32;
+    let mut result = Vec::with_capacity(w * h * 3);
+
+    for y in 0 .. h {
+        for x in 0 .. w {
+            for c in 0 .. 3 {
+                let mut sum = 0.0f32;
+                for j in y * f .. (y + 1) * f {
+                    for i in x * f .. (x + 1) * f {
+                        let value = rgb[(j * width as usize + i) * 3 + c];
+                        sum = sum + gamma_expand(value as f32 / 255.0);
+                    }
+                }
+                let value = gamma_correct(sum / n);
+                result.push((value * 255.0 + 0.5).max(0.0).min(255.0) as u8);
+            }
+        }
+    }
+
+    result
+}
+
+/// Converts a CIE XYZ tristimulus to a linear sRGB colour,
+/// before gamma correction.
+pub fn xyz_to_srgb(cie: Vector3) -> Vector3 {
+    // Apply the sRGB matrix.
+    Vector3 {
+        x:  3.2406 * cie.x - 1.5372 * cie.y - 0.4986 * cie.z,
+        y: -0.9689 * cie.x + 1.8758 * cie.y + 0.0415 * cie.z,
+        z:  0.0557 * cie.x - 0.2040 * cie.y + 1.0570 * cie.z
+    }
+}
+
+/// Applies the sRGB gamma correction to a linear sRGB colour.
+pub fn gamma_correct_rgb(rgb: Vector3) -> Vector3 {
+    Vector3 {
+        x: gamma_correct(rgb.x),
+        y: gamma_correct(rgb.y),
+        z: gamma_correct(rgb.z)
+    }
+}
+
 /// Converts a CIE XYZ tristimulus to an sRGB colour.
 pub fn transform(cie: Vector3) -> Vector3 {
-    // Apply the sRGB matrix.
-    let r =  3.2406 * cie.x - 1.5372 * cie.y - 0.4986 * cie.z;
-    let g = -0.9689 * cie.x + 1.8758 * cie.y + 0.0415 * cie.z;
-    let b =  0.0557 * cie.x - 0.2040 * cie.y + 1.0570 * cie.z;
+    gamma_correct_rgb(xyz_to_srgb(cie))
+}
 
-    // Then do gamma correction.
+/// Converts a linear sRGB colour (before gamma correction) to a CIE XYZ
+/// tristimulus. This is the inverse of `xyz_to_srgb`.
+pub fn srgb_to_xyz(rgb: Vector3) -> Vector3 {
     Vector3 {
-        x: gamma_correct(r),
-        y: gamma_correct(g),
-        z: gamma_correct(b)
+        x: 0.4124 * rgb.x + 0.3576 * rgb.y + 0.1805 * rgb.z,
+        y: 0.2126 * rgb.x + 0.7152 * rgb.y + 0.0722 * rgb.z,
+        z: 0.0193 * rgb.x + 0.1192 * rgb.y + 0.9505 * rgb.z
+    }
+}
+
+/// Returns the Rec. 709 luma of a linear sRGB colour, which is the luminance
+/// of the colour, like `cie1931::luminance` is for CIE XYZ values.
+pub fn luma(rgb: Vector3) -> f32 {
+    0.2126 * rgb.x + 0.7152 * rgb.y + 0.0722 * rgb.z
+}
+
+/// Returns the intensity at `wavelength` (in nm) of a smooth spectrum with
+/// approximately the colour of the linear sRGB value `rgb`. The red, green
+/// and blue components each cover a band of the visible spectrum, with
+/// linear transitions in between. Because the bands sum to one everywhere,
+/// white maps to a flat spectrum.
+pub fn upsample(rgb: Vector3, wavelength: f32) -> f32 {
+    fn ramp(x: f32, from: f32, to: f32) -> f32 {
+        if x <= from { 0.0 }
+        else if x >= to { 1.0 }
+        else { (x - from) / (to - from) }
+    }
+
+    let red = ramp(wavelength, 560.0, 600.0);
+    let blue = 1.0 - ramp(wavelength, 470.0, 510.0);
+    let green = 1.0 - red - blue;
+
+    rgb.x * red + rgb.y * green + rgb.z * blue
+}
+
+#[test]
+fn downscale_checkerboard_is_mid_grey() {
+    // A 4x4 checkerboard of black and white pixels.
+    let mut rgb = Vec::new();
+    for y in 0 .. 4 {
+        for x in 0 .. 4 {
+            let value = if (x + y) % 2 == 0 { 255 } else { 0 };
+            rgb.extend_from_slice(&[value, value, value]);
+        }
+    }
+
+    let thumbnail = downscale(&rgb, 4, 4, 2);
+    assert_eq!(thumbnail.len(), 2 * 2 * 3);
+    assert!(thumbnail.iter().all(|&x| x == 188));
+}
+
+#[test]
+fn xyz_round_trips_through_srgb() {
+    for &xyz in [Vector3::new(0.9505, 1.0, 1.089), Vector3::new(0.2, 0.1, 0.7),
+                 Vector3::new(0.4, 0.6, 0.05), Vector3::zero()].iter() {
+        let back = srgb_to_xyz(xyz_to_srgb(xyz));
+        assert!((back - xyz).magnitude() < 1.0e-3);
     }
 }
@@ -15,8 +15,12 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp::max;
+use std::collections::HashSet;
 use std::collections::vec_deque::VecDeque;
+use std::iter::repeat;
+use std::path::{Path, PathBuf};
 use time::{Duration, Timespec, get_time};
+use checkpoint::SamplingState;
 use gather_unit::GatherUnit;
 use plot_unit::PlotUnit;
 use pop_iter::PopFrontIter;
@@ -45,18 +49,62 @@ fn tonemap_interval() -> Duration {
     Duration::seconds(30)
 }
 
+/// Returns the file for `camera` that corresponds to `path`: the path itself
+/// for the first camera, and for the others the path with the index of the
+/// camera appended to the file name, so that every camera has its own file.
+pub fn camera_path(path: &Path, camera: usize) -> PathBuf {
+    if camera == 0 { return path.to_path_buf(); }
+    let stem = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, camera, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, camera)
+    };
+    path.with_file_name(name)
+}
+
 /// Handles splitting the workload across threads.
 pub struct TaskScheduler {
     /// The number of completed trace batches. Used to measure performance.
     traces_completed: u32,
 
+    /// The number of completed trace batches since rendering (re)started.
+    batches_traced: u32,
+
+    /// The number of trace batches after which no new ones are started,
+    /// or `None` to render indefinitely.
+    batch_budget: Option<u32>,
+
+    /// The time after which no new trace tasks are started, or `None` to
+    /// render indefinitely.
+    deadline: Option<Timespec>,
+
     /// Previous measurements of batches/second, used to determine variance.
     performance: VecDeque<f32>,
 
+    /// The number of cameras through which the scene is rendered. Every
+    /// camera has its own plot units, gather unit and tonemap unit.
+    number_of_cameras: usize,
+
+    /// The size of the canvas that the units render to.
+    width: u32,
+    height: u32,
+
+    /// The camera that the next trace task renders. Trace tasks go to the
+    /// cameras in turn, so the images converge at the same rate.
+    next_camera: usize,
+
     /// The number of trace units to use. Not all of them have to be
     /// active simultaneously.
     number_of_trace_units: usize,
 
+    /// The number of plot units to use per camera.
+    number_of_plot_units: usize,
+
+    /// The number of trace units that a plot task takes at least, if that
+    /// many are done. With small trace batches, plotting a few of them at
+    /// once saves scheduling work per batch.
+    plot_batch_size: usize,
+
     /// The trace units which are available for tracing rays.
     available_trace_units: VecDeque<Box<TraceUnit>>,
 
@@ -64,63 +112,389 @@ pub struct TaskScheduler {
     /// before the trace unit can be used again.
     done_trace_units: VecDeque<Box<TraceUnit>>,
 
-    /// The plot units which are available for plotting mapped photons.
-    available_plot_units: VecDeque<Box<PlotUnit>>,
+    /// The plot units which are available for plotting mapped photons,
+    /// per camera.
+    available_plot_units: Vec<VecDeque<Box<PlotUnit>>>,
 
     /// The plot units which have a screen that must be accumulated
-    /// before the plot unit can be used again.
-    done_plot_units: VecDeque<Box<PlotUnit>>,
+    /// before the plot unit can be used again, per camera.
+    done_plot_units: Vec<VecDeque<Box<PlotUnit>>>,
 
-    /// The gather unit, when it is available.
-    gather_unit: Option<Box<GatherUnit>>,
+    /// Empty vectors that held the trace units of a plot task, so that a new
+    /// plot task does not have to allocate one.
+    spare_trace_batches: Vec<Vec<Box<TraceUnit>>>,
 
-    /// The tonemap unit, when it is available.
-    tonemap_unit: Option<Box<TonemapUnit>>,
+    /// The gather unit of every camera, when it is available.
+    gather_units: Vec<Option<Box<GatherUnit>>>,
 
-    /// The last time the image was tonemapped (and displayed).
-    last_tonemap_time: Timespec,
+    /// The tonemap unit of every camera, when it is available.
+    tonemap_units: Vec<Option<Box<TonemapUnit>>>,
+
+    /// The last time the image of every camera was tonemapped (and
+    /// displayed).
+    last_tonemap_times: Vec<Timespec>,
+
+    /// The time between two tonemapping tasks.
+    tonemap_interval: Duration,
 
     /// Whether a new gather task has been executed since the last
-    /// tonemapping task was executed.
-    image_changed: bool
+    /// tonemapping task was executed, per camera.
+    image_changed: Vec<bool>,
+
+    /// Whether the image of every camera has been tonemapped since rendering
+    /// (re)started.
+    tonemapped: Vec<bool>,
+
+    /// IDs of the trace units that were busy when rendering was restarted.
+    /// Their results must be discarded.
+    stale_trace_units: HashSet<usize>,
+
+    /// IDs of the plot units that were busy when rendering was restarted.
+    stale_plot_units: HashSet<usize>,
+
+    /// Whether the gather unit of every camera was busy when rendering was
+    /// restarted.
+    stale_gather_units: Vec<bool>,
+
+    /// The frame seed that trace units render with, if any.
+    frame_seed: Option<u32>,
+
+    /// The seed from which the random numbers of every trace unit are
+    /// derived, if any.
+    seed: Option<u32>,
+
+    /// The sampling state of the batches that have been plotted, which are
+    /// all in the gathered image after the next gather task.
+    plotted_state: SamplingState
 }
 
 impl TaskScheduler {
-    /// Creates a new task scheduler, that will render `scene` to a
-    /// canvas of the specified size, using `concurrency` threads.
-    pub fn new(concurrency: usize, width: u32, height: u32) -> TaskScheduler {
+    /// Creates a new task scheduler, that will render the specified number
+    /// of cameras to canvases of the specified size, using `concurrency`
+    /// threads.
+    pub fn new(concurrency: usize, cameras: usize, width: u32, height: u32) -> TaskScheduler {
+        assert!(cameras > 0, "there must be at least one camera");
+
         // More trace units than threads seems sensible,
         // but less plot units is acceptable,
         // because one plot unit can handle multiple trace units.
         let n_trace_units = concurrency * 3;
         let n_plot_units = max(1, concurrency / 2);
 
-        // Build the trace units.
+        // Build the trace units, which are shared by the cameras.
         let trace_units = (0 .. n_trace_units)
         .map(|i| { Box::new(TraceUnit::new(i, width, height)) })
         .collect::<VecDeque<Box<TraceUnit>>>();
 
-        // Then build the plot units.
-        let plot_units = (0 .. n_plot_units)
-        .map(|i| { Box::new(PlotUnit::new(i, width, height)) })
-        .collect::<VecDeque<Box<PlotUnit>>>();
-
-        // There must be one gather unit and one tonemap unit.
-        let gather_unit = Some(Box::new(GatherUnit::new(width, height)));
-        let tonemap_unit = Some(Box::new(TonemapUnit::new(width, height)));
+        // Then build the plot units of every camera.
+        let plot_units = (0 .. cameras).map(|camera| {
+            (0 .. n_plot_units).map(|i| {
+                let mut unit = Box::new(PlotUnit::new(camera * n_plot_units + i, width, height));
+                unit.camera = camera;
+                unit
+            }).collect::<VecDeque<Box<PlotUnit>>>()
+        }).collect();
+
+        // There must be one gather unit and one tonemap unit per camera.
+        let gather_units = (0 .. cameras).map(|camera| {
+            let mut unit = Box::new(GatherUnit::new(width, height));
+            unit.camera = camera;
+            Some(unit)
+        }).collect();
+        let tonemap_units = (0 .. cameras).map(|camera| {
+            let mut unit = Box::new(TonemapUnit::new(width, height));
+            unit.camera = camera;
+            Some(unit)
+        }).collect();
 
         TaskScheduler {
             traces_completed: 0,
+            batches_traced: 0,
+            batch_budget: None,
+            deadline: None,
             performance: VecDeque::new(),
+            number_of_cameras: cameras,
+            width: width,
+            height: height,
+            next_camera: 0,
             number_of_trace_units: n_trace_units,
+            number_of_plot_units: n_plot_units,
+            plot_batch_size: 1,
             available_trace_units: trace_units,
             done_trace_units: VecDeque::new(),
             available_plot_units: plot_units,
-            done_plot_units: VecDeque::new(),
-            gather_unit: gather_unit,
-            tonemap_unit: tonemap_unit,
-            last_tonemap_time: get_time(),
-            image_changed: false
+            done_plot_units: (0 .. cameras).map(|_| VecDeque::new()).collect(),
+            spare_trace_batches: Vec::new(),
+            gather_units: gather_units,
+            tonemap_units: tonemap_units,
+            last_tonemap_times: repeat(get_time()).take(cameras).collect(),
+            tonemap_interval: tonemap_interval(),
+            image_changed: repeat(false).take(cameras).collect(),
+            tonemapped: repeat(false).take(cameras).collect(),
+            stale_trace_units: HashSet::new(),
+            stale_plot_units: HashSet::new(),
+            stale_gather_units: repeat(false).take(cameras).collect(),
+            frame_seed: None,
+            seed: None,
+            plotted_state: SamplingState {
+                seed: None,
+                batches_traced: 0,
+                unit_batches: repeat(0).take(n_trace_units).collect()
+            }
+        }
+    }
+
+    /// Returns the number of cameras that the scheduler renders.
+    pub fn number_of_cameras(&self) -> usize {
+        self.number_of_cameras
+    }
+
+    /// Applies `configure` to every trace unit, for example to set what they
+    /// render. Must be called before rendering starts.
+    pub fn configure_trace_units<F: FnMut(&mut TraceUnit)>(&mut self, mut configure: F) {
+        for unit in self.available_trace_units.iter_mut() {
+            configure(unit);
+        }
+    }
+
+    /// Applies `configure` to the plot units of every camera, for example to
+    /// split the light into groups. Must be called before rendering starts.
+    pub fn configure_plot_units<F: FnMut(&mut PlotUnit)>(&mut self, mut configure: F) {
+        for unit in self.available_plot_units.iter_mut().flat_map(|us| us.iter_mut()) {
+            configure(unit);
+        }
+    }
+
+    /// Applies `configure` to the gather unit of every camera, for example
+    /// to set how it accumulates. Must be called before rendering starts.
+    pub fn configure_gather_units<F: FnMut(&mut GatherUnit)>(&mut self, mut configure: F) {
+        for unit in self.gather_units.iter_mut().filter_map(|u| u.as_mut()) {
+            configure(unit);
+        }
+    }
+
+    /// Applies `configure` to the tonemap unit of every camera, for example
+    /// to set how images are exposed. Must be called before rendering starts.
+    pub fn configure_tonemap_units<F: FnMut(&mut TonemapUnit)>(&mut self, mut configure: F) {
+        for unit in self.tonemap_units.iter_mut().filter_map(|u| u.as_mut()) {
+            configure(unit);
+        }
+    }
+
+    /// Sets the file that the tonemap units save the exposure to, so that the
+    /// first image of a resumed render is exposed like the last one. Cameras
+    /// other than the first save to a file next to it, see `camera_path`.
+    /// With `None`, which is the default, nothing is saved. Must be called
+    /// before rendering starts.
+    pub fn set_exposure_path(&mut self, path: Option<PathBuf>) {
+        for unit in self.tonemap_units.iter_mut().filter_map(|u| u.as_mut()) {
+            unit.set_exposure_path(path.as_ref().map(|p| camera_path(p, unit.camera)));
+        }
+    }
+
+    /// Sets the file that the gather units save checkpoints to. Cameras other
+    /// than the first save to a file next to it, see `camera_path`. With
+    /// `None`, which is the default, nothing is saved. Must be called before
+    /// rendering starts.
+    pub fn set_checkpoint_path(&mut self, path: Option<PathBuf>) {
+        for unit in self.gather_units.iter_mut().filter_map(|u| u.as_mut()) {
+            unit.set_checkpoint_path(path.as_ref().map(|p| camera_path(p, unit.camera)));
+        }
+    }
+
+    /// Sets the fingerprint of the scene and settings that are rendered,
+    /// see `Fingerprint`. It is saved with the checkpoints, and checkpoints
+    /// with a different fingerprint are not resumed. Must be called before
+    /// `resume`.
+    pub fn set_fingerprint(&mut self, fingerprint: u64) {
+        for unit in self.gather_units.iter_mut().filter_map(|u| u.as_mut()) {
+            unit.fingerprint = fingerprint;
+        }
+    }
+
+    /// Continues the render from the checkpoints that were saved last, if
+    /// there is one for every camera: the gathered images, the progress, and
+    /// for a seeded render also where every trace unit was in its stream of
+    /// random numbers. The saved exposures are loaded too. Must be called
+    /// before rendering starts. Returns whether the checkpoints were resumed.
+    pub fn resume(&mut self) -> bool {
+        // The images of the cameras only belong together if all of them
+        // can be resumed.
+        let mut resumed = true;
+        for unit in self.gather_units.iter_mut() {
+            resumed = match *unit {
+                Some(ref mut unit) => unit.resume(),
+                None => false
+            } && resumed;
+        }
+        if !resumed {
+            for unit in self.gather_units.iter_mut().filter_map(|u| u.as_mut()) {
+                unit.clear();
+            }
+            return false;
+        }
+
+        // The saved exposures only fit the images they were saved with.
+        for unit in self.tonemap_units.iter_mut().filter_map(|u| u.as_mut()) {
+            unit.resume();
+        }
+
+        let state = match self.gather_units[0] {
+            Some(ref unit) => unit.sampling_state.clone(),
+            None => return false
+        };
+
+        if state.seed.is_some() { self.seed = state.seed; }
+        self.batches_traced = state.batches_traced;
+        for unit in self.available_trace_units.iter_mut() {
+            let batches = state.unit_batches.get(unit.id).cloned().unwrap_or(0);
+            unit.set_seeded_batches(batches);
+            self.plotted_state.unit_batches[unit.id] = batches;
+        }
+        self.plotted_state.batches_traced = state.batches_traced;
+        true
+    }
+
+    /// Sets the time between two tonemapping tasks (and thus images).
+    pub fn set_tonemap_interval(&mut self, interval: Duration) {
+        self.tonemap_interval = interval;
+    }
+
+    /// Sets the number of trace batches to render. When that many batches
+    /// have been traced, no new trace tasks are started, but the batches
+    /// that are in progress still complete, so there may be a few more.
+    /// With `None`, rendering continues indefinitely, which is the default.
+    pub fn set_batch_budget(&mut self, batches: Option<u32>) {
+        self.batch_budget = batches;
+    }
+
+    /// Sets the time to render for, from now. When the time is up, no new
+    /// trace tasks are started, but the batches that are in progress still
+    /// complete. With `None`, rendering continues indefinitely, which is the
+    /// default.
+    pub fn set_time_budget(&mut self, duration: Option<Duration>) {
+        self.deadline = duration.map(|duration| get_time() + duration);
+    }
+
+    /// Sets the frame seed of the trace units, so that camera paths draw their
+    /// random numbers from seeded generators. See `TraceUnit::frame_seed`.
+    pub fn set_frame_seed(&mut self, seed: Option<u32>) {
+        self.frame_seed = seed;
+    }
+
+    /// Sets the seed of the trace units, so that a render can be reproduced.
+    /// Every unit derives its own random numbers from it, together with its
+    /// ID. See `TraceUnit::seed`.
+    pub fn set_seed(&mut self, seed: Option<u32>) {
+        self.seed = seed;
+    }
+
+    /// Sets the number of trace units that a plot task takes at least. Done
+    /// trace units are only plotted early once that many of a camera are
+    /// done; when there is nothing else to do, fewer are plotted anyway.
+    /// The default is 1.
+    pub fn set_plot_batch_size(&mut self, units: usize) {
+        self.plot_batch_size = max(1, units);
+    }
+
+    /// Returns the fraction of the batch budget that has been traced, between
+    /// 0.0 and 1.0, for showing progress. Without a budget this is 0.0.
+    pub fn progress_fraction(&self) -> f32 {
+        match self.batch_budget {
+            Some(0) => 1.0,
+            Some(budget) => (self.batches_traced as f32 / budget as f32).min(1.0),
+            None => 0.0
+        }
+    }
+
+    /// Returns whether the batch budget and the time budget allow tracing
+    /// more batches.
+    fn is_within_budget(&self) -> bool {
+        self.batch_budget.map_or(true, |budget| self.batches_traced < budget) &&
+        self.deadline.map_or(true, |deadline| get_time() < deadline)
+    }
+
+    /// Returns whether no unit is busy, and nothing waits to be plotted
+    /// or gathered.
+    fn is_idle(&self) -> bool {
+        self.available_trace_units.len() == self.number_of_trace_units &&
+        self.available_plot_units.iter().all(|units| units.len() == self.number_of_plot_units) &&
+        self.gather_units.iter().all(|unit| unit.is_some()) &&
+        self.tonemap_units.iter().all(|unit| unit.is_some())
+    }
+
+    /// Returns whether the render is done: the budget is used up, and the
+    /// final image of every camera has been tonemapped. Without a budget, a
+    /// render is never done.
+    pub fn is_finished(&self) -> bool {
+        !self.is_within_budget() && self.is_idle() &&
+        (0 .. self.number_of_cameras).all(|c| self.tonemapped[c] && !self.image_changed[c])
+    }
+
+    /// Discards everything that has been rendered so far, so that rendering
+    /// starts afresh, for example because the scene changed. Units that are
+    /// busy with a task are discarded when the task completes.
+    pub fn restart(&mut self) {
+        // Photons that have not been plotted yet are simply dropped.
+        for trace_unit in self.done_trace_units.pop_front_iter() {
+            self.available_trace_units.push_back(trace_unit);
+        }
+
+        // Plots that have not been gathered yet are cleared.
+        for camera in 0 .. self.number_of_cameras {
+            for mut plot_unit in self.done_plot_units[camera].pop_front_iter() {
+                plot_unit.clear();
+                self.available_plot_units[camera].push_back(plot_unit);
+            }
+        }
+
+        // All units that are not available now, must be busy.
+        self.stale_trace_units = (0 .. self.number_of_trace_units)
+            .filter(|&i| !self.available_trace_units.iter().any(|u| u.id == i))
+            .collect();
+        self.stale_plot_units = (0 .. self.number_of_cameras * self.number_of_plot_units)
+            .filter(|&i| !self.available_plot_units.iter().flat_map(|us| us.iter()).any(|u| u.id == i))
+            .collect();
+
+        for camera in 0 .. self.number_of_cameras {
+            match self.gather_units[camera] {
+                Some(ref mut gather_unit) => gather_unit.clear(),
+                None => self.stale_gather_units[camera] = true
+            }
+            self.image_changed[camera] = false;
+            self.tonemapped[camera] = false;
+        }
+
+        self.batches_traced = 0;
+        self.plotted_state.batches_traced = 0;
+    }
+
+    /// Changes the size of the canvas, and discards everything that has been
+    /// rendered so far, because an image cannot be scaled. Units that are
+    /// busy with a task are resized when the task completes.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.restart();
+
+        // Trace units are resized when they get a task.
+        for plot_unit in self.available_plot_units.iter_mut().flat_map(|us| us.iter_mut()) {
+            plot_unit.resize(width, height);
+        }
+        for camera in 0 .. self.number_of_cameras {
+            if let Some(ref mut gather_unit) = self.gather_units[camera] {
+                TaskScheduler::resize_gather_unit(gather_unit, width, height);
+            }
+            if let Some(ref mut tonemap_unit) = self.tonemap_units[camera] {
+                tonemap_unit.resize(width, height);
+            }
+        }
+    }
+
+    /// Resizes a gather unit, which also clears it.
+    fn resize_gather_unit(gather_unit: &mut GatherUnit, width: u32, height: u32) {
+        if let Err(reason) = gather_unit.resize(width, height) {
+            println!("failed to resize the gather buffers: {}", reason);
         }
     }
 
@@ -128,52 +502,72 @@ impl TaskScheduler {
         // Make the units that were used by the completed task available again.
         self.complete_task(completed_task);
 
-        // If the last tonemapping time was more than x seconds ago,
-        // an update should be done.
+        // If the last tonemapping time of a camera was at least x seconds
+        // ago, its image should be updated.
         let now = get_time();
-        if now - self.last_tonemap_time > tonemap_interval() {
-            // If the image has changed since it was last tonemapped,
-            // tonemap it now.
-            if self.image_changed {
-                // Tonemapping can only be done if no gathering
-                // and tonemapping are busy.
-                if self.gather_unit.is_some() && self.tonemap_unit.is_some() {
-                    return self.create_tonemap_task();
-                }
-            } else {
-                // Otherwise, the plots must first be gathered, tonemapping
-                // will happen once that is done.
-                if self.gather_unit.is_some() &&
-                   !self.done_plot_units.is_empty() {
-                    return self.create_gather_task();
+        for camera in 0 .. self.number_of_cameras {
+            if now - self.last_tonemap_times[camera] >= self.tonemap_interval {
+                // If the image has changed since it was last tonemapped,
+                // tonemap it now.
+                if self.image_changed[camera] {
+                    // Tonemapping can only be done if no gathering
+                    // and tonemapping are busy.
+                    if self.gather_units[camera].is_some() &&
+                       self.tonemap_units[camera].is_some() {
+                        return self.create_tonemap_task(camera);
+                    }
+                } else {
+                    // Otherwise, the plots must first be gathered, tonemapping
+                    // will happen once that is done.
+                    if self.gather_units[camera].is_some() &&
+                       !self.done_plot_units[camera].is_empty() {
+                        return self.create_gather_task(camera);
+                    }
                 }
             }
         }
 
         // If a substantial number of trace units is done, plot them first
         // so they can be recycled soon.
-        if self.done_trace_units.len() > self.number_of_trace_units / 2 &&
-            !self.available_plot_units.is_empty() {
-            return self.create_plot_task();
+        if self.done_trace_units.len() > self.number_of_trace_units / 2 {
+            let batch_size = self.plot_batch_size;
+            if let Some(camera) = self.get_plottable_camera(batch_size) {
+                return self.create_plot_task(camera);
+            }
         }
 
-        // Then, if there are enough trace units available, go trace some rays!
-        if !self.available_trace_units.is_empty() {
+        // Then, if there are enough trace units available, and the budget
+        // has not been used up, go trace some rays!
+        if !self.available_trace_units.is_empty() && self.is_within_budget() {
             return self.create_trace_task();
         }
 
         // Otherwise, some trace units need to be plotted to make them
         // available again.
-        if !self.available_plot_units.is_empty() &&
-           !self.done_trace_units.is_empty() {
-            return self.create_plot_task();
+        if let Some(camera) = self.get_plottable_camera(1) {
+            return self.create_plot_task(camera);
         }
 
         // If no plot units are available (or all trace units are busy,
         // which should be impossible), gather some plots to make the plot
         // units available again.
-        if self.gather_unit.is_some() && !self.done_plot_units.is_empty() {
-            return self.create_gather_task();
+        let gatherable = (0 .. self.number_of_cameras).find(|&camera| {
+            self.gather_units[camera].is_some() && !self.done_plot_units[camera].is_empty()
+        });
+        if let Some(camera) = gatherable {
+            return self.create_gather_task(camera);
+        }
+
+        // When the budget is used up and everything has been gathered, the
+        // final image of every camera is tonemapped, without waiting for
+        // the interval.
+        if !self.is_within_budget() && self.is_idle() {
+            let unfinished = (0 .. self.number_of_cameras).find(|&camera| {
+                self.image_changed[camera] || !self.tonemapped[camera]
+            });
+            if let Some(camera) = unfinished {
+                return self.create_tonemap_task(camera);
+            }
         }
 
         // If everything is locked in dependencies and everything is a big
@@ -181,52 +575,138 @@ impl TaskScheduler {
         Task::Sleep
     }
 
+    /// Returns the camera of the trace unit that has been done the longest,
+    /// of the ones that have a plot unit available, and at least `min_units`
+    /// trace units that are done.
+    fn get_plottable_camera(&self, min_units: usize) -> Option<usize> {
+        self.done_trace_units.iter()
+            .map(|unit| unit.camera)
+            .find(|&camera| {
+                !self.available_plot_units[camera].is_empty() &&
+                self.done_trace_units.iter().filter(|u| u.camera == camera).count() >= min_units
+            })
+    }
+
     fn create_trace_task(&mut self) -> Task {
         // Pick the first available trace unit, and use it for the task.
         // We know a unit is available, because this method would not
         // have been called otherwise.
-        let trace_unit = self.available_trace_units.pop_front().unwrap();
+        let mut trace_unit = self.available_trace_units.pop_front().unwrap();
+        trace_unit.frame_seed = self.frame_seed;
+        trace_unit.seed = self.seed;
+        trace_unit.camera = self.next_camera;
+        trace_unit.resize(self.width, self.height);
+        self.next_camera = (self.next_camera + 1) % self.number_of_cameras;
         Task::Trace(trace_unit)
     }
 
-    fn create_plot_task(&mut self) -> Task {
+    fn create_plot_task(&mut self, camera: usize) -> Task {
         // Pick the first available plot unit, and use it for the task.
         // We know a unit is available, because this method would not
         // have been called otherwise.
-        let plot_unit = self.available_plot_units.pop_front().unwrap();
-
-        // Take around half of the trace units which are done for this task.
-        let done = self.done_trace_units.len();
-        let n = max(1, done / 2);
-
-        // Have it plot the trace units which are done.
-        let trace_units: Vec<Box<TraceUnit>> = self.done_trace_units
-        .pop_front_iter().take(n).collect();
+        let plot_unit = self.available_plot_units[camera].pop_front().unwrap();
+
+        // Take around half of the trace units of the camera which are done
+        // for this task, but at least a batch.
+        let done = self.done_trace_units.iter().filter(|u| u.camera == camera).count();
+        let n = max(self.plot_batch_size, done / 2);
+
+        // Have it plot the trace units which are done, the others stay in
+        // the queue in the same order.
+        let mut trace_units = self.spare_trace_batches.pop().unwrap_or_else(Vec::new);
+        for _ in 0 .. self.done_trace_units.len() {
+            let trace_unit = self.done_trace_units.pop_front().unwrap();
+            if trace_unit.camera == camera && trace_units.len() < n {
+                trace_units.push(trace_unit);
+            } else {
+                self.done_trace_units.push_back(trace_unit);
+            }
+        }
 
         Task::Plot(plot_unit, trace_units)
     }
 
-    fn create_gather_task(&mut self) -> Task {
+    fn create_gather_task(&mut self, camera: usize) -> Task {
         // We know the gather unit is available, because this method would
         // not have been called otherwise.
-        let gather_unit = self.gather_unit.take().unwrap();
+        let mut gather_unit = self.gather_units[camera].take().unwrap();
+
+        // Everything that was plotted ends up in the gathered image now.
+        gather_unit.sampling_state = SamplingState {
+            seed: self.seed,
+            .. self.plotted_state.clone()
+        };
 
         // Have it gather all plot units which are done.
-        let plot_units: Vec<Box<PlotUnit>> = self.done_plot_units
+        let plot_units: Vec<Box<PlotUnit>> = self.done_plot_units[camera]
         .pop_front_iter().collect();
 
         Task::Gather(gather_unit, plot_units)
     }
 
-    fn create_tonemap_task(&mut self) -> Task {
+    fn create_tonemap_task(&mut self, camera: usize) -> Task {
         // We know the units are available, because this method would
         // not have been called otherwise.
-        let gather_unit = self.gather_unit.take().unwrap();
-        let tonemap_unit = self.tonemap_unit.take().unwrap();
+        let gather_unit = self.gather_units[camera].take().unwrap();
+        let tonemap_unit = self.tonemap_units[camera].take().unwrap();
 
         Task::Tonemap(tonemap_unit, gather_unit)
     }
 
+    /// Makes the units of a task that did not complete, because it panicked
+    /// halfway, available again. Their results are incomplete, so they are
+    /// discarded, except for the gathered image: plots that were gathered
+    /// before the panic remain in it.
+    pub fn abandon_task(&mut self, task: Task) {
+        match task {
+            Task::Sleep => { },
+            Task::Trace(trace_unit) => self.abandon_trace_units(vec!(trace_unit)),
+            Task::Plot(plot_unit, trace_units) => {
+                self.abandon_trace_units(trace_units);
+                self.abandon_plot_units(vec!(plot_unit));
+            },
+            Task::Gather(mut gather_unit, plot_units) => {
+                self.abandon_plot_units(plot_units);
+                let camera = gather_unit.camera;
+                if self.stale_gather_units[camera] {
+                    TaskScheduler::resize_gather_unit(&mut gather_unit, self.width, self.height);
+                    self.stale_gather_units[camera] = false;
+                }
+                self.gather_units[camera] = Some(gather_unit);
+                self.image_changed[camera] = true;
+            },
+            Task::Tonemap(mut tonemap_unit, mut gather_unit) => {
+                let camera = tonemap_unit.camera;
+                if self.stale_gather_units[camera] {
+                    TaskScheduler::resize_gather_unit(&mut gather_unit, self.width, self.height);
+                    tonemap_unit.resize(self.width, self.height);
+                    self.stale_gather_units[camera] = false;
+                }
+                self.gather_units[camera] = Some(gather_unit);
+                self.tonemap_units[camera] = Some(tonemap_unit);
+            }
+        }
+    }
+
+    fn abandon_trace_units(&mut self, trace_units: Vec<Box<TraceUnit>>) {
+        for mut trace_unit in trace_units.into_iter() {
+            trace_unit.mapped_photons.clear();
+            self.stale_trace_units.remove(&trace_unit.id);
+            self.available_trace_units.push_back(trace_unit);
+        }
+    }
+
+    fn abandon_plot_units(&mut self, plot_units: Vec<Box<PlotUnit>>) {
+        for mut plot_unit in plot_units.into_iter() {
+            if self.stale_plot_units.remove(&plot_unit.id) {
+                plot_unit.resize(self.width, self.height);
+            } else {
+                plot_unit.clear();
+            }
+            self.available_plot_units[plot_unit.camera].push_back(plot_unit);
+        }
+    }
+
     /// Makes resources used by the task available again.
     fn complete_task(&mut self, task: Task) {
         match task {
@@ -241,76 +721,124 @@ impl TaskScheduler {
     fn complete_trace_task(&mut self, trace_unit: Box<TraceUnit>) {
         println!("done tracing with unit {}", trace_unit.id);
 
+        // If rendering was restarted while tracing, the photons are useless.
+        if self.stale_trace_units.remove(&trace_unit.id) {
+            self.available_trace_units.push_back(trace_unit);
+            return;
+        }
+
         // The trace unit used for the task, now needs plotting before
         // it is available again.
         self.done_trace_units.push_back(trace_unit);
 
-        // Keep statatistics about performance.
+        // Keep statatistics about performance and progress.
         self.traces_completed += 1;
+        self.batches_traced += 1;
     }
 
     fn complete_plot_task(&mut self,
-                          plot_unit: Box<PlotUnit>,
-                          trace_units: Vec<Box<TraceUnit>>) {
+                          mut plot_unit: Box<PlotUnit>,
+                          mut trace_units: Vec<Box<TraceUnit>>) {
         println!("done plotting with unit {}", plot_unit.id);
         print!("the following trace units are available again: ");
 
         // All trace units that were plotted, can be used again now.
-        for trace_unit in trace_units.into_iter() {
+        let stale = self.stale_plot_units.remove(&plot_unit.id);
+        for trace_unit in trace_units.drain(..) {
             print!(" {} ", trace_unit.id);
+            self.stale_trace_units.remove(&trace_unit.id);
+            if !stale {
+                self.plotted_state.unit_batches[trace_unit.id] = trace_unit.get_seeded_batches();
+                self.plotted_state.batches_traced += 1;
+            }
             self.available_trace_units.push_back(trace_unit);
         }
 
         println!("");
+        self.spare_trace_batches.push(trace_units);
+
+        // If rendering was restarted while plotting, the plot is useless.
+        let camera = plot_unit.camera;
+        if stale {
+            plot_unit.resize(self.width, self.height);
+            self.available_plot_units[camera].push_back(plot_unit);
+            return;
+        }
 
         // And the plot unit that was used, needs to be gathered before
         // it can be used again.
-        self.done_plot_units.push_back(plot_unit);
+        self.done_plot_units[camera].push_back(plot_unit);
     }
 
     fn complete_gather_task(&mut self,
-                            gather_unit: Box<GatherUnit>,
+                            mut gather_unit: Box<GatherUnit>,
                             plot_units: Vec<Box<PlotUnit>>) {
         println!("done gathering");
         print!("the following plot units are available again: ");
 
         // All plot units that were gathered, can be used again now.
-        for plot_unit in plot_units.into_iter() {
+        for mut plot_unit in plot_units.into_iter() {
             print!(" {} ", plot_unit.id);
-            self.available_plot_units.push_back(plot_unit);
+            if self.stale_plot_units.remove(&plot_unit.id) {
+                plot_unit.resize(self.width, self.height);
+            }
+            self.available_plot_units[plot_unit.camera].push_back(plot_unit);
         }
 
         println!("");
 
+        // If rendering was restarted while gathering,
+        // the gathered image is useless.
+        let camera = gather_unit.camera;
+        if self.stale_gather_units[camera] {
+            TaskScheduler::resize_gather_unit(&mut gather_unit, self.width, self.height);
+            self.stale_gather_units[camera] = false;
+            self.gather_units[camera] = Some(gather_unit);
+            return;
+        }
+
         // The gather unit can now be used again as well.
-        self.gather_unit = Some(gather_unit);
+        self.gather_units[camera] = Some(gather_unit);
 
         // The image must have changed because of gathering.
-        self.image_changed = true;
+        self.image_changed[camera] = true;
     }
 
     fn complete_tonemap_task(&mut self,
-                             tonemap_unit: Box<TonemapUnit>,
-                             gather_unit: Box<GatherUnit>) {
+                             mut tonemap_unit: Box<TonemapUnit>,
+                             mut gather_unit: Box<GatherUnit>) {
         println!("done tonemapping");
 
+        // If rendering was restarted while tonemapping,
+        // the gathered image is useless.
+        let camera = tonemap_unit.camera;
+        let stale = self.stale_gather_units[camera];
+        if stale {
+            TaskScheduler::resize_gather_unit(&mut gather_unit, self.width, self.height);
+            tonemap_unit.resize(self.width, self.height);
+            self.stale_gather_units[camera] = false;
+        }
+
         // The tonemapper needed the gather unit,
         // so the gather unit is free now.
-        self.gather_unit = Some(gather_unit);
+        self.gather_units[camera] = Some(gather_unit);
 
         // And of course the tonemap unit itself is available again.
-        self.tonemap_unit = Some(tonemap_unit);
+        self.tonemap_units[camera] = Some(tonemap_unit);
 
         // The image is tonemapped now, so until a new gathering happens,
         // it will not change.
-        self.image_changed = false;
-
-        // Measure how many rays per seconds the renderer can handle.
+        self.image_changed[camera] = false;
+        self.tonemapped[camera] = !stale;
         let now = get_time();
-        let render_time = now - self.last_tonemap_time;
+        let render_time = now - self.last_tonemap_times[camera];
+        self.last_tonemap_times[camera] = now;
+
+        // Measure how many rays per seconds the renderer can handle, once
+        // per interval, at the first camera.
+        if camera != 0 { return; }
         let batches_per_sec = self.traces_completed as f32 * 1000.0 /
                               render_time.num_milliseconds() as f32;
-        self.last_tonemap_time = now;
         self.traces_completed = 0;
 
         // Store the latest 512 measurements (should be about 4.25 hours).
@@ -325,3 +853,95 @@ impl TaskScheduler {
         println!("performance: {} +- {} batches/sec", mean, variance.sqrt());
     }
 }
+
+#[test]
+fn progress_is_fraction_of_budget() {
+    let mut ts = TaskScheduler::new(1, 1, 4, 4);
+    ts.set_batch_budget(Some(4));
+    assert_eq!(ts.progress_fraction(), 0.0);
+
+    // Execute tasks like a single worker would, without doing the work.
+    fn next_task(ts: &mut TaskScheduler, task: Task, traced: &mut u32) -> Task {
+        let new_task = ts.get_new_task(task);
+        if let Task::Trace(_) = new_task { *traced += 1; }
+        new_task
+    }
+    let mut traced = 0;
+    let mut task = Task::Sleep;
+
+    // Trace two batches, half of the budget.
+    while traced < 2 { task = next_task(&mut ts, task, &mut traced); }
+    task = next_task(&mut ts, task, &mut traced);
+    assert!((ts.progress_fraction() - 0.5).abs() < 1.0e-6);
+
+    // Once the budget is used up, no more batches are traced.
+    for _ in 0 .. 20 { task = next_task(&mut ts, task, &mut traced); }
+    assert_eq!(traced, 4);
+    assert_eq!(ts.progress_fraction(), 1.0);
+}
+
+#[test]
+fn every_camera_has_its_own_files() {
+    let path = Path::new("/tmp/checkpoint.raw");
+    assert_eq!(camera_path(path, 0), Path::new("/tmp/checkpoint.raw"));
+    assert_eq!(camera_path(path, 2), Path::new("/tmp/checkpoint-2.raw"));
+    assert_eq!(camera_path(Path::new("exposure"), 1), Path::new("exposure-1"));
+}
+
+#[test]
+fn plot_tasks_take_a_batch_of_trace_units() {
+    // Executes tasks like a single worker would, without doing the work, and
+    // returns the number of trace units of every plot task.
+    fn plot_sizes(batch_size: usize) -> Vec<usize> {
+        let mut ts = TaskScheduler::new(4, 1, 4, 4);
+        ts.set_batch_budget(Some(48));
+        ts.set_plot_batch_size(batch_size);
+        let mut sizes = Vec::new();
+        let mut task = Task::Sleep;
+        loop {
+            task = ts.get_new_task(task);
+            match task {
+                Task::Sleep => break,
+                Task::Plot(_, ref units) => sizes.push(units.len()),
+                _ => { }
+            }
+        }
+        sizes
+    }
+
+    // Every batch that was traced is plotted once.
+    let single = plot_sizes(1);
+    let batched = plot_sizes(8);
+    assert_eq!(single.iter().cloned().sum::<usize>(), 48);
+    assert_eq!(batched.iter().cloned().sum::<usize>(), 48);
+
+    // While the budget lasts, plot tasks take a full batch, so there are
+    // fewer of them.
+    assert!(batched[.. batched.len() - 1].iter().all(|&n| n >= 8));
+    assert!(batched.len() < single.len());
+}
+
+#[test]
+fn finished_render_tonemaps_every_camera() {
+    let mut ts = TaskScheduler::new(1, 2, 4, 4);
+    ts.set_batch_budget(Some(4));
+
+    // Execute tasks like a single worker would, without doing the work. The
+    // tonemap interval has not passed, but the final images are tonemapped.
+    let mut tonemapped = vec!(0, 0);
+    let mut task = Task::Sleep;
+    loop {
+        task = ts.get_new_task(task);
+        match task {
+            Task::Sleep => break,
+            Task::Tonemap(ref unit, _) => tonemapped[unit.camera] += 1,
+            _ => assert!(!ts.is_finished())
+        }
+    }
+    assert_eq!(tonemapped, vec!(1, 1));
+    assert!(ts.is_finished());
+
+    // After a restart there is more to render.
+    ts.restart();
+    assert!(!ts.is_finished());
+}
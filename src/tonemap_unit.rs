@@ -15,9 +15,213 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp::PartialOrd;
+use std::f32;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
 use std::iter::repeat;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use cie1931::luminance;
 use vector3::Vector3;
 
+/// Compresses the intensities of an image into the displayable range.
+pub trait ToneMap {
+    /// Maps an intensity, relative to the intensity that the exposure
+    /// chose to be white, to a displayed intensity, which should be in the
+    /// range [0, 1] for a ratio of 1 or less.
+    fn map(&self, luminance_ratio: f32) -> f32;
+}
+
+/// Maps intensities logarithmically, such that white is displayed at 0.5,
+/// the default. This keeps details in highlights up to a few times brighter
+/// than white.
+pub struct LogToneMap;
+
+impl ToneMap for LogToneMap {
+    fn map(&self, luminance_ratio: f32) -> f32 {
+        (luminance_ratio + 1.0).ln() / 4.0f32.ln()
+    }
+}
+
+/// The operator of Reinhard et al., which maps intensity x to x / (1 + x).
+pub struct ReinhardToneMap;
+
+impl ToneMap for ReinhardToneMap {
+    fn map(&self, luminance_ratio: f32) -> f32 {
+        luminance_ratio / (1.0 + luminance_ratio)
+    }
+}
+
+/// An approximation of the ACES filmic curve by Narkowicz, which has more
+/// contrast than the other operators, and saturates smoothly to white.
+pub struct AcesToneMap;
+
+impl ToneMap for AcesToneMap {
+    fn map(&self, luminance_ratio: f32) -> f32 {
+        let x = luminance_ratio;
+        (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+    }
+}
+
+/// The response of a film or sensor, which maps linear luminance
+/// (after exposure) to the luminance that is displayed.
+#[derive(Clone)]
+pub enum ResponseCurve {
+    /// Display luminance as it is, the default.
+    Identity,
+
+    /// A piecewise linear curve through the control points, which must be
+    /// sorted by their first coordinate. Beyond the last point, the curve
+    /// continues with slope 1.
+    Points(Vec<(f32, f32)>)
+}
+
+impl ResponseCurve {
+    /// An S-shaped curve that darkens shadows and brightens highlights,
+    /// for a filmic look with more contrast.
+    pub fn s_curve() -> ResponseCurve {
+        ResponseCurve::Points(vec!((0.0, 0.0), (0.25, 0.15), (0.5, 0.5), (0.75, 0.85), (1.0, 1.0)))
+    }
+
+    /// Maps the luminance `x` through the curve.
+    pub fn apply(&self, x: f32) -> f32 {
+        let points = match *self {
+            ResponseCurve::Identity => return x,
+            ResponseCurve::Points(ref points) => points
+        };
+
+        let (x0, y0) = match points.first() {
+            Some(&first) => first,
+            None => return x
+        };
+        if x <= x0 { return y0; }
+
+        // Interpolate linearly within the segment that contains x.
+        for segment in points.windows(2) {
+            let (xa, ya) = segment[0];
+            let (xb, yb) = segment[1];
+            if x <= xb {
+                return if xb > xa { ya + (yb - ya) * (x - xa) / (xb - xa) } else { yb };
+            }
+        }
+
+        let (xn, yn) = points[points.len() - 1];
+        yn + (x - xn)
+    }
+}
+
+/// Determines how the exposure of an image is chosen.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ExposureMode {
+    /// Expose such that the intensity one standard deviation above the
+    /// average becomes (nearly) white, the default.
+    StandardDeviation,
+
+    /// Equalize the histogram of log luminance, so every luminance is
+    /// displayed at its percentile. This keeps both shadows and highlights
+    /// visible in scenes with a very high dynamic range.
+    Histogram
+}
+
+/// The number of bins in a luminance histogram.
+const HISTOGRAM_BINS: usize = 256;
+
+/// The cumulative histogram of log luminance of an image.
+struct LuminanceHistogram {
+    /// The smallest log luminance in the image.
+    min_log: f32,
+
+    /// The width of a bin, in log luminance.
+    bin_width: f32,
+
+    /// For every bin, the fraction of pixels in the bins before it.
+    cdf: Vec<f32>
+}
+
+impl LuminanceHistogram {
+    /// Builds the histogram of the pixels that have a positive luminance.
+    fn new(tristimuli: &[Vector3]) -> LuminanceHistogram {
+        let logs: Vec<f32> = tristimuli.iter().map(|&cie| luminance(cie))
+                                       .filter(|&y| y > 0.0)
+                                       .map(|y| y.ln()).collect();
+        let min_log = logs.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max_log = logs.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let mut histogram = LuminanceHistogram {
+            min_log: min_log,
+            bin_width: (max_log - min_log) / HISTOGRAM_BINS as f32,
+            cdf: repeat(0.0).take(HISTOGRAM_BINS + 1).collect()
+        };
+
+        for &log in &logs {
+            let bin = histogram.get_bin(log);
+            histogram.cdf[bin + 1] += 1.0;
+        }
+        for i in 0 .. HISTOGRAM_BINS {
+            histogram.cdf[i + 1] += histogram.cdf[i];
+        }
+        let n = logs.len().max(1) as f32;
+        for x in &mut histogram.cdf {
+            *x = *x / n;
+        }
+
+        histogram
+    }
+
+    /// Returns the bin that contains the log luminance `log`.
+    fn get_bin(&self, log: f32) -> usize {
+        if self.bin_width > 0.0 {
+            (((log - self.min_log) / self.bin_width) as usize).min(HISTOGRAM_BINS - 1)
+        } else {
+            0
+        }
+    }
+
+    /// Maps a CIE XYZ value to linear sRGB, such that its luminance becomes
+    /// the fraction of pixels that are darker, counting half of its own bin.
+    fn expose(&self, cie: Vector3) -> Vector3 {
+        let y = luminance(cie);
+        if !(y > 0.0) { return Vector3::zero(); }
+        let bin = self.get_bin(y.ln());
+        let percentile = (self.cdf[bin] + self.cdf[bin + 1]) * 0.5;
+        ::srgb::xyz_to_srgb(cie * (percentile / y))
+    }
+}
+
+/// The exposure that was chosen for an image.
+enum Exposure {
+    /// Intensities are scaled such that `max_intensity` becomes white.
+    Global(f32),
+
+    /// Luminance is mapped through the histogram.
+    Equalized(LuminanceHistogram)
+}
+
+/// Determines what happens to pixels that are not finite (NaN or infinite),
+/// which are usually caused by a bug in a material.
+#[derive(Copy, Clone, PartialEq)]
+pub enum FiniteCheck {
+    /// Do not look for non-finite pixels, the default.
+    Off,
+
+    /// Count non-finite pixels, and store the result in `non_finite`.
+    Report,
+
+    /// Panic when a non-finite pixel is found, useful in tests.
+    Panic
+}
+
+/// The non-finite pixels found in a tonemapped image.
+#[derive(Copy, Clone, Debug)]
+pub struct NonFiniteReport {
+    /// The number of pixels that have a non-finite component.
+    pub count: usize,
+
+    /// The column and row of the first such pixel.
+    pub first: Option<(u32, u32)>
+}
+
 /// Converts the result of a `GatherUnit` into an sRGB image.
 pub struct TonemapUnit {
     /// The width of the canvas (in pixels).
@@ -27,7 +231,63 @@ pub struct TonemapUnit {
     image_height: u32,
 
     /// The buffer of sRGB values.
-    pub rgb_buffer: Vec<u8>
+    pub rgb_buffer: Vec<u8>,
+
+    /// If enabled, the linear sRGB values of the image, clamped like the
+    /// sRGB values, but before gamma correction. These are produced in the
+    /// same pass, with the same exposure.
+    pub linear_buffer: Option<Vec<Vector3>>,
+
+    /// If shared, a copy of the sRGB values that another thread can read at
+    /// any time without locking, one pixel per element as 0x00rrggbb.
+    shared_buffer: Option<Arc<[AtomicU32]>>,
+
+    /// Gains for the red, green and blue channel, applied to linear sRGB
+    /// values before gamma correction. All 1.0 by default.
+    pub white_balance: Vector3,
+
+    /// If set, the rectangle of pixels (x, y, width, height) that the white
+    /// balance is calibrated on before every tonemap, as with a grey card,
+    /// see `calibrate_white_balance`. `None` by default.
+    pub grey_card: Option<(u32, u32, u32, u32)>,
+
+    /// How the exposure is chosen, based on the standard deviation of
+    /// luminance by default.
+    pub exposure_mode: ExposureMode,
+
+    /// The operator that compresses intensities after exposure, applied to
+    /// every CIE XYZ component. `LogToneMap` by default. Not used when the
+    /// exposure mode equalizes the histogram.
+    pub tone_map: Box<ToneMap + Sync + Send>,
+
+    /// The response curve applied to luminance after white balance.
+    pub response_curve: ResponseCurve,
+
+    /// Whether to write linear light values instead of gamma encoded sRGB,
+    /// for compositing in a linear workflow. False by default. The image is
+    /// still exposed and clamped to the displayable range.
+    pub linear_output: bool,
+
+    /// Whether to look for non-finite input pixels when tonemapping.
+    pub finite_check: FiniteCheck,
+
+    /// The non-finite pixels found by the last tonemap, if checked.
+    pub non_finite: Option<NonFiniteReport>,
+
+    /// The intensity that became white in the last tonemap, if the exposure
+    /// was the same for the entire image.
+    last_exposure: Option<f32>,
+
+    /// An exposure of a previous render, loaded by `load_exposure`, that is
+    /// used for the next tonemap instead of finding one.
+    resumed_exposure: Option<f32>,
+
+    /// The index of the scene camera whose image this unit tonemaps.
+    pub camera: usize,
+
+    /// The file that the exposure is saved to and resumed from, if any.
+    /// `None` by default.
+    exposure_path: Option<PathBuf>
 }
 
 /// Clamps `x` to the interval [0, 1].
@@ -45,10 +305,63 @@ impl TonemapUnit {
         TonemapUnit {
             image_width: width,
             image_height: height,
-            rgb_buffer: repeat(0).take(sz * 3).collect()
+            rgb_buffer: repeat(0).take(sz * 3).collect(),
+            linear_buffer: None,
+            shared_buffer: None,
+            white_balance: Vector3::new(1.0, 1.0, 1.0),
+            grey_card: None,
+            exposure_mode: ExposureMode::StandardDeviation,
+            tone_map: Box::new(LogToneMap),
+            response_curve: ResponseCurve::Identity,
+            linear_output: false,
+            finite_check: FiniteCheck::Off,
+            non_finite: None,
+            last_exposure: None,
+            resumed_exposure: None,
+            camera: 0,
+            exposure_path: None
         }
     }
 
+    /// Adapts the unit to a canvas of a different size. The shared buffer
+    /// no longer fits, so it is no longer written to; share a new one.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let sz = (width * height) as usize;
+        self.image_width = width;
+        self.image_height = height;
+        self.rgb_buffer.clear();
+        self.rgb_buffer.extend(repeat(0).take(sz * 3));
+        if let Some(ref mut linear) = self.linear_buffer {
+            linear.clear();
+            linear.extend(repeat(Vector3::zero()).take(sz));
+        }
+        self.shared_buffer = None;
+    }
+
+    /// Enables or disables producing the linear buffer along with the
+    /// sRGB buffer.
+    #[allow(dead_code)]
+    pub fn set_linear_buffer(&mut self, enabled: bool) {
+        let sz = (self.image_width * self.image_height) as usize;
+        self.linear_buffer = if enabled {
+            Some(repeat(Vector3::zero()).take(sz).collect())
+        } else {
+            None
+        };
+    }
+
+    /// Returns a buffer that every tonemap writes the sRGB image into, for a
+    /// user interface that shows the image while rendering. Pixels can be
+    /// read at any time, but during a tonemap, the image may be part old
+    /// and part new. Replaces any previously shared buffer.
+    #[allow(dead_code)]
+    pub fn share_buffer(&mut self) -> Arc<[AtomicU32]> {
+        let sz = (self.image_width * self.image_height) as usize;
+        let buffer: Arc<[AtomicU32]> = (0 .. sz).map(|_| AtomicU32::new(0)).collect();
+        self.shared_buffer = Some(buffer.clone());
+        buffer
+    }
+
     /// Returns an exposure estimate based on the average cieY value.
     /// The returned value is the maximum acceptable intensity, the
     /// intensity that should become (nearly) white.
@@ -58,44 +371,517 @@ impl TonemapUnit {
         // Compute the average intensity.
         // Calculations are based on the CIE Y value,
         // which corresponds to lightness.
-        let mean = tristimuli.iter().map(|cie| cie.y).sum::<f32>() / n;
+        let mean = tristimuli.iter().map(|&cie| luminance(cie)).sum::<f32>() / n;
 
         // Then compute the standard deviation.
-        let sqr_mean = tristimuli.iter().map(|cie| cie.y * cie.y).sum::<f32>() / n;
+        let sqr_mean = tristimuli.iter().map(|&cie| luminance(cie).powi(2)).sum::<f32>() / n;
         let variance = sqr_mean - mean * mean;
 
         // The desired 'white' is one standard deviation above average.
-        mean + variance.sqrt()
+        // An image that is entirely black, for example before any light
+        // was found, stays black with any exposure, but dividing by zero
+        // would turn it into NaNs.
+        let max_intensity = mean + variance.max(0.0).sqrt();
+        if max_intensity > 0.0 { max_intensity } else { 1.0 }
+    }
+
+    /// Chooses the exposure for the image according to the exposure mode.
+    fn get_exposure(&self, tristimuli: &[Vector3]) -> Exposure {
+        match self.exposure_mode {
+            ExposureMode::StandardDeviation => Exposure::Global(self.find_exposure(tristimuli)),
+            ExposureMode::Histogram => Exposure::Equalized(LuminanceHistogram::new(tristimuli))
+        }
+    }
+
+    /// Returns the intensity that became white in the last tonemap, if the
+    /// exposure was the same for the entire image.
+    #[allow(dead_code)]
+    pub fn get_last_exposure(&self) -> Option<f32> {
+        self.last_exposure
+    }
+
+    /// Saves the exposure of the last tonemap to a file, so that a resumed
+    /// render can start with the same exposure. Does nothing if the exposure
+    /// was not the same for the entire image.
+    pub fn save_exposure<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        match self.last_exposure {
+            Some(max_intensity) => {
+                let mut file = try!(File::create(path));
+                writeln!(file, "{}", max_intensity)
+            }
+            None => Ok(())
+        }
+    }
+
+    /// Loads an exposure saved by `save_exposure`, and uses it for the next
+    /// tonemap. When a render is resumed, the buffer it starts with may be
+    /// too sparse to find a good exposure, and the first image would look
+    /// very different from the last one before it stopped. Afterwards the
+    /// exposure is found from the image again.
+    pub fn load_exposure<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        match text.trim().parse::<f32>() {
+            Ok(max_intensity) if max_intensity > 0.0 && max_intensity.is_finite() => {
+                self.resumed_exposure = Some(max_intensity);
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid exposure"))
+        }
+    }
+
+    /// Sets the file that the exposure is saved to by `save`, and resumed
+    /// from by `resume`. With `None`, nothing is saved.
+    pub fn set_exposure_path(&mut self, path: Option<PathBuf>) {
+        self.exposure_path = path;
+    }
+
+    /// Saves the exposure of the last tonemap to the exposure file, if there
+    /// is one. See `save_exposure`.
+    pub fn save(&self) -> io::Result<()> {
+        match self.exposure_path {
+            Some(ref path) => self.save_exposure(path),
+            None => Ok(())
+        }
+    }
+
+    /// Loads the exposure from the exposure file, if there is one, for the
+    /// first tonemap of a resumed render. Returns whether it was loaded.
+    pub fn resume(&mut self) -> bool {
+        let path = match self.exposure_path {
+            Some(ref path) => path.clone(),
+            None => return false
+        };
+        self.load_exposure(path).is_ok()
+    }
+
+    /// Applies exposure correction to a CIE XYZ value, and returns it as
+    /// linear sRGB, before white balance.
+    fn expose(cie: Vector3, exposure: &Exposure, tone_map: &ToneMap) -> Vector3 {
+        let max_intensity = match *exposure {
+            Exposure::Global(max_intensity) => max_intensity,
+            Exposure::Equalized(ref histogram) => return histogram.expose(cie)
+        };
+        let cie = Vector3 {
+            x: tone_map.map(cie.x / max_intensity),
+            y: tone_map.map(cie.y / max_intensity),
+            z: tone_map.map(cie.z / max_intensity)
+        };
+        ::srgb::xyz_to_srgb(cie)
+    }
+
+    /// Sets the white balance such that the rectangle of `width` by `height`
+    /// pixels at (`x`, `y`) becomes neutral grey, like a photographer would
+    /// with a grey card. The exposure is determined from `tristimuli` as in
+    /// `tonemap`. The rectangle is clipped to the image, and if nothing of it
+    /// remains, the white balance is left unchanged.
+    pub fn calibrate_white_balance(&mut self,
+                                   tristimuli: &[Vector3],
+                                   x: u32, y: u32,
+                                   width: u32, height: u32) {
+        let x_end = x.saturating_add(width).min(self.image_width);
+        let y_end = y.saturating_add(height).min(self.image_height);
+        if x >= x_end || y >= y_end { return; }
+
+        let exposure = self.get_exposure(tristimuli);
+        let mut sum = Vector3::zero();
+        for j in y .. y_end {
+            for i in x .. x_end {
+                let cie = tristimuli[(j * self.image_width + i) as usize];
+                sum = sum + TonemapUnit::expose(cie, &exposure, &*self.tone_map);
+            }
+        }
+
+        // Scale red and blue to match green, so the brightness of the
+        // region stays roughly the same. A channel that is black cannot
+        // be corrected.
+        let gain = |c: f32| if c > 0.0 { sum.y / c } else { 1.0 };
+        self.white_balance = Vector3::new(gain(sum.x), 1.0, gain(sum.z));
+    }
+
+    /// Counts the pixels that have a non-finite component.
+    fn find_non_finite(&self, tristimuli: &[Vector3]) -> NonFiniteReport {
+        let mut report = NonFiniteReport { count: 0, first: None };
+        for (i, cie) in tristimuli.iter().enumerate() {
+            if !(cie.x.is_finite() && cie.y.is_finite() && cie.z.is_finite()) {
+                if report.first.is_none() {
+                    let i = i as u32;
+                    report.first = Some((i % self.image_width, i / self.image_width));
+                }
+                report.count += 1;
+            }
+        }
+        report
     }
 
     /// Converts the unweighted CIE XYZ values in the buffer
     /// to tonemapped sRGB values.
     pub fn tonemap(&mut self, tristimuli: &[Vector3]) {
-        let max_intensity = self.find_exposure(tristimuli);
+        self.non_finite = match self.finite_check {
+            FiniteCheck::Off => None,
+            _ => Some(self.find_non_finite(tristimuli))
+        };
+        if let Some(report) = self.non_finite {
+            if report.count > 0 && self.finite_check == FiniteCheck::Panic {
+                panic!("{} non-finite pixels, the first at {:?}", report.count, report.first.unwrap());
+            }
+        }
+        if let Some((x, y, width, height)) = self.grey_card {
+            self.calibrate_white_balance(tristimuli, x, y, width, height);
+        }
+
+        let exposure = match self.resumed_exposure.take() {
+            Some(max_intensity) if self.exposure_mode == ExposureMode::StandardDeviation => {
+                Exposure::Global(max_intensity)
+            }
+            _ => self.get_exposure(tristimuli)
+        };
+        self.last_exposure = match exposure {
+            Exposure::Global(max_intensity) => Some(max_intensity),
+            Exposure::Equalized(_) => None
+        };
+        let white_balance = self.white_balance;
         let buffer = (&mut self.rgb_buffer).chunks_mut(3);
-        let ln_4 = 4.0f32.ln();
 
         // Loop through all pixels.
-        for (px, cie) in buffer.zip(tristimuli.iter()) {
-            // Apply exposure correction.
-            let cie = Vector3 {
-                x: (cie.x / max_intensity + 1.0).ln() / ln_4,
-                y: (cie.y / max_intensity + 1.0).ln() / ln_4,
-                z: (cie.z / max_intensity + 1.0).ln() / ln_4
+        for (i, (px, &cie)) in buffer.zip(tristimuli.iter()).enumerate() {
+            // Apply exposure correction and white balance.
+            let linear = TonemapUnit::expose(cie, &exposure, &*self.tone_map);
+            let balanced = Vector3 {
+                x: linear.x * white_balance.x,
+                y: linear.y * white_balance.y,
+                z: linear.z * white_balance.z
             };
 
-            // Then convert to sRGB.
-            let rgb = ::srgb::transform(cie);
+            // Then the film response, which changes the luminance
+            // but preserves the colour.
+            let luma = ::srgb::luma(balanced);
+            let response = if luma > 0.0 {
+                balanced * (self.response_curve.apply(luma) / luma)
+            } else {
+                balanced
+            };
 
             // Clamp colours to saturate.
-            let r = clamp(rgb.x);
-            let g = clamp(rgb.y);
-            let b = clamp(rgb.z);
+            let clamped = Vector3::new(clamp(response.x), clamp(response.y), clamp(response.z));
+            if let Some(ref mut linear) = self.linear_buffer {
+                linear[i] = clamped;
+            }
+
+            // Then convert to sRGB, unless linear output is requested.
+            // Gamma correction maps [0, 1] onto itself, so it does not
+            // matter that the colour was clamped first.
+            let rgb = if self.linear_output {
+                clamped
+            } else {
+                ::srgb::gamma_correct_rgb(clamped)
+            };
+            let (r, g, b) = (rgb.x, rgb.y, rgb.z);
 
             // Then convert to integers.
             px[0] = (r * 255.0) as u8;
             px[1] = (g * 255.0) as u8;
             px[2] = (b * 255.0) as u8;
+
+            if let Some(ref shared) = self.shared_buffer {
+                let packed = (px[0] as u32) << 16 | (px[1] as u32) << 8 | px[2] as u32;
+                shared[i].store(packed, Ordering::Relaxed);
+            }
         }
     }
 }
+
+#[test]
+fn grey_card_becomes_neutral() {
+    // An image with a warm colour cast everywhere.
+    let cast = ::srgb::srgb_to_xyz(Vector3::new(0.6, 0.4, 0.3));
+    let tristimuli: Vec<Vector3> = repeat(cast).take(16).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+
+    unit.tonemap(&tristimuli);
+    assert!(unit.rgb_buffer[0] > unit.rgb_buffer[2] + 10);
+
+    unit.calibrate_white_balance(&tristimuli, 1, 1, 2, 2);
+    unit.tonemap(&tristimuli);
+
+    let (mut r, mut g, mut b) = (0u32, 0u32, 0u32);
+    for &(i, j) in [(1, 1), (2, 1), (1, 2), (2, 2)].iter() {
+        let px = &unit.rgb_buffer[(j * 4 + i) * 3 ..];
+        r += px[0] as u32;
+        g += px[1] as u32;
+        b += px[2] as u32;
+    }
+    assert!((r as i32 - g as i32).abs() <= 4);
+    assert!((b as i32 - g as i32).abs() <= 4);
+}
+
+#[test]
+fn grey_card_outside_image_is_clipped() {
+    let cast = ::srgb::srgb_to_xyz(Vector3::new(0.6, 0.4, 0.3));
+    let tristimuli: Vec<Vector3> = repeat(cast).take(16).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+
+    // Entirely outside of the image, nothing changes.
+    unit.calibrate_white_balance(&tristimuli, 4, 0, 2, 2);
+    unit.calibrate_white_balance(&tristimuli, 0, 9, 2, 2);
+    assert_eq!((unit.white_balance.x, unit.white_balance.z), (1.0, 1.0));
+
+    // Partly outside, the part inside is used.
+    unit.calibrate_white_balance(&tristimuli, 3, 3, 8, ::std::u32::MAX);
+    assert!(unit.white_balance.x < 1.0);
+    assert!(unit.white_balance.z > 1.0);
+}
+
+#[test]
+fn grey_card_is_calibrated_on_every_tonemap() {
+    let cast = ::srgb::srgb_to_xyz(Vector3::new(0.6, 0.4, 0.3));
+    let tristimuli: Vec<Vector3> = repeat(cast).take(16).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+    unit.grey_card = Some((1, 1, 2, 2));
+    unit.tonemap(&tristimuli);
+
+    // The cast is removed, so every pixel is grey.
+    let rgb = &unit.rgb_buffer[.. 3];
+    assert!((rgb[0] as i32 - rgb[1] as i32).abs() <= 1);
+    assert!((rgb[2] as i32 - rgb[1] as i32).abs() <= 1);
+}
+
+#[test]
+fn s_curve_increases_contrast() {
+    // A dark and a bright pixel, with average pixels in between.
+    let grey = |y: f32| Vector3::new(0.9505 * y, y, 1.089 * y);
+    let tristimuli = vec!(grey(0.1), grey(1.0), grey(1.0), grey(3.0));
+    let mut unit = TonemapUnit::new(2, 2);
+
+    unit.tonemap(&tristimuli);
+    let plain = unit.rgb_buffer.clone();
+
+    unit.response_curve = ResponseCurve::Points(vec!((0.0, 0.0), (1.0, 1.0)));
+    unit.tonemap(&tristimuli);
+    assert_eq!(unit.rgb_buffer, plain);
+
+    unit.response_curve = ResponseCurve::s_curve();
+    unit.tonemap(&tristimuli);
+    assert!(unit.rgb_buffer[1] < plain[1]);
+    assert!(unit.rgb_buffer[10] > plain[10]);
+}
+
+#[test]
+fn non_finite_pixel_is_reported() {
+    let mut tristimuli: Vec<Vector3> = repeat(Vector3::new(0.5, 0.5, 0.5)).take(12).collect();
+    tristimuli[7] = Vector3::new(0.5, f32::NAN, 0.5);
+    let mut unit = TonemapUnit::new(4, 3);
+
+    unit.tonemap(&tristimuli);
+    assert!(unit.non_finite.is_none());
+
+    unit.finite_check = FiniteCheck::Report;
+    unit.tonemap(&tristimuli);
+    let report = unit.non_finite.unwrap();
+    assert_eq!(report.count, 1);
+    assert_eq!(report.first, Some((3, 1)));
+}
+
+#[test]
+fn histogram_exposure_keeps_both_modes_visible() {
+    // Half of the image is very dark, the other half very bright.
+    let grey = |y: f32| Vector3::new(0.9505 * y, y, 1.089 * y);
+    let tristimuli: Vec<Vector3> = (0 .. 16).map(|i| if i < 8 { grey(0.01) } else { grey(100.0) }).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+
+    // A global exposure for the highlights crushes the shadows to black.
+    unit.tonemap(&tristimuli);
+    assert_eq!(unit.rgb_buffer[0], 0);
+
+    unit.exposure_mode = ExposureMode::Histogram;
+    unit.tonemap(&tristimuli);
+    let dark = unit.rgb_buffer[0];
+    let bright = unit.rgb_buffer[8 * 3];
+    assert!(dark > 64);
+    assert!(bright < 255);
+    assert!(bright > dark + 64);
+}
+
+#[test]
+fn linear_output_skips_gamma() {
+    // In a uniform image, the exposure maps the luminance to exactly 0.5.
+    let grey = Vector3::new(0.9505, 1.0, 1.089);
+    let tristimuli: Vec<Vector3> = repeat(grey).take(16).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+
+    unit.tonemap(&tristimuli);
+    let encoded = unit.rgb_buffer[1] as i32;
+    unit.linear_output = true;
+    unit.tonemap(&tristimuli);
+    let linear = unit.rgb_buffer[1] as i32;
+
+    assert!((encoded - 188).abs() <= 3);
+    assert!((linear - 128).abs() <= 3);
+}
+
+#[test]
+fn black_batch_stays_black() {
+    use gather_unit::GatherUnit;
+    use plot_unit::PlotUnit;
+    use trace_unit::MappedPhoton;
+
+    let photons: Vec<MappedPhoton> = (0 .. 64).map(|i| MappedPhoton {
+        x: (i % 8) as f32 / 4.0 - 1.0,
+        y: (i / 8) as f32 / 8.0 - 0.5,
+        probability: 0.0,
+        wavelength: 550.0,
+        wavelength_pdf: 1.0,
+        tristimulus: None,
+        light_group: 0
+    }).collect();
+    let mut plot_unit = PlotUnit::new(0, 8, 4);
+    plot_unit.plot(&photons);
+    assert!(plot_unit.tristimulus_buffer.iter().all(|px| (px.x, px.y, px.z) == (0.0, 0.0, 0.0)));
+
+    let mut gather_unit = GatherUnit::new(8, 4);
+    gather_unit.clear();
+    gather_unit.accumulate(&plot_unit.tristimulus_buffer);
+
+    let mut unit = TonemapUnit::new(8, 4);
+    unit.finite_check = FiniteCheck::Panic;
+    let exposure = unit.get_exposure(&gather_unit.tristimulus_buffer);
+    for &cie in &gather_unit.tristimulus_buffer {
+        let rgb = TonemapUnit::expose(cie, &exposure, &*unit.tone_map);
+        assert_eq!((rgb.x, rgb.y, rgb.z), (0.0, 0.0, 0.0));
+    }
+    unit.tonemap(&gather_unit.tristimulus_buffer);
+    assert!(unit.rgb_buffer.iter().all(|&c| c == 0));
+}
+
+#[test]
+fn linear_buffer_matches_srgb_buffer() {
+    let tristimuli: Vec<Vector3> = (0 .. 16).map(|i| {
+        let x = i as f32;
+        Vector3::new(0.3 * x, 0.2 * x + 0.1, 0.05 * x * x)
+    }).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+    unit.set_linear_buffer(true);
+    unit.tonemap(&tristimuli);
+
+    let linear = unit.linear_buffer.as_ref().unwrap();
+    for (px, &rgb) in unit.rgb_buffer.chunks(3).zip(linear.iter()) {
+        let encoded = ::srgb::gamma_correct_rgb(rgb);
+        assert_eq!(px[0], (encoded.x * 255.0) as u8);
+        assert_eq!(px[1], (encoded.y * 255.0) as u8);
+        assert_eq!(px[2], (encoded.z * 255.0) as u8);
+    }
+}
+
+#[test]
+fn shared_buffer_reader_sees_image_fill_in() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    let mut unit = TonemapUnit::new(4, 4);
+    let shared = unit.share_buffer();
+    let done = Arc::new(AtomicBool::new(false));
+
+    // Watch the number of lit pixels from another thread, like a user
+    // interface would, while the image is being rendered.
+    let reader = {
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut counts = Vec::new();
+            while !done.load(Ordering::SeqCst) {
+                counts.push(shared.iter().filter(|px| px.load(Ordering::Relaxed) != 0).count());
+            }
+            counts.push(shared.iter().filter(|px| px.load(Ordering::Relaxed) != 0).count());
+            counts
+        })
+    };
+
+    // Light one more pixel with every batch.
+    let mut tristimuli: Vec<Vector3> = repeat(Vector3::zero()).take(16).collect();
+    for i in 0 .. 16 {
+        tristimuli[i] = Vector3::new(1.0, 1.0, 1.0);
+        unit.tonemap(&tristimuli);
+    }
+    done.store(true, Ordering::SeqCst);
+
+    let counts = reader.join().unwrap();
+    assert!(counts.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(*counts.last().unwrap(), 16);
+}
+
+#[test]
+fn saved_exposure_is_used_after_loading() {
+    let tristimuli: Vec<Vector3> = (0 .. 16).map(|i| {
+        Vector3::new(0.1, 0.1, 0.1) * i as f32
+    }).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+    unit.tonemap(&tristimuli);
+    let name = format!("robigo_luculenta_test_exposure_{}.txt", ::std::process::id());
+    let path = ::std::env::temp_dir().join(name);
+    unit.save_exposure(&path).unwrap();
+
+    // A resumed render that has found only some of the pixels yet.
+    let sparse: Vec<Vector3> = tristimuli.iter().enumerate()
+        .map(|(i, &cie)| if i % 4 == 0 { cie } else { Vector3::zero() })
+        .collect();
+    let mut resumed = TonemapUnit::new(4, 4);
+    resumed.load_exposure(&path).unwrap();
+    ::std::fs::remove_file(&path).unwrap();
+    resumed.tonemap(&sparse);
+
+    // The pixels that it has are as bright as before.
+    assert_eq!(resumed.get_last_exposure(), unit.get_last_exposure());
+    for i in (0 .. 16).filter(|i| i % 4 == 0) {
+        assert_eq!(&resumed.rgb_buffer[i * 3 .. i * 3 + 3], &unit.rgb_buffer[i * 3 .. i * 3 + 3]);
+    }
+}
+
+#[test]
+fn identity_tone_map_preserves_luminance() {
+    struct IdentityToneMap;
+
+    impl ToneMap for IdentityToneMap {
+        fn map(&self, luminance_ratio: f32) -> f32 {
+            luminance_ratio
+        }
+    }
+
+    // Shades of the white point of sRGB, so no channel is clamped below 0.
+    let white = Vector3::new(0.9505, 1.0, 1.089);
+    let tristimuli: Vec<Vector3> = (0 .. 16).map(|i| white * (i as f32 / 16.0)).collect();
+    let mut unit = TonemapUnit::new(4, 4);
+    unit.tone_map = Box::new(IdentityToneMap);
+    unit.set_linear_buffer(true);
+    unit.tonemap(&tristimuli);
+
+    // Below the exposure, nothing is clamped above 1 either.
+    let exposure = unit.get_last_exposure().unwrap();
+    let linear = unit.linear_buffer.as_ref().unwrap();
+    for (&cie, &rgb) in tristimuli.iter().zip(linear.iter()) {
+        let ratio = luminance(cie) / exposure;
+        if ratio <= 1.0 {
+            assert!((::srgb::luma(rgb) - ratio).abs() < 1.0e-3);
+        }
+    }
+}
+
+#[test]
+fn exposure_is_only_saved_with_a_path() {
+    let tristimuli: Vec<Vector3> = repeat(Vector3::new(0.5, 0.5, 0.5)).take(16).collect();
+    let name = format!("robigo_luculenta_test_exposure_path_{}.txt", ::std::process::id());
+    let path = ::std::env::temp_dir().join(name);
+    let _ = ::std::fs::remove_file(&path);
+
+    // Without a path, saving does nothing, and there is nothing to resume.
+    let mut unit = TonemapUnit::new(4, 4);
+    unit.tonemap(&tristimuli);
+    unit.save().unwrap();
+    assert!(!unit.resume());
+    assert!(!path.exists());
+
+    unit.set_exposure_path(Some(path.clone()));
+    unit.save().unwrap();
+    let mut resumed = TonemapUnit::new(4, 4);
+    resumed.set_exposure_path(Some(path.clone()));
+    assert!(resumed.resume());
+    ::std::fs::remove_file(&path).unwrap();
+}
@@ -14,10 +14,23 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use std::iter::repeat;
-use object::MaterialBox::{Emissive, Reflective};
+use std::f32::consts::PI;
+use std::mem;
+use camera::Camera;
+use intersection::Intersection;
+use material::Material;
+use medium::Medium;
+use monte_carlo::{SOBOL_DIMENSIONS, Sobol, WavelengthSampling};
+use object::Object;
+use object::MaterialBox::{Emissive, EmissiveReflective, Reflective};
 use ray::Ray;
 use scene::Scene;
+use screen::{ndc_to_screen, pixel_to_ndc};
+use vector3::{Vector3, dot};
+
+/// In the edges render mode, neighbouring normals with a smaller cosine
+/// than this (an angle of more than about 25 degrees) form an edge.
+const EDGE_MIN_COS_ANGLE: f32 = 0.9;
 
 /// Represents a photon that has been traced.
 #[derive(Copy, Clone)]
@@ -33,18 +46,184 @@ pub struct MappedPhoton {
     pub probability: f32,
 
     /// The wavelength of the simulated photon (in nm).
-    pub wavelength: f32
+    pub wavelength: f32,
+
+    /// The probability density with which the wavelength was chosen,
+    /// relative to choosing it uniformly.
+    pub wavelength_pdf: f32,
+
+    /// For debug render modes, the CIE XYZ value to plot directly,
+    /// instead of the response to the wavelength.
+    pub tristimulus: Option<Vector3>,
+
+    /// The light group of the light that contributed most to the photon.
+    pub light_group: usize
 }
 
-impl MappedPhoton {
-    fn new() -> MappedPhoton {
-        MappedPhoton {
-            x: 0.0,
-            y: 0.0,
-            probability: 0.0,
-            wavelength: 0.0
+/// Determines what a trace unit computes for a camera ray.
+#[derive(Copy, Clone)]
+pub enum RenderMode {
+    /// Full spectral path tracing, the default.
+    PathTracing,
+
+    /// Ignore materials and render a grey ambient occlusion image instead.
+    /// A surface is lit if a diffuse probe ray does not hit anything within
+    /// the specified radius.
+    AmbientOcclusion(f32),
+
+    /// Render the surface normal of the first intersection as a colour,
+    /// where the x, y and z components map to red, green and blue.
+    Normals,
+
+    /// Render the distance to the first intersection as a grey value.
+    Depth,
+
+    /// Render the edges of objects in white, and everything else in black.
+    /// A pixel is on an edge if the ray through the neighbouring pixel to
+    /// the right or below hits a different object, or a surface at a very
+    /// different angle. This works best with a pinhole camera.
+    Edges,
+
+    /// Start paths at the lights instead of at the camera, and connect
+    /// every surface they hit to the camera. This finds caustics that camera
+    /// paths rarely find, but lights that are seen directly, and surfaces
+    /// that cannot be evaluated, such as mirrors, are not visible.
+    LightTracing
+}
+
+/// Determines how light that reaches a surface directly from a light
+/// source is found.
+#[derive(Copy, Clone)]
+pub enum DirectLighting {
+    /// Only count light when a path happens to hit a light, the default.
+    BsdfSampling,
+
+    /// At every surface, send a ray towards a random point on a light.
+    /// Lights are then only counted when hit directly by the camera, or
+    /// after a bounce off a material that cannot be evaluated.
+    LightSampling,
+
+    /// Do both, and weight the two estimates with the power heuristic.
+    /// This works well for small lights and for glossy surfaces alike.
+    MultipleImportance
+}
+
+/// Determines how the screen positions of camera paths are chosen.
+#[derive(Copy, Clone)]
+pub enum ScreenSampling {
+    /// Every position on the screen is equally likely, the default.
+    Uniform,
+
+    /// Positions near a point of interest, given in normalised device
+    /// coordinates, are chosen more often, so the image converges faster
+    /// there. Photons are weighted to compensate, so the image converges to
+    /// the same result. The third value is the strength in [0, 1): the
+    /// fraction of positions that are concentrated towards the point.
+    Focused(f32, f32, f32)
+}
+
+/// Returns the probability density of a coordinate `x` in [-1, 1] that is
+/// chosen uniformly with probability `1 - strength`, and from a triangle
+/// that peaks at `focus` otherwise.
+fn get_focused_pdf(x: f32, focus: f32, strength: f32) -> f32 {
+    let triangle = if x < focus { (x + 1.0) / (focus + 1.0) } else { (1.0 - x) / (1.0 - focus) };
+    (1.0 - strength) * 0.5 + strength * triangle
+}
+
+/// Maps `t` in [0, 1] to a coordinate in [-1, 1] with the density of
+/// `get_focused_pdf`, by inverting its cumulative distribution.
+fn sample_focused(t: f32, focus: f32, strength: f32) -> f32 {
+    // Left of the focus, the cumulative distribution is quadratic in the
+    // distance to -1, and right of it in the distance to 1. For any strength,
+    // the probability of ending up left of the focus is (focus + 1) / 2.
+    let solve = |p: f32, width: f32| {
+        let a = strength / (2.0 * width);
+        let b = (1.0 - strength) * 0.5;
+        2.0 * p / (b + (b * b + 4.0 * a * p).sqrt())
+    };
+    if t < (focus + 1.0) * 0.5 {
+        -1.0 + solve(t, focus + 1.0)
+    } else {
+        1.0 - solve(1.0 - t, 1.0 - focus)
+    }
+}
+
+/// Keeps the point of interest away from the edges, and the strength below
+/// 1.0, so that every position on the screen can still be chosen.
+fn clamp_focus(fu: f32, fv: f32, strength: f32) -> (f32, f32, f32) {
+    (fu.max(-0.999).min(0.999), fv.max(-0.999).min(0.999), strength.max(0.0).min(0.99))
+}
+
+impl ScreenSampling {
+    /// Returns the position in normalised device coordinates that the point
+    /// (`u`, `v`) in the unit square maps to.
+    pub fn sample_at(self, u: f32, v: f32) -> (f32, f32) {
+        match self {
+            ScreenSampling::Uniform => (u * 2.0 - 1.0, v * 2.0 - 1.0),
+            ScreenSampling::Focused(fu, fv, strength) => {
+                let (fu, fv, strength) = clamp_focus(fu, fv, strength);
+                (sample_focused(u, fu, strength), sample_focused(v, fv, strength))
+            }
         }
     }
+
+    /// Returns the probability density of `sample_at` returning the position
+    /// (in normalised device coordinates), relative to uniform sampling.
+    pub fn get_relative_pdf(self, u: f32, v: f32) -> f32 {
+        match self {
+            ScreenSampling::Uniform => 1.0,
+            ScreenSampling::Focused(fu, fv, strength) => {
+                let (fu, fv, strength) = clamp_focus(fu, fv, strength);
+                get_focused_pdf(u, fu, strength) * get_focused_pdf(v, fv, strength) * 4.0
+            }
+        }
+    }
+}
+
+/// A wavelength that follows the path of another wavelength, the hero
+/// wavelength, instead of being traced along a path of its own.
+struct SecondaryWavelength {
+    /// The wavelength, in nm.
+    wavelength: f32,
+
+    /// The fraction of light at this wavelength that the path transports
+    /// so far. It becomes zero when the path splits.
+    intensity: f32,
+
+    /// Light at this wavelength that was found along the path.
+    gathered: f32
+}
+
+/// The inside of a transmissive object, or of an object filled with a
+/// medium, that a path is in.
+struct Interior {
+    /// The index of refraction inside.
+    ior: f32,
+
+    /// The absorption coefficient of the material (per meter), at the
+    /// wavelength of the ray.
+    absorption: f32,
+
+    /// The participating medium that fills the inside, if any.
+    medium: Option<Medium>
+}
+
+/// Returns a copy of the ray, at a different wavelength.
+fn at_wavelength(ray: &Ray, wavelength: f32) -> Ray {
+    Ray {
+        origin: ray.origin,
+        direction: ray.direction,
+        wavelength: wavelength,
+        probability: ray.probability
+    }
+}
+
+/// Returns the power heuristic weight (with exponent 2) of a sample that was
+/// drawn with density `pdf`, when another strategy has density `other_pdf`.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let p = pdf * pdf;
+    let q = other_pdf * other_pdf;
+    if p + q > 0.0 { p / (p + q) } else { 0.0 }
 }
 
 /// Handles ray tracing.
@@ -52,9 +231,106 @@ pub struct TraceUnit {
     /// The aspect ratio of the image that will be rendered.
     aspect_ratio: f32,
 
-    /// The photons that were rendered.
+    /// The size of the image in pixels.
+    width: u32,
+    height: u32,
+
+    /// The number of camera paths traced with a frame seed so far.
+    seeded_paths: u32,
+
+    /// The number of batches rendered with a seed so far.
+    seeded_batches: u32,
+
+    /// If enabled, the sequence that the screen position, wavelength and
+    /// point on the lens of camera paths are taken from.
+    sobol: Option<Sobol>,
+
+    /// The index of the next point of the Sobol sequence.
+    sobol_index: u32,
+
+    /// The number of paths to trace in one batch.
+    batch_size: usize,
+
+    /// The photons that were rendered. For camera paths there is one photon
+    /// per path and wavelength, a light path can produce any number of them.
     pub mapped_photons: Vec<MappedPhoton>,
 
+    /// The number of bounces that a path always makes before Russian
+    /// roulette can terminate it. Defaults to 0, which means roulette
+    /// applies from the first bounce onwards.
+    pub min_depth: u32,
+
+    /// The number of bounces after which a path is always terminated, no
+    /// limit by default. A limit makes rendering faster, but darker, as the
+    /// light that longer paths would find is lost.
+    pub max_depth: Option<u32>,
+
+    /// Whether a path that reaches the maximum depth estimates the light
+    /// that its next bounce would have found by sampling a light directly,
+    /// instead of losing it. Off by default. Only lights with a finite area
+    /// can be sampled, so indirect light is still lost.
+    pub max_depth_fallback: bool,
+
+    /// The factor by which the chance of continuing a path decreases at
+    /// every bounce, 0.96 by default. Lower values give shorter paths,
+    /// which are faster to trace but noisier.
+    pub continue_chance_decay: f32,
+
+    /// How likely Russian roulette is to terminate a path, 0.85 by default.
+    /// At 0.0 paths are never terminated, higher values terminate paths
+    /// sooner.
+    pub roulette_threshold: f32,
+
+    /// How sharply the chance of continuing falls off for paths with a low
+    /// intensity, 20.0 by default. With higher values, dim paths are traced
+    /// longer, which helps for dim scenes.
+    pub roulette_falloff: f32,
+
+    /// What to render, path tracing by default.
+    pub render_mode: RenderMode,
+
+    /// How the wavelengths of photons are chosen, uniformly by default.
+    pub wavelength_sampling: WavelengthSampling,
+
+    /// How the screen positions of camera paths are chosen, uniformly by
+    /// default. Not used when paths go through the pixels in order, with a
+    /// frame seed.
+    pub screen_sampling: ScreenSampling,
+
+    /// How direct light is found, by following the material by default.
+    pub direct_lighting: DirectLighting,
+
+    /// The number of additional wavelengths that every camera path carries,
+    /// 0 by default. They are spread evenly over the spectrum, offset from
+    /// the wavelength of the path, and share the path until it reaches a
+    /// material that treats wavelengths differently, such as glass. Then
+    /// only the wavelength of the path continues. This reduces colour noise
+    /// at little cost. Only used for path tracing without light sampling,
+    /// and with a camera without chromatic abberation.
+    pub secondary_wavelengths: u32,
+
+    /// The index of the scene camera through which this unit renders.
+    pub camera: usize,
+
+    /// If set, camera paths go through the pixels in order, and every path
+    /// draws its random numbers from a generator seeded from its pixel, its
+    /// sample number, and this seed. Frames of an animation that are rendered
+    /// with the same seed then have the same noise pattern, so it does not
+    /// flicker. Light paths are not seeded. `None` by default.
+    pub frame_seed: Option<u32>,
+
+    /// If set, every batch draws its random numbers from a generator seeded
+    /// from this seed, the ID of the unit, and the batch number, so a render
+    /// can be reproduced, and units with different IDs never trace the same
+    /// paths. Camera paths with a frame seed are seeded by that instead.
+    /// `None` by default.
+    pub seed: Option<u32>,
+
+    /// For debugging: when set, paths that escape the scene see a magenta
+    /// void instead of the background, to tell escaped paths apart from
+    /// absorbed ones. Off by default.
+    pub debug_void: bool,
+
     /// An ID for identifying this unit in the UI.
     pub id: usize
 }
@@ -71,41 +347,550 @@ impl TraceUnit {
 
         TraceUnit {
             aspect_ratio: width as f32 / height as f32,
-            mapped_photons: repeat(MappedPhoton::new()).take(NUMBER_OF_PHOTONS).collect(),
+            width: width,
+            height: height,
+            seeded_paths: 0,
+            seeded_batches: 0,
+            sobol: None,
+            sobol_index: 0,
+            batch_size: NUMBER_OF_PHOTONS,
+            mapped_photons: Vec::with_capacity(NUMBER_OF_PHOTONS),
+            min_depth: 0,
+            max_depth: None,
+            max_depth_fallback: false,
+            continue_chance_decay: 0.96,
+            roulette_threshold: 0.85,
+            roulette_falloff: 20.0,
+            render_mode: RenderMode::PathTracing,
+            wavelength_sampling: WavelengthSampling::Uniform,
+            screen_sampling: ScreenSampling::Uniform,
+            direct_lighting: DirectLighting::BsdfSampling,
+            secondary_wavelengths: 0,
+            camera: 0,
+            frame_seed: None,
+            seed: None,
+            debug_void: false,
             id: id
         }
     }
 
+    /// Returns the number of batches rendered with a seed so far, which is
+    /// where the next batch continues in the stream of random numbers.
+    pub fn get_seeded_batches(&self) -> u32 {
+        self.seeded_batches
+    }
+
+    /// Continues the stream of random numbers of a seeded render at batch
+    /// `batches`, to resume a render as if it never stopped.
+    pub fn set_seeded_batches(&mut self, batches: u32) {
+        self.seeded_batches = batches;
+    }
+
+    /// Adapts the unit to render an image of a different size. The photon
+    /// buffer does not depend on the size, so it is kept.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.aspect_ratio = width as f32 / height as f32;
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Enables or disables choosing the screen position, wavelength and point
+    /// on the lens of camera paths from a scrambled Sobol sequence, instead of
+    /// randomly. Quasi-random points cover the screen more evenly, so the
+    /// image converges faster. Units scramble the sequence by their ID, so
+    /// they do not trace the same points.
+    pub fn set_sobol_sampling(&mut self, enabled: bool) {
+        self.sobol = if enabled { Some(Sobol::new(Some(self.id as u32))) } else { None };
+        self.sobol_index = 0;
+    }
+
+    /// Returns the next point of the Sobol sequence, if it is enabled.
+    fn next_sobol_point(&mut self) -> Option<[f32; SOBOL_DIMENSIONS]> {
+        let sobol = match self.sobol {
+            Some(ref sobol) => sobol,
+            None => return None
+        };
+        let mut point = [0.0; SOBOL_DIMENSIONS];
+        for (d, x) in point.iter_mut().enumerate() {
+            *x = sobol.get(self.sobol_index, d);
+        }
+        self.sobol_index = self.sobol_index.wrapping_add(1);
+        Some(point)
+    }
+
     /// Return the contribution of a photon travelling backwards
-    /// the specified ray.
-    fn render_ray(scene: &Scene, initial_ray: Ray) -> f32 {
+    /// the specified ray at time `t`, and the light group it belongs to. The
+    /// light at secondary wavelengths is added to the secondaries.
+    fn render_ray(&self, scene: &Scene, initial_ray: Ray, t: f32,
+                  secondaries: &mut [SecondaryWavelength]) -> (f32, usize) {
+        let (contribution, _, light_group) = self.trace_path(scene, initial_ray, t, secondaries);
+        (contribution, light_group)
+    }
+
+    /// Returns the secondary wavelengths for a path at `wavelength`, evenly
+    /// spaced and wrapping around the visible spectrum.
+    fn get_secondary_wavelengths(&self, wavelength: f32) -> Vec<SecondaryWavelength> {
+        let n = self.secondary_wavelengths;
+        (1 .. n + 1).map(|i| {
+            let offset = i as f32 * 400.0 / (n + 1) as f32;
+            SecondaryWavelength {
+                wavelength: 380.0 + (wavelength - 380.0 + offset) % 400.0,
+                intensity: 1.0,
+                gathered: 0.0
+            }
+        }).collect()
+    }
+
+    /// Returns the weight of light that was sampled directly, with density
+    /// `light_pdf`, when following the material has density `bsdf_pdf`.
+    fn get_light_weight(&self, light_pdf: f32, bsdf_pdf: f32) -> f32 {
+        match self.direct_lighting {
+            DirectLighting::BsdfSampling => 0.0,
+            DirectLighting::LightSampling => 1.0,
+            DirectLighting::MultipleImportance => power_heuristic(light_pdf, bsdf_pdf)
+        }
+    }
+
+    /// Returns the weight of a light that was hit by following the material
+    /// with density `bsdf_pdf`, when sampling it has density `light_pdf`.
+    fn get_bsdf_weight(&self, bsdf_pdf: f32, light_pdf: f32) -> f32 {
+        match self.direct_lighting {
+            DirectLighting::BsdfSampling => 1.0,
+            DirectLighting::LightSampling => 0.0,
+            DirectLighting::MultipleImportance => power_heuristic(bsdf_pdf, light_pdf)
+        }
+    }
+
+    /// Returns the probability density (per steradian) with which light
+    /// sampling would choose the direction from `origin` to the intersection
+    /// with a light.
+    fn get_light_direction_pdf(scene: &Scene,
+                               light: &Object,
+                               ray: &Ray,
+                               intersection: &Intersection)
+                               -> f32 {
+        // Convert the density per unit area to a density per solid angle.
+        let cos_light = dot(ray.direction, intersection.normal).abs();
+        let d = intersection.distance;
+        if cos_light > 0.0 {
+            scene.get_light_pdf(light, Some(ray.origin)) * d * d / cos_light
+        } else {
+            0.0
+        }
+    }
+
+    /// Sends a ray from the intersection towards a random point on a light,
+    /// and returns the weighted intensity of the light that arrives there and
+    /// continues along the path, together with the light group of the light.
+    /// If `remainder` is true, the light is weighted with the part that
+    /// following the material would have found, for a path that ends. The
+    /// light emits as it does at time `t`.
+    fn sample_direct_light(&self,
+                           scene: &Scene,
+                           incoming_ray: &Ray,
+                           intersection: &Intersection,
+                           t: f32,
+                           evaluate: &Fn(Vector3) -> Option<(f32, f32)>,
+                           remainder: bool)
+                           -> (f32, usize) {
+        let from = Some(intersection.position);
+        let (light, position, normal, area_pdf) = match scene.sample_light(from) {
+            Some(sample) => sample,
+            None => return (0.0, 0)
+        };
+        let group = light.light_group;
+
+        let to_light = position - intersection.position;
+        let distance = to_light.magnitude();
+        let direction = to_light * (1.0 / distance);
+        let cos_light = dot(direction, normal).abs();
+        if distance <= 0.0 || cos_light <= 0.0 { return (0.0, group); }
+
+        let (reflected, bsdf_pdf) = match evaluate(direction) {
+            Some((reflected, pdf)) if reflected > 0.0 => (reflected, pdf),
+            _ => return (0.0, group)
+        };
+
+        // The light only contributes if nothing is in between, not even
+        // another part of the light itself.
+        let shadow_ray = Ray {
+            origin: intersection.position + direction * 0.00001,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: 1.0
+        };
+        let light_intersection = match scene.intersect(&shadow_ray) {
+            Some((isect, obj)) if obj as *const Object == light as *const Object
+                && isect.distance > distance * 0.999 => isect,
+            _ => return (0.0, group)
+        };
+
+        let emitted = match light.material {
+            Emissive(ref mat) | EmissiveReflective(ref mat, _) => {
+                mat.get_intensity_at_time(incoming_ray.wavelength, &light_intersection, t)
+            },
+            Reflective(_) => return (0.0, group)
+        };
+
+        let light_pdf = area_pdf * distance * distance / cos_light;
+        let weight = if remainder {
+            1.0 - self.get_light_weight(light_pdf, bsdf_pdf)
+        } else {
+            self.get_light_weight(light_pdf, bsdf_pdf)
+        };
+        (emitted * reflected / light_pdf * weight, group)
+    }
+
+    /// Returns the probability density (per steradian) with which
+    /// `sample_environment` would choose the direction of an escaped ray.
+    fn get_environment_pdf(&self, scene: &Scene, direction: Vector3) -> f32 {
+        match scene.environment {
+            Some(ref env) if !self.debug_void => env.get_pdf(direction),
+            _ => 0.0
+        }
+    }
+
+    /// Sends a ray from the intersection in a random direction, chosen by
+    /// the brightness of the environment, and returns the weighted intensity
+    /// of the environment light that arrives there and continues along the
+    /// path. The `remainder` is as for `sample_direct_light`.
+    fn sample_environment(&self,
+                          scene: &Scene,
+                          incoming_ray: &Ray,
+                          intersection: &Intersection,
+                          evaluate: &Fn(Vector3) -> Option<(f32, f32)>,
+                          remainder: bool)
+                          -> f32 {
+        let env = match scene.environment {
+            Some(ref env) if !self.debug_void => env,
+            _ => return 0.0
+        };
+        let (direction, env_pdf) = match env.sample_direction() {
+            Some(sample) => sample,
+            None => return 0.0
+        };
+        let (reflected, bsdf_pdf) = match evaluate(direction) {
+            Some((reflected, pdf)) if reflected > 0.0 => (reflected, pdf),
+            _ => return 0.0
+        };
+
+        // The environment is only visible if the ray escapes the scene.
+        let shadow_ray = Ray {
+            origin: intersection.position + direction * 0.00001,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: 1.0
+        };
+        if scene.intersect(&shadow_ray).is_some() {
+            return 0.0;
+        }
+
+        let weight = if remainder {
+            1.0 - self.get_light_weight(env_pdf, bsdf_pdf)
+        } else {
+            self.get_light_weight(env_pdf, bsdf_pdf)
+        };
+        env.sample(direction, incoming_ray.wavelength) * reflected / env_pdf * weight
+    }
+
+    /// Decides by Russian roulette whether a path of the specified depth
+    /// continues, given its chance of continuing and its intensity.
+    fn survives_roulette(&self, depth: u32, continue_chance: f32, intensity: f32) -> bool {
+        // The first bounces are always traced, roulette only kicks in
+        // once the path is deep enough.
+        if depth < self.min_depth {
+            return true;
+        }
+
+        // Use a sharp falloff based on intensity, so with the default
+        // settings an intensity of 0.1 still has 86% chance of continuing,
+        // but an intensity of 0.01 has only 18% chance of continuing.
+        ::monte_carlo::get_unit() * self.roulette_threshold <= continue_chance
+            * (1.0 - (intensity * -self.roulette_falloff).exp())
+    }
+
+    /// Traces a photon backwards along the specified ray, and returns its
+    /// contribution together with the number of bounces the path made, and
+    /// the light group of the light that contributed most. A path that gathers
+    /// light from several groups by light sampling is attributed to one of
+    /// them as a whole; the background belongs to group 0. The secondary
+    /// wavelengths follow the path too, but light sampling is only done for
+    /// the wavelength of the ray. Lights emit as they do at time `t`.
+    fn trace_path(&self, scene: &Scene, initial_ray: Ray, t: f32,
+                  secondaries: &mut [SecondaryWavelength]) -> (f32, u32, usize) {
         // The path starts with the ray, and there is a chance it continues.
         let mut ray = initial_ray;
         let mut continue_chance = 1.0f32;
+        let mut depth = 0u32;
 
         // Apart from the chance, which might decrease even for specular
         // bounces, light intensity is affected by interaction probabilities.
         let mut intensity = 1.0f32;
 
+        // Light that was found along the way, by sampling lights directly,
+        // or by passing surfaces that both emit and reflect.
+        let mut gathered = 0.0f32;
+
+        // The density with which the material chose the current ray,
+        // if light sampling was done at its origin.
+        let mut bsdf_pdf: Option<f32> = None;
+
+        // The objects that the path is inside, the innermost one last. The
+        // path starts in air, unless it starts in a medium, such as fog
+        // around the camera.
+        let mut media: Vec<Interior> = Vec::new();
+        if let Some(medium) = scene.get_medium(ray.origin) {
+            media.push(Interior { ior: 1.0, absorption: 0.0, medium: Some(medium) });
+        }
+
+        // Whether the secondary wavelengths stopped following the path.
+        let mut split = false;
+
+        // The light group that contributed most so far.
+        let mut group = 0usize;
+        let mut group_contribution = 0.0f32;
+
+        let sample_lights = match self.direct_lighting {
+            DirectLighting::BsdfSampling => false,
+            _ => true
+        };
+
         loop {
-            match scene.intersect(&ray) {
-                // If nothing was intersected, the path ends,
-                // and the only thing left is the utter darkness of The Void.
-                None => return 0.0,
+            // Only hollow objects can be seen from the inside, but a path
+            // inside a transmissive object or a medium must still find the
+            // way out, so there every crossing counts.
+            let hit = if media.is_empty() {
+                scene.intersect(&ray)
+            } else {
+                scene.intersect_all(&ray).into_iter().next()
+            };
+
+            // Inside a participating medium, the photon might not make it to
+            // the surface. It is absorbed along the way, and it can scatter
+            // before it gets there. The medium ends at the surface of the
+            // object that it fills, so the surface hit bounds the flight.
+            if let Some(medium) = media.last().and_then(|interior| interior.medium) {
+                let hit_distance = hit.as_ref().map_or(::std::f32::INFINITY, |&(ref i, _)| i.distance);
+                let scatter_distance = medium.sample_scatter_distance() * scene.units_per_meter;
+                let distance = scatter_distance.min(hit_distance);
+
+                if medium.absorption > 0.0 {
+                    let transmittance = scene.get_transmittance(medium.absorption, distance);
+                    intensity = intensity * transmittance;
+                    for s in secondaries.iter_mut() {
+                        s.intensity = s.intensity * transmittance;
+                    }
+                }
+
+                // Scattering is isotropic, so every direction is equally
+                // likely, for all wavelengths alike.
+                if scatter_distance < hit_distance {
+                    if self.max_depth.map_or(false, |max| depth >= max) {
+                        return (gathered, depth, group);
+                    }
+
+                    ray = Ray {
+                        origin: ray.origin + ray.direction * scatter_distance,
+                        direction: ::monte_carlo::get_sphere_vector(),
+                        wavelength: ray.wavelength,
+                        probability: 1.0
+                    };
+                    bsdf_pdf = None;
+                    depth += 1;
+
+                    continue_chance = continue_chance * self.continue_chance_decay;
+                    if !self.survives_roulette(depth, continue_chance, intensity) {
+                        break;
+                    }
+                    continue;
+                }
+            }
+
+            match hit {
+                // If nothing was intersected, the path ends, and the only
+                // thing left is the background; the utter darkness of
+                // The Void, unless the scene has an environment.
+                None => {
+                    for s in secondaries.iter_mut() {
+                        let background = self.get_background(scene, &at_wavelength(&ray, s.wavelength));
+                        s.gathered = s.gathered + s.intensity * background;
+                    }
+                    // If the environment could have been sampled directly
+                    // too, that estimate must be weighted.
+                    let weight = match bsdf_pdf {
+                        Some(pdf) => {
+                            let env_pdf = self.get_environment_pdf(scene, ray.direction);
+                            if env_pdf > 0.0 { self.get_bsdf_weight(pdf, env_pdf) } else { 1.0 }
+                        }
+                        None => 1.0
+                    };
+                    let contribution = intensity * self.get_background(scene, &ray) * weight;
+                    if contribution > group_contribution { group = 0; }
+                    return (gathered + contribution, depth, group);
+                },
                 Some((intersection, object)) => {
-                    match object.material {
-                        // If a light was hit, the path ends, and the intensity
-                        // of the light determines the intensity of the path.
-                        Emissive(ref mat) => {
-                            return intensity * mat.get_intensity(ray.wavelength);
-                        },
-                        // Otherwise, the ray must have hit a non-emissive surface,
-                        // and so the journey continues ...
-                        Reflective(ref mat) => {
-                            ray = mat.get_new_ray(&ray, &intersection);
-                            intensity = intensity * ray.probability;
+                    // On the way here, the medium absorbed some of the light.
+                    // Secondary wavelengths split off at the surface of a
+                    // medium, so only the wavelength of the ray is affected.
+                    if let Some(interior) = media.last() {
+                        if interior.absorption > 0.0 {
+                            let distance = intersection.distance;
+                            intensity = intensity
+                                * scene.get_transmittance(interior.absorption, distance);
+                        }
+                    }
+
+                    let (emissive, reflective) = match object.material {
+                        Emissive(ref mat) => (Some(mat), None),
+                        Reflective(ref mat) => (None, Some(mat)),
+                        EmissiveReflective(ref emissive, ref reflective) => {
+                            (Some(emissive), Some(reflective))
+                        }
+                    };
+
+                    // If a light was hit, its intensity contributes to the
+                    // path. If the light could have been sampled directly
+                    // too, that estimate must be weighted.
+                    if let Some(mat) = emissive {
+                        let weight = match bsdf_pdf {
+                            Some(pdf) => {
+                                let light_pdf = TraceUnit::get_light_direction_pdf(
+                                    scene, object, &ray, &intersection);
+                                if light_pdf > 0.0 {
+                                    self.get_bsdf_weight(pdf, light_pdf)
+                                } else {
+                                    1.0
+                                }
+                            }
+                            None => 1.0
+                        };
+                        for s in secondaries.iter_mut() {
+                            let emitted = mat.get_intensity_at_time(s.wavelength, &intersection, t);
+                            s.gathered = s.gathered + s.intensity * emitted * weight;
+                        }
+                        let emitted = mat.get_intensity_at_time(ray.wavelength, &intersection, t);
+                        let contribution = intensity * emitted * weight;
+                        if contribution > group_contribution {
+                            group = object.light_group;
+                            group_contribution = contribution;
+                        }
+                        gathered = gathered + contribution;
+                    }
+
+                    // A light that does not reflect ends the path. Otherwise,
+                    // the journey continues ...
+                    let mat = match reflective {
+                        Some(mat) => mat,
+                        None => return (gathered, depth, group)
+                    };
+
+                    let evaluate = |direction| mat.evaluate(&ray, &intersection, direction);
+                    if sample_lights {
+                        let (light, light_group) =
+                            self.sample_direct_light(scene, &ray, &intersection, t,
+                                                     &evaluate, false);
+                        let contribution = intensity * light;
+                        if contribution > group_contribution {
+                            group = light_group;
+                            group_contribution = contribution;
+                        }
+                        gathered = gathered + contribution;
+
+                        // Escaped rays find the environment, which has
+                        // light group 0.
+                        let env = self.sample_environment(scene, &ray, &intersection, &evaluate, false);
+                        let contribution = intensity * env;
+                        if contribution > group_contribution {
+                            group = 0;
+                            group_contribution = contribution;
+                        }
+                        gathered = gathered + contribution;
+                    }
+
+                    // At the maximum depth the path does not bounce again.
+                    // The direct light that the bounce would have found can
+                    // still be estimated by sampling a light.
+                    if self.max_depth.map_or(false, |max| depth >= max) {
+                        if self.max_depth_fallback {
+                            let (light, light_group) =
+                                self.sample_direct_light(scene, &ray, &intersection, t,
+                                                         &evaluate, true);
+                            let env = self.sample_environment(scene, &ray, &intersection, &evaluate, true);
+                            let contribution = intensity * light;
+                            if contribution > group_contribution {
+                                group = light_group;
+                                group_contribution = contribution;
+                            }
+                            gathered = gathered + contribution;
+
+                            // The path ends here, so this is the last
+                            // contribution that can claim the path.
+                            let contribution = intensity * env;
+                            if contribution > group_contribution { group = 0; }
+                            gathered = gathered + contribution;
                         }
+                        return (gathered, depth, group);
                     }
+
+                    // A ray that enters a medium comes from the innermost
+                    // medium, a ray that leaves it goes to the one around it.
+                    let ior = mat.get_index_of_refraction(ray.wavelength);
+                    let entering = dot(ray.direction, intersection.normal) < 0.0;
+                    let exterior_ior = if entering {
+                        media.last().map_or(1.0, |interior| interior.ior)
+                    } else if media.len() >= 2 {
+                        media[media.len() - 2].ior
+                    } else {
+                        1.0
+                    };
+
+                    let new_ray = mat.get_new_ray(&ray, &intersection, exterior_ior);
+
+                    // If the ray went through the surface of a transmissive
+                    // object, or of one filled with a medium, the path is now
+                    // inside or outside it.
+                    let crossed = (dot(new_ray.direction, intersection.normal) < 0.0) == entering;
+                    if crossed && (ior.is_some() || object.medium.is_some()) {
+                        if entering {
+                            media.push(Interior {
+                                ior: ior.unwrap_or(exterior_ior),
+                                absorption: mat.get_absorption(ray.wavelength),
+                                medium: object.medium
+                            });
+                        } else {
+                            media.pop();
+                        }
+                    }
+
+                    // Materials that cannot be evaluated were not combined
+                    // with light sampling.
+                    bsdf_pdf = if sample_lights {
+                        evaluate(new_ray.direction).map(|(_, pdf)| pdf)
+                    } else {
+                        None
+                    };
+
+                    // The secondary wavelengths go in the same direction, if
+                    // the material can tell how much of them goes there.
+                    // Otherwise the path splits, and only the wavelength of
+                    // the ray continues, carrying the weight of the others.
+                    if !split && secondaries.len() > 0 {
+                        for s in secondaries.iter_mut() {
+                            let ray_s = at_wavelength(&ray, s.wavelength);
+                            match mat.evaluate(&ray_s, &intersection, new_ray.direction) {
+                                Some((f, pdf)) if pdf > 0.0 => s.intensity = s.intensity * f / pdf,
+                                _ => split = true
+                            }
+                        }
+                        if split {
+                            for s in secondaries.iter_mut() { s.intensity = 0.0; }
+                            intensity = intensity * (secondaries.len() + 1) as f32;
+                        }
+                    }
+
+                    ray = new_ray;
+                    intensity = intensity * ray.probability;
+                    depth += 1;
                 }
             }
 
@@ -114,56 +899,1023 @@ impl TraceUnit {
             ray.origin = ray.origin + ray.direction * 0.00001;
 
             // And the chance of a new bounce decreases slightly.
-            continue_chance = continue_chance * 0.96;
+            continue_chance = continue_chance * self.continue_chance_decay;
 
-            // Use a sharp falloff based on intensity, so an intensity of
-            // 0.1 still has 86% chance of continuing, but an intensity of
-            // 0.01 has only 18% chance of continuing.
-            if ::monte_carlo::get_unit() * 0.85 > continue_chance
-                * (1.0 - (intensity * -20.0).exp()) {
+            if !self.survives_roulette(depth, continue_chance, intensity) {
                 break;
             }
         }
 
-        // If Russian roulette terminated the path, there is always
-        // an option of trying direct illumination, which could be
-        // implemented here, but is not.
-        0.0
+        // If Russian roulette terminated the path, only the light that
+        // was gathered along the way remains.
+        (gathered, depth, group)
     }
 
-    /// Returns the contribution of a ray
-    /// through the specified creen coordinate.
-    fn render_camera_ray(scene: &Scene, x: f32, y: f32, wavelength: f32) -> f32 {
-        // Get a random time to sample at.
-        let t = ::monte_carlo::get_unit();
+    /// Returns the ambient occlusion term for a camera ray: 1.0 if the
+    /// surface it hits is exposed, 0.0 if it is occluded.
+    fn render_occlusion(scene: &Scene, ray: Ray, radius: f32) -> f32 {
+        match scene.intersect(&ray) {
+            // There is nothing to occlude in The Void, but there is also
+            // nothing to be lit, so it remains black.
+            None => 0.0,
+            Some((intersection, _)) => {
+                TraceUnit::probe_occlusion(scene, &ray, &intersection, radius)
+            }
+        }
+    }
+
+    /// Casts a diffuse probe ray from the intersection, and returns 0.0 if
+    /// it hits something within `radius`, or 1.0 otherwise.
+    fn probe_occlusion(scene: &Scene,
+                       incoming_ray: &Ray,
+                       intersection: &Intersection,
+                       radius: f32)
+                       -> f32 {
+        // The probe must leave on the side of the surface where the
+        // incoming ray came from.
+        let normal = if dot(incoming_ray.direction, intersection.normal) < 0.0 {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+        let direction = ::monte_carlo::get_hemisphere_vector().rotate_towards(normal);
+        let probe = Ray {
+            origin: intersection.position + direction * 0.00001,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: 1.0
+        };
+
+        match scene.intersect(&probe) {
+            Some((isect, _)) if isect.distance < radius => 0.0,
+            _ => 1.0
+        }
+    }
 
-        // Get the camera at that time.
-        let camera = (scene.get_camera_at_time)(t);
+    /// Returns the colour that represents the surface normal of the first
+    /// intersection, as a linear sRGB value.
+    fn render_normal(scene: &Scene, ray: Ray) -> Vector3 {
+        match scene.intersect(&ray) {
+            None => Vector3::zero(),
+            Some((intersection, _)) => {
+                // Map the components from [-1, 1] to [0, 1].
+                intersection.normal * 0.5 + Vector3::new(0.5, 0.5, 0.5)
+            }
+        }
+    }
+
+    /// Returns the distance to the first intersection as a grey,
+    /// linear sRGB value.
+    fn render_depth(scene: &Scene, ray: Ray) -> Vector3 {
+        match scene.intersect(&ray) {
+            None => Vector3::zero(),
+            Some((intersection, _)) => {
+                let d = intersection.distance;
+                Vector3::new(d, d, d)
+            }
+        }
+    }
+
+    /// Returns 1.0 if the ray through screen coordinate (`x`, `y`) lies on
+    /// an edge, and 0.0 otherwise. See `RenderMode::Edges`.
+    fn render_edge(&self, scene: &Scene, camera: &Camera, x: f32, y: f32, wavelength: f32) -> f32 {
+        // The size of a pixel in screen coordinates.
+        let dx = 2.0 / (self.width as f32 - 1.0).max(1.0);
+        let dy = 2.0 / (self.height as f32 - 1.0).max(1.0) / self.aspect_ratio;
+
+        let hit = |x: f32, y: f32| {
+            scene.intersect(&camera.get_ray(x, y, wavelength))
+                 .map(|(isect, obj)| (obj as *const Object, isect.normal))
+        };
+
+        let centre = hit(x, y);
+        let is_edge = [hit(x + dx, y), hit(x, y + dy)].iter().any(|neighbour| {
+            match (centre, *neighbour) {
+                (None, None) => false,
+                (Some((obj0, n0)), Some((obj1, n1))) => {
+                    obj0 != obj1 || dot(n0, n1) < EDGE_MIN_COS_ANGLE
+                }
+                _ => true
+            }
+        });
+
+        if is_edge { 1.0 } else { 0.0 }
+    }
 
-        // Create a camera ray for the specified pixel and wavelength.
-        let ray = camera.get_ray(x, y, wavelength);
+    /// Connects a surface that a light path hit to the camera, and returns
+    /// the light that it reflects towards the camera as a photon, if the
+    /// camera can see the surface. The probability of `photon` is the light
+    /// that arrived along `ray`, its other fields are copied.
+    fn connect_to_camera(&self,
+                         scene: &Scene,
+                         camera: &Camera,
+                         ray: &Ray,
+                         intersection: &Intersection,
+                         material: &Material,
+                         photon: MappedPhoton)
+                         -> Option<MappedPhoton> {
+        let (x, y, area_per_sr) = match camera.project(intersection.position) {
+            Some(projection) => projection,
+            None => return None
+        };
+        if x.abs() > 1.0 || y.abs() > 1.0 / self.aspect_ratio { return None; }
+
+        let to_camera = camera.position - intersection.position;
+        let distance = to_camera.magnitude();
+        let direction = to_camera * (1.0 / distance);
+        let reflected = match material.evaluate(ray, intersection, direction) {
+            Some((reflected, _)) if reflected > 0.0 => reflected,
+            _ => return None
+        };
+
+        let shadow_ray = Ray {
+            origin: intersection.position + direction * 0.00001,
+            direction: direction,
+            wavelength: ray.wavelength,
+            probability: 1.0
+        };
+        if let Some((isect, _)) = scene.intersect(&shadow_ray) {
+            if isect.distance < distance * 0.999 { return None; }
+        }
 
-        // And render this camera ray.
-        TraceUnit::render_ray(scene, ray)
+        // The light is spread over the screen area that the surface covers,
+        // relative to the screen area in which camera paths are sampled.
+        let screen_area = 4.0 / self.aspect_ratio;
+        let probability = photon.probability * reflected * area_per_sr
+                        / (distance * distance * screen_area);
+
+        Some(MappedPhoton {
+            x: x,
+            y: y,
+            probability: probability,
+            .. photon
+        })
+    }
+
+    /// Traces a path forward from a random point on a light, at the
+    /// specified wavelength, and adds a photon for every surface along the
+    /// path that the camera can see.
+    fn trace_light_path(&self,
+                        scene: &Scene,
+                        wavelength: f32,
+                        wavelength_pdf: f32,
+                        photons: &mut Vec<MappedPhoton>) {
+        let (camera, t) = self.get_camera(scene);
+        let (light, position, normal, area_pdf) = match scene.sample_light(None) {
+            Some(sample) => sample,
+            None => return
+        };
+
+        // Intersect the light at the point to find its texture coordinates,
+        // so it emits there as it would for a camera path that hits it, and
+        // at the same time as the camera that the path is connected to.
+        let probe = Ray {
+            origin: position + normal * 0.00001,
+            direction: -normal,
+            wavelength: wavelength,
+            probability: 1.0
+        };
+        let light_intersection = light.surface.intersect(&probe)
+            .unwrap_or_else(|| Intersection::new(position, normal, Vector3::zero(), 0.0));
+        let emitted = match light.material {
+            Emissive(ref mat) | EmissiveReflective(ref mat, _) => {
+                mat.get_intensity_at_time(wavelength, &light_intersection, t)
+            },
+            Reflective(_) => return
+        };
+
+        // Light leaves the surface on the side of the normal, in a
+        // cosine-weighted direction, which cancels the cosine of emission.
+        let direction = ::monte_carlo::get_hemisphere_vector().rotate_towards(normal);
+        let mut ray = Ray {
+            origin: position + direction * 0.00001,
+            direction: direction,
+            wavelength: wavelength,
+            probability: 1.0
+        };
+        let mut intensity = emitted * PI / area_pdf;
+        let mut continue_chance = 1.0f32;
+        let mut depth = 0u32;
+
+        let photon = MappedPhoton {
+            x: 0.0,
+            y: 0.0,
+            probability: 0.0,
+            wavelength: wavelength,
+            wavelength_pdf: wavelength_pdf,
+            tristimulus: None,
+            light_group: light.light_group
+        };
+
+        loop {
+            // The path ends when it escapes or hits a light.
+            let (intersection, mat) = match scene.intersect(&ray) {
+                Some((intersection, object)) => match object.material {
+                    Reflective(ref mat) | EmissiveReflective(_, ref mat) => (intersection, mat),
+                    Emissive(_) => break
+                },
+                None => break
+            };
+
+            let arrived = MappedPhoton { probability: intensity, .. photon };
+            photons.extend(self.connect_to_camera(scene, &camera, &ray, &intersection,
+                                                  &**mat, arrived));
+
+            // Nested media are not tracked for light paths, every surface
+            // is assumed to border on air.
+            ray = mat.get_new_ray(&ray, &intersection, 1.0);
+            ray.origin = ray.origin + ray.direction * 0.00001;
+            intensity = intensity * ray.probability;
+            depth += 1;
+
+            continue_chance = continue_chance * self.continue_chance_decay;
+            if !self.survives_roulette(depth, continue_chance, intensity) {
+                break;
+            }
+        }
+    }
+
+    /// Returns the intensity of the background for an escaped ray,
+    /// or of the debug void if it is enabled.
+    fn get_background(&self, scene: &Scene, ray: &Ray) -> f32 {
+        if self.debug_void {
+            // Emit only at the ends of the spectrum, so the void has
+            // a colour that no ordinary light has.
+            if ray.wavelength < 490.0 || ray.wavelength > 610.0 { 1.0 } else { 0.0 }
+        } else {
+            scene.get_background(ray)
+        }
+    }
+
+    /// Returns the camera at a random time, to sample motion blur, together
+    /// with that time.
+    fn get_camera(&self, scene: &Scene) -> (Camera, f32) {
+        let t = ::monte_carlo::get_unit();
+        (scene.get_camera(self.camera, t), t)
+    }
+
+    /// Seeds the random numbers of this thread for the next path with a
+    /// frame seed, and returns the pixel that the path goes through.
+    fn seed_next_path(&mut self, frame_seed: u32) -> (u32, u32) {
+        let i = self.seeded_paths;
+        self.seeded_paths = self.seeded_paths.wrapping_add(1);
+        let px = i % self.width;
+        let py = (i / self.width) % self.height;
+
+        // Units trace different samples of the same pixel,
+        // so the id is part of the seed.
+        let sample = i / (self.width * self.height);
+        let seed = ::monte_carlo::get_pixel_seed(px, py, self.id, sample, frame_seed);
+        ::monte_carlo::seed_thread(Some(seed));
+        (px, py)
     }
 
     /// Fills the buffer of mapped photons once.
     pub fn render(&mut self, scene: &Scene) {
-        for mapped_photon in &mut self.mapped_photons {
+        let mut photons = mem::replace(&mut self.mapped_photons, Vec::new());
+        photons.clear();
+
+        // Every batch of every unit gets its own stream of random numbers.
+        if let Some(seed) = self.seed {
+            let batch = self.seeded_batches;
+            self.seeded_batches = self.seeded_batches.wrapping_add(1);
+            ::monte_carlo::seed_thread(Some(::monte_carlo::get_unit_seed(self.id, batch, seed)));
+        }
+
+        for _ in 0 .. self.batch_size {
+            let pixel = match (self.frame_seed, self.render_mode) {
+                (_, RenderMode::LightTracing) => None,
+                (Some(seed), _) => Some(self.seed_next_path(seed)),
+                (None, _) => None
+            };
+
+            // The quasi-random point for this photon, if enabled. Dimensions
+            // 0 and 1 are the screen position, 2 is the wavelength, and 3 and
+            // 4 are the point on the lens.
+            let point = match self.render_mode {
+                RenderMode::LightTracing => None,
+                _ => self.next_sobol_point()
+            };
+
             // Pick a wavelength for this photon.
-            let wavelength = ::monte_carlo::get_wavelength();
+            let wavelength = match point {
+                Some(p) => self.wavelength_sampling.sample_at(p[2]),
+                None => self.wavelength_sampling.sample()
+            };
+            let wavelength_pdf = self.wavelength_sampling.get_relative_pdf(wavelength);
+
+            // Light paths go from the light to the screen instead.
+            if let RenderMode::LightTracing = self.render_mode {
+                self.trace_light_path(scene, wavelength, wavelength_pdf, &mut photons);
+                continue;
+            }
+
+            // Pick a screen coordinate for the photon, anywhere on the
+            // screen, or in the pixel it was seeded for.
+            let (su, sv) = match point {
+                Some(p) => (p[0], p[1]),
+                None => (::monte_carlo::get_unit(), ::monte_carlo::get_unit())
+            };
+            let (u, v) = match pixel {
+                Some((px, py)) => pixel_to_ndc(px as f32 + su - 0.5, py as f32 + sv - 0.5,
+                                               self.width, self.height),
+                None => self.screen_sampling.sample_at(su, sv)
+            };
+
+            // Photons in places that are sampled more often weigh less.
+            let screen_weight = match pixel {
+                Some(_) => 1.0,
+                None => 1.0 / self.screen_sampling.get_relative_pdf(u, v)
+            };
+            let (x, y) = ndc_to_screen(u, v, self.aspect_ratio);
+
+            // Then trace the scene at this wavelength. The debug modes
+            // bypass the spectral response and produce a colour directly.
+            let (camera, time) = self.get_camera(scene);
+            let ray = match point {
+                Some(p) => camera.get_ray_at(x, y, wavelength, p[3], p[4]),
+                None => camera.get_ray(x, y, wavelength)
+            };
+            let (probability, tristimulus, light_group) = match self.render_mode {
+                RenderMode::PathTracing => {
+                    // Secondary wavelengths would need rays of their own
+                    // if the camera disperses light.
+                    let mut secondaries = match self.direct_lighting {
+                        DirectLighting::BsdfSampling if camera.chromatic_abberation == 0.0 => {
+                            self.get_secondary_wavelengths(wavelength)
+                        }
+                        _ => Vec::new()
+                    };
+                    let (probability, light_group) =
+                        self.render_ray(scene, ray, time, &mut secondaries);
+
+                    // A secondary wavelength is distributed like the
+                    // wavelength it is offset from.
+                    for s in &secondaries {
+                        photons.push(MappedPhoton {
+                            x: x,
+                            y: y,
+                            probability: s.gathered * screen_weight,
+                            wavelength: s.wavelength,
+                            wavelength_pdf: wavelength_pdf,
+                            tristimulus: None,
+                            light_group: light_group
+                        });
+                    }
+                    (probability, None, light_group)
+                },
+                RenderMode::AmbientOcclusion(radius) => {
+                    let grey = TraceUnit::render_occlusion(scene, ray, radius);
+                    let rgb = Vector3::new(grey, grey, grey);
+                    (1.0, Some(::srgb::srgb_to_xyz(rgb)), 0)
+                },
+                RenderMode::Normals => {
+                    let rgb = TraceUnit::render_normal(scene, ray);
+                    (1.0, Some(::srgb::srgb_to_xyz(rgb)), 0)
+                },
+                RenderMode::Depth => {
+                    let rgb = TraceUnit::render_depth(scene, ray);
+                    (1.0, Some(::srgb::srgb_to_xyz(rgb)), 0)
+                },
+                RenderMode::Edges => {
+                    let grey = self.render_edge(scene, &camera, x, y, wavelength);
+                    let rgb = Vector3::new(grey, grey, grey);
+                    (1.0, Some(::srgb::srgb_to_xyz(rgb)), 0)
+                },
+                RenderMode::LightTracing => unreachable!()
+            };
+
+            photons.push(MappedPhoton {
+                x: x,
+                y: y,
+                probability: probability * screen_weight,
+                wavelength: wavelength,
+                wavelength_pdf: wavelength_pdf,
+                tristimulus: tristimulus,
+                light_group: light_group
+            });
+        }
+
+        // Do not let the last seed affect anything else on this thread.
+        if self.frame_seed.is_some() || self.seed.is_some() {
+            ::monte_carlo::seed_thread(None);
+        }
+
+        self.mapped_photons = photons;
+    }
+}
+
+/// Two perfect mirrors facing each other, at z = -1 and z = 1. A ray that
+/// bounces between them perpendicularly never escapes, so a path along the
+/// z-axis can only be terminated by Russian roulette.
+#[cfg(test)]
+fn facing_mirrors() -> Scene {
+    use geometry::Plane;
+    use material::GlossyMirrorMaterial;
+
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let floor = Object::new(Box::new(Plane::new(up, -up)),
+                            Reflective(Box::new(GlossyMirrorMaterial::new(0.0))));
+    let ceiling = Object::new(Box::new(Plane::new(up, up)),
+                              Reflective(Box::new(GlossyMirrorMaterial::new(0.0))));
+    Scene::new(vec!(floor, ceiling), ::camera::pinhole_camera_at_origin)
+}
+
+#[test]
+fn min_depth_is_always_traced() {
+    let scene = facing_mirrors();
+    let up = Vector3::new(0.0, 0.0, 1.0);
+
+    let mut unit = TraceUnit::new(0, 16, 9);
+    unit.min_depth = 10;
+
+    for _ in 0 .. 1000 {
+        let ray = Ray {
+            origin: Vector3::zero(),
+            direction: -up,
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let (_, depth, _) = unit.trace_path(&scene, ray, 0.0, &mut []);
+        assert!(depth >= 10);
+    }
+}
+
+#[test]
+fn occlusion_is_lower_in_crevice() {
+    use geometry::Plane;
+    use material::DiffuseGreyMaterial;
+    use object::Object;
+    use vector3::Vector3;
+
+    // A floor with a wall standing on it, at x = 0.
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let right = Vector3::new(1.0, 0.0, 0.0);
+    let floor = Object::new(Box::new(Plane::new(up, Vector3::zero())),
+                            Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let wall = Object::new(Box::new(Plane::new(right, Vector3::zero())),
+                           Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec!(floor, wall), ::camera::pinhole_camera_at_origin);
+
+    let occlusion_at = |x: f32| {
+        let n = 2000;
+        let total: f32 = (0 .. n).map(|_| {
+            let ray = Ray {
+                origin: Vector3::new(x, 0.0, 1.0),
+                direction: -up,
+                wavelength: 550.0,
+                probability: 1.0
+            };
+            TraceUnit::render_occlusion(&scene, ray, 1.0)
+        }).sum();
+        total / n as f32
+    };
+
+    let crevice = occlusion_at(0.01);
+    let exposed = occlusion_at(100.0);
+    assert!(exposed > 0.99);
+    assert!(crevice < 0.8);
+}
+
+#[test]
+fn normal_of_sphere_faces_camera() {
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+    use object::Object;
+
+    let sphere = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 10.0, 0.0), 2.0)),
+                             Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec!(sphere), ::camera::pinhole_camera_at_origin);
+
+    // The camera looks along the positive y-axis, straight at the sphere.
+    let ray = TraceUnit::new(0, 1, 1).get_camera(&scene).0.get_ray(0.0, 0.0, 550.0);
+    let rgb = TraceUnit::render_normal(&scene, ray);
+
+    // Decode the colour into a normal again; it should point back
+    // towards the camera.
+    let normal = rgb * 2.0 - Vector3::new(1.0, 1.0, 1.0);
+    assert!(dot(normal, Vector3::new(0.0, -1.0, 0.0)) > 0.99);
+}
+
+#[test]
+fn multiple_importance_sampling_reduces_variance() {
+    use geometry::{Plane, Sphere};
+    use material::{BlackBodyMaterial, PhongMaterial};
+    use object::MaterialBox;
+
+    // A glossy floor that reflects a small light.
+    let floor = Object::new(Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::zero())),
+                            MaterialBox::Reflective(Box::new(PhongMaterial::new(0.8, 100.0))));
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(1.0, 0.0, 1.0), 0.1)),
+                            MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec!(floor, light), ::camera::pinhole_camera_at_origin);
 
-            // Pick a screen coordinate for the photon.
-            let x = ::monte_carlo::get_bi_unit();
-            let y = ::monte_carlo::get_bi_unit() / self.aspect_ratio;
+    let estimate = |direct_lighting: DirectLighting| {
+        let mut unit = TraceUnit::new(0, 1, 1);
+        unit.direct_lighting = direct_lighting;
+        let n = 40000;
+        let mut sum = 0.0f64;
+        let mut sum_sqr = 0.0f64;
+        for _ in 0 .. n {
+            let ray = Ray {
+                origin: Vector3::new(-1.0, 0.0, 1.0),
+                direction: Vector3::new(1.0, 0.0, -1.0).normalise(),
+                wavelength: 550.0,
+                probability: 1.0
+            };
+            let x = unit.render_ray(&scene, ray, 0.0, &mut []).0 as f64;
+            sum += x;
+            sum_sqr += x * x;
+        }
+        let mean = sum / n as f64;
+        let variance = sum_sqr / n as f64 - mean * mean;
+        (mean, variance)
+    };
+
+    let (bsdf_mean, bsdf_var) = estimate(DirectLighting::BsdfSampling);
+    let (light_mean, light_var) = estimate(DirectLighting::LightSampling);
+    let (mis_mean, mis_var) = estimate(DirectLighting::MultipleImportance);
+
+    // All strategies converge to the same value ...
+    assert!(mis_mean > 0.0);
+    assert!((bsdf_mean - mis_mean).abs() < 0.1 * mis_mean);
+    assert!((light_mean - mis_mean).abs() < 0.1 * mis_mean);
+
+    // ... but combining them converges faster.
+    assert!(mis_var < bsdf_var);
+    assert!(mis_var < light_var);
+}
+
+#[test]
+fn resized_unit_uses_new_aspect_ratio() {
+    let scene = Scene::new(Vec::new(), ::camera::pinhole_camera_at_origin);
+    let mut unit = TraceUnit::new(0, 4, 4);
+    unit.resize(16, 8);
+    assert_eq!(unit.aspect_ratio, 2.0);
+
+    // Photons must cover the wider image, and not fall outside of it.
+    unit.render(&scene);
+    assert!(unit.mapped_photons.iter().all(|p| p.y.abs() <= 0.5));
+    assert!(unit.mapped_photons.iter().any(|p| p.y.abs() <= 0.5 && p.x.abs() > 0.5));
+}
+
+#[test]
+fn faster_decay_gives_shorter_paths() {
+    let scene = facing_mirrors();
+    let up = Vector3::new(0.0, 0.0, 1.0);
+
+    let mean_depth = |decay: f32| {
+        let mut unit = TraceUnit::new(0, 16, 9);
+        unit.continue_chance_decay = decay;
+        let mut total = 0;
+        for _ in 0 .. 1000 {
+            let ray = Ray {
+                origin: Vector3::zero(),
+                direction: -up,
+                wavelength: 550.0,
+                probability: 1.0
+            };
+            let (_, depth, _) = unit.trace_path(&scene, ray, 0.0, &mut []);
+            total += depth;
+        }
+        total as f32 / 1000.0
+    };
 
-            // Store the coordinates already.
-            mapped_photon.wavelength = wavelength;
-            mapped_photon.x = x;
-            mapped_photon.y = y;
+    assert!(mean_depth(0.8) < mean_depth(0.96));
+}
+
+#[test]
+fn light_path_is_seen_where_it_hits() {
+    use geometry::{Extent, Plane, Sphere};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+
+    // A card in front of the camera, lit by a light above it. With a field
+    // of view of 90 degrees, the card covers 0.1 around (0.12, -0.06) on
+    // the screen.
+    let extent = Extent::new(Vector3::new(1.0, 0.0, 0.0), 0.5, Vector3::new(0.0, 0.0, 1.0), 0.5);
+    let card = Plane::new_bounded(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.6, 5.0, 0.3), extent);
+    let light = Sphere::new(Vector3::new(0.6, 3.5, 1.5), 0.3);
+    let scene = Scene::new(vec!(
+        Object::new(Box::new(card), Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))),
+        Object::new(Box::new(light), Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))))
+    ), ::camera::pinhole_camera_at_origin);
 
-            // And then trace the scene at this wavelength.
-            mapped_photon.probability = TraceUnit::render_camera_ray(scene, x, y, wavelength);
+    let mut unit = TraceUnit::new(0, 16, 9);
+    unit.render_mode = RenderMode::LightTracing;
+    unit.render(&scene);
+
+    assert!(unit.mapped_photons.len() > 0);
+    for photon in &unit.mapped_photons {
+        assert!((photon.x - 0.12).abs() <= 0.101);
+        assert!((photon.y + 0.06).abs() <= 0.101);
+        assert!(photon.probability > 0.0);
+    }
+}
+
+#[test]
+fn light_path_is_lit_at_the_time_of_the_camera() {
+    use geometry::{Extent, Plane, Sphere};
+    use material::{BlendedEmissiveMaterial, DiffuseGreyMaterial, FlatEmissiveMaterial};
+    use quaternion::Quaternion;
+
+    // The camera of `light_path_is_seen_where_it_hits`, which turns away
+    // from the card halfway through the shot.
+    fn camera_turning_away(t: f32) -> Camera {
+        let camera = ::camera::pinhole_camera_at_origin(t);
+        if t < 0.5 {
+            camera
+        } else {
+            Camera { orientation: Quaternion::rotation(0.0, 0.0, 1.0, PI), .. camera }
         }
     }
+
+    // Returns the light that reaches the camera from the card, with a light
+    // that blends from `start` to `end` over the shot.
+    let render = |start: f32, end: f32| -> f32 {
+        let extent = Extent::new(Vector3::new(1.0, 0.0, 0.0), 0.5, Vector3::new(0.0, 0.0, 1.0), 0.5);
+        let card = Plane::new_bounded(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.6, 5.0, 0.3), extent);
+        let light = Sphere::new(Vector3::new(0.6, 3.5, 1.5), 0.3);
+        let emission = BlendedEmissiveMaterial::new(Box::new(FlatEmissiveMaterial::new(start)),
+                                                    Box::new(FlatEmissiveMaterial::new(end)));
+        let scene = Scene::new(vec!(
+            Object::new(Box::new(card), Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))),
+            Object::new(Box::new(light), Emissive(Box::new(emission)))
+        ), camera_turning_away);
+
+        let mut unit = TraceUnit::new(0, 16, 9);
+        unit.render_mode = RenderMode::LightTracing;
+        let mut total = 0.0;
+        for _ in 0 .. 100 {
+            unit.render(&scene);
+            total += unit.mapped_photons.iter().map(|p| p.probability).sum::<f32>();
+        }
+        total
+    };
+
+    // While the camera sees the card, a light that fades out is 3/4 as
+    // bright on average, and one that fades in only 1/4.
+    let fading_out = render(1.0, 0.0);
+    let fading_in = render(0.0, 1.0);
+    assert!(fading_out > 0.0);
+    assert!(fading_out > fading_in * 2.0);
+}
+
+#[test]
+fn glowing_surface_emits_and_reflects() {
+    use geometry::Plane;
+    use material::{DiffuseGreyMaterial, FlatEmissiveMaterial};
+
+    // A glowing grey wall in front of the camera, and a light behind it.
+    // Every path sees the glow of the wall, and half of the light that
+    // the wall reflects.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let wall = Object::new(Box::new(Plane::new(-forward, forward * 5.0)),
+                           EmissiveReflective(Box::new(FlatEmissiveMaterial::new(1.0)),
+                                              Box::new(DiffuseGreyMaterial::new(0.5))));
+    let light = Object::new(Box::new(Plane::new(forward, forward * -5.0)),
+                            Emissive(Box::new(FlatEmissiveMaterial::new(1.0))));
+    let scene = Scene::new(vec!(wall, light), ::camera::pinhole_camera_at_origin);
+
+    let mut unit = TraceUnit::new(0, 16, 9);
+    unit.min_depth = 2;
+
+    for _ in 0 .. 100 {
+        let ray = Ray {
+            origin: Vector3::zero(),
+            direction: forward,
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let (contribution, depth, _) = unit.trace_path(&scene, ray, 0.0, &mut []);
+        assert!((contribution - 1.5).abs() < 1.0e-5);
+        assert_eq!(depth, 1);
+    }
+}
+
+#[test]
+fn max_depth_fallback_reduces_bias() {
+    use geometry::{Plane, Sphere};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::MaterialBox;
+
+    // A diffuse floor lit by a light, which is only reached after a bounce.
+    let floor = Object::new(Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::zero())),
+                            MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(1.0, 0.0, 1.0), 0.3)),
+                            MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec!(floor, light), ::camera::pinhole_camera_at_origin);
+
+    let estimate = |max_depth: Option<u32>, fallback: bool| {
+        let mut unit = TraceUnit::new(0, 1, 1);
+        unit.max_depth = max_depth;
+        unit.max_depth_fallback = fallback;
+        let n = 40000;
+        let sum = (0 .. n).map(|_| {
+            let ray = Ray {
+                origin: Vector3::new(-1.0, 0.0, 1.0),
+                direction: Vector3::new(1.0, 0.0, -1.0).normalise(),
+                wavelength: 550.0,
+                probability: 1.0
+            };
+            unit.render_ray(&scene, ray, 0.0, &mut []).0 as f64
+        }).sum::<f64>();
+        sum / n as f64
+    };
+
+    let reference = estimate(None, false);
+    let capped = estimate(Some(0), false);
+    let fallback = estimate(Some(0), true);
+
+    // Without bounces, the floor is black, unless the light is sampled.
+    assert!(reference > 0.0);
+    assert_eq!(capped, 0.0);
+    assert!((fallback - reference).abs() < 0.1 * reference);
+}
+
+#[test]
+fn secondary_wavelengths_match_their_own_paths() {
+    use geometry::{Plane, Sphere};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial, EmissiveMaterial};
+
+    // Nothing in the scene treats wavelengths differently, except for
+    // the spectrum of the light.
+    let floor = Object::new(Box::new(Plane::new(Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0))),
+                            Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 4.0, 1.0), 2.0)),
+                            Emissive(Box::new(BlackBodyMaterial::new(4000.0, 1.0))));
+    let scene = Scene::new(vec!(floor, light), ::camera::pinhole_camera_at_origin);
+    let spectrum = BlackBodyMaterial::new(4000.0, 1.0);
+
+    let mut unit = TraceUnit::new(0, 16, 9);
+    unit.secondary_wavelengths = 3;
+    unit.render(&scene);
+    assert_eq!(unit.mapped_photons.len(), unit.batch_size * 4);
+
+    // A secondary wavelength along a path receives what the path would
+    // have received if it had been traced at that wavelength.
+    let mut lit = 0;
+    for path in unit.mapped_photons.chunks(4) {
+        let hero = path[3];
+        for secondary in &path[.. 3] {
+            assert_eq!((secondary.x, secondary.y), (hero.x, hero.y));
+            assert!(secondary.wavelength != hero.wavelength);
+            let expected = hero.probability * spectrum.get_intensity(secondary.wavelength)
+                         / spectrum.get_intensity(hero.wavelength);
+            assert!((secondary.probability - expected).abs() <= 1.0e-4 * expected);
+        }
+        if hero.probability > 0.0 { lit += 1; }
+    }
+    assert!(lit > 0);
+}
+
+#[test]
+fn frame_seed_gives_same_noise_every_frame() {
+    let scene = Scene::new(Vec::new(), ::camera::pinhole_camera_at_origin);
+    let render_frame = |frame_seed: u32| {
+        let mut unit = TraceUnit::new(0, 8, 8);
+        unit.frame_seed = Some(frame_seed);
+        unit.render(&scene);
+        unit.mapped_photons.iter().map(|p| (p.x, p.y, p.wavelength)).collect::<Vec<_>>()
+    };
+
+    // Two frames with the same seed trace exactly the same samples,
+    // a different seed gives different samples.
+    let frame = render_frame(7);
+    assert_eq!(frame, render_frame(7));
+    assert!(frame != render_frame(8));
+
+    // The seed depends on the pixel, not on the frame.
+    let seed = ::monte_carlo::get_pixel_seed(3, 5, 0, 0, 7);
+    assert_eq!(seed, ::monte_carlo::get_pixel_seed(3, 5, 0, 0, 7));
+    assert!(seed != ::monte_carlo::get_pixel_seed(5, 3, 0, 0, 7));
+
+    // The unit ID does not alias with the sample number, also not for
+    // IDs that do not fit in a byte.
+    assert!(::monte_carlo::get_pixel_seed(3, 5, 256, 0, 7) !=
+            ::monte_carlo::get_pixel_seed(3, 5, 0, 1, 7));
+    assert!(::monte_carlo::get_pixel_seed(3, 5, 257, 0, 7) !=
+            ::monte_carlo::get_pixel_seed(3, 5, 1, 1, 7));
+}
+
+#[test]
+fn debug_void_is_magenta() {
+    use plot_unit::PlotUnit;
+
+    let scene = Scene::new(Vec::new(), ::camera::pinhole_camera_at_origin);
+    let mut unit = TraceUnit::new(0, 4, 4);
+    let mut plot_unit = PlotUnit::new(0, 4, 4);
+    unit.render(&scene);
+    plot_unit.plot(&unit.mapped_photons);
+    assert!(plot_unit.tristimulus_buffer.iter().all(|px| px.y == 0.0));
+
+    unit.debug_void = true;
+    unit.render(&scene);
+    plot_unit.plot(&unit.mapped_photons);
+    let cie = plot_unit.tristimulus_buffer.iter().fold(Vector3::zero(), |acc, &px| acc + px);
+    let rgb = ::srgb::xyz_to_srgb(cie);
+    assert!(rgb.x > 2.0 * rgb.y);
+    assert!(rgb.z > 2.0 * rgb.y);
+}
+
+#[test]
+fn sphere_silhouette_is_an_edge() {
+    use geometry::Sphere;
+    use material::DiffuseGreyMaterial;
+    use object::Object;
+
+    let sphere = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 10.0, 0.0), 2.0)),
+                             Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec!(sphere), ::camera::pinhole_camera_at_origin);
+    let unit = TraceUnit::new(0, 64, 64);
+    let (camera, _) = unit.get_camera(&scene);
+
+    // Walk along the middle row of pixels, through the centre of the sphere.
+    let edges: Vec<f32> = (0 .. 64).map(|px| {
+        let (u, v) = pixel_to_ndc(px as f32, 31.5, 64, 64);
+        let (x, y) = ndc_to_screen(u, v, unit.aspect_ratio);
+        unit.render_edge(&scene, &camera, x, y, 550.0)
+    }).collect();
+
+    // Neither the void nor the middle of the sphere are edges,
+    // but there is an edge on both sides of the sphere.
+    assert_eq!(edges[0], 0.0);
+    assert_eq!(edges[32], 0.0);
+    assert_eq!(edges[63], 0.0);
+    assert!(edges[.. 32].iter().any(|&e| e == 1.0));
+    assert!(edges[32 ..].iter().any(|&e| e == 1.0));
+}
+
+#[test]
+fn focused_screen_sampling_is_unbiased() {
+    let scene = Scene::new(Vec::new(), ::camera::pinhole_camera_at_origin);
+    let mut unit = TraceUnit::new(0, 64, 64);
+
+    // In the normals mode, every photon has probability 1.0 before the
+    // weight for its screen position is applied.
+    unit.render_mode = RenderMode::Normals;
+    unit.screen_sampling = ScreenSampling::Focused(0.2, -0.1, 0.8);
+
+    let mut n = 0;
+    let mut n_centre = 0;
+    let mut weight = 0.0;
+    let mut weight_centre = 0.0;
+    for _ in 0 .. 20 {
+        unit.render(&scene);
+        for photon in &unit.mapped_photons {
+            n += 1;
+            weight += photon.probability;
+            if photon.x.abs() < 0.5 && photon.y.abs() < 0.5 {
+                n_centre += 1;
+                weight_centre += photon.probability;
+            }
+        }
+    }
+
+    // The centre covers a quarter of the screen, but it gets more than
+    // a quarter of the photons. Weighted, it is a quarter again.
+    assert!(n_centre as f32 > 0.4 * n as f32);
+    assert!((weight / n as f32 - 1.0).abs() < 0.05);
+    assert!((weight_centre / n as f32 - 0.25).abs() < 0.02);
+}
+
+#[test]
+fn medium_absorbs_light_inside_its_volume() {
+    use geometry::{Plane, Sphere};
+    use material::{BlackBodyMaterial, Sf10GlassMaterial};
+    use medium::Medium;
+    use object::Object;
+
+    // An emissive wall at y = 5, and a glass ball of diameter 2, which may
+    // be filled with fog. The rays go through the centre, so they are not
+    // deflected.
+    let make_scene = |centre: Vector3, fog: bool| {
+        let wall = Object::new(Box::new(Plane::new(Vector3::new(0.0, -1.0, 0.0),
+                                                   Vector3::new(0.0, 5.0, 0.0))),
+                               Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+        let mut ball = Object::new(Box::new(Sphere::new(centre, 1.0)),
+                                   Reflective(Box::new(Sf10GlassMaterial)));
+        if fog { ball.set_medium(Medium::new(0.2, 0.0)); }
+        Scene::new(vec!(wall, ball), ::camera::pinhole_camera_at_origin)
+    };
+    let unit = TraceUnit::new(0, 16, 9);
+    let trace = |scene: &Scene, y: f32| {
+        let ray = Ray {
+            origin: Vector3::new(0.0, y, 0.0),
+            direction: Vector3::new(0.0, 1.0, 0.0),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        unit.trace_path(scene, ray, 0.0, &mut []).0
+    };
+
+    // A ray that passes through the ball is absorbed over its diameter only,
+    // not all the way from its origin to the wall.
+    let centre = Vector3::new(0.0, 2.0, 0.0);
+    let clear = trace(&make_scene(centre, false), 0.0);
+    let foggy = trace(&make_scene(centre, true), 0.0);
+    assert!(clear > 0.0);
+    assert!((foggy / clear - (-0.2f32 * 2.0).exp()).abs() < 1.0e-4);
+
+    // A ray that starts in the fog is absorbed until it leaves the ball.
+    let clear = trace(&make_scene(centre, false), 1.5);
+    let foggy = trace(&make_scene(centre, true), 1.5);
+    assert!((foggy / clear - (-0.2f32 * 1.5).exp()).abs() < 1.0e-4);
+
+    // A point on the surface counts as inside.
+    assert!(make_scene(centre, true).get_medium(Vector3::new(0.0, 3.0, 0.0)).is_some());
+}
+
+#[test]
+fn coloured_glass_absorbs_per_meter() {
+    use geometry::{Plane, Sphere};
+    use material::{BlackBodyMaterial, ColouredGlassMaterial, Material};
+    use object::Object;
+
+    // An emissive wall at y = 5, seen through a glass ball of diameter 2,
+    // head-on, so the ray is not deflected.
+    let glass = ColouredGlassMaterial::new(0.5, 450.0, 20.0);
+    let wall = || {
+        Object::new(Box::new(Plane::new(Vector3::new(0.0, -1.0, 0.0),
+                                        Vector3::new(0.0, 5.0, 0.0))),
+                    Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))))
+    };
+    let ball = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 2.0, 0.0), 1.0)),
+                           Reflective(Box::new(ColouredGlassMaterial::new(0.5, 450.0, 20.0))));
+    let mut scene = Scene::new(vec!(wall(), ball), ::camera::pinhole_camera_at_origin);
+    let clear_scene = Scene::new(vec!(wall()), ::camera::pinhole_camera_at_origin);
+    let unit = TraceUnit::new(0, 16, 9);
+
+    let trace = |scene: &Scene, wavelength: f32| {
+        let ray = Ray {
+            origin: Vector3::zero(),
+            direction: Vector3::new(0.0, 1.0, 0.0),
+            wavelength: wavelength,
+            probability: 1.0
+        };
+        unit.trace_path(scene, ray, 0.0, &mut []).0
+    };
+
+    // Red light is absorbed, blue light passes.
+    let absorption = glass.get_absorption(650.0);
+    assert!(absorption > 0.49);
+    assert_eq!(glass.get_absorption(450.0), 0.0);
+    assert!((trace(&scene, 450.0) / trace(&clear_scene, 450.0) - 1.0).abs() < 1.0e-4);
+
+    // The light travels 2 units through the glass; that is two meters, or
+    // if the scene is modelled in centimeters, two centimeters.
+    let expected = (-absorption * 2.0).exp();
+    assert!((trace(&scene, 650.0) / trace(&clear_scene, 650.0) - expected).abs() < 1.0e-4);
+    scene.units_per_meter = 100.0;
+    let expected = (-absorption * 0.02).exp();
+    assert!((trace(&scene, 650.0) / trace(&clear_scene, 650.0) - expected).abs() < 1.0e-4);
+}
+
+#[test]
+fn seeded_units_trace_independent_streams() {
+    let scene = Scene::new(Vec::new(), ::camera::pinhole_camera_at_origin);
+    let render_batch = |id: usize| {
+        let mut unit = TraceUnit::new(id, 8, 8);
+        unit.seed = Some(7);
+        unit.render(&scene);
+        unit.mapped_photons.iter().map(|p| p.x as f64).collect::<Vec<f64>>()
+    };
+
+    // With the same seed and ID, a unit traces exactly the same paths.
+    let a = render_batch(3);
+    let b = render_batch(4);
+    assert_eq!(a, render_batch(3));
+
+    // The screen positions of units with adjacent IDs are uncorrelated.
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let cov: f64 = a.iter().zip(b.iter()).map(|(x, y)| (x - mean_a) * (y - mean_b)).sum();
+    let var_a: f64 = a.iter().map(|x| (x - mean_a) * (x - mean_a)).sum();
+    let var_b: f64 = b.iter().map(|y| (y - mean_b) * (y - mean_b)).sum();
+    let correlation = cov / (var_a * var_b).sqrt();
+    assert!(correlation.abs() < 0.1);
+}
+
+#[test]
+fn ambient_occlusion_is_neutral_grey() {
+    use geometry::Plane;
+    use material::DiffuseGreyMaterial;
+    use object::Object;
+
+    // A wall in front of the camera, with nothing to occlude it.
+    let wall = Object::new(Box::new(Plane::new(Vector3::new(0.0, -1.0, 0.0),
+                                               Vector3::new(0.0, 5.0, 0.0))),
+                           Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec!(wall), ::camera::pinhole_camera_at_origin);
+    let mut unit = TraceUnit::new(0, 8, 8);
+    unit.render_mode = RenderMode::AmbientOcclusion(1.0);
+    unit.render(&scene);
+
+    // The wall is white everywhere, not tinted by a spectrum.
+    for photon in &unit.mapped_photons {
+        let rgb = ::srgb::xyz_to_srgb(photon.tristimulus.unwrap());
+        assert!((rgb.x - 1.0).abs() < 1.0e-2);
+        assert!((rgb.y - 1.0).abs() < 1.0e-2);
+        assert!((rgb.z - 1.0).abs() < 1.0e-2);
+    }
 }
@@ -14,10 +14,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use std::ops::{Add, Sub, Neg, Mul};
+use std::fmt;
+use std::ops::{Add, Sub, Neg, Mul, Index, IndexMut};
 use quaternion::Quaternion;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Vector3 {
     pub x: f32,
     pub y: f32,
@@ -91,6 +92,46 @@ impl Vector3 {
     pub fn reflect(self, normal: Vector3) -> Vector3 {
         self - normal * 2.0 * dot(normal, self)
     }
+
+    /// Returns the x, y or z component for axis 0, 1 or 2 respectively.
+    pub fn component(self, axis: usize) -> f32 {
+        self[axis]
+    }
+
+    /// Returns the component along the specified axis,
+    /// or `None` if the axis is not 0, 1 or 2.
+    pub fn get(self, axis: usize) -> Option<f32> {
+        match axis {
+            0 => Some(self.x),
+            1 => Some(self.y),
+            2 => Some(self.z),
+            _ => None
+        }
+    }
+}
+
+impl Index<usize> for Vector3 {
+    type Output = f32;
+
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("axis {} out of range for Vector3", axis)
+        }
+    }
+}
+
+impl IndexMut<usize> for Vector3 {
+    fn index_mut(&mut self, axis: usize) -> &mut f32 {
+        match axis {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("axis {} out of range for Vector3", axis)
+        }
+    }
 }
 
 impl Add for Vector3 {
@@ -140,3 +181,73 @@ impl Mul<f32> for Vector3 {
         }
     }
 }
+
+/// A vector with double precision components, for accumulating many
+/// small values without losing precision.
+#[derive(Copy, Clone, Debug)]
+pub struct Vector3d {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64
+}
+
+impl Vector3d {
+    pub fn zero() -> Vector3d {
+        Vector3d { x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    /// Widens a single precision vector.
+    pub fn from_vector3(v: Vector3) -> Vector3d {
+        Vector3d { x: v.x as f64, y: v.y as f64, z: v.z as f64 }
+    }
+
+    /// Rounds the vector to single precision.
+    pub fn to_vector3(self) -> Vector3 {
+        Vector3::new(self.x as f32, self.y as f32, self.z as f32)
+    }
+}
+
+impl Add for Vector3d {
+    type Output = Vector3d;
+
+    fn add(self, other: Vector3d) -> Vector3d {
+        Vector3d {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z
+        }
+    }
+}
+
+impl fmt::Display for Vector3 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
+#[test]
+fn index_returns_components() {
+    let mut v = Vector3::new(1.0, 2.0, 3.0);
+    assert_eq!(v[0], 1.0);
+    assert_eq!(v[1], 2.0);
+    assert_eq!(v[2], 3.0);
+    assert_eq!(v.component(1), 2.0);
+    assert_eq!(v.get(2), Some(3.0));
+    assert_eq!(v.get(3), None);
+
+    v[1] = 5.0;
+    assert_eq!(v.y, 5.0);
+}
+
+#[test]
+#[should_panic]
+fn index_out_of_range_panics() {
+    let v = Vector3::new(1.0, 2.0, 3.0);
+    let _ = v[3];
+}
+
+#[test]
+fn display_shows_components() {
+    let v = Vector3::new(1.0, 2.5, -3.0);
+    assert_eq!(format!("{}", v), "(1, 2.5, -3)");
+}